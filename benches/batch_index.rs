@@ -0,0 +1,62 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use csv::ReaderBuilder;
+use std::fs::File;
+use zzap::search::{engine_by_name, DEFAULT_LIMIT};
+use zzap::storage::Storage;
+
+fn documents() -> Vec<(String, String)> {
+    let file = File::open("assets/tests/search_synthetic_dataset.csv").unwrap();
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(file);
+    reader
+        .records()
+        .enumerate()
+        .map(|(id, record)| (id.to_string(), record.unwrap()[0].to_string()))
+        .collect()
+}
+
+/// Indexing the dataset at once, which the dash engines spread over the rayon thread pool, against
+/// indexing it a document at a time.
+fn batch_index(c: &mut Criterion) {
+    let documents = documents();
+    let storage = Storage::new("bench.db");
+    let mut group = c.benchmark_group("batch_index");
+    // indexing the whole dataset takes about a second
+    group.sample_size(10);
+
+    for name in ["std", "dash", "dash2"] {
+        let engine = || engine_by_name(name, DEFAULT_LIMIT).unwrap();
+        group.bench_function(BenchmarkId::new("batch", name), |b| {
+            b.iter_batched(
+                || (engine(), documents.clone()),
+                |(engine, documents)| {
+                    engine
+                        .batch_index(&storage, "bucket", "collection", documents)
+                        .unwrap();
+                    engine
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("one_by_one", name), |b| {
+            b.iter_batched(
+                engine,
+                |engine| {
+                    for (id, content) in &documents {
+                        engine
+                            .index(&storage, "bucket", "collection", id, content)
+                            .unwrap();
+                    }
+                    engine
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, batch_index);
+criterion_main!(benches);
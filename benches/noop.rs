@@ -0,0 +1,71 @@
+#![cfg(target_os = "linux")]
+
+use iai_callgrind::{library_benchmark, library_benchmark_group, main};
+use std::hint::black_box;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use zzap::config::ZzapConfig;
+use zzap::encryption::{Encryption, MockEncryptor};
+use zzap::search::StdSearchEngine;
+use zzap::server::ZzapServer;
+use zzap::storage::Storage;
+
+const BENCH_PORT: u16 = 13499;
+
+struct ServerSetup {
+    // keeps the server running for the duration of the benchmark
+    _runtime: Runtime,
+    stream: TcpStream,
+    requests: usize,
+}
+
+fn server_setup(requests: usize) -> ServerSetup {
+    let addr = SocketAddr::from(([127, 0, 0, 1], BENCH_PORT));
+    let runtime = Runtime::new().unwrap();
+    let server = ZzapServer::new(
+        addr,
+        Storage::new("bench.db"),
+        MockEncryptor::new(),
+        Box::new(StdSearchEngine::new()),
+        ZzapConfig::default(),
+    );
+    runtime.spawn(async move { server.run().await.unwrap() });
+
+    let stream = loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => break stream,
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    ServerSetup {
+        _runtime: runtime,
+        stream,
+        requests,
+    }
+}
+
+#[library_benchmark(setup = server_setup)]
+#[bench::single(1)]
+#[bench::hundred(100)]
+fn noop(setup: ServerSetup) {
+    let mut writer = setup.stream.try_clone().unwrap();
+    let mut reader = BufReader::new(setup.stream);
+    let mut response = String::new();
+
+    for _ in 0..setup.requests {
+        writer.write_all(b"NOOP\n").unwrap();
+        response.clear();
+        reader.read_line(&mut response).unwrap();
+        assert_eq!(black_box(&response), "+OK\n");
+    }
+}
+
+library_benchmark_group!(
+    name = noop_group;
+    benchmarks = noop
+);
+
+main!(library_benchmark_groups = noop_group);
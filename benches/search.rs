@@ -7,7 +7,8 @@ use std::fs::File;
 use std::hint::black_box;
 use std::ops::Range;
 use zzap::search::{
-    BTreeSearchEngine, Dash2SearchEngine, DashSearchEngine, SearchEngine, StdSearchEngine,
+    BTreeSearchEngine, Bm25SearchEngine, Dash2SearchEngine, DashSearchEngine, NgramSearchEngine,
+    SearchEngine, StdSearchEngine,
 };
 use zzap::storage::Storage;
 
@@ -23,6 +24,8 @@ fn engine_setup(engine_type: &str) -> EngineSetup {
         "dash" => Box::new(DashSearchEngine::new()),
         "dash2" => Box::new(Dash2SearchEngine::new()),
         "std" => Box::new(StdSearchEngine::new()),
+        "bm25" => Box::new(Bm25SearchEngine::new()),
+        "ngram" => Box::new(NgramSearchEngine::new()),
         _ => panic!("Unknown engine type"),
     };
     let storage = Storage::new("bench.db");
@@ -69,6 +72,8 @@ fn search_setup(engine_type: &str) -> EngineSetup {
 #[bench::dash("dash")]
 #[bench::dash2("dash2")]
 #[bench::std("std")]
+#[bench::bm25("bm25")]
+#[bench::ngram("ngram")]
 fn index(setup: EngineSetup) {
     black_box(
         setup
@@ -83,6 +88,8 @@ fn index(setup: EngineSetup) {
 #[bench::dash("dash")]
 #[bench::dash2("dash2")]
 #[bench::std("std")]
+#[bench::bm25("bm25")]
+#[bench::ngram("ngram")]
 fn search(setup: EngineSetup) {
     black_box(
         setup
@@ -102,9 +109,21 @@ fn search(setup: EngineSetup) {
     );
 }
 
+// "in" is in close to half of the articles, so every engine goes through a huge match set
+#[library_benchmark(setup = search_setup)]
+#[bench::btree("btree")]
+#[bench::dash("dash")]
+#[bench::dash2("dash2")]
+#[bench::std("std")]
+#[bench::bm25("bm25")]
+#[bench::ngram("ngram")]
+fn search_common_token(setup: EngineSetup) {
+    black_box(setup.engine.search("bucket", "collection", "in").unwrap());
+}
+
 library_benchmark_group!(
     name = search_group;
-    benchmarks = index, search
+    benchmarks = index, search, search_common_token
 );
 
 main!(library_benchmark_groups = search_group);
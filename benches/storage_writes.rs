@@ -0,0 +1,59 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use std::sync::RwLock;
+use std::thread;
+use zzap::storage::{Document, Storage, StorageOperations};
+
+const WRITERS: usize = 4;
+const DOCUMENTS: usize = 1000;
+
+/// Runs `write` for every document of every writer, each writer on a thread of its own and in a
+/// collection of its own.
+fn write_concurrently(write: impl Fn(&str, Document) + Sync) {
+    thread::scope(|scope| {
+        for writer in 0..WRITERS {
+            let write = &write;
+            scope.spawn(move || {
+                let collection = format!("c{}", writer);
+                for i in 0..DOCUMENTS {
+                    write(&collection, Document::new(&i.to_string(), "some content"));
+                }
+            });
+        }
+    });
+}
+
+/// Writers sharing the storage, which locks per bucket and collection, against writers taking
+/// turns behind a lock around the whole storage as they did before.
+fn storage_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("storage_writes");
+
+    group.bench_function(BenchmarkId::new("shared", WRITERS), |b| {
+        b.iter_batched(
+            || Storage::new("bench.db"),
+            |storage| {
+                write_concurrently(|collection, document| {
+                    storage.add_document("b", collection, document).unwrap()
+                });
+                storage
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function(BenchmarkId::new("outer_lock", WRITERS), |b| {
+        b.iter_batched(
+            || RwLock::new(Storage::new("bench.db")),
+            |storage| {
+                write_concurrently(|collection, document| {
+                    let storage = storage.write().unwrap();
+                    storage.add_document("b", collection, document).unwrap()
+                });
+                storage
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, storage_writes);
+criterion_main!(benches);
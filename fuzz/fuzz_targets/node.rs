@@ -2,20 +2,23 @@
 
 use libfuzzer_sys::fuzz_target;
 use std::sync::{Arc, RwLock};
+use zzap::config::ZzapConfig;
 use zzap::encryption::MockEncryptor;
 use zzap::protocol::Request;
-use zzap::search::StdSearchEngine;
+use zzap::search::{DynSearchEngine, StdSearchEngine};
 use zzap::server::handler::handle_request;
 use zzap::storage::Storage;
 
 fuzz_target!(|requests: Vec<Request>| {
-    let storage = Arc::new(RwLock::new(Storage::new("test.db")));
+    let storage = Storage::new("test.db");
     let encryptor = MockEncryptor;
-    let search_engine = Arc::new(RwLock::new(StdSearchEngine::new()));
+    let search_engine: Arc<RwLock<DynSearchEngine>> =
+        Arc::new(RwLock::new(Box::new(StdSearchEngine::new())));
+    let config = RwLock::new(ZzapConfig::default());
 
     tokio::runtime::Runtime::new().unwrap().block_on(async {
         for req in requests {
-            let _ = handle_request(req, &storage, &encryptor, &search_engine).await;
+            let _ = handle_request(req, &storage, &encryptor, &search_engine, &config, None).await;
         }
     });
 });
@@ -0,0 +1,435 @@
+//! Async client of the zzap protocol, sending [`Request`]s and reading back whole [`Response`]s.
+
+use crate::protocol::{DecodingError, Message, Request, Response};
+use crate::search::SearchOptions;
+use std::fmt;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Escapes text for a query to search it literally, i.e. with [`ZzapClient::search`].
+pub use crate::lang::query::escape_word;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    Decoding(DecodingError),
+    /// The server answered with an error, holding its message
+    Server(String),
+    /// The server answered with a response the command does not return
+    UnexpectedResponse(Response),
+    /// The response is longer than the client accepts, see
+    /// [`ZzapClient::with_max_response_bytes`]
+    ResponseTooLarge,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "I/O error: {}", e),
+            ClientError::Decoding(e) => write!(f, "Decoding error: {}", e),
+            ClientError::Server(message) => write!(f, "Server error: {}", message),
+            ClientError::UnexpectedResponse(response) => {
+                write!(f, "Unexpected response: {:?}", response)
+            }
+            ClientError::ResponseTooLarge => write!(f, "Response too large"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<DecodingError> for ClientError {
+    fn from(e: DecodingError) -> Self {
+        ClientError::Decoding(e)
+    }
+}
+
+/// Default of the longest response a [`ZzapClient`] reads, 64 MiB like the longest request a
+/// server reads by default.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = crate::config::DEFAULT_MAX_REQUEST_BYTES;
+
+/// Connection to a server, over a plain TCP stream unless another one is given, i.e. a TLS stream.
+///
+/// Requests are sent one at a time, each method returns once its response is read. Buckets,
+/// collections and ids are sent as they are, so they must not contain whitespace.
+pub struct ZzapClient<S = TcpStream> {
+    stream: BufReader<S>,
+    max_response_bytes: usize,
+}
+
+impl ZzapClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, ClientError> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+}
+
+impl<S> ZzapClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
+    }
+
+    /// Fails the requests whose response is longer than `max_response_bytes` rather than reading
+    /// it, so a length announced by the server can't exhaust the memory of the client.
+    pub fn with_max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    /// Sends any request and returns the response, errors of the server included.
+    pub async fn request(&mut self, request: Request) -> Result<Response, ClientError> {
+        self.stream.write_all(&request.to_bytes()).await?;
+        read_response(&mut self.stream, self.max_response_bytes).await
+    }
+
+    pub async fn ping(&mut self) -> Result<(), ClientError> {
+        match self.request(Request::Ping).await? {
+            Response::Success => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Stores the document, overwriting the one with the same id if any.
+    pub async fn set(
+        &mut self,
+        bucket: &str,
+        collection: &str,
+        id: &str,
+        content: &str,
+    ) -> Result<(), ClientError> {
+        let request = Request::Set {
+            bucket: bucket.to_string(),
+            collection: collection.to_string(),
+            id: id.to_string(),
+            content: content.to_string(),
+            key: None,
+            ttl: None,
+        };
+        // the server may be configured to tell whether the document is new
+        match self.request(request).await? {
+            Response::Success | Response::Created | Response::Updated => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The content of the document, `None` if there is no such document.
+    pub async fn get(
+        &mut self,
+        bucket: &str,
+        collection: &str,
+        id: &str,
+    ) -> Result<Option<String>, ClientError> {
+        let request = Request::Get {
+            bucket: bucket.to_string(),
+            collection: collection.to_string(),
+            id: id.to_string(),
+            key: None,
+        };
+        match self.request(request).await? {
+            Response::BulkString(content) => Ok(Some(content)),
+            Response::Null => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Ids of the documents matching the query, best ranked first.
+    pub async fn search(
+        &mut self,
+        bucket: &str,
+        collection: &str,
+        query: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let request = Request::Search {
+            bucket: bucket.to_string(),
+            collection: collection.to_string(),
+            query: query.to_string(),
+            options: SearchOptions::default(),
+        };
+        match self.request(request).await? {
+            Response::Array(ids) => Ok(ids),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn remove(
+        &mut self,
+        bucket: &str,
+        collection: &str,
+        id: &str,
+    ) -> Result<(), ClientError> {
+        let request = Request::Remove {
+            bucket: bucket.to_string(),
+            collection: collection.to_string(),
+            id: id.to_string(),
+        };
+        match self.request(request).await? {
+            Response::Success => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> ClientError {
+    match response {
+        Response::Error(message) => ClientError::Server(message),
+        response => ClientError::UnexpectedResponse(response),
+    }
+}
+
+/// Reads a whole response, reading on through the items of an array or a stream and through
+/// bulk strings holding newlines. Fails with [`ClientError::ResponseTooLarge`] past `max_bytes`.
+pub async fn read_response(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    max_bytes: usize,
+) -> Result<Response, ClientError> {
+    let mut bytes = Vec::new();
+    read_line(reader, &mut bytes, max_bytes).await?;
+
+    if bytes == b"*STREAM\n" {
+        loop {
+            let start = bytes.len();
+            read_line(reader, &mut bytes, max_bytes).await?;
+            if &bytes[start..] == b"*END\n" {
+                break;
+            }
+            read_bulk_content(reader, &mut bytes, start, max_bytes).await?;
+        }
+    } else if bytes.starts_with(b"*") {
+        let count: usize = std::str::from_utf8(&bytes[1..bytes.len() - 1])
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .ok_or(DecodingError::InvalidResponseFormat)?;
+        for _ in 0..count {
+            let start = bytes.len();
+            read_line(reader, &mut bytes, max_bytes).await?;
+            read_bulk_content(reader, &mut bytes, start, max_bytes).await?;
+        }
+    } else if bytes.starts_with(b"$") {
+        read_bulk_content(reader, &mut bytes, 0, max_bytes).await?;
+    }
+
+    Ok(Response::from_bytes(&bytes)?)
+}
+
+/// Appends the next line to the bytes, newline included, as long as they stay within `max_bytes`.
+async fn read_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    bytes: &mut Vec<u8>,
+    max_bytes: usize,
+) -> Result<(), ClientError> {
+    let allowed = max_bytes.saturating_sub(bytes.len()) as u64;
+    // one more byte than allowed tells a line too long from a line cut short
+    let read = reader
+        .take(allowed.saturating_add(1))
+        .read_until(b'\n', bytes)
+        .await?;
+    if bytes.len() > max_bytes {
+        return Err(ClientError::ResponseTooLarge);
+    }
+    if read == 0 || !bytes.ends_with(b"\n") {
+        return Err(ClientError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(())
+}
+
+/// Appends the content announced by the `$<length>` header starting at `header_start`, along with
+/// the newline closing it, as long as the bytes stay within `max_bytes`. A null, `$-1`, has no
+/// content.
+async fn read_bulk_content(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    bytes: &mut Vec<u8>,
+    header_start: usize,
+    max_bytes: usize,
+) -> Result<(), ClientError> {
+    let header = std::str::from_utf8(&bytes[header_start..bytes.len() - 1])
+        .ok()
+        .and_then(|header| header.strip_prefix('$'))
+        .ok_or(DecodingError::InvalidResponseFormat)?;
+    if header == "-1" {
+        return Ok(());
+    }
+    let len: usize = header
+        .parse()
+        .map_err(|_| DecodingError::InvalidResponseFormat)?;
+
+    let start = bytes.len();
+    let end = len
+        .checked_add(1)
+        .and_then(|item_len| start.checked_add(item_len))
+        .filter(|&end| end <= max_bytes)
+        .ok_or(ClientError::ResponseTooLarge)?;
+    bytes.resize(end, 0);
+    reader.read_exact(&mut bytes[start..]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ZzapConfig;
+    use crate::encryption::MockEncryptor;
+    use crate::protocol::ResponseStream;
+    use crate::search::StdSearchEngine;
+    use crate::server::ZzapServer;
+    use crate::storage::Storage;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    const PERSISTENCE_PATH: &str = "test_client.db";
+
+    /// Serves until the sender is used or dropped.
+    async fn spawn_server() -> (SocketAddr, oneshot::Sender<()>, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ZzapServer::new(
+            addr,
+            Storage::new(PERSISTENCE_PATH),
+            MockEncryptor,
+            Box::new(StdSearchEngine::new()),
+            ZzapConfig {
+                persist_interval: None,
+                ..Default::default()
+            },
+        );
+        let (shutdown, shutdown_received) = oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            server
+                .serve(listener, async {
+                    let _ = shutdown_received.await;
+                })
+                .await
+                .unwrap();
+        });
+        (addr, shutdown, serving)
+    }
+
+    #[tokio::test]
+    async fn test_client_round_trip() {
+        let (addr, shutdown, serving) = spawn_server().await;
+        let mut client = ZzapClient::connect(addr).await.unwrap();
+
+        client.ping().await.unwrap();
+        client
+            .set("b", "c", "1", "hello world\nsecond line")
+            .await
+            .unwrap();
+        client.set("b", "c", "2", "hello again").await.unwrap();
+        assert_eq!(
+            client.get("b", "c", "1").await.unwrap().as_deref(),
+            Some("hello world\nsecond line")
+        );
+        assert_eq!(client.search("b", "c", "hello").await.unwrap(), ["1", "2"]);
+        assert_eq!(client.search("b", "c", "again").await.unwrap(), ["2"]);
+
+        client.remove("b", "c", "1").await.unwrap();
+        assert_eq!(client.get("b", "c", "1").await.unwrap(), None);
+        assert_eq!(
+            client.search("b", "c", "world").await.unwrap(),
+            Vec::<String>::new()
+        );
+
+        // errors of the server are told apart from the responses of other commands
+        assert!(matches!(
+            client.search("missing", "c", "hello").await,
+            Err(ClientError::Server(_))
+        ));
+        let response = client
+            .request(Request::Exists {
+                bucket: "b".into(),
+                collection: "c".into(),
+                id: "2".into(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response, Response::Integer(1));
+
+        drop(client);
+        shutdown.send(()).unwrap();
+        serving.await.unwrap();
+        let _ = std::fs::remove_file(PERSISTENCE_PATH);
+        let _ = std::fs::remove_file("test_client.zzap_collections");
+    }
+
+    #[tokio::test]
+    async fn test_read_response() {
+        let responses = [
+            Response::Success,
+            Response::Error("message".to_string()),
+            Response::Integer(-3),
+            Response::Null,
+            Response::BulkString("line\n$2\nnot a header".to_string()),
+            Response::Array(vec!["a\nb".to_string(), String::new()]),
+            Response::NullableArray(vec![Some("a".to_string()), None]),
+        ];
+        let mut bytes = Vec::new();
+        for response in &responses {
+            bytes.extend_from_slice(&response.to_bytes());
+        }
+        let items = ["first\nitem", "second"].map(String::from);
+        let stream = Response::Stream(ResponseStream::new(items.clone().into_iter()));
+        bytes.extend_from_slice(&stream.to_bytes());
+
+        // read one after the other, each stops at its own end
+        let mut reader = &bytes[..];
+        for response in responses {
+            assert_eq!(
+                read_response(&mut reader, DEFAULT_MAX_RESPONSE_BYTES)
+                    .await
+                    .unwrap(),
+                response
+            );
+        }
+        assert_eq!(
+            read_response(&mut reader, DEFAULT_MAX_RESPONSE_BYTES)
+                .await
+                .unwrap(),
+            Response::Array(items.to_vec())
+        );
+
+        let mut cut_short: &[u8] = b"$10\nhello";
+        assert!(matches!(
+            read_response(&mut cut_short, DEFAULT_MAX_RESPONSE_BYTES).await,
+            Err(ClientError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_response_too_large() {
+        let cases: Vec<&[u8]> = vec![
+            // a length that overflows, or that is past the maximum
+            b"$18446744073709551615\nhello\n",
+            b"$17\nhello\n",
+            b"*2\n$5\nhello\n$5\nworld\n",
+            // a line that never ends
+            b"+OK, and on and on\n",
+        ];
+        for input in cases {
+            let mut reader = input;
+            assert!(matches!(
+                read_response(&mut reader, 16).await,
+                Err(ClientError::ResponseTooLarge)
+            ));
+        }
+
+        let mut reader: &[u8] = b"$5\nhello\n";
+        assert_eq!(
+            read_response(&mut reader, 9).await.unwrap(),
+            Response::BulkString("hello".to_string())
+        );
+    }
+}
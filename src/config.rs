@@ -0,0 +1,636 @@
+use crate::protocol::ParseMode;
+use crate::search::{DEFAULT_LIMIT, MAX_PAGE_BOUND};
+use crate::storage::Compression;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+/// Server configuration
+#[derive(Clone, Debug)]
+pub struct ZzapConfig {
+    /// Address the server listens on
+    pub addr: SocketAddr,
+    /// File the data is saved to and loaded from on startup
+    pub persistence_path: PathBuf,
+    /// Codec and level snapshots are written with. Snapshots are read whatever they were
+    /// written with, so it can be changed between restarts.
+    pub compression: Compression,
+    /// Name of the search engine in use, the one started with until `SETENGINE` swaps in
+    /// another, see [`engine_by_name`]
+    ///
+    /// [`engine_by_name`]: crate::search::engine_by_name
+    pub engine: String,
+    /// Ids a search returns when it sets no `LIMIT`, passed on to the search engine
+    pub max_results: usize,
+    /// Time between two automatic saves of the data, never saved automatically if `None`
+    pub persist_interval: Option<Duration>,
+    /// How strictly incoming requests are parsed
+    pub parse_mode: ParseMode,
+    /// Least severe events logged, i.e. `DEBUG` also logs every request
+    pub log_level: LevelFilter,
+    /// Bucket used by commands that leave it out with `_`
+    pub default_bucket: Option<String>,
+    /// Collection used by commands that leave it out with `_`
+    pub default_collection: Option<String>,
+    /// How buckets, collections and ids are rewritten before reaching the storage and the index.
+    /// Only set on startup, as keys stored under another policy would no longer be reachable.
+    pub key_normalization: KeyNormalization,
+    /// Connections served at once, those past it are answered with an error and closed. Unlimited
+    /// if `None`, only set on startup.
+    pub max_connections: Option<usize>,
+    /// Bytes a single connection may read and write in total before it is closed, unlimited if `None`
+    pub max_connection_bytes: Option<u64>,
+    /// Bytes a single request may take, content included, unlimited if `None`
+    pub max_request_bytes: Option<usize>,
+    /// Time a connection may take to send a complete request before it is closed, never if `None`
+    pub read_timeout: Option<Duration>,
+    /// `SET` returns once the document is stored and leaves indexing to a background worker,
+    /// so it becomes searchable eventually. `SYNC` waits for the backlog to drain.
+    pub async_indexing: bool,
+    /// `SET` answers `+CREATED` or `+UPDATED` instead of `+OK`, so clients can detect overwrites
+    pub report_set_outcome: bool,
+    /// Password connections must send with `AUTH` before any other command, open to anyone if
+    /// `None`. Only set on startup, and never reported by `CONFIG`.
+    pub password: Option<String>,
+    /// PEM file of the certificate chain connections are encrypted with, together with
+    /// `tls_key_path`. Connections are plaintext if `None`.
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM file of the private key of the certificate
+    pub tls_key_path: Option<PathBuf>,
+}
+
+impl Default for ZzapConfig {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([0, 0, 0, 0], 13413)),
+            persistence_path: PathBuf::from("storage.db"),
+            compression: Compression::None,
+            engine: "std".to_string(),
+            max_results: DEFAULT_LIMIT,
+            persist_interval: Some(Duration::from_secs(60)),
+            parse_mode: ParseMode::default(),
+            log_level: LevelFilter::INFO,
+            default_bucket: None,
+            default_collection: None,
+            key_normalization: KeyNormalization::None,
+            max_connections: None,
+            max_connection_bytes: None,
+            max_request_bytes: Some(DEFAULT_MAX_REQUEST_BYTES),
+            read_timeout: None,
+            async_indexing: false,
+            report_set_outcome: false,
+            password: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+        }
+    }
+}
+
+/// Rewriting applied to buckets, collections and ids, so that near-duplicate keys sent by
+/// clients end up as the same one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyNormalization {
+    /// Keys are taken verbatim
+    #[default]
+    None,
+    /// Leading and trailing whitespace is removed
+    Trim,
+    /// Keys are trimmed and lowercased
+    Lowercase,
+}
+
+impl KeyNormalization {
+    pub fn normalize(&self, key: String) -> String {
+        match self {
+            KeyNormalization::None => key,
+            KeyNormalization::Trim if key.trim().len() == key.len() => key,
+            KeyNormalization::Trim => key.trim().to_string(),
+            KeyNormalization::Lowercase => key.trim().to_lowercase(),
+        }
+    }
+}
+
+impl fmt::Display for KeyNormalization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyNormalization::None => write!(f, "none"),
+            KeyNormalization::Trim => write!(f, "trim"),
+            KeyNormalization::Lowercase => write!(f, "lowercase"),
+        }
+    }
+}
+
+impl FromStr for KeyNormalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(KeyNormalization::None),
+            "trim" => Ok(KeyNormalization::Trim),
+            "lowercase" => Ok(KeyNormalization::Lowercase),
+            _ => Err(format!(
+                "invalid key normalization {}, expected none, trim or lowercase",
+                s
+            )),
+        }
+    }
+}
+
+/// Default of [`ZzapConfig::max_request_bytes`], 64 MiB.
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 64 * 1024 * 1024;
+
+/// Settings in the order `CONFIG LIST` reports them.
+const SETTINGS: &[&str] = &[
+    "PARSEMODE",
+    "DEFAULTBUCKET",
+    "DEFAULTCOLLECTION",
+    "MAXCONNECTIONS",
+    "MAXCONNECTIONBYTES",
+    "MAXREQUESTBYTES",
+    "READTIMEOUT",
+    "MAXRESULTS",
+    "AUTOSAVEINTERVAL",
+    "ASYNCINDEXING",
+    "REPORTSETOUTCOME",
+    "KEYNORMALIZATION",
+    "LOGLEVEL",
+];
+
+/// Wire representation of a setting left unset.
+const NONE: &str = "none";
+
+impl ZzapConfig {
+    /// Current value of a setting, in its wire representation.
+    pub fn get(&self, setting: &str) -> Result<String, String> {
+        Ok(match setting.to_uppercase().as_str() {
+            "PARSEMODE" => match self.parse_mode {
+                ParseMode::Lenient => "lenient".to_string(),
+                ParseMode::Strict => "strict".to_string(),
+            },
+            "LOGLEVEL" => self.log_level.to_string().to_lowercase(),
+            "DEFAULTBUCKET" => self.default_bucket.clone().unwrap_or(NONE.to_string()),
+            "DEFAULTCOLLECTION" => self.default_collection.clone().unwrap_or(NONE.to_string()),
+            "MAXCONNECTIONS" => self
+                .max_connections
+                .map_or(NONE.to_string(), |max| max.to_string()),
+            "MAXCONNECTIONBYTES" => self
+                .max_connection_bytes
+                .map_or(NONE.to_string(), |bytes| bytes.to_string()),
+            "MAXREQUESTBYTES" => self
+                .max_request_bytes
+                .map_or(NONE.to_string(), |bytes| bytes.to_string()),
+            "READTIMEOUT" => self
+                .read_timeout
+                .map_or(NONE.to_string(), |timeout| timeout.as_secs().to_string()),
+            "MAXRESULTS" => self.max_results.to_string(),
+            "AUTOSAVEINTERVAL" => self
+                .persist_interval
+                .map_or(NONE.to_string(), |interval| interval.as_secs().to_string()),
+            "ASYNCINDEXING" => self.async_indexing.to_string(),
+            "REPORTSETOUTCOME" => self.report_set_outcome.to_string(),
+            "KEYNORMALIZATION" => self.key_normalization.to_string(),
+            _ => return Err(format!("unknown setting {}", setting)),
+        })
+    }
+
+    /// Every setting with its current value.
+    pub fn list(&self) -> Vec<(&'static str, String)> {
+        SETTINGS
+            .iter()
+            .map(|&setting| (setting, self.get(setting).unwrap_or_default()))
+            .collect()
+    }
+
+    /// Updates a setting from its wire representation, taking effect with the next request.
+    ///
+    /// Settings the server only reads on startup are rejected.
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), String> {
+        match setting.to_uppercase().as_str() {
+            "PARSEMODE" => {
+                self.parse_mode = match value.to_lowercase().as_str() {
+                    "lenient" => ParseMode::Lenient,
+                    "strict" => ParseMode::Strict,
+                    _ => return Err(invalid_value(setting, value)),
+                }
+            }
+            "LOGLEVEL" => {
+                self.log_level =
+                    parse_log_level(value).map_err(|_| invalid_value(setting, value))?
+            }
+            "DEFAULTBUCKET" => self.default_bucket = parse_optional(value, |v| Ok(v.to_string()))?,
+            "DEFAULTCOLLECTION" => {
+                self.default_collection = parse_optional(value, |v| Ok(v.to_string()))?
+            }
+            "MAXCONNECTIONBYTES" => {
+                self.max_connection_bytes =
+                    parse_optional(value, |v| v.parse().map_err(|_| invalid_value(setting, v)))?
+            }
+            "MAXREQUESTBYTES" => {
+                self.max_request_bytes =
+                    parse_optional(value, |v| v.parse().map_err(|_| invalid_value(setting, v)))?
+            }
+            "READTIMEOUT" => {
+                // a zero timeout would close every connection before its first request
+                self.read_timeout = parse_optional(value, |v| match v.parse() {
+                    Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+                    _ => Err(invalid_value(setting, v)),
+                })?
+            }
+            "MAXRESULTS" => {
+                self.max_results = match value.parse() {
+                    Ok(max) if (1..=MAX_PAGE_BOUND).contains(&max) => max,
+                    _ => return Err(invalid_value(setting, value)),
+                }
+            }
+            "AUTOSAVEINTERVAL" => {
+                self.persist_interval = parse_optional(value, |v| match v.parse() {
+                    Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+                    _ => Err(invalid_value(setting, v)),
+                })?
+            }
+            "REPORTSETOUTCOME" => {
+                self.report_set_outcome =
+                    value.parse().map_err(|_| invalid_value(setting, value))?
+            }
+            "ASYNCINDEXING" | "KEYNORMALIZATION" | "MAXCONNECTIONS" => {
+                return Err(format!("{} can only be changed on startup", setting));
+            }
+            _ => return Err(format!("unknown setting {}", setting)),
+        }
+        Ok(())
+    }
+}
+
+impl ZzapConfig {
+    /// Defaults overridden by the `ZZAP_ADDR`, `ZZAP_PERSISTENCE_PATH`, `ZZAP_COMPRESSION`,
+    /// `ZZAP_ENGINE`, `ZZAP_PERSIST_INTERVAL`, `ZZAP_KEY_NORMALIZATION`, `ZZAP_LOG_LEVEL`,
+    /// `ZZAP_MAX_CONNECTIONS`, `ZZAP_ASYNC_INDEXING`, `ZZAP_PASSWORD`, `ZZAP_TLS_CERT` and
+    /// `ZZAP_TLS_KEY` environment variables, then by the `--addr`, `--persistence-path`,
+    /// `--compression`, `--engine`, `--persist-interval`, `--key-normalization`, `--log-level`,
+    /// `--max-connections`, `--async-indexing`, `--password`, `--tls-cert` and `--tls-key`
+    /// command line arguments. The interval is in seconds, `0` disables automatic saves.
+    /// Compression is a codec, `none`, `zstd` or `deflate`, with an optional level as in
+    /// `zstd:19`. Key normalization is `none`, `trim` or `lowercase`. The log level is `off`,
+    /// `error`, `warn`, `info`, `debug` or `trace`. The maximum of connections is a number, `0`
+    /// lifts the limit. Asynchronous indexing is `true` or `false`.
+    pub fn from_env_and_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = Self::default();
+        config.apply_overrides(|name| std::env::var(name).ok(), args)?;
+        Ok(config)
+    }
+
+    fn apply_overrides(
+        &mut self,
+        var: impl Fn(&str) -> Option<String>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<(), String> {
+        if let Some(addr) = var("ZZAP_ADDR") {
+            self.addr = parse_addr(&addr)?;
+        }
+        if let Some(path) = var("ZZAP_PERSISTENCE_PATH") {
+            self.persistence_path = PathBuf::from(path);
+        }
+        if let Some(compression) = var("ZZAP_COMPRESSION") {
+            self.compression = compression.parse()?;
+        }
+        if let Some(engine) = var("ZZAP_ENGINE") {
+            self.engine = engine;
+        }
+        if let Some(interval) = var("ZZAP_PERSIST_INTERVAL") {
+            self.persist_interval = parse_interval(&interval)?;
+        }
+        if let Some(normalization) = var("ZZAP_KEY_NORMALIZATION") {
+            self.key_normalization = normalization.parse()?;
+        }
+        if let Some(level) = var("ZZAP_LOG_LEVEL") {
+            self.log_level = parse_log_level(&level)?;
+        }
+        if let Some(max) = var("ZZAP_MAX_CONNECTIONS") {
+            self.max_connections = parse_max_connections(&max)?;
+        }
+        if let Some(enabled) = var("ZZAP_ASYNC_INDEXING") {
+            self.async_indexing = parse_async_indexing(&enabled)?;
+        }
+        if let Some(password) = var("ZZAP_PASSWORD") {
+            self.password = Some(password);
+        }
+        if let Some(path) = var("ZZAP_TLS_CERT") {
+            self.tls_cert_path = Some(PathBuf::from(path));
+        }
+        if let Some(path) = var("ZZAP_TLS_KEY") {
+            self.tls_key_path = Some(PathBuf::from(path));
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+            match arg.as_str() {
+                "--addr" => self.addr = parse_addr(&value()?)?,
+                "--persistence-path" => self.persistence_path = PathBuf::from(value()?),
+                "--compression" => self.compression = value()?.parse()?,
+                "--engine" => self.engine = value()?,
+                "--persist-interval" => self.persist_interval = parse_interval(&value()?)?,
+                "--key-normalization" => self.key_normalization = value()?.parse()?,
+                "--log-level" => self.log_level = parse_log_level(&value()?)?,
+                "--max-connections" => self.max_connections = parse_max_connections(&value()?)?,
+                "--async-indexing" => self.async_indexing = parse_async_indexing(&value()?)?,
+                "--password" => self.password = Some(value()?),
+                "--tls-cert" => self.tls_cert_path = Some(PathBuf::from(value()?)),
+                "--tls-key" => self.tls_key_path = Some(PathBuf::from(value()?)),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return Err("TLS needs both a certificate and a private key".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, String> {
+    addr.parse()
+        .map_err(|_| format!("invalid address {}, expected ip:port", addr))
+}
+
+fn parse_interval(seconds: &str) -> Result<Option<Duration>, String> {
+    match seconds.parse() {
+        Ok(0) => Ok(None),
+        Ok(seconds) => Ok(Some(Duration::from_secs(seconds))),
+        Err(_) => Err(format!("invalid interval {}, expected seconds", seconds)),
+    }
+}
+
+fn parse_max_connections(max: &str) -> Result<Option<usize>, String> {
+    match max.parse() {
+        Ok(0) => Ok(None),
+        Ok(max) => Ok(Some(max)),
+        Err(_) => Err(format!(
+            "invalid maximum of connections {}, expected a number",
+            max
+        )),
+    }
+}
+
+fn parse_async_indexing(enabled: &str) -> Result<bool, String> {
+    enabled.parse().map_err(|_| {
+        format!(
+            "invalid asynchronous indexing {}, expected true or false",
+            enabled
+        )
+    })
+}
+
+fn parse_log_level(level: &str) -> Result<LevelFilter, String> {
+    level.parse().map_err(|_| {
+        format!(
+            "invalid log level {}, expected off, error, warn, info, debug or trace",
+            level
+        )
+    })
+}
+
+fn parse_optional<T>(
+    value: &str,
+    parse: impl FnOnce(&str) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    if value.eq_ignore_ascii_case(NONE) {
+        Ok(None)
+    } else {
+        parse(value).map(Some)
+    }
+}
+
+fn invalid_value(setting: &str, value: &str) -> String {
+    format!("invalid value {} for {}", value, setting)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_and_set() {
+        let mut config = ZzapConfig::default();
+        assert_eq!(config.get("maxconnectionbytes"), Ok("none".to_string()));
+
+        config.set("MAXCONNECTIONBYTES", "1024").unwrap();
+        assert_eq!(config.max_connection_bytes, Some(1024));
+        config.set("DEFAULTBUCKET", "posts").unwrap();
+        config.set("PARSEMODE", "strict").unwrap();
+        config.set("READTIMEOUT", "30").unwrap();
+        assert_eq!(config.read_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(
+            config.list(),
+            [
+                ("PARSEMODE", "strict".to_string()),
+                ("DEFAULTBUCKET", "posts".to_string()),
+                ("DEFAULTCOLLECTION", "none".to_string()),
+                ("MAXCONNECTIONS", "none".to_string()),
+                ("MAXCONNECTIONBYTES", "1024".to_string()),
+                ("MAXREQUESTBYTES", "67108864".to_string()),
+                ("READTIMEOUT", "30".to_string()),
+                ("MAXRESULTS", "10".to_string()),
+                ("AUTOSAVEINTERVAL", "60".to_string()),
+                ("ASYNCINDEXING", "false".to_string()),
+                ("REPORTSETOUTCOME", "false".to_string()),
+                ("KEYNORMALIZATION", "none".to_string()),
+                ("LOGLEVEL", "info".to_string()),
+            ]
+        );
+
+        config.set("MAXCONNECTIONBYTES", "none").unwrap();
+        assert_eq!(config.max_connection_bytes, None);
+        config.set("LOGLEVEL", "DEBUG").unwrap();
+        assert_eq!(config.log_level, LevelFilter::DEBUG);
+        assert_eq!(config.get("LOGLEVEL"), Ok("debug".to_string()));
+        config.set("MAXRESULTS", "3").unwrap();
+        assert_eq!(config.max_results, 3);
+        config.set("AUTOSAVEINTERVAL", "none").unwrap();
+        assert_eq!(config.persist_interval, None);
+        config.set("AUTOSAVEINTERVAL", "5").unwrap();
+        assert_eq!(config.persist_interval, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_set_rejected() {
+        let mut config = ZzapConfig::default();
+        assert_eq!(
+            config.set("MAXCONNECTIONBYTES", "lots"),
+            Err("invalid value lots for MAXCONNECTIONBYTES".to_string())
+        );
+        assert_eq!(
+            config.set("READTIMEOUT", "1m"),
+            Err("invalid value 1m for READTIMEOUT".to_string())
+        );
+        for (setting, value) in [
+            ("MAXRESULTS", "0"),
+            ("MAXRESULTS", "100001"),
+            ("AUTOSAVEINTERVAL", "0"),
+            ("READTIMEOUT", "0"),
+        ] {
+            assert_eq!(
+                config.set(setting, value),
+                Err(format!("invalid value {} for {}", value, setting))
+            );
+        }
+        assert_eq!(
+            config.set("ASYNCINDEXING", "true"),
+            Err("ASYNCINDEXING can only be changed on startup".to_string())
+        );
+        assert_eq!(
+            config.set("COLOR", "blue"),
+            Err("unknown setting COLOR".to_string())
+        );
+        assert_eq!(
+            config.get("COLOR"),
+            Err("unknown setting COLOR".to_string())
+        );
+        assert!(!config.async_indexing);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let vars = |name: &str| match name {
+            "ZZAP_ADDR" => Some("127.0.0.1:7000".to_string()),
+            "ZZAP_PERSISTENCE_PATH" => Some("/var/lib/zzap/env.db".to_string()),
+            _ => None,
+        };
+
+        let mut config = ZzapConfig::default();
+        config.apply_overrides(vars, []).unwrap();
+        assert_eq!(config.addr, SocketAddr::from(([127, 0, 0, 1], 7000)));
+        assert_eq!(
+            config.persistence_path,
+            PathBuf::from("/var/lib/zzap/env.db")
+        );
+
+        // arguments win over the environment
+        let args = [
+            "--addr",
+            "127.0.0.1:7001",
+            "--persistence-path",
+            "cli.db",
+            "--engine",
+            "btree",
+        ];
+        config
+            .apply_overrides(vars, args.map(String::from))
+            .unwrap();
+        assert_eq!(config.addr, SocketAddr::from(([127, 0, 0, 1], 7001)));
+        assert_eq!(config.persistence_path, PathBuf::from("cli.db"));
+        assert_eq!(config.engine, "btree");
+
+        config
+            .apply_overrides(|_| None, ["--persist-interval", "5"].map(String::from))
+            .unwrap();
+        assert_eq!(config.persist_interval, Some(Duration::from_secs(5)));
+        config
+            .apply_overrides(
+                |name| (name == "ZZAP_PERSIST_INTERVAL").then(|| "0".to_string()),
+                [],
+            )
+            .unwrap();
+        assert_eq!(config.persist_interval, None);
+
+        config
+            .apply_overrides(
+                |name| (name == "ZZAP_PASSWORD").then(|| "from-env".to_string()),
+                ["--password", "from-args"].map(String::from),
+            )
+            .unwrap();
+        assert_eq!(config.password.as_deref(), Some("from-args"));
+
+        config
+            .apply_overrides(
+                |name| (name == "ZZAP_COMPRESSION").then(|| "deflate".to_string()),
+                ["--compression", "zstd:19"].map(String::from),
+            )
+            .unwrap();
+        assert_eq!(config.compression, Compression::Zstd(19));
+
+        config
+            .apply_overrides(
+                |name| (name == "ZZAP_KEY_NORMALIZATION").then(|| "Lowercase".to_string()),
+                [],
+            )
+            .unwrap();
+        assert_eq!(config.key_normalization, KeyNormalization::Lowercase);
+        config
+            .apply_overrides(|_| None, ["--key-normalization", "trim"].map(String::from))
+            .unwrap();
+        assert_eq!(config.key_normalization, KeyNormalization::Trim);
+
+        config
+            .apply_overrides(
+                |name| (name == "ZZAP_LOG_LEVEL").then(|| "warn".to_string()),
+                ["--log-level", "off"].map(String::from),
+            )
+            .unwrap();
+        assert_eq!(config.log_level, LevelFilter::OFF);
+
+        config
+            .apply_overrides(
+                |name| (name == "ZZAP_MAX_CONNECTIONS").then(|| "100".to_string()),
+                [],
+            )
+            .unwrap();
+        assert_eq!(config.max_connections, Some(100));
+        config
+            .apply_overrides(|_| None, ["--max-connections", "0"].map(String::from))
+            .unwrap();
+        assert_eq!(config.max_connections, None);
+
+        config
+            .apply_overrides(
+                |name| (name == "ZZAP_ASYNC_INDEXING").then(|| "true".to_string()),
+                [],
+            )
+            .unwrap();
+        assert!(config.async_indexing);
+        config
+            .apply_overrides(|_| None, ["--async-indexing", "false"].map(String::from))
+            .unwrap();
+        assert!(!config.async_indexing);
+
+        let mut config = ZzapConfig::default();
+        config.apply_overrides(|_| None, []).unwrap();
+        assert_eq!(config.addr, SocketAddr::from(([0, 0, 0, 0], 13413)));
+        assert_eq!(config.persistence_path, PathBuf::from("storage.db"));
+
+        let fail = |args: &[&str]| {
+            ZzapConfig::default()
+                .apply_overrides(|_| None, args.iter().map(|arg| arg.to_string()))
+                .unwrap_err()
+        };
+        assert_eq!(fail(&["--addr"]), "missing value for --addr");
+        assert_eq!(
+            fail(&["--addr", "13413"]),
+            "invalid address 13413, expected ip:port"
+        );
+        assert_eq!(
+            fail(&["--persist-interval", "1m"]),
+            "invalid interval 1m, expected seconds"
+        );
+        assert_eq!(
+            fail(&["--compression", "gzip"]),
+            "invalid compression gzip, expected codec[:level]"
+        );
+        assert_eq!(
+            fail(&["--log-level", "loud"]),
+            "invalid log level loud, expected off, error, warn, info, debug or trace"
+        );
+        assert_eq!(
+            fail(&["--async-indexing", "yes"]),
+            "invalid asynchronous indexing yes, expected true or false"
+        );
+        assert_eq!(fail(&["--port", "1"]), "unknown argument --port");
+        assert_eq!(
+            fail(&["--tls-cert", "cert.pem"]),
+            "TLS needs both a certificate and a private key"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use super::{Encryption, EncryptionError};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::Sha256;
+
+/// PBKDF2 rounds turning a key string into an AES key, slowing down guesses of weak keys.
+const KDF_ROUNDS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Authenticated encryption with AES-256-GCM.
+///
+/// The AES key is derived from the key string with PBKDF2-HMAC-SHA256 and a random salt. The
+/// ciphertext is base64 of the salt, then the random nonce, then the encrypted data and its tag,
+/// so encrypting the same data twice gives different results.
+pub struct AesGcmEncryptor;
+
+impl AesGcmEncryptor {
+    fn cipher(key: &str, salt: &[u8]) -> Result<Aes256Gcm, EncryptionError> {
+        if key.is_empty() {
+            return Err(EncryptionError::InvalidKey);
+        }
+
+        let mut derived = Key::<Aes256Gcm>::default();
+        pbkdf2::pbkdf2_hmac::<Sha256>(key.as_bytes(), salt, KDF_ROUNDS, &mut derived);
+        Ok(Aes256Gcm::new(&derived))
+    }
+}
+
+impl Encryption for AesGcmEncryptor {
+    fn new() -> Self {
+        AesGcmEncryptor
+    }
+
+    fn encrypt(&self, data: &str, key: &str) -> Result<String, EncryptionError> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = Self::cipher(key, &salt)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, data.as_bytes())
+            .map_err(|_| EncryptionError::EncryptionFailed)?;
+
+        let mut encrypted = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+        encrypted.extend_from_slice(&salt);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(BASE64.encode(encrypted))
+    }
+
+    fn decrypt(&self, data: &str, key: &str) -> Result<String, EncryptionError> {
+        let encrypted = BASE64
+            .decode(data)
+            .map_err(|e| EncryptionError::DecryptionFailed(format!("invalid base64: {}", e)))?;
+        if encrypted.len() < SALT_LEN + NONCE_LEN {
+            return Err(EncryptionError::DecryptionFailed(
+                "data too short".to_string(),
+            ));
+        }
+
+        let (salt, rest) = encrypted.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let cipher = Self::cipher(key, salt)?;
+        // fails on a wrong key as well as on altered data, the tag cannot tell them apart
+        let decrypted = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError::DecryptionFailed("authentication failed".to_string()))?;
+
+        String::from_utf8(decrypted)
+            .map_err(|_| EncryptionError::DecryptionFailed("data is not UTF-8".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let encryptor = AesGcmEncryptor::new();
+        for original in ["Hello, World!", "", "multi\nline ünïcode"] {
+            let encrypted = encryptor.encrypt(original, "secret").unwrap();
+            assert_ne!(encrypted, original);
+            assert_eq!(encryptor.decrypt(&encrypted, "secret").unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn test_random_salt_and_nonce() {
+        let encryptor = AesGcmEncryptor::new();
+        assert_ne!(
+            encryptor.encrypt("same", "secret").unwrap(),
+            encryptor.encrypt("same", "secret").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_fails() {
+        let encryptor = AesGcmEncryptor::new();
+        let encrypted = encryptor.encrypt("Hello, World!", "secret").unwrap();
+
+        let mut bytes = BASE64.decode(&encrypted).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(
+            encryptor.decrypt(&BASE64.encode(bytes), "secret"),
+            Err(EncryptionError::DecryptionFailed(
+                "authentication failed".to_string()
+            ))
+        );
+        assert_eq!(
+            encryptor.decrypt(&encrypted, "wrong"),
+            Err(EncryptionError::DecryptionFailed(
+                "authentication failed".to_string()
+            ))
+        );
+        assert!(matches!(
+            encryptor.decrypt("not base64!", "secret"),
+            Err(EncryptionError::DecryptionFailed(_))
+        ));
+        assert!(matches!(
+            encryptor.decrypt(&BASE64.encode([0; 8]), "secret"),
+            Err(EncryptionError::DecryptionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let encryptor = AesGcmEncryptor::new();
+        assert_eq!(
+            encryptor.encrypt("data", ""),
+            Err(EncryptionError::InvalidKey)
+        );
+    }
+}
@@ -1,7 +1,10 @@
 use concrete_csprng::generators::SoftwareRandomGenerator;
 use tfhe::boolean::{client_key::ClientKey, parameters::PARAMETERS_ERROR_PROB_2_POW_MINUS_165};
 use tfhe::core_crypto::commons::generators::SecretRandomGenerator;
-use tfhe::core_crypto::prelude::{allocate_and_generate_new_binary_lwe_secret_key, allocate_and_generate_new_binary_glwe_secret_key};
+use tfhe::core_crypto::prelude::{
+    allocate_and_generate_new_binary_glwe_secret_key,
+    allocate_and_generate_new_binary_lwe_secret_key,
+};
 use tfhe::Seed;
 
 use super::EncryptionError;
@@ -12,12 +15,15 @@ pub trait Key {
 
 impl Key for String {
     fn to_tfhe(&self) -> Result<ClientKey, EncryptionError> {
-        let seed = self.as_bytes()[0..16].try_into().map_err(|_| EncryptionError::WrongKeySize)?;
+        let seed = self.as_bytes()[0..16]
+            .try_into()
+            .map_err(|_| EncryptionError::WrongKeySize)?;
         let seed = Seed(u128::from_le_bytes(seed));
 
         let parameters = PARAMETERS_ERROR_PROB_2_POW_MINUS_165;
 
-        let mut generator: SecretRandomGenerator<SoftwareRandomGenerator> = SecretRandomGenerator::new(seed);
+        let mut generator: SecretRandomGenerator<SoftwareRandomGenerator> =
+            SecretRandomGenerator::new(seed);
 
         let lwe_secret_key = allocate_and_generate_new_binary_lwe_secret_key(
             parameters.lwe_dimension,
@@ -31,6 +37,10 @@ impl Key for String {
             &mut generator,
         );
 
-        Ok(ClientKey::new_from_raw_parts(lwe_secret_key, glwe_secret_key, PARAMETERS_ERROR_PROB_2_POW_MINUS_165))
+        Ok(ClientKey::new_from_raw_parts(
+            lwe_secret_key,
+            glwe_secret_key,
+            PARAMETERS_ERROR_PROB_2_POW_MINUS_165,
+        ))
     }
-}
\ No newline at end of file
+}
@@ -38,4 +38,4 @@ impl Message for String {
 
         Ok(string)
     }
-}
\ No newline at end of file
+}
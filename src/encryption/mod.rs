@@ -1,6 +1,9 @@
+mod aes;
 mod key;
 mod message;
 
+pub use aes::AesGcmEncryptor;
+
 use std::error::Error;
 use std::fmt;
 // use tfhe::integer::BooleanBlock;
@@ -1,32 +1,265 @@
-// TODO: Tokenize, stem, lemmatize, remove stop words
+// TODO: lemmatize
 
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+pub mod query;
+pub mod stem;
 
-pub fn tokenize(text: &str) -> Vec<String> {
-    text.to_lowercase()
-        .split_whitespace()
-        .map(|s| {
-            s.chars()
-                .filter(|c| c.is_alphanumeric())
-                .collect::<String>()
-        })
-        .filter(|s| !s.is_empty())
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+use unicode_normalization::{
+    char::is_combining_mark, is_nfkc_quick, IsNormalized, UnicodeNormalization,
+};
+
+/// Optional steps applied to text before it is split into tokens.
+///
+/// The same options must be used to index a collection and to query it.
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenizerOptions {
+    /// Strip diacritics, so "résumé" and "resume" produce the same token.
+    pub deaccent: bool,
+    /// Keep the case of the text, so "Apple" and "apple" are different tokens.
+    pub case_sensitive: bool,
+    /// Tokens too common to be worth indexing or searching for, see [`generate_blacklist`].
+    pub blacklist: Arc<HashSet<String>>,
+    /// Words dropped from documents and queries alike, whatever their case, see [`stop_words`].
+    pub stop_words: Arc<HashSet<String>>,
+    /// Reduce English words to their stem, so "running" and "runs" produce the same token, see
+    /// [`stem::stem`].
+    pub stem: bool,
+}
+
+impl TokenizerOptions {
+    /// Applies the enabled steps to the text as a whole.
+    pub fn prepare<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.deaccent {
+            Cow::Owned(strip_diacritics(text))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// Whether the token is left out of the index and of queries.
+    pub fn drops(&self, token: &str) -> bool {
+        if self.blacklist.contains(token) {
+            return true;
+        }
+        match self.case_sensitive {
+            _ if self.stop_words.is_empty() => false,
+            true => self.stop_words.contains(&token.to_lowercase()),
+            false => self.stop_words.contains(token),
+        }
+    }
+}
+
+/// Common English words carrying little meaning on their own.
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "by", "did", "do", "does", "for", "from", "had",
+    "has", "have", "in", "is", "it", "of", "on", "or", "that", "the", "this", "to", "was", "were",
+    "with",
+];
+
+/// The built-in stop words, shared by every collection dropping them.
+pub fn stop_words() -> Arc<HashSet<String>> {
+    static WORDS: OnceLock<Arc<HashSet<String>>> = OnceLock::new();
+    WORDS
+        .get_or_init(|| Arc::new(STOP_WORDS.iter().map(|word| word.to_string()).collect()))
+        .clone()
+}
+
+/// Decomposes characters and drops the combining marks, i.e. "é" becomes "e".
+pub fn strip_diacritics(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .nfc()
         .collect()
 }
 
-pub fn tokenize_iter(text: &mut String) -> impl Iterator<Item = &str> {
-    text.to_lowercase();
-    text.split_whitespace()
+/// Composes characters and replaces compatibility variants with their plain form (NFKC), so a
+/// decomposed "é" becomes the precomposed one and "ﬁ" becomes "fi".
+fn normalize_unicode(text: &str) -> Cow<'_, str> {
+    match is_nfkc_quick(text.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(text),
+        _ => Cow::Owned(text.nfkc().collect()),
+    }
 }
 
-fn cleanup_useless_tokens(tokens: Vec<String>) -> Vec<String> {
-    let word_blacklist = [
-        "the", "and", "is", "are", "was", "were", "have", "has", "had", "do", "does", "did",
-    ];
+pub fn tokenize_with(text: &str, options: &TokenizerOptions) -> Vec<String> {
+    let text = options.prepare(text);
+    let mut tokens = if options.case_sensitive {
+        tokenize_text(&text, false)
+    } else {
+        tokenize(&text)
+    };
+    if !options.blacklist.is_empty() || !options.stop_words.is_empty() {
+        tokens.retain(|token| !options.drops(token));
+    }
+    if options.stem {
+        for token in &mut tokens {
+            *token = stem_token(token);
+        }
+    }
     tokens
-        .into_par_iter()
-        .filter(|token| !word_blacklist.contains(&token.as_str()))
-        .collect()
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    tokenize_text(text, true)
+}
+
+fn tokenize_text(text: &str, lowercase: bool) -> Vec<String> {
+    if let Some(tokens) = tokenize_json(text, lowercase) {
+        return tokens;
+    }
+
+    // not before parsing JSON, a fullwidth quote would become a plain one
+    let text = normalize_unicode(text);
+    let text = if lowercase {
+        Cow::Owned(text.to_lowercase())
+    } else {
+        text
+    };
+    text.split_whitespace().filter_map(normalize_word).collect()
+}
+
+/// Keeps only alphanumeric characters of a word, except for the `:` of a field-scoped
+/// `field:term` word, which is kept so the token only matches inside that field.
+fn normalize_word(word: &str) -> Option<String> {
+    let alphanumeric = |s: &str| {
+        s.chars()
+            .filter(|c| c.is_alphanumeric())
+            .collect::<String>()
+    };
+
+    let token = match word.split_once(':') {
+        Some((field, term)) if is_field_name(field) => {
+            let term = alphanumeric(term);
+            if term.is_empty() {
+                return None;
+            }
+            field_token(field, &term)
+        }
+        _ => alphanumeric(word),
+    };
+
+    (!token.is_empty()).then_some(token)
+}
+
+/// A field name, or a dotted path to a field of a nested object, i.e. `author.name`.
+fn is_field_name(name: &str) -> bool {
+    name.split('.').all(|segment| {
+        segment.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && segment.chars().all(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+fn field_token(field: &str, term: &str) -> String {
+    format!("{}:{}", field, term)
+}
+
+/// Tokenizes a JSON object document.
+///
+/// Every field value is indexed both as plain tokens and as `field:token`. Nested objects are
+/// indexed under the dotted path of their fields, i.e. `author.name:token`. Arrays are flattened,
+/// so each element is indexed as its own token under the field.
+/// Returns `None` when the text is not a JSON object.
+fn tokenize_json(text: &str, lowercase: bool) -> Option<Vec<String>> {
+    if !text.trim_start().starts_with('{') {
+        return None;
+    }
+    let value @ serde_json::Value::Object(_) = serde_json::from_str(text).ok()? else {
+        return None;
+    };
+
+    let mut tokens = Vec::new();
+    push_json_tokens(None, value, lowercase, &mut tokens);
+    Some(tokens)
+}
+
+fn push_json_tokens(
+    field: Option<&str>,
+    value: serde_json::Value,
+    lowercase: bool,
+    tokens: &mut Vec<String>,
+) {
+    let text = match value {
+        serde_json::Value::Object(fields) => {
+            for (name, value) in fields {
+                let name = if lowercase { name.to_lowercase() } else { name };
+                let path = match field {
+                    Some(field) => format!("{}.{}", field, name),
+                    None => name,
+                };
+                push_json_tokens(Some(&path), value, lowercase, tokens);
+            }
+            return;
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                push_json_tokens(field, value, lowercase, tokens);
+            }
+            return;
+        }
+        serde_json::Value::String(text) => text,
+        serde_json::Value::Number(number) => number.to_string(),
+        serde_json::Value::Bool(boolean) => boolean.to_string(),
+        serde_json::Value::Null => return,
+    };
+
+    let field = field.filter(|field| is_field_name(field));
+    for token in tokenize_text(&text, lowercase) {
+        if let Some(field) = field {
+            tokens.push(field_token(field, &token));
+        }
+        tokens.push(token);
+    }
+}
+
+/// Stems a token, or the term of a field-scoped `field:term` token.
+fn stem_token(token: &str) -> String {
+    match token.split_once(':') {
+        Some((field, term)) => format!("{}:{}", field, stem::stem(term)),
+        None => stem::stem(token),
+    }
+}
+
+/// Every run of `n` consecutive characters of a token, i.e. the 3-grams of "rust" are "rus" and
+/// "ust". A token of `n` characters or fewer is its own single n-gram.
+pub fn ngrams(token: &str, n: usize) -> Vec<String> {
+    let chars: Vec<char> = token.chars().collect();
+    if chars.len() <= n || n == 0 {
+        return vec![token.to_string()];
+    }
+    chars.windows(n).map(|gram| gram.iter().collect()).collect()
+}
+
+/// Levenshtein distance between two tokens, counted in characters, or `None` if it is over `max`.
+///
+/// Gives up as soon as every edit path is over `max`, so comparing a token against many costs
+/// little more than the length of each.
+pub fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+
+    // distances from the prefix of `a` read so far to every prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut previous_diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous_diagonal + usize::from(a_char != b_char);
+            previous_diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+        if row.iter().all(|&distance| distance > max) {
+            return None;
+        }
+    }
+
+    let distance = row[b.len()];
+    (distance <= max).then_some(distance)
 }
 
 /// Generate a token blacklist from the index
@@ -34,23 +267,31 @@ fn cleanup_useless_tokens(tokens: Vec<String>) -> Vec<String> {
 /// This is used to remove tokens that are too common, such as "the", "and", "is", etc,
 /// therefore not adding much value to the search and increasing the size of the index.
 ///
-/// This function iterates over the index and collects all the tokens, then filters out the most common (top 1%)
-/// and returns them as a blacklist. It does not run if there are less than 1000 tokens in the index.
+/// Takes every token of the index with the number of documents containing it, and returns the most
+/// common (top 1%) as the blacklist. Tokens found in a single document are never blacklisted. It
+/// does not run if there are less than 1000 tokens in the index, and returns an empty blacklist.
 ///
 /// There may be a more sophisticated approach to this in the future, but for now this is a simple solution.
-// TODO: When to run it? Need some kind of scheduler for this.
-// async fn generate_token_blacklist(engine: &SearchEngine) -> Vec<String> {
-//     unimplemented!();
-//     // let mut blacklist = HashSet::new();
-//     // for bucket in engine.index.iter() {
-//     //     for collection in bucket.iter() {
-//     //         for token in collection.iter() {
-//     //             blacklist.insert(token.key().to_string());
-//     //         }
-//     //     }
-//     // }
-//     // blacklist.into_iter().collect()
-// }
+pub fn generate_blacklist(
+    frequencies: impl IntoIterator<Item = (String, usize)>,
+) -> HashSet<String> {
+    const MIN_TOKENS: usize = 1000;
+
+    let mut frequencies: Vec<(String, usize)> = frequencies.into_iter().collect();
+    if frequencies.len() < MIN_TOKENS {
+        return HashSet::new();
+    }
+
+    let top = frequencies.len().div_ceil(100);
+    frequencies.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    frequencies
+        .into_iter()
+        .take(top)
+        .filter(|(_, count)| *count > 1)
+        .map(|(token, _)| token)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,4 +315,181 @@ mod tests {
         let tokens = tokenize(text);
         assert_eq!(tokens, ["hello", "world", "こんにちは", "привет", "мир"]);
     }
+
+    #[test]
+    fn test_tokenize_normalizes_unicode() {
+        let composed = "caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+        assert_eq!(tokenize(composed), ["café"]);
+        assert_eq!(tokenize(decomposed), tokenize(composed));
+        // compatibility characters fold into their plain form
+        assert_eq!(tokenize("\u{fb01}ne ＡＢＣ"), ["fine", "abc"]);
+
+        assert_eq!(
+            tokenize_with(decomposed, &TokenizerOptions::default()),
+            ["café"]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_field_scoped_query() {
+        let tokens = tokenize("Tags:Rust? plain 10:30");
+        assert_eq!(tokens, ["tags:rust", "plain", "1030"]);
+    }
+
+    #[test]
+    fn test_tokenize_json_array_field() {
+        let tokens = tokenize(r#"{"title": "Hello", "tags": ["a", "b c"], "views": 3}"#);
+        assert_eq!(
+            tokens,
+            [
+                "tags:a",
+                "a",
+                "tags:b",
+                "b",
+                "tags:c",
+                "c",
+                "title:hello",
+                "hello",
+                "views:3",
+                "3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_json_nested_field() {
+        let tokens = tokenize(r#"{"author": {"name": "Ada", "links": [{"site": "gh"}]}}"#);
+        assert_eq!(
+            tokens,
+            ["author.links.site:gh", "gh", "author.name:ada", "ada"]
+        );
+        assert_eq!(tokenize("author.name:Ada"), ["author.name:ada"]);
+        assert_eq!(tokenize("author.:ada .name:ada"), ["authorada", "nameada"]);
+    }
+
+    #[test]
+    fn test_tokenize_json_like_text() {
+        let tokens = tokenize("{not json}");
+        assert_eq!(tokens, ["not", "json"]);
+    }
+
+    #[test]
+    fn test_tokenize_deaccent() {
+        let deaccent = TokenizerOptions {
+            deaccent: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            tokenize_with("Résumé naïve", &deaccent),
+            ["resume", "naive"]
+        );
+        assert_eq!(
+            tokenize_with("Résumé naïve", &TokenizerOptions::default()),
+            ["résumé", "naïve"]
+        );
+        // decomposed input strips the same way
+        assert_eq!(tokenize_with("re\u{301}sume\u{301}", &deaccent), ["resume"]);
+    }
+
+    #[test]
+    fn test_generate_blacklist() {
+        let rare = (0..1200).map(|i| (format!("rare{}", i), 1));
+        let frequencies: Vec<(String, usize)> = rare
+            .chain([("common".to_string(), 40), ("frequent".to_string(), 3)])
+            .collect();
+
+        let blacklist = generate_blacklist(frequencies.clone());
+        assert_eq!(
+            blacklist,
+            HashSet::from(["common".to_string(), "frequent".to_string()])
+        );
+
+        // too few tokens to tell what is common
+        assert!(generate_blacklist(frequencies.into_iter().skip(300)).is_empty());
+    }
+
+    #[test]
+    fn test_tokenize_blacklist() {
+        let options = TokenizerOptions {
+            blacklist: Arc::new(HashSet::from(["the".to_string()])),
+            ..Default::default()
+        };
+        assert_eq!(tokenize_with("The cat", &options), ["cat"]);
+    }
+
+    #[test]
+    fn test_tokenize_stop_words() {
+        let options = TokenizerOptions {
+            stop_words: stop_words(),
+            ..Default::default()
+        };
+        assert_eq!(
+            tokenize_with("The cat and the hat", &options),
+            ["cat", "hat"]
+        );
+        assert!(tokenize_with("the and", &options).is_empty());
+
+        // lowercase in the list, dropped whatever the case of the text
+        let case_sensitive = TokenizerOptions {
+            case_sensitive: true,
+            ..options
+        };
+        assert_eq!(tokenize_with("The Cat", &case_sensitive), ["Cat"]);
+    }
+
+    #[test]
+    fn test_tokenize_stem() {
+        let options = TokenizerOptions {
+            stem: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            tokenize_with("Running runs, run!", &options),
+            ["run", "run", "run"]
+        );
+        assert_eq!(
+            tokenize_with(r#"{"tags": ["connections"]}"#, &options),
+            ["tags:connect", "connect"]
+        );
+
+        assert_eq!(tokenize_with("Hopping cats", &options), ["hop", "cat"]);
+    }
+
+    #[test]
+    fn test_ngrams() {
+        assert_eq!(ngrams("rust", 3), ["rus", "ust"]);
+        assert_eq!(ngrams("is", 3), ["is"]);
+        assert_eq!(ngrams("héllo", 4), ["héll", "éllo"]);
+        assert_eq!(ngrams("abc", 0), ["abc"]);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("hello", "hello", 0), Some(0));
+        assert_eq!(edit_distance("helo", "hello", 1), Some(1));
+        assert_eq!(edit_distance("hello", "jello", 1), Some(1));
+        assert_eq!(edit_distance("hlelo", "hello", 2), Some(2));
+        assert_eq!(edit_distance("hlelo", "hello", 1), None);
+        assert_eq!(edit_distance("héllo", "hello", 1), Some(1));
+        assert_eq!(edit_distance("", "abc", 3), Some(3));
+        assert_eq!(edit_distance("kitten", "sitting", 3), Some(3));
+        assert_eq!(edit_distance("kitten", "sitting", 2), None);
+    }
+
+    #[test]
+    fn test_tokenize_case_sensitive() {
+        let case_sensitive = TokenizerOptions {
+            case_sensitive: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            tokenize_with("Apple apple", &case_sensitive),
+            ["Apple", "apple"]
+        );
+        assert_eq!(
+            tokenize_with(r#"{"Name": "Apple"}"#, &case_sensitive),
+            ["Name:Apple", "Apple"]
+        );
+    }
 }
@@ -0,0 +1,405 @@
+//! Parsing of search queries, done before their words are tokenized.
+//!
+//! A word starting with `"` opens a quoted phrase. A backslash makes the next character literal,
+//! so `\"hello` is the word `"hello` rather than the start of a phrase, `\ ` is a space within
+//! the word and `\\` is a literal backslash. `\n` is a newline, which
+//! could not be sent as is. [`escape_word`] turns any text into the word parsed back as it.
+
+use super::TokenizerOptions;
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// Characters with a meaning of their own at the start of a query word.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryOperator {
+    /// `"word`
+    Quote,
+}
+
+impl QueryOperator {
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            '"' => Some(QueryOperator::Quote),
+            _ => None,
+        }
+    }
+}
+
+/// A whitespace-separated word of a query, with escapes resolved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryWord {
+    pub text: String,
+    pub operator: Option<QueryOperator>,
+}
+
+pub fn parse_query(query: &str) -> Vec<QueryWord> {
+    split_words(query).map(parse_word).collect()
+}
+
+/// Splits a query on whitespace that is not escaped, escapes are left for [`parse_word`].
+fn split_words(query: &str) -> impl Iterator<Item = &str> {
+    let mut rest = query;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start();
+        let mut escaped = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| match c {
+                _ if escaped => {
+                    escaped = false;
+                    false
+                }
+                '\\' => {
+                    escaped = true;
+                    false
+                }
+                c => c.is_whitespace(),
+            })
+            .map_or(rest.len(), |(i, _)| i);
+
+        let (word, after) = rest.split_at(end);
+        rest = after;
+        (!word.is_empty()).then_some(word)
+    })
+}
+
+/// Escapes text so that, as a query word, it is parsed back as exactly that text without an
+/// operator. Backslashes, quotes and whitespace are escaped, and newlines are written `\n`.
+pub fn escape_word(text: &str) -> String {
+    let mut word = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => word.push_str("\\n"),
+            c if c == '\\' || c == '"' || c.is_whitespace() => {
+                word.push('\\');
+                word.push(c);
+            }
+            c => word.push(c),
+        }
+    }
+    word
+}
+
+fn parse_word(word: &str) -> QueryWord {
+    let operator = word.chars().next().and_then(QueryOperator::from_char);
+    let rest = match operator {
+        Some(_) => &word[1..],
+        None => word,
+    };
+
+    let mut text = String::with_capacity(rest.len());
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => text.push('\n'),
+                Some(c) => text.push(c),
+                // a trailing backslash has nothing to escape and is kept as is
+                None => text.push('\\'),
+            },
+            c => text.push(c),
+        }
+    }
+
+    QueryWord { text, operator }
+}
+
+/// Tokenizes a query the way documents are tokenized, once escapes are resolved.
+///
+/// Quotes are not interpreted here, their words are searched like any other. Engines matching
+/// phrases look them up with [`quoted_phrases`].
+pub fn tokenize_query(query: &str, options: &TokenizerOptions) -> Vec<String> {
+    parse_query(query)
+        .iter()
+        .flat_map(|word| super::tokenize_with(&word.text, options))
+        .collect()
+}
+
+/// Tokens of every phrase quoted in the query, in order.
+///
+/// A phrase opens with a word starting with `"` and closes with a word ending with an unescaped
+/// `"`, or at the end of the query. A quoted single word is a phrase of one token.
+pub fn quoted_phrases(query: &str, options: &TokenizerOptions) -> Vec<Vec<String>> {
+    // the quote is escaped when an odd number of backslashes precede it
+    let closes = |word: &str| {
+        word.strip_suffix('"').is_some_and(|word| {
+            let backslashes = word.len() - word.trim_end_matches('\\').len();
+            backslashes % 2 == 0
+        })
+    };
+
+    let mut phrases = Vec::new();
+    let mut words = split_words(query);
+    while let Some(word) = words.next() {
+        let Some(rest) = word.strip_prefix('"') else {
+            continue;
+        };
+
+        // punctuation, quotes included, is dropped by the tokenizer
+        let mut text = rest.to_string();
+        let mut closed = closes(rest);
+        while !closed {
+            let Some(word) = words.next() else { break };
+            closed = closes(word);
+            text.push(' ');
+            text.push_str(word);
+        }
+
+        let tokens = super::tokenize_with(&text, options);
+        if !tokens.is_empty() {
+            phrases.push(tokens);
+        }
+    }
+    phrases
+}
+
+/// Byte ranges of the words of `content` matching a token of the query.
+///
+/// Computed from the content itself, so it works with any engine. Words are split on whitespace
+/// and JSON punctuation, and each is tokenized like the document was: a query for "resume" in a
+/// collection stripping diacritics highlights "Résumé". Field-scoped query tokens match their term
+/// anywhere in the content.
+pub fn match_offsets(content: &str, query: &str, options: &TokenizerOptions) -> Vec<Range<usize>> {
+    let terms: HashSet<String> = tokenize_query(query, options)
+        .into_iter()
+        .map(|token| match token.split_once(':') {
+            Some((_, term)) => term.to_string(),
+            None => token,
+        })
+        .collect();
+
+    words(content)
+        .filter(|word| {
+            super::tokenize_with(&content[word.clone()], options)
+                .iter()
+                .any(|token| terms.contains(token))
+        })
+        .collect()
+}
+
+/// Bytes of content kept on each side of the match in a snippet, at most.
+pub const SNIPPET_CONTEXT: usize = 40;
+
+/// Delimiters of the matched word in a snippet.
+pub const SNIPPET_OPEN: &str = "<em>";
+pub const SNIPPET_CLOSE: &str = "</em>";
+
+/// A short excerpt of `content` around the first word matching the query, delimited with
+/// [`SNIPPET_OPEN`] and [`SNIPPET_CLOSE`].
+///
+/// The context is cut at whitespace so words are kept whole, and an ellipsis marks the sides
+/// where content was left out. Without a match, the excerpt is the start of the content.
+pub fn snippet(content: &str, query: &str, options: &TokenizerOptions) -> String {
+    let matched = match_offsets(content, query, options).into_iter().next();
+    let matched = matched.unwrap_or(0..0);
+
+    let mut start = matched.start.saturating_sub(SNIPPET_CONTEXT);
+    while !content.is_char_boundary(start) {
+        start += 1;
+    }
+    // the word cut in half is dropped, unless it is the only one
+    if start > 0
+        && let Some(space) = content[start..matched.start].find(char::is_whitespace)
+    {
+        start += space;
+    }
+    let mut end = (matched.end + SNIPPET_CONTEXT).min(content.len());
+    while !content.is_char_boundary(end) {
+        end -= 1;
+    }
+    if end < content.len()
+        && let Some(space) = content[matched.end..end].rfind(char::is_whitespace)
+    {
+        end = matched.end + space;
+    }
+
+    let mut snippet = String::with_capacity(end - start + 16);
+    if start > 0 {
+        snippet.push_str("...");
+    }
+    snippet.push_str(content[start..matched.start].trim_start());
+    if !matched.is_empty() {
+        snippet.push_str(SNIPPET_OPEN);
+        snippet.push_str(&content[matched.clone()]);
+        snippet.push_str(SNIPPET_CLOSE);
+    }
+    snippet.push_str(content[matched.end..end].trim_end());
+    if end < content.len() {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+/// Splits on whitespace and JSON punctuation, trimming other punctuation around each word.
+fn words(content: &str) -> impl Iterator<Item = Range<usize>> + '_ {
+    let is_separator = |c: char| c.is_whitespace() || "\"{}[],:".contains(c);
+    let mut start = 0;
+    content
+        .split(is_separator)
+        .map(move |word| {
+            let range = start..start + word.len();
+            // skip the word and the single-char separator following it
+            start = range.end
+                + content[range.end..]
+                    .chars()
+                    .next()
+                    .map_or(0, char::len_utf8);
+            range
+        })
+        .filter_map(move |range| {
+            let word = &content[range.clone()];
+            let trimmed = word.trim_start_matches(|c: char| !c.is_alphanumeric());
+            let start = range.end - trimmed.len();
+            let trimmed = trimmed.trim_end_matches(|c: char| !c.is_alphanumeric());
+            (!trimmed.is_empty()).then(|| start..start + trimmed.len())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(text: &str, operator: Option<QueryOperator>) -> QueryWord {
+        QueryWord {
+            text: text.to_string(),
+            operator,
+        }
+    }
+
+    #[test]
+    fn test_parse_query_operators() {
+        assert_eq!(
+            parse_query("+rust -python \"hello"),
+            [
+                word("+rust", None),
+                word("-python", None),
+                word("hello", Some(QueryOperator::Quote)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_query_escapes() {
+        assert_eq!(
+            parse_query(r#"\-python \+1 \"quoted\" c\+\+ back\\slash trailing\"#),
+            [
+                word("-python", None),
+                word("+1", None),
+                word("\"quoted\"", None),
+                word("c++", None),
+                word("back\\slash", None),
+                word("trailing\\", None),
+            ]
+        );
+        // only the leading character is an operator
+        assert_eq!(
+            parse_query("\"\\\"x"),
+            [word("\"x", Some(QueryOperator::Quote))]
+        );
+    }
+
+    #[test]
+    fn test_escape_word_roundtrip() {
+        let cases = [
+            ("line\nbreak", r"line\nbreak"),
+            ("back\\slash", r"back\\slash"),
+            ("say \"hi\"", r#"say\ \"hi\""#),
+            ("two words\tapart", "two\\ words\\\tapart"),
+            ("-python", "-python"),
+            ("+1 and c++", r"+1\ and\ c++"),
+            ("n\\n", r"n\\n"),
+        ];
+        for (text, escaped) in cases {
+            assert_eq!(escape_word(text).as_bytes(), escaped.as_bytes());
+            assert_eq!(parse_query(escaped), [word(text, None)]);
+        }
+
+        let query = format!("{} \"{}", escape_word("a \"b\""), escape_word("\\\n"));
+        assert_eq!(query.as_bytes(), br#"a\ \"b\" "\\\n"#);
+        assert_eq!(
+            parse_query(&query),
+            [
+                word("a \"b\"", None),
+                word("\\\n", Some(QueryOperator::Quote))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_match_offsets() {
+        let options = TokenizerOptions::default();
+        let content = "Hello, world! Say hello again.";
+        assert_eq!(match_offsets(content, "hello", &options), [0..5, 18..23]);
+        assert_eq!(
+            match_offsets(content, "world missing again", &options),
+            [7..12, 24..29]
+        );
+        assert!(match_offsets(content, "missing", &options).is_empty());
+
+        let json = r#"{"tags":["rust","db"],"title":"Rust book"}"#;
+        assert_eq!(match_offsets(json, "tags:rust", &options), [10..14, 31..35]);
+
+        let deaccent = TokenizerOptions {
+            deaccent: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            match_offsets("un Résumé resume", "resume", &deaccent),
+            [3..11, 12..18]
+        );
+    }
+
+    #[test]
+    fn test_snippet() {
+        let options = TokenizerOptions::default();
+        assert_eq!(
+            snippet("Hello, world!", "world", &options),
+            "Hello, <em>world</em>!"
+        );
+
+        let content = format!("{} needle {}", "lead ".repeat(20), "tail ".repeat(20));
+        let excerpt = snippet(&content, "needle", &options);
+        assert!(excerpt.starts_with("...lead "), "{}", excerpt);
+        assert!(excerpt.contains(" <em>needle</em> "), "{}", excerpt);
+        assert!(excerpt.ends_with(" tail..."), "{}", excerpt);
+        assert!(excerpt.len() <= 2 * SNIPPET_CONTEXT + 30, "{}", excerpt);
+
+        // at the boundaries of the content, multi-byte characters included
+        let content = format!("été {}", "x".repeat(100));
+        assert_eq!(snippet(&content, "été", &options), "<em>été</em>...");
+        assert_eq!(snippet("abc", "missing", &options), "abc");
+    }
+
+    #[test]
+    fn test_quoted_phrases() {
+        let options = TokenizerOptions::default();
+        assert_eq!(
+            quoted_phrases(r#"rust "Hello, World" book "single" "open ended"#, &options),
+            [
+                vec!["hello", "world"],
+                vec!["single"],
+                vec!["open", "ended"]
+            ]
+        );
+        // an escaped quote does not close the phrase
+        assert_eq!(
+            quoted_phrases(r#""say \" more" after"#, &options),
+            [vec!["say", "more"]]
+        );
+        assert!(quoted_phrases(r#"no \"phrase" "" "!""#, &options).is_empty());
+        // an escaped backslash does not escape the quote after it
+        assert_eq!(
+            quoted_phrases(r#""ends here\\" after"#, &options),
+            [vec!["ends", "here"]]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_query() {
+        let options = TokenizerOptions::default();
+        assert_eq!(
+            tokenize_query(r"\-Python title:rust", &options),
+            ["python", "title:rust"]
+        );
+    }
+}
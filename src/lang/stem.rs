@@ -0,0 +1,256 @@
+//! English stemming with the Porter algorithm, as published by Martin Porter in 1980 and amended
+//! in his reference implementation.
+
+/// Reduces an English word to its stem, i.e. "running", "runs" and "run" all become "run".
+///
+/// Stems are not always words: "relational" becomes "relat". Expects a lowercase word, anything
+/// of two letters or fewer or holding characters other than `a` to `z` is returned as is.
+pub fn stem(word: &str) -> String {
+    if word.len() <= 2 || !word.bytes().all(|b| b.is_ascii_lowercase()) {
+        return word.to_string();
+    }
+
+    let mut word = Word(word.as_bytes().to_vec());
+    word.step1a();
+    word.step1b();
+    word.step1c();
+    word.replace_first(STEP2, 0);
+    word.replace_first(STEP3, 0);
+    word.step4();
+    word.step5();
+    // only ever holds the ASCII letters it started with, and `e`, `i` and suffixes added back
+    String::from_utf8(word.0).unwrap_or_default()
+}
+
+const STEP2: &[(&str, &str)] = &[
+    ("ational", "ate"),
+    ("tional", "tion"),
+    ("enci", "ence"),
+    ("anci", "ance"),
+    ("izer", "ize"),
+    ("bli", "ble"),
+    ("alli", "al"),
+    ("entli", "ent"),
+    ("eli", "e"),
+    ("ousli", "ous"),
+    ("ization", "ize"),
+    ("ation", "ate"),
+    ("ator", "ate"),
+    ("alism", "al"),
+    ("iveness", "ive"),
+    ("fulness", "ful"),
+    ("ousness", "ous"),
+    ("aliti", "al"),
+    ("iviti", "ive"),
+    ("biliti", "ble"),
+    ("logi", "log"),
+];
+
+const STEP3: &[(&str, &str)] = &[
+    ("icate", "ic"),
+    ("ative", ""),
+    ("alize", "al"),
+    ("iciti", "ic"),
+    ("ical", "ic"),
+    ("ful", ""),
+    ("ness", ""),
+];
+
+// longer suffixes first where one ends another, only the first one found is considered
+const STEP4: &[&str] = &[
+    "al", "ance", "ence", "er", "ic", "able", "ible", "ant", "ement", "ment", "ent", "ion", "ou",
+    "ism", "ate", "iti", "ous", "ive", "ize",
+];
+
+/// Lowercase ASCII word being stemmed.
+struct Word(Vec<u8>);
+
+impl Word {
+    fn is_consonant(&self, i: usize) -> bool {
+        match self.0[i] {
+            b'a' | b'e' | b'i' | b'o' | b'u' => false,
+            // a consonant unless it follows one, as in "toy" but not "syzygy"
+            b'y' => i == 0 || !self.is_consonant(i - 1),
+            _ => true,
+        }
+    }
+
+    /// Number of vowel-consonant sequences in the first `len` letters, `m` in the paper.
+    fn measure(&self, len: usize) -> usize {
+        let mut measure = 0;
+        let mut after_vowel = false;
+        for i in 0..len {
+            let consonant = self.is_consonant(i);
+            if consonant && after_vowel {
+                measure += 1;
+            }
+            after_vowel = !consonant;
+        }
+        measure
+    }
+
+    fn has_vowel(&self, len: usize) -> bool {
+        (0..len).any(|i| !self.is_consonant(i))
+    }
+
+    fn ends_with_double_consonant(&self, len: usize) -> bool {
+        len >= 2 && self.0[len - 1] == self.0[len - 2] && self.is_consonant(len - 1)
+    }
+
+    /// Whether the first `len` letters end with a consonant, a vowel and a consonant other than
+    /// `w`, `x` or `y`, as in "hop" but not in "snow".
+    fn ends_with_cvc(&self, len: usize) -> bool {
+        len >= 3
+            && self.is_consonant(len - 3)
+            && !self.is_consonant(len - 2)
+            && self.is_consonant(len - 1)
+            && !matches!(self.0[len - 1], b'w' | b'x' | b'y')
+    }
+
+    fn ends_with(&self, suffix: &str) -> bool {
+        self.0.ends_with(suffix.as_bytes())
+    }
+
+    fn last(&self) -> u8 {
+        self.0[self.0.len() - 1]
+    }
+
+    fn replace_suffix(&mut self, suffix: &str, replacement: &str) {
+        self.0.truncate(self.0.len() - suffix.len());
+        self.0.extend_from_slice(replacement.as_bytes());
+    }
+
+    /// Replaces the first suffix of `rules` the word ends with, when the stem before it measures
+    /// more than `min_measure`.
+    fn replace_first(&mut self, rules: &[(&str, &str)], min_measure: usize) {
+        let Some((suffix, replacement)) = rules.iter().find(|(suffix, _)| self.ends_with(suffix))
+        else {
+            return;
+        };
+        if self.measure(self.0.len() - suffix.len()) > min_measure {
+            self.replace_suffix(suffix, replacement);
+        }
+    }
+
+    /// Plurals: "caresses" to "caress", "ponies" to "poni", "cats" to "cat".
+    fn step1a(&mut self) {
+        if self.ends_with("sses") {
+            self.replace_suffix("sses", "ss");
+        } else if self.ends_with("ies") {
+            self.replace_suffix("ies", "i");
+        } else if self.ends_with("s") && !self.ends_with("ss") {
+            self.replace_suffix("s", "");
+        }
+    }
+
+    /// Past tenses and gerunds: "agreed" to "agree", "hopping" to "hop", "filing" to "file".
+    fn step1b(&mut self) {
+        if self.ends_with("eed") {
+            if self.measure(self.0.len() - 3) > 0 {
+                self.replace_suffix("eed", "ee");
+            }
+            return;
+        }
+
+        let Some(suffix) = ["ed", "ing"]
+            .into_iter()
+            .find(|suffix| self.ends_with(suffix) && self.has_vowel(self.0.len() - suffix.len()))
+        else {
+            return;
+        };
+        self.replace_suffix(suffix, "");
+
+        let len = self.0.len();
+        if self.ends_with("at") || self.ends_with("bl") || self.ends_with("iz") {
+            self.0.push(b'e');
+        } else if self.ends_with_double_consonant(len) && !matches!(self.last(), b'l' | b's' | b'z')
+        {
+            self.0.pop();
+        } else if self.measure(len) == 1 && self.ends_with_cvc(len) {
+            self.0.push(b'e');
+        }
+    }
+
+    /// "happy" to "happi", but not "sky".
+    fn step1c(&mut self) {
+        let len = self.0.len();
+        if self.ends_with("y") && self.has_vowel(len - 1) {
+            self.0[len - 1] = b'i';
+        }
+    }
+
+    fn step4(&mut self) {
+        let Some(suffix) = STEP4.iter().find(|suffix| self.ends_with(suffix)) else {
+            return;
+        };
+        let stem = self.0.len() - suffix.len();
+        // "adoption" loses its "ion", "onion" does not
+        if *suffix == "ion" && (stem == 0 || !matches!(self.0[stem - 1], b's' | b't')) {
+            return;
+        }
+        if self.measure(stem) > 1 {
+            self.0.truncate(stem);
+        }
+    }
+
+    /// A final "e" and double "l": "probate" to "probat", "controll" to "control".
+    fn step5(&mut self) {
+        let len = self.0.len();
+        if self.last() == b'e' {
+            let measure = self.measure(len - 1);
+            if measure > 1 || (measure == 1 && !self.ends_with_cvc(len - 1)) {
+                self.0.pop();
+            }
+        }
+
+        let len = self.0.len();
+        if self.last() == b'l' && self.ends_with_double_consonant(len) && self.measure(len) > 1 {
+            self.0.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stem() {
+        let cases = [
+            ("caresses", "caress"),
+            ("ponies", "poni"),
+            ("cats", "cat"),
+            ("feed", "feed"),
+            ("agreed", "agre"),
+            ("plastered", "plaster"),
+            ("bled", "bled"),
+            ("motoring", "motor"),
+            ("sing", "sing"),
+            ("conflated", "conflat"),
+            ("hopping", "hop"),
+            ("falling", "fall"),
+            ("filing", "file"),
+            ("happy", "happi"),
+            ("sky", "sky"),
+            ("relational", "relat"),
+            ("conditional", "condit"),
+            ("rational", "ration"),
+            ("generalization", "gener"),
+            ("adoption", "adopt"),
+            ("controlling", "control"),
+            ("running", "run"),
+            ("runs", "run"),
+            ("run", "run"),
+        ];
+        for (word, expected) in cases {
+            assert_eq!(stem(word), expected, "{}", word);
+        }
+    }
+
+    #[test]
+    fn test_stem_leaves_other_words_alone() {
+        for word in ["is", "Running", "résumés", "c3po", ""] {
+            assert_eq!(stem(word), word);
+        }
+    }
+}
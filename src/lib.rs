@@ -5,28 +5,46 @@
 #![feature(async_fn_track_caller)]
 #![feature(let_chains)]
 
-use crate::{encryption::Encryption, search::SearchEngine, storage::StorageOperations};
-use std::net::SocketAddr;
+use crate::{config::ZzapConfig, encryption::Encryption, storage::StorageOperations};
 
+pub mod client;
+pub mod config;
 pub mod encryption;
 mod lang;
+mod logging;
 pub mod protocol;
 pub mod search;
 pub mod server;
 pub mod storage;
 
+/// Starts the server with the default configuration, see [`start_with`].
 pub async fn start() -> Result<(), Box<dyn std::error::Error>> {
-    let mut storage = storage::Storage::new("storage.db");
-    let encryption = encryption::MockEncryptor::new();
-    let search_engine = search::StdSearchEngine::new();
-
-    storage.initialize()?;
-    search_engine.initialize(&storage)?;
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 13413));
-    let server = server::ZzapServer::new(addr, storage, encryption, search_engine);
+    start_with(ZzapConfig::default()).await
+}
 
-    println!("zzap server starting on {}", addr);
+pub async fn start_with(config: ZzapConfig) -> Result<(), Box<dyn std::error::Error>> {
+    logging::init(config.log_level);
+    let mut storage =
+        storage::Storage::new(&config.persistence_path).with_compression(config.compression);
+    let encryption = encryption::MockEncryptor::new();
+    let search_engine = search::engine_by_name(&config.engine, config.max_results)
+        .ok_or_else(|| format!("unknown search engine {}", config.engine))?;
+
+    // reading the snapshot and indexing it blocks, keep it off the runtime threads
+    let (storage, search_engine) = tokio::task::spawn_blocking(move || {
+        storage.initialize()?;
+        search_engine.initialize(&storage)?;
+        Ok::<_, storage::StorageError>((storage, search_engine))
+    })
+    .await??;
+
+    let addr = config.addr;
+    tracing::info!(
+        "zzap server starting on {} with the {} engine",
+        addr,
+        config.engine
+    );
+    let server = server::ZzapServer::new(addr, storage, encryption, search_engine, config);
 
     server.run().await?;
 
@@ -0,0 +1,35 @@
+//! Logs of the server, written to stderr through [`tracing`].
+//!
+//! Connections and requests open spans, so every event logged while handling a request carries
+//! the peer, command, bucket and collection it is about.
+
+use std::sync::OnceLock;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+
+/// Changes the level of the subscriber installed by [`init`].
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Installs the global subscriber, logging events at `level` and above.
+///
+/// Does nothing if a subscriber is already installed, i.e. by an application embedding the
+/// server, which then decides what is logged.
+pub fn init(level: LevelFilter) {
+    let (filter, handle) = reload::Layer::new(level);
+    let installed = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .try_init();
+    if installed.is_ok() {
+        let _ = LEVEL.set(handle);
+    }
+}
+
+/// Logs events at `level` and above from now on, if [`init`] installed the subscriber.
+pub fn set_level(level: LevelFilter) {
+    if let Some(handle) = LEVEL.get() {
+        let _ = handle.reload(level);
+    }
+}
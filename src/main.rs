@@ -1,8 +1,9 @@
 #![warn(clippy::all)]
 
-use zzap::start;
+use zzap::{config::ZzapConfig, start_with};
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    start().await
+    let config = ZzapConfig::from_env_and_args(std::env::args().skip(1))?;
+    start_with(config).await
 }
\ No newline at end of file
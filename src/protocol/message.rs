@@ -10,4 +10,4 @@ pub trait Message {
     fn from_bytes(bytes: &[u8]) -> Result<Self, DecodingError>
     where
         Self: Sized;
-}
\ No newline at end of file
+}
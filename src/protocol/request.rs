@@ -1,15 +1,99 @@
 use super::message::{DecodingError, Message};
+use crate::search::{SearchOptions, MAX_FUZZY_DISTANCE, MAX_PAGE_BOUND};
+use std::iter::Peekable;
 
+/// How forgiving the parser is about malformed input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Extra trailing arguments are ignored.
+    #[default]
+    Lenient,
+    /// Commands carrying more arguments than expected are rejected.
+    Strict,
+}
+
+/// What `BLACKLIST` does before reporting the blacklist.
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlacklistAction {
+    Show,
+    /// Rebuilds it from the most common tokens of the index
+    Regenerate,
+    Clear,
+}
+
+/// What `CONFIG` does with the server settings, see [`ZzapConfig`].
+///
+/// [`ZzapConfig`]: crate::config::ZzapConfig
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigAction {
+    Get { setting: String },
+    Set { setting: String, value: String },
+    List,
+}
+
+/// What a conditional write requires of the document it replaces.
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetCondition {
+    /// `SETNX`, only creates the document
+    IfAbsent,
+    /// `UPDATE`, only replaces an existing document
+    IfPresent,
+}
+
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Request {
     Ping,
+    /// Reports whether the server is ready to take traffic, failing while it starts, shuts down or
+    /// rebuilds an index
+    Health,
+    /// Authenticates the connection, required before other commands when the server has a password
+    Auth {
+        password: String,
+    },
+    /// Selects the bucket used by the following requests of the connection that leave it as `_`,
+    /// over the configured default. An empty bucket goes back to the configured default.
+    Use {
+        bucket: String,
+    },
+    /// Does nothing, used to measure the protocol overhead alone
+    Noop,
+    /// Waits until every document stored so far is searchable
+    Sync,
     Set {
         bucket: String,
         collection: String,
         id: String,
         content: String,
         key: Option<String>,
+        /// Seconds until the document expires, never if `None`
+        ttl: Option<u64>,
+    },
+    /// A `SET` only carried out if the document exists, or only if it does not
+    SetIf {
+        condition: SetCondition,
+        bucket: String,
+        collection: String,
+        id: String,
+        content: String,
+        key: Option<String>,
+        ttl: Option<u64>,
+    },
+    /// `SET` of several `(id, content)` documents of a collection, all written or none
+    MSet {
+        bucket: String,
+        collection: String,
+        docs: Vec<(String, String)>,
+    },
+    /// `SET` of a new document, under an id generated by the server
+    Add {
+        bucket: String,
+        collection: String,
+        content: String,
+        key: Option<String>,
     },
     Get {
         bucket: String,
@@ -17,34 +101,256 @@ pub enum Request {
         id: String,
         key: Option<String>,
     },
+    /// `GET` of several documents of a collection, in the order of their ids
+    MGet {
+        bucket: String,
+        collection: String,
+        ids: Vec<String>,
+    },
+    /// Sets the seconds until the document expires
+    Expire {
+        bucket: String,
+        collection: String,
+        id: String,
+        seconds: u64,
+    },
+    /// Appends to the content of the document, creating it if it is missing
+    Append {
+        bucket: String,
+        collection: String,
+        id: String,
+        content: String,
+    },
+    /// Adds to the integer stored as the content of the document, a missing one counting as 0
+    Incr {
+        bucket: String,
+        collection: String,
+        id: String,
+        delta: i64,
+    },
+    /// Whether the document is stored, without its content
+    Exists {
+        bucket: String,
+        collection: String,
+        id: String,
+    },
+    /// Ids of the documents of a collection, sorted and at most `limit` of them
+    ListIds {
+        bucket: String,
+        collection: String,
+        limit: Option<usize>,
+    },
+    /// Up to `count` ids of a collection sorted ascending, the first ones sorting after the
+    /// `cursor` id or from the first if `None`
+    Scan {
+        bucket: String,
+        collection: String,
+        cursor: Option<String>,
+        count: Option<usize>,
+    },
+    /// Every document of a collection as newline-delimited JSON, one `{"id","content"}` per item
+    Export {
+        bucket: String,
+        collection: String,
+    },
+    /// Documents of an `EXPORT` dump stored into a collection, existing ids are skipped unless
+    /// `replace` is set
+    Import {
+        bucket: String,
+        collection: String,
+        content: String,
+        replace: bool,
+    },
+    /// `GET` that only returns the document when it changed after `since_version`
+    GetIf {
+        bucket: String,
+        collection: String,
+        id: String,
+        since_version: u64,
+    },
+    /// `GET` of the content bytes from `start` up to, but not including, `end`
+    GetRange {
+        bucket: String,
+        collection: String,
+        id: String,
+        start: usize,
+        end: usize,
+        key: Option<String>,
+    },
     Search {
         bucket: String,
         collection: String,
         query: String,
+        options: SearchOptions,
+    },
+    /// `SEARCH` matching every token starting with a word of the query
+    SearchPrefix {
+        bucket: String,
+        collection: String,
+        query: String,
+        options: SearchOptions,
+    },
+    /// `SEARCH` matching every token within `max_distance` edits of a word of the query
+    SearchFuzzy {
+        bucket: String,
+        collection: String,
+        max_distance: usize,
+        query: String,
+        options: SearchOptions,
+    },
+    /// `SEARCH` returning a snippet of each matching document around its first match
+    SearchHighlight {
+        bucket: String,
+        collection: String,
+        query: String,
+        options: SearchOptions,
+    },
+    /// `SEARCH` over a comma-separated list of collections
+    MultiSearch {
+        bucket: String,
+        collections: Vec<String>,
+        query: String,
+        options: SearchOptions,
     },
     Remove {
         bucket: String,
         collection: String,
         id: String,
     },
+    /// Moves a document to a new id, replacing any document stored under it
+    Rename {
+        bucket: String,
+        collection: String,
+        old_id: String,
+        new_id: String,
+    },
+    /// Copies a document to a new id, in the same collection or in `to_collection`
+    Copy {
+        bucket: String,
+        collection: String,
+        src_id: String,
+        dst_id: String,
+        to_collection: Option<String>,
+        /// Replaces a document already stored under the new id instead of failing
+        replace: bool,
+    },
+    /// Removes a collection with every document in it
+    DropCollection {
+        bucket: String,
+        collection: String,
+    },
+    /// Removes a bucket with every collection and document in it
+    DropBucket {
+        bucket: String,
+    },
+    DryRun(Box<Request>),
+    Save,
+    /// Reports how much is stored and indexed
+    Stats,
+    /// Reports the version of the server and what it runs with
+    Info,
+    /// Reports differences between the stored documents of a collection and its index
+    Verify {
+        bucket: String,
+        collection: String,
+    },
+    /// Rebuilds the index of a collection from its stored documents
+    Reindex {
+        bucket: String,
+        collection: String,
+    },
+    /// Ids of the documents a collection has indexed under the token of a word
+    DumpToken {
+        bucket: String,
+        collection: String,
+        token: String,
+    },
+    /// Swaps the search engine for a freshly built one, reindexing every document
+    SetEngine {
+        name: String,
+    },
+    /// Manages the tokens left out of every index
+    Blacklist {
+        action: BlacklistAction,
+    },
+    /// Reads or changes the server settings while it runs
+    Config {
+        action: ConfigAction,
+    },
+    /// Changes a single option of the collection's configuration
+    Configure {
+        bucket: String,
+        collection: String,
+        option: String,
+        value: String,
+    },
 }
 
 impl Message for Request {
     fn to_bytes(&self) -> Vec<u8> {
+        if let Some(request) = self.with_default_fields_encoded() {
+            return request.to_bytes();
+        }
         match self {
             Request::Ping => b"PING\n".to_vec(),
+            Request::Health => b"HEALTH\n".to_vec(),
+            Request::Auth { password } => format!("AUTH {}\n", password).into_bytes(),
+            Request::Use { bucket } if bucket.is_empty() => {
+                format!("USE {}\n", DEFAULT_FIELD).into_bytes()
+            }
+            Request::Use { bucket } => format!("USE {}\n", bucket).into_bytes(),
+            Request::Noop => b"NOOP\n".to_vec(),
+            Request::Sync => b"SYNC\n".to_vec(),
+            Request::Save => b"SAVE\n".to_vec(),
+            Request::Stats => b"STATS\n".to_vec(),
+            Request::Info => b"INFO\n".to_vec(),
             Request::Set {
                 bucket,
                 collection,
                 id,
                 content,
                 key,
+                ttl,
+            } => encode_set("SET", bucket, collection, id, content, key, ttl),
+            Request::SetIf {
+                condition,
+                bucket,
+                collection,
+                id,
+                content,
+                key,
+                ttl,
+            } => {
+                let command = match condition {
+                    SetCondition::IfAbsent => "SETNX",
+                    SetCondition::IfPresent => "UPDATE",
+                };
+                encode_set(command, bucket, collection, id, content, key, ttl)
+            }
+            Request::MSet {
+                bucket,
+                collection,
+                docs,
+            } => {
+                let mut bytes =
+                    format!("MSET {} {} {}", bucket, collection, docs.len()).into_bytes();
+                for (id, content) in docs {
+                    bytes.extend_from_slice(format!(" {} {}:", id, content.len()).as_bytes());
+                    bytes.extend_from_slice(content.as_bytes());
+                }
+                bytes.push(b'\n');
+                bytes
+            }
+            Request::Add {
+                bucket,
+                collection,
+                content,
+                key,
             } => {
                 let mut bytes = format!(
-                    "SET {} {} {} {}:{}",
+                    "ADD {} {} {}:{}",
                     bucket,
                     collection,
-                    id,
                     content.len(),
                     content
                 )
@@ -74,24 +380,514 @@ impl Message for Request {
                 bucket,
                 collection,
                 query,
-            } => format!("SEARCH {} {} {}\n", bucket, collection, query).into_bytes(),
+                options,
+            } => encode_search("SEARCH", bucket, collection, query, options),
+            Request::SearchPrefix {
+                bucket,
+                collection,
+                query,
+                options,
+            } => encode_search("SEARCHPREFIX", bucket, collection, query, options),
+            Request::SearchFuzzy {
+                bucket,
+                collection,
+                max_distance,
+                query,
+                options,
+            } => {
+                // the distance goes before the clauses, as if it was part of the collection
+                let collection = format!("{} {}", collection, max_distance);
+                encode_search("SEARCHFUZZY", bucket, &collection, query, options)
+            }
+            Request::SearchHighlight {
+                bucket,
+                collection,
+                query,
+                options,
+            } => encode_search("SEARCHHL", bucket, collection, query, options),
+            Request::MultiSearch {
+                bucket,
+                collections,
+                query,
+                options,
+            } => encode_search("SEARCH", bucket, &collections.join(","), query, options),
+            Request::GetIf {
+                bucket,
+                collection,
+                id,
+                since_version,
+            } => format!("GETIF {} {} {} {}\n", bucket, collection, id, since_version).into_bytes(),
+            Request::GetRange {
+                bucket,
+                collection,
+                id,
+                start,
+                end,
+                key,
+            } => {
+                let mut bytes = format!(
+                    "GETRANGE {} {} {} {} {}",
+                    bucket, collection, id, start, end
+                )
+                .into_bytes();
+                if let Some(k) = key {
+                    bytes.extend_from_slice(b" ");
+                    bytes.extend_from_slice(k.as_bytes());
+                }
+                bytes.push(b'\n');
+                bytes
+            }
+            Request::Exists {
+                bucket,
+                collection,
+                id,
+            } => format!("EXISTS {} {} {}\n", bucket, collection, id).into_bytes(),
+            Request::MGet {
+                bucket,
+                collection,
+                ids,
+            } => format!("MGET {} {} {}\n", bucket, collection, ids.join(" ")).into_bytes(),
+            Request::Expire {
+                bucket,
+                collection,
+                id,
+                seconds,
+            } => format!("EXPIRE {} {} {} {}\n", bucket, collection, id, seconds).into_bytes(),
+            Request::Append {
+                bucket,
+                collection,
+                id,
+                content,
+            } => {
+                let mut bytes =
+                    format!("APPEND {} {} {} {}:", bucket, collection, id, content.len())
+                        .into_bytes();
+                bytes.extend_from_slice(content.as_bytes());
+                bytes.push(b'\n');
+                bytes
+            }
+            Request::Incr {
+                bucket,
+                collection,
+                id,
+                delta,
+            } => match delta {
+                1 => format!("INCR {} {} {}\n", bucket, collection, id),
+                -1 => format!("DECR {} {} {}\n", bucket, collection, id),
+                _ => format!("INCRBY {} {} {} {}\n", bucket, collection, id, delta),
+            }
+            .into_bytes(),
+            Request::ListIds {
+                bucket,
+                collection,
+                limit,
+            } => match limit {
+                Some(limit) => format!("LISTIDS {} {} {}\n", bucket, collection, limit),
+                None => format!("LISTIDS {} {}\n", bucket, collection),
+            }
+            .into_bytes(),
+            Request::Scan {
+                bucket,
+                collection,
+                cursor,
+                count,
+            } => {
+                let cursor = scan_cursor(cursor.as_deref());
+                match count {
+                    Some(count) => {
+                        format!("SCAN {} {} {} {}\n", bucket, collection, cursor, count)
+                    }
+                    None => format!("SCAN {} {} {}\n", bucket, collection, cursor),
+                }
+                .into_bytes()
+            }
+            Request::Export { bucket, collection } => {
+                format!("EXPORT {} {}\n", bucket, collection).into_bytes()
+            }
+            Request::Import {
+                bucket,
+                collection,
+                content,
+                replace,
+            } => {
+                let mut bytes = format!("IMPORT {} {}", bucket, collection).into_bytes();
+                if *replace {
+                    bytes.extend_from_slice(b" REPLACE");
+                }
+                bytes.extend_from_slice(format!(" {}:", content.len()).as_bytes());
+                bytes.extend_from_slice(content.as_bytes());
+                bytes.push(b'\n');
+                bytes
+            }
             Request::Remove {
                 bucket,
                 collection,
                 id,
             } => format!("REMOVE {} {} {}\n", bucket, collection, id).into_bytes(),
+            Request::Rename {
+                bucket,
+                collection,
+                old_id,
+                new_id,
+            } => format!("RENAME {} {} {} {}\n", bucket, collection, old_id, new_id).into_bytes(),
+            Request::Copy {
+                bucket,
+                collection,
+                src_id,
+                dst_id,
+                to_collection,
+                replace,
+            } => {
+                let mut command = format!("COPY {} {} {} {}", bucket, collection, src_id, dst_id);
+                if let Some(to_collection) = to_collection {
+                    command.push_str(&format!(" TO {}", to_collection));
+                }
+                if *replace {
+                    command.push_str(" REPLACE");
+                }
+                command.push('\n');
+                command.into_bytes()
+            }
+            Request::DropCollection { bucket, collection } => {
+                format!("DROPCOLLECTION {} {}\n", bucket, collection).into_bytes()
+            }
+            Request::DropBucket { bucket } => format!("DROPBUCKET {}\n", bucket).into_bytes(),
+            Request::SetEngine { name } => format!("SETENGINE {}\n", name).into_bytes(),
+            Request::Blacklist { action } => {
+                let action = match action {
+                    BlacklistAction::Show => "SHOW",
+                    BlacklistAction::Regenerate => "REGENERATE",
+                    BlacklistAction::Clear => "CLEAR",
+                };
+                format!("BLACKLIST {}\n", action).into_bytes()
+            }
+            Request::Verify { bucket, collection } => {
+                format!("VERIFY {} {}\n", bucket, collection).into_bytes()
+            }
+            Request::Reindex { bucket, collection } => {
+                format!("REINDEX {} {}\n", bucket, collection).into_bytes()
+            }
+            Request::DumpToken {
+                bucket,
+                collection,
+                token,
+            } => format!("DUMPTOKEN {} {} {}\n", bucket, collection, token).into_bytes(),
+            Request::Config { action } => match action {
+                ConfigAction::Get { setting } => format!("CONFIG GET {}\n", setting).into_bytes(),
+                ConfigAction::Set { setting, value } => {
+                    format!("CONFIG SET {} {}\n", setting, value).into_bytes()
+                }
+                ConfigAction::List => b"CONFIG LIST\n".to_vec(),
+            },
+            Request::Configure {
+                bucket,
+                collection,
+                option,
+                value,
+            } => format!("CONFIGURE {} {} {} {}\n", bucket, collection, option, value).into_bytes(),
+            Request::DryRun(request) => {
+                let mut bytes = b"DRYRUN ".to_vec();
+                bytes.extend_from_slice(&request.to_bytes());
+                bytes
+            }
         }
     }
 
     fn from_bytes(bytes: &[u8]) -> Result<Self, DecodingError> {
+        Request::from_bytes_with_mode(bytes, ParseMode::Lenient)
+    }
+}
+
+impl Request {
+    /// The command of the request, as sent on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Ping => "PING",
+            Request::Health => "HEALTH",
+            Request::Auth { .. } => "AUTH",
+            Request::Use { .. } => "USE",
+            Request::Noop => "NOOP",
+            Request::Sync => "SYNC",
+            Request::Set { .. } => "SET",
+            Request::SetIf { condition, .. } => match condition {
+                SetCondition::IfAbsent => "SETNX",
+                SetCondition::IfPresent => "UPDATE",
+            },
+            Request::MSet { .. } => "MSET",
+            Request::Add { .. } => "ADD",
+            Request::Get { .. } => "GET",
+            Request::MGet { .. } => "MGET",
+            Request::Expire { .. } => "EXPIRE",
+            Request::Append { .. } => "APPEND",
+            Request::Incr { delta, .. } => match delta {
+                1 => "INCR",
+                -1 => "DECR",
+                _ => "INCRBY",
+            },
+            Request::Exists { .. } => "EXISTS",
+            Request::ListIds { .. } => "LISTIDS",
+            Request::Scan { .. } => "SCAN",
+            Request::Export { .. } => "EXPORT",
+            Request::Import { .. } => "IMPORT",
+            Request::GetIf { .. } => "GETIF",
+            Request::GetRange { .. } => "GETRANGE",
+            Request::Search { .. } | Request::MultiSearch { .. } => "SEARCH",
+            Request::SearchPrefix { .. } => "SEARCHPREFIX",
+            Request::SearchFuzzy { .. } => "SEARCHFUZZY",
+            Request::SearchHighlight { .. } => "SEARCHHL",
+            Request::Remove { .. } => "REMOVE",
+            Request::Rename { .. } => "RENAME",
+            Request::Copy { .. } => "COPY",
+            Request::DropCollection { .. } => "DROPCOLLECTION",
+            Request::DropBucket { .. } => "DROPBUCKET",
+            Request::DryRun(_) => "DRYRUN",
+            Request::Save => "SAVE",
+            Request::Stats => "STATS",
+            Request::Info => "INFO",
+            Request::Verify { .. } => "VERIFY",
+            Request::Reindex { .. } => "REINDEX",
+            Request::DumpToken { .. } => "DUMPTOKEN",
+            Request::SetEngine { .. } => "SETENGINE",
+            Request::Blacklist { .. } => "BLACKLIST",
+            Request::Config { .. } => "CONFIG",
+            Request::Configure { .. } => "CONFIGURE",
+        }
+    }
+
+    /// The bucket the request reads or writes, if any.
+    pub fn bucket(&self) -> Option<&str> {
+        match self {
+            Request::Set { bucket, .. }
+            | Request::SetIf { bucket, .. }
+            | Request::MSet { bucket, .. }
+            | Request::Add { bucket, .. }
+            | Request::Get { bucket, .. }
+            | Request::MGet { bucket, .. }
+            | Request::Expire { bucket, .. }
+            | Request::Append { bucket, .. }
+            | Request::Incr { bucket, .. }
+            | Request::Exists { bucket, .. }
+            | Request::ListIds { bucket, .. }
+            | Request::Scan { bucket, .. }
+            | Request::Export { bucket, .. }
+            | Request::Import { bucket, .. }
+            | Request::GetIf { bucket, .. }
+            | Request::GetRange { bucket, .. }
+            | Request::Search { bucket, .. }
+            | Request::SearchPrefix { bucket, .. }
+            | Request::SearchFuzzy { bucket, .. }
+            | Request::SearchHighlight { bucket, .. }
+            | Request::MultiSearch { bucket, .. }
+            | Request::Remove { bucket, .. }
+            | Request::Rename { bucket, .. }
+            | Request::Copy { bucket, .. }
+            | Request::DropCollection { bucket, .. }
+            | Request::DropBucket { bucket }
+            | Request::Verify { bucket, .. }
+            | Request::Reindex { bucket, .. }
+            | Request::DumpToken { bucket, .. }
+            | Request::Configure { bucket, .. } => Some(bucket),
+            Request::DryRun(request) => request.bucket(),
+            _ => None,
+        }
+    }
+
+    /// The bucket the request reads or writes, for filling it in, see [`Request::bucket`].
+    pub fn bucket_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Request::Set { bucket, .. }
+            | Request::SetIf { bucket, .. }
+            | Request::MSet { bucket, .. }
+            | Request::Add { bucket, .. }
+            | Request::Get { bucket, .. }
+            | Request::MGet { bucket, .. }
+            | Request::Expire { bucket, .. }
+            | Request::Append { bucket, .. }
+            | Request::Incr { bucket, .. }
+            | Request::Exists { bucket, .. }
+            | Request::ListIds { bucket, .. }
+            | Request::Scan { bucket, .. }
+            | Request::Export { bucket, .. }
+            | Request::Import { bucket, .. }
+            | Request::GetIf { bucket, .. }
+            | Request::GetRange { bucket, .. }
+            | Request::Search { bucket, .. }
+            | Request::SearchPrefix { bucket, .. }
+            | Request::SearchFuzzy { bucket, .. }
+            | Request::SearchHighlight { bucket, .. }
+            | Request::MultiSearch { bucket, .. }
+            | Request::Remove { bucket, .. }
+            | Request::Rename { bucket, .. }
+            | Request::Copy { bucket, .. }
+            | Request::DropCollection { bucket, .. }
+            | Request::DropBucket { bucket }
+            | Request::Verify { bucket, .. }
+            | Request::Reindex { bucket, .. }
+            | Request::DumpToken { bucket, .. }
+            | Request::Configure { bucket, .. } => Some(bucket),
+            Request::DryRun(request) => request.bucket_mut(),
+            _ => None,
+        }
+    }
+
+    /// The collection the request reads or writes, if there is a single one.
+    pub fn collection(&self) -> Option<&str> {
+        match self {
+            Request::Set { collection, .. }
+            | Request::SetIf { collection, .. }
+            | Request::MSet { collection, .. }
+            | Request::Add { collection, .. }
+            | Request::Get { collection, .. }
+            | Request::MGet { collection, .. }
+            | Request::Expire { collection, .. }
+            | Request::Append { collection, .. }
+            | Request::Incr { collection, .. }
+            | Request::Exists { collection, .. }
+            | Request::ListIds { collection, .. }
+            | Request::Scan { collection, .. }
+            | Request::Export { collection, .. }
+            | Request::Import { collection, .. }
+            | Request::GetIf { collection, .. }
+            | Request::GetRange { collection, .. }
+            | Request::Search { collection, .. }
+            | Request::SearchPrefix { collection, .. }
+            | Request::SearchFuzzy { collection, .. }
+            | Request::SearchHighlight { collection, .. }
+            | Request::Remove { collection, .. }
+            | Request::Rename { collection, .. }
+            | Request::Copy { collection, .. }
+            | Request::DropCollection { collection, .. }
+            | Request::Verify { collection, .. }
+            | Request::Reindex { collection, .. }
+            | Request::DumpToken { collection, .. }
+            | Request::Configure { collection, .. } => Some(collection),
+            Request::DryRun(request) => request.collection(),
+            _ => None,
+        }
+    }
+
+    /// The collection the request reads or writes, for filling it in, see [`Request::collection`].
+    pub fn collection_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Request::Set { collection, .. }
+            | Request::SetIf { collection, .. }
+            | Request::MSet { collection, .. }
+            | Request::Add { collection, .. }
+            | Request::Get { collection, .. }
+            | Request::MGet { collection, .. }
+            | Request::Expire { collection, .. }
+            | Request::Append { collection, .. }
+            | Request::Incr { collection, .. }
+            | Request::Exists { collection, .. }
+            | Request::ListIds { collection, .. }
+            | Request::Scan { collection, .. }
+            | Request::Export { collection, .. }
+            | Request::Import { collection, .. }
+            | Request::GetIf { collection, .. }
+            | Request::GetRange { collection, .. }
+            | Request::Search { collection, .. }
+            | Request::SearchPrefix { collection, .. }
+            | Request::SearchFuzzy { collection, .. }
+            | Request::SearchHighlight { collection, .. }
+            | Request::Remove { collection, .. }
+            | Request::Rename { collection, .. }
+            | Request::Copy { collection, .. }
+            | Request::DropCollection { collection, .. }
+            | Request::Verify { collection, .. }
+            | Request::Reindex { collection, .. }
+            | Request::DumpToken { collection, .. }
+            | Request::Configure { collection, .. } => Some(collection),
+            Request::DryRun(request) => request.collection_mut(),
+            _ => None,
+        }
+    }
+
+    /// The same request with `_` in place of its empty buckets and collections, `None` if it has
+    /// none. An empty field would merge with the separators around it once encoded, while `_`
+    /// still leaves it to the server default.
+    fn with_default_fields_encoded(&self) -> Option<Request> {
+        let empty = |field: Option<&str>| field.is_some_and(str::is_empty);
+        let empty_collections = matches!(
+            self,
+            Request::MultiSearch { collections, .. } if collections.iter().any(String::is_empty)
+        );
+        if !empty(self.bucket()) && !empty(self.collection()) && !empty_collections {
+            return None;
+        }
+
+        let mut request = self.clone();
+        let encode = |field: Option<&mut String>| {
+            if let Some(field) = field.filter(|field| field.is_empty()) {
+                *field = DEFAULT_FIELD.to_string();
+            }
+        };
+        encode(request.bucket_mut());
+        encode(request.collection_mut());
+        if let Request::MultiSearch { collections, .. } = &mut request {
+            for collection in collections.iter_mut().filter(|c| c.is_empty()) {
+                *collection = DEFAULT_FIELD.to_string();
+            }
+        }
+        Some(request)
+    }
+
+    pub fn from_bytes_with_mode(bytes: &[u8], mode: ParseMode) -> Result<Self, DecodingError> {
         let input = String::from_utf8_lossy(bytes);
         let parts = input.clone();
         let mut parts = parts.trim_end().split_whitespace();
 
         match parts.next() {
-            Some("PING") => Ok(Request::Ping),
-            Some("SET") => {
-                let bucket = parts
+            Some("PING") => {
+                check_no_extra_arguments(parts, mode)?;
+                Ok(Request::Ping)
+            }
+            Some("HEALTH") => {
+                check_no_extra_arguments(parts, mode)?;
+                Ok(Request::Health)
+            }
+            Some("AUTH") => {
+                let password = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest(
+                        "Missing password".to_string(),
+                    ))?
+                    .to_string();
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Auth { password })
+            }
+            Some("USE") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Use { bucket })
+            }
+            Some("NOOP") => {
+                check_no_extra_arguments(parts, mode)?;
+                Ok(Request::Noop)
+            }
+            Some("SYNC") => {
+                check_no_extra_arguments(parts, mode)?;
+                Ok(Request::Sync)
+            }
+            // `PERSIST` is an alias, encoded back as `SAVE`
+            Some("SAVE") | Some("PERSIST") => {
+                check_no_extra_arguments(parts, mode)?;
+                Ok(Request::Save)
+            }
+            Some("STATS") => {
+                check_no_extra_arguments(parts, mode)?;
+                Ok(Request::Stats)
+            }
+            Some("INFO") => {
+                check_no_extra_arguments(parts, mode)?;
+                Ok(Request::Info)
+            }
+            Some(command @ ("SET" | "SETNX" | "UPDATE")) => {
+                let bucket = parts
                     .next()
                     .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?
                     .to_string();
@@ -107,83 +903,62 @@ impl Message for Request {
                     .to_string();
 
                 let after_params = input
-                    .replacen("SET ", "", 1)
+                    .replacen(&format!("{} ", command), "", 1)
                     .replacen(&format!("{} ", bucket), "", 1)
                     .replacen(&format!("{} ", collection), "", 1)
                     .replacen(&format!("{} ", id), "", 1);
 
                 let after_params = after_params.trim_start();
 
-                let maybe_len = after_params.find(':');
-                let (content, key) = match maybe_len {
-                    Some(len_pos) => {
-                        // it is in form of "4:content [key]"
-                        let len = after_params[..len_pos].trim();
-                        let len: Result<usize, _> = len.parse();
-
-                        if let Err(_) = len {
-                            return Err(DecodingError::InvalidRequest(
-                                "Invalid content length".to_string(),
-                            ));
-                        }
-                        let len = len.unwrap();
-
-                        let content_end = len_pos + 1 + len;
-                        if content_end > after_params.len() {
-                            return Err(DecodingError::InvalidRequest(
-                                "Content length exceeds input length".to_string(),
-                            ));
-                        }
-                        let position = len_pos + 1;
-                        if !after_params.is_char_boundary(position)
-                            || !after_params.is_char_boundary(content_end)
-                        {
-                            return Err(DecodingError::InvalidRequest(
-                                "Invalid content length".to_string(),
-                            ));
-                        }
-                        let content = &after_params[position..content_end];
-                        let key = after_params[content_end..].trim();
-                        let key = if key.is_empty() {
-                            None
-                        } else {
-                            Some(key.to_string())
-                        };
+                let (content, key, ttl) = parse_set_content(after_params)?;
+                let (bucket, collection) = (decode_field(&bucket), decode_field(&collection));
+                let content = content.to_string();
 
-                        (content.to_string(), key)
-                    }
-                    None => {
-                        // it is in form of "content [key]"
-
-                        let last_whitespace = after_params.rfind(|c: char| c.is_whitespace());
-
-                        match last_whitespace {
-                            Some(last_whitespace) => {
-                                let content = after_params[..last_whitespace].trim();
-                                let key = after_params[last_whitespace..].trim();
-
-                                if content.is_empty() && !key.is_empty() {
-                                    (key.to_string(), None)
-                                } else if !content.is_empty() && key.is_empty() {
-                                    (content.to_string(), None)
-                                } else {
-                                    (content.to_string(), Some(key.to_string()))
-                                }
-                            }
-                            None => (after_params.to_string(), None),
-                        }
+                let condition = match command {
+                    "SETNX" => SetCondition::IfAbsent,
+                    "UPDATE" => SetCondition::IfPresent,
+                    _ => {
+                        return Ok(Request::Set {
+                            bucket,
+                            collection,
+                            id,
+                            content,
+                            key,
+                            ttl,
+                        })
                     }
                 };
-
-                Ok(Request::Set {
+                Ok(Request::SetIf {
+                    condition,
                     bucket,
                     collection,
                     id,
-                    content: content.to_string(),
+                    content,
                     key,
+                    ttl,
                 })
             }
-            Some("GET") => {
+            Some("MSET") => {
+                let bucket = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection = parts.next().ok_or(DecodingError::InvalidRequest(
+                    "Missing collection".to_string(),
+                ))?;
+                let count: usize = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing count".to_string()))?
+                    .parse()
+                    .map_err(|_| DecodingError::InvalidRequest("Invalid count".to_string()))?;
+
+                let docs = parse_documents(skip_fields(&input, 4), count, mode)?;
+                Ok(Request::MSet {
+                    bucket: decode_field(bucket),
+                    collection: decode_field(collection),
+                    docs,
+                })
+            }
+            Some("ADD") => {
                 let bucket = parts
                     .next()
                     .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?
@@ -194,6 +969,73 @@ impl Message for Request {
                         "Missing collection".to_string(),
                     ))?
                     .to_string();
+                if parts.next().is_none() {
+                    return Err(DecodingError::InvalidRequest("Missing content".to_string()));
+                }
+
+                let after_params = input
+                    .replacen("ADD ", "", 1)
+                    .replacen(&format!("{} ", bucket), "", 1)
+                    .replacen(&format!("{} ", collection), "", 1);
+                let (content, key) = parse_content(after_params.trim_start())?;
+
+                Ok(Request::Add {
+                    bucket: decode_field(&bucket),
+                    collection: decode_field(&collection),
+                    content,
+                    key,
+                })
+            }
+            Some("GETRANGE") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
+                    .to_string();
+                let mut offset = |name: &str| {
+                    parts
+                        .next()
+                        .ok_or_else(|| DecodingError::InvalidRequest(format!("Missing {}", name)))?
+                        .parse()
+                        .map_err(|_| DecodingError::InvalidRequest(format!("Invalid {}", name)))
+                };
+                let start = offset("start")?;
+                let end = offset("end")?;
+                let key = parts.collect::<Vec<&str>>().join(" ").trim().to_string();
+
+                let key = if key.is_empty() { None } else { Some(key) };
+
+                Ok(Request::GetRange {
+                    bucket,
+                    collection,
+                    id,
+                    start,
+                    end,
+                    key,
+                })
+            }
+            Some("GET") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
                 let id = parts
                     .next()
                     .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
@@ -209,40 +1051,436 @@ impl Message for Request {
                     key,
                 })
             }
-            Some("SEARCH") => {
+            Some("EXISTS") => {
                 let bucket = parts
                     .next()
-                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
                     .to_string();
-                let collection = parts
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Exists {
+                    bucket,
+                    collection,
+                    id,
+                })
+            }
+            Some("MGET") => {
+                let bucket = parts
                     .next()
-                    .ok_or(DecodingError::InvalidRequest(
-                        "Missing collection".to_string(),
-                    ))?
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let ids: Vec<String> = parts.map(str::to_string).collect();
+                if ids.is_empty() {
+                    return Err(DecodingError::InvalidRequest("Missing id".to_string()));
+                }
+
+                Ok(Request::MGet {
+                    bucket,
+                    collection,
+                    ids,
+                })
+            }
+            Some("LISTIDS") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let limit = parts
+                    .next()
+                    .map(|limit| parse_count(Some(limit), "limit"))
+                    .transpose()?;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::ListIds {
+                    bucket,
+                    collection,
+                    limit,
+                })
+            }
+            Some("SCAN") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let cursor = parse_scan_cursor(parts.next())?;
+                let count = parts
+                    .next()
+                    .map(|count| match parse_count(Some(count), "count") {
+                        // a page of nothing would never move the cursor
+                        Ok(0) => Err(DecodingError::InvalidRequest("Invalid count".to_string())),
+                        count => count,
+                    })
+                    .transpose()?;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Scan {
+                    bucket,
+                    collection,
+                    cursor,
+                    count,
+                })
+            }
+            Some("EXPORT") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Export { bucket, collection })
+            }
+            Some("IMPORT") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let replace = parts.next() == Some("REPLACE");
+                let after_params = skip_fields(&input, if replace { 4 } else { 3 });
+                if after_params.is_empty() {
+                    return Err(DecodingError::InvalidRequest("Missing content".to_string()));
+                }
+                let (content, rest) = split_sized_content(after_params)?;
+                check_no_extra_arguments(rest.split_whitespace(), mode)?;
+
+                Ok(Request::Import {
+                    bucket,
+                    collection,
+                    content: content.to_string(),
+                    replace,
+                })
+            }
+            Some("EXPIRE") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
+                    .to_string();
+                let seconds = parse_count(parts.next(), "seconds")? as u64;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Expire {
+                    bucket,
+                    collection,
+                    id,
+                    seconds,
+                })
+            }
+            Some("APPEND") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
+                    .to_string();
+                let after_params = skip_fields(&input, 4);
+                if after_params.is_empty() {
+                    return Err(DecodingError::InvalidRequest("Missing content".to_string()));
+                }
+                let (content, rest) = split_sized_content(after_params)?;
+                check_no_extra_arguments(rest.split_whitespace(), mode)?;
+
+                Ok(Request::Append {
+                    bucket,
+                    collection,
+                    id,
+                    content: content.to_string(),
+                })
+            }
+            // `DECRBY n` is encoded back as `INCRBY -n`
+            Some(command @ ("INCR" | "DECR" | "INCRBY" | "DECRBY")) => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
+                    .to_string();
+                let delta = match command {
+                    "INCR" => 1,
+                    "DECR" => -1,
+                    _ => {
+                        let invalid =
+                            || DecodingError::InvalidRequest("Invalid increment".to_string());
+                        let delta: i64 = parts
+                            .next()
+                            .ok_or(DecodingError::InvalidRequest(
+                                "Missing increment".to_string(),
+                            ))?
+                            .parse()
+                            .map_err(|_| invalid())?;
+                        if command == "DECRBY" {
+                            delta.checked_neg().ok_or_else(invalid)?
+                        } else {
+                            delta
+                        }
+                    }
+                };
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Incr {
+                    bucket,
+                    collection,
+                    id,
+                    delta,
+                })
+            }
+            Some("GETIF") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
                     .to_string();
+                let since_version = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing version".to_string()))?
+                    .parse()
+                    .map_err(|_| DecodingError::InvalidRequest("Invalid version".to_string()))?;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::GetIf {
+                    bucket,
+                    collection,
+                    id,
+                    since_version,
+                })
+            }
+            Some("SEARCH") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let mut parts = parts.peekable();
+                let options = parse_search_clauses(&mut parts)?;
                 let query = parts.collect::<Vec<&str>>().join(" ");
 
+                if collection.contains(',') {
+                    let collections = collection
+                        .split(',')
+                        .filter(|c| !c.is_empty())
+                        .map(decode_field)
+                        .collect::<Vec<String>>();
+                    if collections.is_empty() {
+                        return Err(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ));
+                    }
+                    return Ok(Request::MultiSearch {
+                        bucket,
+                        collections,
+                        query,
+                        options,
+                    });
+                }
+
                 Ok(Request::Search {
                     bucket,
                     collection,
                     query,
+                    options,
                 })
             }
-            Some("REMOVE") => {
+            Some("SEARCHPREFIX") => {
                 let bucket = parts
                     .next()
-                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?
-                    .to_string();
-                let collection = parts
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let mut parts = parts.peekable();
+                let options = parse_search_clauses(&mut parts)?;
+                if options.highlight {
+                    // offsets are computed for whole words, a prefix would highlight nothing
+                    return Err(DecodingError::InvalidRequest(
+                        "HIGHLIGHT is not supported by SEARCHPREFIX".to_string(),
+                    ));
+                }
+                let query = parts.collect::<Vec<&str>>().join(" ");
+
+                Ok(Request::SearchPrefix {
+                    bucket,
+                    collection,
+                    query,
+                    options,
+                })
+            }
+            Some("SEARCHFUZZY") => {
+                let bucket = parts
                     .next()
-                    .ok_or(DecodingError::InvalidRequest(
-                        "Missing collection".to_string(),
-                    ))?
-                    .to_string();
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let max_distance = parse_count(parts.next(), "distance")?;
+                if max_distance > MAX_FUZZY_DISTANCE {
+                    return Err(DecodingError::InvalidRequest(format!(
+                        "Distance over {}",
+                        MAX_FUZZY_DISTANCE
+                    )));
+                }
+                let mut parts = parts.peekable();
+                let options = parse_search_clauses(&mut parts)?;
+                if options.highlight {
+                    // offsets are computed for the words of the query, not for the close ones
+                    return Err(DecodingError::InvalidRequest(
+                        "HIGHLIGHT is not supported by SEARCHFUZZY".to_string(),
+                    ));
+                }
+                let query = parts.collect::<Vec<&str>>().join(" ");
+
+                Ok(Request::SearchFuzzy {
+                    bucket,
+                    collection,
+                    max_distance,
+                    query,
+                    options,
+                })
+            }
+            Some("SEARCHHL") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let mut parts = parts.peekable();
+                let options = parse_search_clauses(&mut parts)?;
+                if options.highlight {
+                    // snippets already mark the matches
+                    return Err(DecodingError::InvalidRequest(
+                        "HIGHLIGHT is not supported by SEARCHHL".to_string(),
+                    ));
+                }
+                let query = parts.collect::<Vec<&str>>().join(" ");
+
+                Ok(Request::SearchHighlight {
+                    bucket,
+                    collection,
+                    query,
+                    options,
+                })
+            }
+            Some("REMOVE") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
                 let id = parts
                     .next()
                     .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
                     .to_string();
+                check_no_extra_arguments(parts, mode)?;
 
                 Ok(Request::Remove {
                     bucket,
@@ -250,24 +1488,615 @@ impl Message for Request {
                     id,
                 })
             }
-            _ => Err(DecodingError::InvalidRequest("Invalid command".to_string())),
-        }
-    }
-}
+            Some("RENAME") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let old_id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
+                    .to_string();
+                let new_id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing new id".to_string()))?
+                    .to_string();
+                check_no_extra_arguments(parts, mode)?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+                Ok(Request::Rename {
+                    bucket,
+                    collection,
+                    old_id,
+                    new_id,
+                })
+            }
+            Some("COPY") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let src_id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing id".to_string()))?
+                    .to_string();
+                let dst_id = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing new id".to_string()))?
+                    .to_string();
+                let mut parts = parts.peekable();
+                let mut to_collection = None;
+                let mut replace = false;
+                while let Some(&clause) = parts.peek() {
+                    match clause {
+                        "TO" => {
+                            parts.next();
+                            to_collection = Some(parts.next().map(decode_field).ok_or(
+                                DecodingError::InvalidRequest("Missing collection".to_string()),
+                            )?);
+                        }
+                        "REPLACE" => {
+                            parts.next();
+                            replace = true;
+                        }
+                        _ => break,
+                    }
+                }
+                check_no_extra_arguments(parts, mode)?;
 
-    #[test]
-    fn simple() {
-        let request = Request::from_bytes(b"PING\n").unwrap();
-        assert_eq!(request, Request::Ping);
-    }
+                Ok(Request::Copy {
+                    bucket,
+                    collection,
+                    src_id,
+                    dst_id,
+                    to_collection,
+                    replace,
+                })
+            }
+            Some("DROPCOLLECTION") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                check_no_extra_arguments(parts, mode)?;
 
-    #[test]
-    fn test_decode_set_command() {
-        let binary_data = std::fs::read_to_string("assets/tests/binary_data").unwrap();
+                Ok(Request::DropCollection { bucket, collection })
+            }
+            Some("DROPBUCKET") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::DropBucket { bucket })
+            }
+            Some("SETENGINE") => {
+                let name = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing engine".to_string()))?
+                    .to_string();
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::SetEngine { name })
+            }
+            Some("BLACKLIST") => {
+                let action = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing action".to_string()))?;
+                let action = match action.to_uppercase().as_str() {
+                    "SHOW" => BlacklistAction::Show,
+                    "REGENERATE" => BlacklistAction::Regenerate,
+                    "CLEAR" => BlacklistAction::Clear,
+                    _ => {
+                        return Err(DecodingError::InvalidRequest(format!(
+                            "Unknown action {}",
+                            action
+                        )))
+                    }
+                };
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Blacklist { action })
+            }
+            Some("VERIFY") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Verify { bucket, collection })
+            }
+            Some("REINDEX") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Reindex { bucket, collection })
+            }
+            Some("DUMPTOKEN") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let token = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing token".to_string()))?
+                    .to_string();
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::DumpToken {
+                    bucket,
+                    collection,
+                    token,
+                })
+            }
+            Some("CONFIG") => {
+                let action = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing action".to_string()))?;
+                let mut setting = || {
+                    parts
+                        .next()
+                        .map(|setting| setting.to_string())
+                        .ok_or(DecodingError::InvalidRequest("Missing setting".to_string()))
+                };
+                let action = match action.to_uppercase().as_str() {
+                    "GET" => ConfigAction::Get {
+                        setting: setting()?,
+                    },
+                    "SET" => ConfigAction::Set {
+                        setting: setting()?,
+                        value: parts
+                            .next()
+                            .ok_or(DecodingError::InvalidRequest("Missing value".to_string()))?
+                            .to_string(),
+                    },
+                    "LIST" => ConfigAction::List,
+                    _ => {
+                        return Err(DecodingError::InvalidRequest(format!(
+                            "Unknown action {}",
+                            action
+                        )))
+                    }
+                };
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Config { action })
+            }
+            Some("CONFIGURE") => {
+                let bucket = parts
+                    .next()
+                    .map(decode_field)
+                    .ok_or(DecodingError::InvalidRequest("Missing bucket".to_string()))?;
+                let collection =
+                    parts
+                        .next()
+                        .map(decode_field)
+                        .ok_or(DecodingError::InvalidRequest(
+                            "Missing collection".to_string(),
+                        ))?;
+                let option = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing option".to_string()))?
+                    .to_string();
+                let value = parts
+                    .next()
+                    .ok_or(DecodingError::InvalidRequest("Missing value".to_string()))?
+                    .to_string();
+                check_no_extra_arguments(parts, mode)?;
+
+                Ok(Request::Configure {
+                    bucket,
+                    collection,
+                    option,
+                    value,
+                })
+            }
+            Some("DRYRUN") => {
+                let command = input
+                    .trim_start()
+                    .strip_prefix("DRYRUN")
+                    .unwrap_or_default();
+                let request = Request::from_bytes_with_mode(command.trim_start().as_bytes(), mode)?;
+
+                if let Request::DryRun(_) = request {
+                    return Err(DecodingError::InvalidRequest(
+                        "DRYRUN cannot be nested".to_string(),
+                    ));
+                }
+
+                Ok(Request::DryRun(Box::new(request)))
+            }
+            _ => Err(DecodingError::InvalidRequest("Invalid command".to_string())),
+        }
+    }
+}
+
+/// Parses the `<content> [key]` tail of a `SET` or an `ADD`, `content` being either sized as in
+/// [`parse_sized_content`] or a run of words, the last of which is the key when there are several.
+fn parse_content(input: &str) -> Result<(String, Option<String>), DecodingError> {
+    Ok(match input.find(':') {
+        // it is in form of "4:content [key]"
+        Some(_) => parse_sized_content(input)?,
+        None => {
+            // it is in form of "content [key]"
+
+            let last_whitespace = input.rfind(|c: char| c.is_whitespace());
+
+            match last_whitespace {
+                Some(last_whitespace) => {
+                    let content = input[..last_whitespace].trim();
+                    let key = input[last_whitespace..].trim();
+
+                    if content.is_empty() && !key.is_empty() {
+                        (key.to_string(), None)
+                    } else if !content.is_empty() && key.is_empty() {
+                        (content.to_string(), None)
+                    } else {
+                        (content.to_string(), Some(key.to_string()))
+                    }
+                }
+                None => (input.to_string(), None),
+            }
+        }
+    })
+}
+
+/// Parses the `<content> [key] [EX <seconds>]` tail of a `SET`.
+///
+/// Without a length prefix, content ending with `EX` and a number is read as an expiry.
+fn parse_set_content(input: &str) -> Result<(String, Option<String>, Option<u64>), DecodingError> {
+    if input.contains(':') {
+        let (content, key) = parse_sized_content(input)?;
+        let (key, ttl) = match key {
+            Some(key) => {
+                let (key, ttl) = split_ttl(&key);
+                ((!key.is_empty()).then(|| key.to_string()), ttl)
+            }
+            None => (None, None),
+        };
+        return Ok((content, key, ttl));
+    }
+
+    let (rest, ttl) = match split_ttl(input) {
+        (rest, Some(ttl)) if !rest.is_empty() => (rest, Some(ttl)),
+        // `EX <seconds>` alone is the content rather than the expiry of empty content
+        _ => (input, None),
+    };
+    let (content, key) = parse_content(rest)?;
+    Ok((content, key, ttl))
+}
+
+/// Splits a trailing `EX <seconds>` off the input, which is left as is if it has none.
+fn split_ttl(input: &str) -> (&str, Option<u64>) {
+    let input = input.trim();
+    let ttl = input
+        .rsplit_once(char::is_whitespace)
+        .and_then(|(rest, seconds)| Some((rest.trim_end(), seconds.parse().ok()?)))
+        .and_then(|(rest, seconds)| {
+            let rest = rest.strip_suffix("EX")?;
+            // `EX` must be a word of its own
+            match rest.chars().next_back() {
+                None => Some(("", seconds)),
+                Some(c) if c.is_whitespace() => Some((rest.trim_end(), seconds)),
+                Some(_) => None,
+            }
+        });
+
+    match ttl {
+        Some((rest, seconds)) => (rest, Some(seconds)),
+        None => (input, None),
+    }
+}
+
+/// Parses the `<len>:<content> [key]` tail of a `SET` or an `ADD`.
+///
+/// Exactly `len` bytes after the colon are the content, whatever they contain, and whatever
+/// follows them is the key. The content must be followed by whitespace or the end of the input,
+/// so a wrong length is rejected rather than moving part of the content into the key.
+pub fn parse_sized_content(input: &str) -> Result<(String, Option<String>), DecodingError> {
+    let (content, rest) = split_sized_content(input)?;
+    let key = rest.trim();
+    let key = if key.is_empty() {
+        None
+    } else {
+        Some(key.to_string())
+    };
+
+    Ok((content.to_string(), key))
+}
+
+/// Splits the `<len>:<content>` at the start of the input from whatever follows it.
+fn split_sized_content(input: &str) -> Result<(&str, &str), DecodingError> {
+    let invalid_length = || DecodingError::InvalidRequest("Invalid content length".to_string());
+
+    let len_pos = input.find(':').ok_or(DecodingError::InvalidRequest(
+        "Missing content length".to_string(),
+    ))?;
+    let len: usize = input[..len_pos]
+        .trim()
+        .parse()
+        .map_err(|_| invalid_length())?;
+
+    let position = len_pos + 1;
+    // a huge declared length must not wrap around before the bounds check
+    let content_end = position.checked_add(len).ok_or_else(invalid_length)?;
+    if content_end > input.len() {
+        return Err(DecodingError::InvalidRequest(
+            "Content length exceeds input length".to_string(),
+        ));
+    }
+    if !input.is_char_boundary(position) || !input.is_char_boundary(content_end) {
+        return Err(invalid_length());
+    }
+    // a length shorter than the content would silently turn its end into the key
+    if input[content_end..]
+        .chars()
+        .next()
+        .is_some_and(|c| !c.is_whitespace())
+    {
+        return Err(DecodingError::InvalidRequest(
+            "Content length does not match content".to_string(),
+        ));
+    }
+
+    Ok((&input[position..content_end], &input[content_end..]))
+}
+
+/// Parses the `<id> <len>:<content>` documents of an `MSET`, `count` of them.
+fn parse_documents(
+    mut input: &str,
+    count: usize,
+    mode: ParseMode,
+) -> Result<Vec<(String, String)>, DecodingError> {
+    let mut docs = Vec::new();
+    for _ in 0..count {
+        let (id, rest) = input.trim_start().split_once(char::is_whitespace).ok_or(
+            DecodingError::InvalidRequest("Missing document".to_string()),
+        )?;
+        let (content, rest) = split_sized_content(rest.trim_start())?;
+        docs.push((id.to_string(), content.to_string()));
+        input = rest;
+    }
+    check_no_extra_arguments(input.split_whitespace(), mode)?;
+
+    Ok(docs)
+}
+
+/// The input past its first `count` whitespace-separated fields.
+fn skip_fields(input: &str, count: usize) -> &str {
+    let mut rest = input.trim_start();
+    for _ in 0..count {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
+
+/// `_` in place of a bucket or collection leaves it empty, to be filled in with the server default
+const DEFAULT_FIELD: &str = "_";
+
+fn decode_field(field: &str) -> String {
+    if field == DEFAULT_FIELD {
+        String::new()
+    } else {
+        field.to_string()
+    }
+}
+
+fn encode_set(
+    command: &str,
+    bucket: &str,
+    collection: &str,
+    id: &str,
+    content: &str,
+    key: &Option<String>,
+    ttl: &Option<u64>,
+) -> Vec<u8> {
+    let mut bytes = format!(
+        "{} {} {} {} {}:{}",
+        command,
+        bucket,
+        collection,
+        id,
+        content.len(),
+        content
+    )
+    .into_bytes();
+    if let Some(k) = key {
+        bytes.extend_from_slice(b" ");
+        bytes.extend_from_slice(k.as_bytes());
+    }
+    if let Some(ttl) = ttl {
+        bytes.extend_from_slice(format!(" EX {}", ttl).as_bytes());
+    }
+    bytes.push(b'\n');
+    bytes
+}
+
+fn encode_search(
+    command: &str,
+    bucket: &str,
+    collection: &str,
+    query: &str,
+    options: &SearchOptions,
+) -> Vec<u8> {
+    let mut bytes = format!("{} {} {} ", command, bucket, collection).into_bytes();
+    if let Some(prefix) = &options.id_prefix {
+        bytes.extend_from_slice(format!("IDPREFIX {} ", prefix).as_bytes());
+    }
+    if options.highlight {
+        bytes.extend_from_slice(b"HIGHLIGHT ");
+    }
+    if options.case_sensitive {
+        bytes.extend_from_slice(b"CASESENSITIVE ");
+    }
+    if options.match_all {
+        bytes.extend_from_slice(b"MATCHALL ");
+    }
+    if let Some(limit) = options.limit {
+        bytes.extend_from_slice(format!("LIMIT {} ", limit).as_bytes());
+    }
+    if options.offset > 0 {
+        bytes.extend_from_slice(format!("OFFSET {} ", options.offset).as_bytes());
+    }
+    bytes.extend_from_slice(query.as_bytes());
+    bytes.push(b'\n');
+    bytes
+}
+
+/// Parses the optional clauses of `SEARCH`, which go before the query.
+fn parse_search_clauses<'a>(
+    parts: &mut Peekable<impl Iterator<Item = &'a str>>,
+) -> Result<SearchOptions, DecodingError> {
+    let mut options = SearchOptions::default();
+    // optional clauses go before the query
+    while let Some(&clause) = parts.peek() {
+        match clause {
+            "IDPREFIX" => {
+                parts.next();
+                let prefix = parts.next().ok_or(DecodingError::InvalidRequest(
+                    "Missing id prefix".to_string(),
+                ))?;
+                options.id_prefix = Some(prefix.to_string());
+            }
+            "HIGHLIGHT" => {
+                parts.next();
+                options.highlight = true;
+            }
+            "CASESENSITIVE" => {
+                parts.next();
+                options.case_sensitive = true;
+            }
+            "MATCHALL" => {
+                parts.next();
+                options.match_all = true;
+            }
+            "LIMIT" => {
+                parts.next();
+                options.limit = Some(parse_page_bound(parts.next(), "limit")?);
+            }
+            "OFFSET" => {
+                parts.next();
+                options.offset = parse_page_bound(parts.next(), "offset")?;
+            }
+            _ => break,
+        }
+    }
+    Ok(options)
+}
+
+/// Parses the number following a clause such as `LIMIT`.
+fn parse_count(value: Option<&str>, name: &str) -> Result<usize, DecodingError> {
+    value
+        .ok_or_else(|| DecodingError::InvalidRequest(format!("Missing {}", name)))?
+        .parse()
+        .map_err(|_| DecodingError::InvalidRequest(format!("Invalid {}", name)))
+}
+
+/// Parses the number following `LIMIT` or `OFFSET`, at most [`MAX_PAGE_BOUND`].
+fn parse_page_bound(value: Option<&str>, name: &str) -> Result<usize, DecodingError> {
+    let count = parse_count(value, name)?;
+    if count > MAX_PAGE_BOUND {
+        return Err(DecodingError::InvalidRequest(format!(
+            "{} over {}",
+            name, MAX_PAGE_BOUND
+        )));
+    }
+    Ok(count)
+}
+
+/// The `SCAN` cursor resuming after `id`, `0` to start from the first id and once there are
+/// no more.
+pub fn scan_cursor(id: Option<&str>) -> String {
+    match id {
+        Some(id) => format!(">{}", id),
+        None => "0".to_string(),
+    }
+}
+
+/// Parses a cursor written by [`scan_cursor`].
+fn parse_scan_cursor(value: Option<&str>) -> Result<Option<String>, DecodingError> {
+    match value {
+        None => Err(DecodingError::InvalidRequest("Missing cursor".to_string())),
+        Some("0") => Ok(None),
+        Some(value) => match value.strip_prefix('>') {
+            Some(id) if !id.is_empty() => Ok(Some(id.to_string())),
+            _ => Err(DecodingError::InvalidRequest("Invalid cursor".to_string())),
+        },
+    }
+}
+
+fn check_no_extra_arguments<'a>(
+    mut parts: impl Iterator<Item = &'a str>,
+    mode: ParseMode,
+) -> Result<(), DecodingError> {
+    if mode == ParseMode::Strict && parts.next().is_some() {
+        return Err(DecodingError::InvalidRequest(
+            "too many arguments".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple() {
+        let request = Request::from_bytes(b"PING\n").unwrap();
+        assert_eq!(request, Request::Ping);
+    }
+
+    #[test]
+    fn test_decode_set_command() {
+        let binary_data = std::fs::read_to_string("assets/tests/binary_data").unwrap();
         let very_long_symbol = "a".repeat(1000);
 
         let cases: Vec<(&str, Result<Request, DecodingError>)> = vec![
@@ -280,6 +2109,7 @@ mod tests {
                     id: "1".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -290,6 +2120,7 @@ mod tests {
                     id: "123".into(),
                     content: "Hello, World!".into(),
                     key: Some("mykey".into()),
+                    ttl: None,
                 }),
             ),
             (
@@ -300,6 +2131,7 @@ mod tests {
                     id: "1".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -310,6 +2142,7 @@ mod tests {
                     id: "1".into(),
                     content: "username with".into(),
                     key: Some("spaces".into()),
+                    ttl: None,
                 }),
             ),
             (
@@ -320,6 +2153,7 @@ mod tests {
                     id: "1".into(),
                     content: "username with %!/)!(#$)@*!( special".into(),
                     key: Some("characters".into()),
+                    ttl: None,
                 }),
             ),
             (
@@ -330,6 +2164,7 @@ mod tests {
                     id: "1".into(),
                     content: "username with ascii non␍-prin␀␊tab␄le".into(),
                     key: Some("characters␄".into()),
+                    ttl: None,
                 }),
             ),
             // Content variations
@@ -341,6 +2176,7 @@ mod tests {
                     id: "i".into(),
                     content: "".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -351,6 +2187,7 @@ mod tests {
                     id: "i".into(),
                     content: "Hello World".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -361,6 +2198,7 @@ mod tests {
                     id: "i".into(),
                     content: "Hello\nWorld".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -371,12 +2209,17 @@ mod tests {
                     id: "i".into(),
                     content: "!@#$".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
                 "SET b c i 4:abc",
                 Err(DecodingError::InvalidRequest("Content length exceeds input length".to_string())),
             ),
+            (
+                "SET b c i 3:abcd",
+                Err(DecodingError::InvalidRequest("Content length does not match content".to_string())),
+            ),
             (
                 {
                     let s = format!("SET b c i {}:{}", binary_data.len(), binary_data);
@@ -390,6 +2233,7 @@ mod tests {
                         id: "i".into(),
                         content: content.into(),
                         key: None,
+                        ttl: None,
                     })
                 },
             ),
@@ -402,6 +2246,7 @@ mod tests {
                     id: "i".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -412,6 +2257,7 @@ mod tests {
                     id: "i".into(),
                     content: "test".into(),
                     key: Some("key with spaces".into()),
+                    ttl: None,
                 }),
             ),
             (
@@ -422,6 +2268,7 @@ mod tests {
                     id: "i".into(),
                     content: "test".into(),
                     key: Some("!@#$%^&*".into()),
+                    ttl: None,
                 }),
             ),
             (
@@ -435,6 +2282,7 @@ mod tests {
                     id: "i".into(),
                     content: "test".into(),
                     key: Some(very_long_symbol.clone()),
+                    ttl: None,
                 }),
             ),
             // Bucket and collection variations
@@ -446,6 +2294,7 @@ mod tests {
                     id: "4:test".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -456,6 +2305,7 @@ mod tests {
                     id: "4:test".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -472,6 +2322,7 @@ mod tests {
                     id: "1".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             // ID variations
@@ -483,6 +2334,7 @@ mod tests {
                     id: "4:test".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -496,6 +2348,7 @@ mod tests {
                     id: very_long_symbol,
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             // Edge cases
@@ -519,6 +2372,7 @@ mod tests {
                     id: "i".into(),
                     content: "4test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -529,6 +2383,7 @@ mod tests {
                     id: "i".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -546,6 +2401,7 @@ mod tests {
                     id: "i".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -556,6 +2412,7 @@ mod tests {
                     id: "i".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 }),
             ),
             (
@@ -566,6 +2423,7 @@ mod tests {
                     id: "i".into(),
                     content: "test".into(),
                     key: Some("SET b c j 5:test2".into()),
+                    ttl: None,
                 }),
             ),
             ( // case from fuzzer: invalid utf8 boundary
@@ -604,6 +2462,7 @@ mod tests {
                     id: "1".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 },
                 b"SET default users 1 4:test\n".to_vec(),
             ),
@@ -615,6 +2474,7 @@ mod tests {
                     id: "123".into(),
                     content: "Hello, World!".into(),
                     key: Some("mykey".into()),
+                    ttl: None,
                 },
                 b"SET myapp docs 123 13:Hello, World! mykey\n".to_vec(),
             ),
@@ -626,6 +2486,7 @@ mod tests {
                     id: "i".into(),
                     content: "".into(),
                     key: None,
+                    ttl: None,
                 },
                 b"SET b c i 0:\n".to_vec(),
             ),
@@ -637,6 +2498,7 @@ mod tests {
                     id: "doc1".into(),
                     content: "This is a test".into(),
                     key: None,
+                    ttl: None,
                 },
                 b"SET bucket col doc1 14:This is a test\n".to_vec(),
             ),
@@ -648,6 +2510,7 @@ mod tests {
                     id: "i".into(),
                     content: "!@#$%^&*".into(),
                     key: None,
+                    ttl: None,
                 },
                 b"SET b c i 8:!@#$%^&*\n".to_vec(),
             ),
@@ -659,6 +2522,7 @@ mod tests {
                     id: "i".into(),
                     content: "a".repeat(1000),
                     key: None,
+                    ttl: None,
                 },
                 format!("SET b c i 1000:{}\n", "a".repeat(1000)).into_bytes(),
             ),
@@ -670,6 +2534,7 @@ mod tests {
                     id: "i".into(),
                     content: "line1\nline2".into(),
                     key: None,
+                    ttl: None,
                 },
                 b"SET b c i 11:line1\nline2\n".to_vec(),
             ),
@@ -681,6 +2546,7 @@ mod tests {
                     id: "very_long_id_name".into(),
                     content: "test".into(),
                     key: None,
+                    ttl: None,
                 },
                 b"SET very_long_bucket_name very_long_collection_name very_long_id_name 4:test\n"
                     .to_vec(),
@@ -710,6 +2576,154 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_encode_noop_command() {
+        assert_eq!(Request::Noop.to_bytes(), b"NOOP\n".to_vec());
+    }
+
+    #[test]
+    fn test_decode_noop_command() {
+        let variants: Vec<&[u8]> = vec![b"NOOP\n", b"NOOP\r\n", b"\r\nNOOP\n"];
+        for variant in variants {
+            let request = Request::from_bytes(variant).unwrap();
+            assert_eq!(request, Request::Noop);
+        }
+    }
+
+    #[test]
+    fn test_sync_command_roundtrip() {
+        assert_eq!(Request::Sync.to_bytes(), b"SYNC\n".to_vec());
+        assert_eq!(Request::from_bytes(b"SYNC\n"), Ok(Request::Sync));
+    }
+
+    #[test]
+    fn test_encode_save_command() {
+        assert_eq!(Request::Save.to_bytes(), b"SAVE\n".to_vec());
+    }
+
+    #[test]
+    fn test_decode_save_command() {
+        let variants: Vec<&[u8]> = vec![b"SAVE\n", b"SAVE\r\n", b"\r\nSAVE\n", b"PERSIST\n"];
+        for variant in variants {
+            let request = Request::from_bytes(variant).unwrap();
+            assert_eq!(request, Request::Save);
+        }
+    }
+
+    #[test]
+    fn test_health_command_roundtrip() {
+        assert_eq!(Request::Health.to_bytes(), b"HEALTH\n".to_vec());
+        assert_eq!(Request::from_bytes(b"HEALTH\r\n"), Ok(Request::Health));
+    }
+
+    #[test]
+    fn test_use_command_roundtrip() {
+        let request = Request::Use { bucket: "b".into() };
+        assert_eq!(request.to_bytes(), b"USE b\n".to_vec());
+        assert_eq!(Request::from_bytes(b"USE b\n"), Ok(request));
+
+        // `_` goes back to the configured default
+        let request = Request::Use {
+            bucket: String::new(),
+        };
+        assert_eq!(request.to_bytes(), b"USE _\n".to_vec());
+        assert_eq!(Request::from_bytes(b"USE _\n"), Ok(request));
+
+        assert_eq!(
+            Request::from_bytes(b"USE\n"),
+            Err(DecodingError::InvalidRequest("Missing bucket".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_empty_fields_roundtrip() {
+        // left for the server default, sent as `_`
+        let cases = vec![
+            (
+                Request::Get {
+                    bucket: String::new(),
+                    collection: String::new(),
+                    id: "1".into(),
+                    key: None,
+                },
+                &b"GET _ _ 1\n"[..],
+            ),
+            (
+                Request::Remove {
+                    bucket: "b".into(),
+                    collection: String::new(),
+                    id: "1".into(),
+                },
+                b"REMOVE b _ 1\n",
+            ),
+            (
+                Request::DropBucket {
+                    bucket: String::new(),
+                },
+                b"DROPBUCKET _\n",
+            ),
+            (
+                Request::DryRun(Box::new(Request::DropCollection {
+                    bucket: String::new(),
+                    collection: "c".into(),
+                })),
+                b"DRYRUN DROPCOLLECTION _ c\n",
+            ),
+            (
+                Request::Search {
+                    bucket: String::new(),
+                    collection: String::new(),
+                    query: "hello".into(),
+                    options: SearchOptions::default(),
+                },
+                b"SEARCH _ _ hello\n",
+            ),
+            (
+                Request::MultiSearch {
+                    bucket: String::new(),
+                    collections: vec!["c1".into(), String::new()],
+                    query: "hello".into(),
+                    options: SearchOptions::default(),
+                },
+                b"SEARCH _ c1,_ hello\n",
+            ),
+        ];
+        for (request, bytes) in cases {
+            assert_eq!(request.to_bytes(), bytes.to_vec());
+            assert_eq!(Request::from_bytes(bytes), Ok(request));
+        }
+    }
+
+    #[test]
+    fn test_escaped_query_roundtrip() {
+        let query = ["say \"hi\"", "back\\slash", "line\nbreak"]
+            .map(crate::lang::query::escape_word)
+            .join(" ");
+        let request = Request::Search {
+            bucket: "b".into(),
+            collection: "c".into(),
+            query,
+            options: SearchOptions::default(),
+        };
+        let bytes = br#"SEARCH b c say\ \"hi\" back\\slash line\nbreak"#.to_vec();
+        let bytes = [bytes, b"\n".to_vec()].concat();
+
+        assert_eq!(request.to_bytes(), bytes);
+        assert_eq!(Request::from_bytes(&bytes), Ok(request));
+    }
+
+    #[test]
+    fn test_stats_command_roundtrip() {
+        assert_eq!(Request::Stats.to_bytes(), b"STATS\n".to_vec());
+        assert_eq!(Request::from_bytes(b"STATS\r\n"), Ok(Request::Stats));
+    }
+
+    #[test]
+    fn test_info_command_roundtrip() {
+        assert_eq!(Request::Info.to_bytes(), b"INFO\n".to_vec());
+        assert_eq!(Request::from_bytes(b"INFO\n"), Ok(Request::Info));
+    }
+
     #[test]
     fn test_encode_get_command() {
         let cases = vec![
@@ -763,7 +2777,8 @@ mod tests {
                 },
                 b"GET very_long_bucket_name very_long_collection_name very_long_id_name\n".to_vec(),
             ),
-            // GET command with empty bucket, collection, or id (edge case)
+            // GET command with empty bucket, collection, or id (edge case), the bucket and
+            // collection going to the server defaults
             (
                 Request::Get {
                     bucket: "".into(),
@@ -771,7 +2786,7 @@ mod tests {
                     id: "".into(),
                     key: None,
                 },
-                b"GET   \n".to_vec(),
+                b"GET _ _ \n".to_vec(),
             ),
         ];
 
@@ -794,6 +2809,16 @@ mod tests {
                     key: None,
                 }),
             ),
+            // GET command relying on the default bucket and collection
+            (
+                b"GET _ _ 1\n",
+                Ok(Request::Get {
+                    bucket: "".into(),
+                    collection: "".into(),
+                    id: "1".into(),
+                    key: None,
+                }),
+            ),
             // GET command with a key
             (
                 b"GET myapp docs 123 mykey\n",
@@ -891,6 +2916,7 @@ mod tests {
                     bucket: "default".into(),
                     collection: "users".into(),
                     query: "John".into(),
+                    options: SearchOptions::default(),
                 },
                 b"SEARCH default users John\n".to_vec(),
             ),
@@ -900,6 +2926,7 @@ mod tests {
                     bucket: "myapp".into(),
                     collection: "docs".into(),
                     query: "Hello World".into(),
+                    options: SearchOptions::default(),
                 },
                 b"SEARCH myapp docs Hello World\n".to_vec(),
             ),
@@ -909,6 +2936,7 @@ mod tests {
                     bucket: "b".into(),
                     collection: "c".into(),
                     query: "test@example.com".into(),
+                    options: SearchOptions::default(),
                 },
                 b"SEARCH b c test@example.com\n".to_vec(),
             ),
@@ -918,38 +2946,156 @@ mod tests {
                     bucket: "very_long_bucket_name".into(),
                     collection: "very_long_collection_name".into(),
                     query: "test".into(),
+                    options: SearchOptions::default(),
                 },
                 b"SEARCH very_long_bucket_name very_long_collection_name test\n".to_vec(),
             ),
-            // SEARCH command with empty query
+            // SEARCH command with id prefix clause
             (
                 Request::Search {
-                    bucket: "bucket".into(),
-                    collection: "col".into(),
-                    query: "".into(),
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "test".into(),
+                    options: SearchOptions {
+                        id_prefix: Some("user:1:".into()),
+                        ..Default::default()
+                    },
                 },
-                b"SEARCH bucket col \n".to_vec(),
+                b"SEARCH b c IDPREFIX user:1: test\n".to_vec(),
             ),
-        ];
-
-        for (request, expected) in cases {
-            let result = request.to_bytes();
-            assert_eq!(result, expected, "Failed to encode: {:?}", request);
-        }
-    }
-
-    #[test]
-    fn test_decode_search_command() {
-        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
-            // Basic SEARCH command
+            // SEARCH command with both clauses
             (
-                b"SEARCH default users John\n",
-                Ok(Request::Search {
-                    bucket: "default".into(),
-                    collection: "users".into(),
-                    query: "John".into(),
-                }),
-            ),
+                Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "test".into(),
+                    options: SearchOptions {
+                        id_prefix: Some("user:1:".into()),
+                        highlight: true,
+                        ..Default::default()
+                    },
+                },
+                b"SEARCH b c IDPREFIX user:1: HIGHLIGHT test\n".to_vec(),
+            ),
+            (
+                Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "Test".into(),
+                    options: SearchOptions {
+                        case_sensitive: true,
+                        ..Default::default()
+                    },
+                },
+                b"SEARCH b c CASESENSITIVE Test\n".to_vec(),
+            ),
+            (
+                Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "hello world".into(),
+                    options: SearchOptions {
+                        match_all: true,
+                        ..Default::default()
+                    },
+                },
+                b"SEARCH b c MATCHALL hello world\n".to_vec(),
+            ),
+            // SEARCH command with paging clauses
+            (
+                Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "test".into(),
+                    options: SearchOptions {
+                        limit: Some(5),
+                        offset: 20,
+                        ..Default::default()
+                    },
+                },
+                b"SEARCH b c LIMIT 5 OFFSET 20 test\n".to_vec(),
+            ),
+            // SEARCHPREFIX command
+            (
+                Request::SearchPrefix {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "cont".into(),
+                    options: SearchOptions {
+                        limit: Some(5),
+                        ..Default::default()
+                    },
+                },
+                b"SEARCHPREFIX b c LIMIT 5 cont\n".to_vec(),
+            ),
+            // SEARCHFUZZY command
+            (
+                Request::SearchFuzzy {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    max_distance: 1,
+                    query: "helo".into(),
+                    options: SearchOptions {
+                        match_all: true,
+                        ..Default::default()
+                    },
+                },
+                b"SEARCHFUZZY b c 1 MATCHALL helo\n".to_vec(),
+            ),
+            // SEARCHHL command
+            (
+                Request::SearchHighlight {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "hello".into(),
+                    options: SearchOptions {
+                        limit: Some(2),
+                        ..Default::default()
+                    },
+                },
+                b"SEARCHHL b c LIMIT 2 hello\n".to_vec(),
+            ),
+            // SEARCH command over several collections
+            (
+                Request::MultiSearch {
+                    bucket: "b".into(),
+                    collections: vec!["c1".into(), "c2".into()],
+                    query: "test".into(),
+                    options: SearchOptions::default(),
+                },
+                b"SEARCH b c1,c2 test\n".to_vec(),
+            ),
+            // SEARCH command with empty query
+            (
+                Request::Search {
+                    bucket: "bucket".into(),
+                    collection: "col".into(),
+                    query: "".into(),
+                    options: SearchOptions::default(),
+                },
+                b"SEARCH bucket col \n".to_vec(),
+            ),
+        ];
+
+        for (request, expected) in cases {
+            let result = request.to_bytes();
+            assert_eq!(result, expected, "Failed to encode: {:?}", request);
+        }
+    }
+
+    #[test]
+    fn test_decode_search_command() {
+        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
+            // Basic SEARCH command
+            (
+                b"SEARCH default users John\n",
+                Ok(Request::Search {
+                    bucket: "default".into(),
+                    collection: "users".into(),
+                    query: "John".into(),
+                    options: SearchOptions::default(),
+                }),
+            ),
             // SEARCH command with multi-word query
             (
                 b"SEARCH myapp docs Hello World\n",
@@ -957,6 +3103,7 @@ mod tests {
                     bucket: "myapp".into(),
                     collection: "docs".into(),
                     query: "Hello World".into(),
+                    options: SearchOptions::default(),
                 }),
             ),
             // SEARCH command with special characters in query
@@ -966,6 +3113,7 @@ mod tests {
                     bucket: "b".into(),
                     collection: "c".into(),
                     query: "test@example.com".into(),
+                    options: SearchOptions::default(),
                 }),
             ),
             // SEARCH command with very long bucket and collection names
@@ -975,6 +3123,7 @@ mod tests {
                     bucket: "very_long_bucket_name".into(),
                     collection: "very_long_collection_name".into(),
                     query: "test".into(),
+                    options: SearchOptions::default(),
                 }),
             ),
             // SEARCH command with empty query
@@ -984,6 +3133,7 @@ mod tests {
                     bucket: "bucket".into(),
                     collection: "col".into(),
                     query: "".into(),
+                    options: SearchOptions::default(),
                 }),
             ),
             // SEARCH command with trailing whitespace
@@ -993,6 +3143,7 @@ mod tests {
                     bucket: "bucket".into(),
                     collection: "col".into(),
                     query: "query".into(),
+                    options: SearchOptions::default(),
                 }),
             ),
             // SEARCH command with different line endings
@@ -1002,9 +3153,195 @@ mod tests {
                     bucket: "bucket".into(),
                     collection: "col".into(),
                     query: "query".into(),
+                    options: SearchOptions::default(),
+                }),
+            ),
+            // SEARCH command with id prefix clause
+            (
+                b"SEARCH b c IDPREFIX user:1: hello world\n",
+                Ok(Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "hello world".into(),
+                    options: SearchOptions {
+                        id_prefix: Some("user:1:".into()),
+                        ..Default::default()
+                    },
+                }),
+            ),
+            // SEARCH command with case-sensitive clause
+            (
+                b"SEARCH b c CASESENSITIVE Hello\n",
+                Ok(Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "Hello".into(),
+                    options: SearchOptions {
+                        case_sensitive: true,
+                        ..Default::default()
+                    },
+                }),
+            ),
+            (
+                b"SEARCH b c MATCHALL LIMIT 5 hello world\n",
+                Ok(Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "hello world".into(),
+                    options: SearchOptions {
+                        match_all: true,
+                        limit: Some(5),
+                        ..Default::default()
+                    },
+                }),
+            ),
+            // SEARCH command with paging clauses, in any order
+            (
+                b"SEARCH b c OFFSET 10 LIMIT 5 2024 report\n",
+                Ok(Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "2024 report".into(),
+                    options: SearchOptions {
+                        limit: Some(5),
+                        offset: 10,
+                        ..Default::default()
+                    },
+                }),
+            ),
+            // SEARCH command with highlight clause
+            (
+                b"SEARCH b c HIGHLIGHT hello world\n",
+                Ok(Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "hello world".into(),
+                    options: SearchOptions {
+                        highlight: true,
+                        ..Default::default()
+                    },
+                }),
+            ),
+            // SEARCHPREFIX command
+            (
+                b"SEARCHPREFIX b c IDPREFIX user: cont\n",
+                Ok(Request::SearchPrefix {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "cont".into(),
+                    options: SearchOptions {
+                        id_prefix: Some("user:".into()),
+                        ..Default::default()
+                    },
+                }),
+            ),
+            (
+                b"SEARCHPREFIX b c HIGHLIGHT cont\n",
+                Err(DecodingError::InvalidRequest(
+                    "HIGHLIGHT is not supported by SEARCHPREFIX".to_string(),
+                )),
+            ),
+            // SEARCHFUZZY command
+            (
+                b"SEARCHFUZZY b c 2 LIMIT 3 helo wrld\n",
+                Ok(Request::SearchFuzzy {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    max_distance: 2,
+                    query: "helo wrld".into(),
+                    options: SearchOptions {
+                        limit: Some(3),
+                        ..Default::default()
+                    },
+                }),
+            ),
+            (
+                b"SEARCHFUZZY b c helo\n",
+                Err(DecodingError::InvalidRequest(
+                    "Invalid distance".to_string(),
+                )),
+            ),
+            (
+                b"SEARCHFUZZY b c 4 helo\n",
+                Err(DecodingError::InvalidRequest("Distance over 3".to_string())),
+            ),
+            (
+                b"SEARCHFUZZY b c 1 HIGHLIGHT helo\n",
+                Err(DecodingError::InvalidRequest(
+                    "HIGHLIGHT is not supported by SEARCHFUZZY".to_string(),
+                )),
+            ),
+            // SEARCHHL command
+            (
+                b"SEARCHHL b c IDPREFIX post: hello world\n",
+                Ok(Request::SearchHighlight {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "hello world".into(),
+                    options: SearchOptions {
+                        id_prefix: Some("post:".into()),
+                        ..Default::default()
+                    },
+                }),
+            ),
+            (
+                b"SEARCHHL b c HIGHLIGHT hello\n",
+                Err(DecodingError::InvalidRequest(
+                    "HIGHLIGHT is not supported by SEARCHHL".to_string(),
+                )),
+            ),
+            // SEARCH command over several collections
+            (
+                b"SEARCH b c1,c2 hello\n",
+                Ok(Request::MultiSearch {
+                    bucket: "b".into(),
+                    collections: vec!["c1".into(), "c2".into()],
+                    query: "hello".into(),
+                    options: SearchOptions::default(),
+                }),
+            ),
+            (
+                b"SEARCH b c1,,c2, hello\n",
+                Ok(Request::MultiSearch {
+                    bucket: "b".into(),
+                    collections: vec!["c1".into(), "c2".into()],
+                    query: "hello".into(),
+                    options: SearchOptions::default(),
                 }),
             ),
             // Invalid SEARCH commands
+            (
+                b"SEARCH b , hello\n",
+                Err(DecodingError::InvalidRequest(
+                    "Missing collection".to_string(),
+                )),
+            ),
+            (
+                b"SEARCH b c IDPREFIX\n",
+                Err(DecodingError::InvalidRequest(
+                    "Missing id prefix".to_string(),
+                )),
+            ),
+            (
+                b"SEARCH b c LIMIT\n",
+                Err(DecodingError::InvalidRequest("Missing limit".to_string())),
+            ),
+            (
+                b"SEARCH b c OFFSET -1 hello\n",
+                Err(DecodingError::InvalidRequest("Invalid offset".to_string())),
+            ),
+            (
+                b"SEARCH b c LIMIT 18446744073709551615 hello\n",
+                Err(DecodingError::InvalidRequest(
+                    "limit over 100000".to_string(),
+                )),
+            ),
+            (
+                b"SEARCH b c OFFSET 100001 hello\n",
+                Err(DecodingError::InvalidRequest(
+                    "offset over 100000".to_string(),
+                )),
+            ),
             (
                 b"SEARCH\n",
                 Err(DecodingError::InvalidRequest("Missing bucket".to_string())),
@@ -1059,14 +3396,15 @@ mod tests {
                 b"REMOVE very_long_bucket_name very_long_collection_name very_long_id_name\n"
                     .to_vec(),
             ),
-            // REMOVE command with empty bucket, collection, or id (edge case)
+            // REMOVE command with empty bucket, collection, or id (edge case), the bucket and
+            // collection going to the server defaults
             (
                 Request::Remove {
                     bucket: "".into(),
                     collection: "".into(),
                     id: "".into(),
                 },
-                b"REMOVE   \n".to_vec(),
+                b"REMOVE _ _ \n".to_vec(),
             ),
         ];
 
@@ -1152,6 +3490,1042 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_encode_set_engine_command() {
+        let request = Request::SetEngine {
+            name: "btree".into(),
+        };
+        assert_eq!(request.to_bytes(), b"SETENGINE btree\n".to_vec());
+    }
+
+    #[test]
+    fn test_decode_set_engine_command() {
+        assert_eq!(
+            Request::from_bytes(b"SETENGINE btree\n"),
+            Ok(Request::SetEngine {
+                name: "btree".into(),
+            })
+        );
+        assert_eq!(
+            Request::from_bytes(b"SETENGINE\n"),
+            Err(DecodingError::InvalidRequest("Missing engine".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_auth_command() {
+        let request = Request::Auth {
+            password: "secret".into(),
+        };
+        assert_eq!(request.to_bytes(), b"AUTH secret\n".to_vec());
+        assert_eq!(Request::from_bytes(b"AUTH secret\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"AUTH\n"),
+            Err(DecodingError::InvalidRequest(
+                "Missing password".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_encode_verify_command() {
+        let request = Request::Verify {
+            bucket: "b".into(),
+            collection: "c".into(),
+        };
+        assert_eq!(request.to_bytes(), b"VERIFY b c\n".to_vec());
+    }
+
+    #[test]
+    fn test_reindex_command() {
+        let request = Request::Reindex {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+        };
+        assert_eq!(request.to_bytes(), b"REINDEX b c\n".to_vec());
+        assert_eq!(Request::from_bytes(b"REINDEX b c\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"REINDEX b\n"),
+            Err(DecodingError::InvalidRequest(
+                "Missing collection".to_string()
+            ))
+        );
+        assert_eq!(
+            Request::from_bytes_with_mode(b"REINDEX b c d\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_dump_token_command() {
+        let request = Request::DumpToken {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+            token: "hello".to_string(),
+        };
+        assert_eq!(request.to_bytes(), b"DUMPTOKEN b c hello\n".to_vec());
+        assert_eq!(Request::from_bytes(b"DUMPTOKEN b c hello\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"DUMPTOKEN b c\n"),
+            Err(DecodingError::InvalidRequest("Missing token".to_string()))
+        );
+        assert_eq!(
+            Request::from_bytes_with_mode(b"DUMPTOKEN b c hello world\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_decode_verify_command() {
+        assert_eq!(
+            Request::from_bytes(b"VERIFY b c\n"),
+            Ok(Request::Verify {
+                bucket: "b".into(),
+                collection: "c".into(),
+            })
+        );
+        assert_eq!(
+            Request::from_bytes(b"VERIFY b\n"),
+            Err(DecodingError::InvalidRequest(
+                "Missing collection".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_encode_get_if_command() {
+        let request = Request::GetIf {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+            since_version: 4,
+        };
+        assert_eq!(request.to_bytes(), b"GETIF b c 1 4\n".to_vec());
+    }
+
+    #[test]
+    fn test_decode_get_if_command() {
+        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
+            (
+                b"GETIF b c 1 4\n",
+                Ok(Request::GetIf {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    id: "1".into(),
+                    since_version: 4,
+                }),
+            ),
+            (
+                b"GETIF b c 1\n",
+                Err(DecodingError::InvalidRequest("Missing version".to_string())),
+            ),
+            (
+                b"GETIF b c 1 latest\n",
+                Err(DecodingError::InvalidRequest("Invalid version".to_string())),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(Request::from_bytes(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_get_range_command() {
+        let get_range = |key: Option<&str>| Request::GetRange {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+            start: 2,
+            end: 10,
+            key: key.map(String::from),
+        };
+
+        for (request, bytes) in [
+            (get_range(None), &b"GETRANGE b c 1 2 10\n"[..]),
+            (
+                get_range(Some("secret key")),
+                b"GETRANGE b c 1 2 10 secret key\n",
+            ),
+        ] {
+            assert_eq!(request.to_bytes(), bytes.to_vec());
+            assert_eq!(Request::from_bytes(bytes), Ok(request));
+        }
+
+        let invalid = |message: &str| Err(DecodingError::InvalidRequest(message.to_string()));
+        assert_eq!(
+            Request::from_bytes(b"GETRANGE b c 1 2\n"),
+            invalid("Missing end")
+        );
+        assert_eq!(
+            Request::from_bytes(b"GETRANGE b c 1 -1 5\n"),
+            invalid("Invalid start")
+        );
+    }
+
+    #[test]
+    fn test_add_command() {
+        let add = |content: &str, key: Option<&str>| Request::Add {
+            bucket: "b".into(),
+            collection: "c".into(),
+            content: content.into(),
+            key: key.map(|key| key.into()),
+        };
+        assert_eq!(
+            add("hello world", Some("key")).to_bytes(),
+            b"ADD b c 11:hello world key\n".to_vec()
+        );
+
+        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
+            (
+                b"ADD b c 11:hello world key\n",
+                Ok(add("hello world", Some("key"))),
+            ),
+            (b"ADD b c hello\n", Ok(add("hello", None))),
+            (b"ADD b c 0:\n", Ok(add("", None))),
+            (
+                b"ADD b c\n",
+                Err(DecodingError::InvalidRequest("Missing content".to_string())),
+            ),
+            (
+                b"ADD b\n",
+                Err(DecodingError::InvalidRequest(
+                    "Missing collection".to_string(),
+                )),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(Request::from_bytes(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_drop_collection_command() {
+        let request = Request::DropCollection {
+            bucket: "b".into(),
+            collection: "c".into(),
+        };
+        assert_eq!(request.to_bytes(), b"DROPCOLLECTION b c\n".to_vec());
+
+        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
+            (b"DROPCOLLECTION b c\n", Ok(request)),
+            (
+                b"DROPCOLLECTION b\n",
+                Err(DecodingError::InvalidRequest(
+                    "Missing collection".to_string(),
+                )),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(Request::from_bytes(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_rename_command() {
+        let request = Request::Rename {
+            bucket: "b".into(),
+            collection: "c".into(),
+            old_id: "1".into(),
+            new_id: "2".into(),
+        };
+        assert_eq!(request.to_bytes(), b"RENAME b c 1 2\n".to_vec());
+        assert_eq!(Request::from_bytes(b"RENAME b c 1 2\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"RENAME b c 1\n"),
+            Err(DecodingError::InvalidRequest("Missing new id".to_string()))
+        );
+        assert_eq!(
+            Request::from_bytes_with_mode(b"RENAME b c 1 2 3\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_copy_command() {
+        let request = Request::Copy {
+            bucket: "b".into(),
+            collection: "c".into(),
+            src_id: "1".into(),
+            dst_id: "2".into(),
+            to_collection: None,
+            replace: false,
+        };
+        assert_eq!(request.to_bytes(), b"COPY b c 1 2\n".to_vec());
+        assert_eq!(Request::from_bytes(b"COPY b c 1 2\n"), Ok(request));
+
+        let request = Request::Copy {
+            bucket: "b".into(),
+            collection: "c".into(),
+            src_id: "1".into(),
+            dst_id: "2".into(),
+            to_collection: Some("other".into()),
+            replace: true,
+        };
+        assert_eq!(
+            request.to_bytes(),
+            b"COPY b c 1 2 TO other REPLACE\n".to_vec()
+        );
+        assert_eq!(
+            Request::from_bytes(b"COPY b c 1 2 TO other REPLACE\n"),
+            Ok(request)
+        );
+
+        let cases: Vec<(&[u8], DecodingError)> = vec![
+            (
+                b"COPY b c 1\n",
+                DecodingError::InvalidRequest("Missing new id".to_string()),
+            ),
+            (
+                b"COPY b c 1 2 TO\n",
+                DecodingError::InvalidRequest("Missing collection".to_string()),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(Request::from_bytes(input), Err(expected));
+        }
+        assert_eq!(
+            Request::from_bytes_with_mode(b"COPY b c 1 2 OVERWRITE\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_drop_bucket_command() {
+        let request = Request::DropBucket { bucket: "b".into() };
+        assert_eq!(request.to_bytes(), b"DROPBUCKET b\n".to_vec());
+        assert_eq!(Request::from_bytes(b"DROPBUCKET b\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"DROPBUCKET\n"),
+            Err(DecodingError::InvalidRequest("Missing bucket".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_mget_command() {
+        let request = Request::MGet {
+            bucket: "b".into(),
+            collection: "c".into(),
+            ids: vec!["1".into(), "2".into(), "3".into()],
+        };
+        assert_eq!(request.to_bytes(), b"MGET b c 1 2 3\n".to_vec());
+        assert_eq!(Request::from_bytes(b"MGET b c 1 2 3\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"MGET b c\n"),
+            Err(DecodingError::InvalidRequest("Missing id".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_exists_command() {
+        let request = Request::Exists {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+        };
+        assert_eq!(request.to_bytes(), b"EXISTS b c 1\n".to_vec());
+        assert_eq!(Request::from_bytes(b"EXISTS b c 1\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"EXISTS b c\n"),
+            Err(DecodingError::InvalidRequest("Missing id".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_list_ids_command() {
+        let request = Request::ListIds {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+            limit: None,
+        };
+        assert_eq!(request.to_bytes(), b"LISTIDS b c\n".to_vec());
+        assert_eq!(Request::from_bytes(b"LISTIDS b c\n"), Ok(request));
+
+        let request = Request::ListIds {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+            limit: Some(20),
+        };
+        assert_eq!(request.to_bytes(), b"LISTIDS b c 20\n".to_vec());
+        assert_eq!(Request::from_bytes(b"LISTIDS b c 20\n"), Ok(request));
+    }
+
+    #[test]
+    fn test_import_command() {
+        let content = "{\"id\":\"1\",\"content\":\"a b\"}\n{\"id\":\"2\",\"content\":\"c\"}\n";
+        let request = Request::Import {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+            content: content.to_string(),
+            replace: false,
+        };
+        let bytes = format!("IMPORT b c {}:{}\n", content.len(), content).into_bytes();
+        assert_eq!(request.to_bytes(), bytes);
+        assert_eq!(Request::from_bytes(&bytes), Ok(request));
+
+        let request = Request::Import {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+            content: content.to_string(),
+            replace: true,
+        };
+        let bytes = format!("IMPORT b c REPLACE {}:{}\n", content.len(), content).into_bytes();
+        assert_eq!(request.to_bytes(), bytes);
+        assert_eq!(Request::from_bytes(&bytes), Ok(request));
+
+        assert_eq!(
+            Request::from_bytes(b"IMPORT b c\n"),
+            Err(DecodingError::InvalidRequest("Missing content".to_string()))
+        );
+        assert_eq!(
+            Request::from_bytes_with_mode(b"IMPORT b c 2:{} d\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_export_command() {
+        let request = Request::Export {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+        };
+        assert_eq!(request.to_bytes(), b"EXPORT b c\n".to_vec());
+        assert_eq!(Request::from_bytes(b"EXPORT b c\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"EXPORT b\n"),
+            Err(DecodingError::InvalidRequest(
+                "Missing collection".to_string()
+            ))
+        );
+        assert_eq!(
+            Request::from_bytes_with_mode(b"EXPORT b c d\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_scan_command() {
+        let request = Request::Scan {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+            cursor: None,
+            count: None,
+        };
+        assert_eq!(request.to_bytes(), b"SCAN b c 0\n".to_vec());
+        assert_eq!(Request::from_bytes(b"SCAN b c 0\n"), Ok(request));
+
+        let request = Request::Scan {
+            bucket: "b".to_string(),
+            collection: "c".to_string(),
+            cursor: Some("0".to_string()),
+            count: Some(20),
+        };
+        assert_eq!(request.to_bytes(), b"SCAN b c >0 20\n".to_vec());
+        assert_eq!(Request::from_bytes(b"SCAN b c >0 20\n"), Ok(request));
+
+        assert_eq!(
+            Request::from_bytes(b"SCAN b c\n"),
+            Err(DecodingError::InvalidRequest("Missing cursor".to_string()))
+        );
+        for cursor in ["40", ">", "-1"] {
+            assert_eq!(
+                Request::from_bytes(format!("SCAN b c {}\n", cursor).as_bytes()),
+                Err(DecodingError::InvalidRequest("Invalid cursor".to_string()))
+            );
+        }
+        assert_eq!(
+            Request::from_bytes(b"SCAN b c 0 0\n"),
+            Err(DecodingError::InvalidRequest("Invalid count".to_string()))
+        );
+        assert_eq!(
+            Request::from_bytes_with_mode(b"SCAN b c 0 10 more\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+
+        assert_eq!(
+            Request::from_bytes(b"LISTIDS b c many\n"),
+            Err(DecodingError::InvalidRequest("Invalid limit".to_string()))
+        );
+        assert_eq!(
+            Request::from_bytes(b"LISTIDS b\n"),
+            Err(DecodingError::InvalidRequest(
+                "Missing collection".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_expire_command() {
+        let request = Request::Expire {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+            seconds: 30,
+        };
+        assert_eq!(request.to_bytes(), b"EXPIRE b c 1 30\n".to_vec());
+        assert_eq!(Request::from_bytes(b"EXPIRE b c 1 30\n"), Ok(request));
+        assert_eq!(
+            Request::from_bytes(b"EXPIRE b c 1 soon\n"),
+            Err(DecodingError::InvalidRequest("Invalid seconds".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_append_command() {
+        let request = Request::Append {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+            content: " more\nlines".into(),
+        };
+        assert_eq!(
+            request.to_bytes(),
+            b"APPEND b c 1 11: more\nlines\n".to_vec()
+        );
+        assert_eq!(
+            Request::from_bytes(b"APPEND b c 1 11: more\nlines\n"),
+            Ok(request)
+        );
+
+        assert_eq!(
+            Request::from_bytes(b"APPEND b c 1\n"),
+            Err(DecodingError::InvalidRequest("Missing content".to_string()))
+        );
+        assert_eq!(
+            Request::from_bytes(b"APPEND b c 1 more\n"),
+            Err(DecodingError::InvalidRequest(
+                "Missing content length".to_string()
+            ))
+        );
+        assert_eq!(
+            Request::from_bytes_with_mode(b"APPEND b c 1 4:more key\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_incr_command() {
+        let incr = |delta: i64| Request::Incr {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+            delta,
+        };
+        let cases: Vec<(&[u8], Request, &[u8])> = vec![
+            (b"INCR b c 1\n", incr(1), b"INCR b c 1\n"),
+            (b"DECR b c 1\n", incr(-1), b"DECR b c 1\n"),
+            (b"INCRBY b c 1 5\n", incr(5), b"INCRBY b c 1 5\n"),
+            (b"INCRBY b c 1 -5\n", incr(-5), b"INCRBY b c 1 -5\n"),
+            (b"DECRBY b c 1 5\n", incr(-5), b"INCRBY b c 1 -5\n"),
+        ];
+        for (bytes, request, encoded) in cases {
+            assert_eq!(request.to_bytes(), encoded.to_vec());
+            assert_eq!(Request::from_bytes(bytes), Ok(request));
+        }
+
+        assert_eq!(
+            Request::from_bytes(b"INCRBY b c 1\n"),
+            Err(DecodingError::InvalidRequest(
+                "Missing increment".to_string()
+            ))
+        );
+        assert_eq!(
+            Request::from_bytes(b"INCRBY b c 1 many\n"),
+            Err(DecodingError::InvalidRequest(
+                "Invalid increment".to_string()
+            ))
+        );
+        let min = format!("DECRBY b c 1 {}\n", i64::MIN);
+        assert_eq!(
+            Request::from_bytes(min.as_bytes()),
+            Err(DecodingError::InvalidRequest(
+                "Invalid increment".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_conditional_set_roundtrip() {
+        let set_if = |condition: SetCondition, key: Option<&str>| Request::SetIf {
+            condition,
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+            content: "hello\nworld".into(),
+            key: key.map(String::from),
+            ttl: None,
+        };
+
+        let cases: Vec<(Request, &[u8])> = vec![
+            (
+                set_if(SetCondition::IfAbsent, None),
+                b"SETNX b c 1 11:hello\nworld\n",
+            ),
+            (
+                set_if(SetCondition::IfPresent, Some("key")),
+                b"UPDATE b c 1 11:hello\nworld key\n",
+            ),
+        ];
+        for (request, bytes) in cases {
+            assert_eq!(request.to_bytes(), bytes.to_vec());
+            assert_eq!(Request::from_bytes(bytes), Ok(request));
+        }
+        assert_eq!(
+            Request::from_bytes(b"SETNX b c\n"),
+            Err(DecodingError::InvalidRequest("Missing id".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_mset_roundtrip() {
+        let request = Request::MSet {
+            bucket: "b".into(),
+            collection: "c".into(),
+            docs: vec![
+                ("1".into(), "hello\nworld".into()),
+                ("2".into(), "".into()),
+                ("3".into(), "a: b".into()),
+            ],
+        };
+        let bytes = b"MSET b c 3 1 11:hello\nworld 2 0: 3 4:a: b\n";
+        assert_eq!(request.to_bytes(), bytes.to_vec());
+        assert_eq!(Request::from_bytes(bytes), Ok(request));
+
+        let invalid: [(&[u8], &str); 4] = [
+            (b"MSET b c\n", "Missing count"),
+            (b"MSET b c two 1 1:a\n", "Invalid count"),
+            (b"MSET b c 2 1 1:a\n", "Missing document"),
+            (b"MSET b c 1 1 hello\n", "Missing content length"),
+        ];
+        for (bytes, error) in invalid {
+            assert_eq!(
+                Request::from_bytes(bytes),
+                Err(DecodingError::InvalidRequest(error.to_string()))
+            );
+        }
+        assert_eq!(
+            Request::from_bytes_with_mode(b"MSET b c 1 1 1:a extra\n", ParseMode::Strict),
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_set_with_ttl() {
+        let set = |content: &str, key: Option<&str>, ttl: Option<u64>| Request::Set {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+            content: content.into(),
+            key: key.map(String::from),
+            ttl,
+        };
+
+        let request = set("hello world", Some("key"), Some(60));
+        assert_eq!(
+            request.to_bytes(),
+            b"SET b c 1 11:hello world key EX 60\n".to_vec()
+        );
+        assert_eq!(
+            Request::from_bytes(b"SET b c 1 11:hello world key EX 60\n"),
+            Ok(request)
+        );
+        assert_eq!(
+            Request::from_bytes(b"SET b c 1 5:hello EX 60\n"),
+            Ok(set("hello", None, Some(60)))
+        );
+        assert_eq!(
+            Request::from_bytes(b"SET b c 1 hello EX 60\n"),
+            Ok(set("hello", None, Some(60)))
+        );
+        // within the declared length, `EX` is content
+        assert_eq!(
+            Request::from_bytes(b"SET b c 1 8:hello EX\n"),
+            Ok(set("hello EX", None, None))
+        );
+        assert_eq!(
+            Request::from_bytes(b"SET b c 1 EX 60\n"),
+            Ok(set("EX 60", None, None))
+        );
+    }
+
+    #[test]
+    fn test_config_command() {
+        let set = Request::Config {
+            action: ConfigAction::Set {
+                setting: "MAXCONNECTIONBYTES".into(),
+                value: "1024".into(),
+            },
+        };
+        assert_eq!(
+            set.to_bytes(),
+            b"CONFIG SET MAXCONNECTIONBYTES 1024\n".to_vec()
+        );
+
+        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
+            (b"CONFIG SET MAXCONNECTIONBYTES 1024\n", Ok(set)),
+            (
+                b"CONFIG get parsemode\n",
+                Ok(Request::Config {
+                    action: ConfigAction::Get {
+                        setting: "parsemode".into(),
+                    },
+                }),
+            ),
+            (
+                b"CONFIG LIST\n",
+                Ok(Request::Config {
+                    action: ConfigAction::List,
+                }),
+            ),
+            (
+                b"CONFIG\n",
+                Err(DecodingError::InvalidRequest("Missing action".to_string())),
+            ),
+            (
+                b"CONFIG GET\n",
+                Err(DecodingError::InvalidRequest("Missing setting".to_string())),
+            ),
+            (
+                b"CONFIG SET PARSEMODE\n",
+                Err(DecodingError::InvalidRequest("Missing value".to_string())),
+            ),
+            (
+                b"CONFIG RESET\n",
+                Err(DecodingError::InvalidRequest(
+                    "Unknown action RESET".to_string(),
+                )),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(Request::from_bytes(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_blacklist_command() {
+        let request = Request::Blacklist {
+            action: BlacklistAction::Regenerate,
+        };
+        assert_eq!(request.to_bytes(), b"BLACKLIST REGENERATE\n".to_vec());
+
+        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
+            (
+                b"BLACKLIST show\n",
+                Ok(Request::Blacklist {
+                    action: BlacklistAction::Show,
+                }),
+            ),
+            (
+                b"BLACKLIST CLEAR\n",
+                Ok(Request::Blacklist {
+                    action: BlacklistAction::Clear,
+                }),
+            ),
+            (
+                b"BLACKLIST\n",
+                Err(DecodingError::InvalidRequest("Missing action".to_string())),
+            ),
+            (
+                b"BLACKLIST drop\n",
+                Err(DecodingError::InvalidRequest(
+                    "Unknown action drop".to_string(),
+                )),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(Request::from_bytes(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_encode_configure_command() {
+        let request = Request::Configure {
+            bucket: "b".into(),
+            collection: "c".into(),
+            option: "MINTOKENS".into(),
+            value: "3".into(),
+        };
+        assert_eq!(request.to_bytes(), b"CONFIGURE b c MINTOKENS 3\n".to_vec());
+    }
+
+    #[test]
+    fn test_decode_configure_command() {
+        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
+            (
+                b"CONFIGURE b c MINTOKENS 3\n",
+                Ok(Request::Configure {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    option: "MINTOKENS".into(),
+                    value: "3".into(),
+                }),
+            ),
+            (
+                b"CONFIGURE b c\n",
+                Err(DecodingError::InvalidRequest("Missing option".to_string())),
+            ),
+            (
+                b"CONFIGURE b c MINTOKENS\n",
+                Err(DecodingError::InvalidRequest("Missing value".to_string())),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(Request::from_bytes(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_sized_content() {
+        let ok = |content: &str, key: Option<&str>| {
+            Ok((content.to_string(), key.map(|key| key.to_string())))
+        };
+        let err = |message: &str| Err(DecodingError::InvalidRequest(message.to_string()));
+        let huge = format!("{}:test", usize::MAX);
+
+        let cases = vec![
+            ("4:test", ok("test", None)),
+            ("4:test key", ok("test", Some("key"))),
+            ("4:test\n", ok("test", None)),
+            (
+                "4:test  key with spaces \n",
+                ok("test", Some("key with spaces")),
+            ),
+            (" 4:test", ok("test", None)),
+            ("0:", ok("", None)),
+            ("0: key", ok("", Some("key"))),
+            // content ending in digits or holding the separators
+            ("3:a12", ok("a12", None)),
+            ("8:12:34 56 key", ok("12:34 56", Some("key"))),
+            ("5:a\nb c", ok("a\nb c", None)),
+            // a length shorter than the content must not run into the key
+            ("2:test", err("Content length does not match content")),
+            ("3:test key", err("Content length does not match content")),
+            ("2:te st", ok("te", Some("st"))),
+            // multibyte characters
+            ("2:é", ok("é", None)),
+            ("4:éé key", ok("éé", Some("key"))),
+            ("2:éé", err("Content length does not match content")),
+            ("2:é\tkey", ok("é", Some("key"))),
+            ("1:é", err("Invalid content length")),
+            ("3:éé", err("Invalid content length")),
+            // oversized lengths
+            ("5:test", err("Content length exceeds input length")),
+            ("100:", err("Content length exceeds input length")),
+            (huge.as_str(), err("Invalid content length")),
+            // malformed lengths
+            ("test", err("Missing content length")),
+            ("", err("Missing content length")),
+            (":test", err("Invalid content length")),
+            ("x:test", err("Invalid content length")),
+            ("-1:test", err("Invalid content length")),
+            ("4 :test", ok("test", None)),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(parse_sized_content(input), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_decode_set_huge_content_length() {
+        for len in [usize::MAX, usize::MAX - 1] {
+            let input = format!("SET b c i {}:test\n", len);
+            assert_eq!(
+                Request::from_bytes(input.as_bytes()),
+                Err(DecodingError::InvalidRequest(
+                    "Invalid content length".to_string()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_dry_run_command() {
+        let request = Request::DryRun(Box::new(Request::Remove {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "i".into(),
+        }));
+        assert_eq!(request.to_bytes(), b"DRYRUN REMOVE b c i\n".to_vec());
+    }
+
+    #[test]
+    fn test_decode_dry_run_command() {
+        let cases: Vec<(&[u8], Result<Request, DecodingError>)> = vec![
+            (
+                b"DRYRUN REMOVE b c i\n",
+                Ok(Request::DryRun(Box::new(Request::Remove {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    id: "i".into(),
+                }))),
+            ),
+            (
+                b"DRYRUN  REMOVE b c i\r\n",
+                Ok(Request::DryRun(Box::new(Request::Remove {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    id: "i".into(),
+                }))),
+            ),
+            // Errors of the wrapped command are passed through
+            (
+                b"DRYRUN REMOVE b c\n",
+                Err(DecodingError::InvalidRequest("Missing id".to_string())),
+            ),
+            (
+                b"DRYRUN\n",
+                Err(DecodingError::InvalidRequest("Invalid command".to_string())),
+            ),
+            (
+                b"DRYRUN DRYRUN REMOVE b c i\n",
+                Err(DecodingError::InvalidRequest(
+                    "DRYRUN cannot be nested".to_string(),
+                )),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let result = Request::from_bytes(input);
+            assert_eq!(
+                result,
+                expected,
+                "Failed to decode: {:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_extra_arguments() {
+        let too_many = || {
+            Err(DecodingError::InvalidRequest(
+                "too many arguments".to_string(),
+            ))
+        };
+        let remove = || {
+            Ok(Request::Remove {
+                bucket: "b".into(),
+                collection: "c".into(),
+                id: "i".into(),
+            })
+        };
+
+        type Expected = Result<Request, DecodingError>;
+
+        // (input, lenient, strict)
+        let cases: Vec<(&[u8], Expected, Expected)> = vec![
+            (b"PING extra\n", Ok(Request::Ping), too_many()),
+            (b"SAVE extra args\n", Ok(Request::Save), too_many()),
+            (b"NOOP extra\n", Ok(Request::Noop), too_many()),
+            (b"SYNC extra\n", Ok(Request::Sync), too_many()),
+            (b"REMOVE b c i extra args\n", remove(), too_many()),
+            (
+                b"DRYRUN REMOVE b c i extra\n",
+                remove().map(|r| Request::DryRun(Box::new(r))),
+                too_many(),
+            ),
+            // exact arity is accepted in both modes
+            (b"PING\n", Ok(Request::Ping), Ok(Request::Ping)),
+            (b"SAVE\n", Ok(Request::Save), Ok(Request::Save)),
+            (b"REMOVE b c i  \r\n", remove(), remove()),
+            // trailing tokens of GET and SET are the key, SEARCH takes the rest as query
+            (
+                b"GET b c i key with spaces\n",
+                Ok(Request::Get {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    id: "i".into(),
+                    key: Some("key with spaces".into()),
+                }),
+                Ok(Request::Get {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    id: "i".into(),
+                    key: Some("key with spaces".into()),
+                }),
+            ),
+            (
+                b"SET b c i 4:test key with spaces\n",
+                Ok(Request::Set {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    id: "i".into(),
+                    content: "test".into(),
+                    key: Some("key with spaces".into()),
+                    ttl: None,
+                }),
+                Ok(Request::Set {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    id: "i".into(),
+                    content: "test".into(),
+                    key: Some("key with spaces".into()),
+                    ttl: None,
+                }),
+            ),
+            (
+                b"SEARCH b c many words\n",
+                Ok(Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "many words".into(),
+                    options: SearchOptions::default(),
+                }),
+                Ok(Request::Search {
+                    bucket: "b".into(),
+                    collection: "c".into(),
+                    query: "many words".into(),
+                    options: SearchOptions::default(),
+                }),
+            ),
+        ];
+
+        for (input, lenient, strict) in cases {
+            let input_str = String::from_utf8_lossy(input);
+            assert_eq!(
+                Request::from_bytes_with_mode(input, ParseMode::Lenient),
+                lenient,
+                "Failed to decode in lenient mode: {:?}",
+                input_str
+            );
+            assert_eq!(
+                Request::from_bytes(input),
+                lenient,
+                "Default mode is not lenient: {:?}",
+                input_str
+            );
+            assert_eq!(
+                Request::from_bytes_with_mode(input, ParseMode::Strict),
+                strict,
+                "Failed to decode in strict mode: {:?}",
+                input_str
+            );
+        }
+    }
+
     #[test]
     fn test_invalid_command() {
         let result = Request::from_bytes(b"INVALID 123");
@@ -1,18 +1,75 @@
 use crate::protocol::message::{DecodingError, Message};
 use crate::server::handler::HandleError;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Opens a streamed response, followed by one bulk string per item.
+const STREAM_START: &[u8] = b"*STREAM\n";
+/// Closes a streamed response.
+const STREAM_END: &[u8] = b"*END\n";
+/// A [`Response::Null`], alone or as an item of a [`Response::NullableArray`].
+const NULL: &[u8] = b"$-1\n";
 
 #[derive(Debug, PartialEq)]
 pub enum Response {
     Success,
+    /// The requested document did not change since the version the client holds
+    NotModified,
+    /// `SET` stored a new document, see [`ZzapConfig::report_set_outcome`]
+    ///
+    /// [`ZzapConfig::report_set_outcome`]: crate::config::ZzapConfig::report_set_outcome
+    Created,
+    /// `SET` overwrote an existing document
+    Updated,
     Error(String),
     BulkString(String),
+    /// No value, as opposed to an empty [`BulkString`](Response::BulkString)
+    Null,
+    Integer(i64),
     Array(Vec<String>),
+    /// Array where some items may be missing, each encoded as a [`Null`](Response::Null).
+    ///
+    /// Decoded as an [`Array`](Response::Array) when no item is missing, as the two are encoded
+    /// the same.
+    NullableArray(Vec<Option<String>>),
+    /// Items produced lazily and written to the client one by one, so large results are never
+    /// held in memory as a whole. The client reads items until the end marker.
+    Stream(ResponseStream),
+}
+
+/// Lazily produced items of a [`Response::Stream`].
+pub struct ResponseStream(Mutex<Box<dyn Iterator<Item = String> + Send>>);
+
+impl ResponseStream {
+    pub fn new(items: impl Iterator<Item = String> + Send + 'static) -> Self {
+        ResponseStream(Mutex::new(Box::new(items)))
+    }
+
+    fn into_items(self) -> Box<dyn Iterator<Item = String> + Send> {
+        self.0.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for ResponseStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseStream(..)")
+    }
+}
+
+/// Items are only known once consumed, so two streams never compare equal.
+impl PartialEq for ResponseStream {
+    fn eq(&self, _other: &Self) -> bool {
+        false
+    }
 }
 
 impl Message for Response {
     fn to_bytes(&self) -> Vec<u8> {
         match self {
             Response::Success => b"+OK\n".to_vec(),
+            Response::NotModified => b"+NOTMODIFIED\n".to_vec(),
+            Response::Created => b"+CREATED\n".to_vec(),
+            Response::Updated => b"+UPDATED\n".to_vec(),
             Response::Error(message) => {
                 let mut bytes = b"-ERR ".to_vec();
                 bytes.extend_from_slice(message.as_bytes());
@@ -25,12 +82,33 @@ impl Message for Response {
                 bytes.push(b'\n');
                 bytes
             }
+            Response::Null => NULL.to_vec(),
+            Response::Integer(value) => format!(":{}\n", value).into_bytes(),
             Response::Array(items) => {
-                let mut bytes = format!("{}\n", items.len()).into_bytes();
+                let mut bytes = format!("*{}\n", items.len()).into_bytes();
+                for item in items {
+                    bytes.extend_from_slice(&encode_bulk_item(item));
+                }
+                bytes
+            }
+            Response::NullableArray(items) => {
+                let mut bytes = format!("*{}\n", items.len()).into_bytes();
                 for item in items {
-                    bytes.extend_from_slice(item.as_bytes());
-                    bytes.push(b'\n');
+                    match item {
+                        Some(item) => bytes.extend_from_slice(&encode_bulk_item(item)),
+                        None => bytes.extend_from_slice(NULL),
+                    }
+                }
+                bytes
+            }
+            // materializes the whole stream, `into_chunks` writes it piece by piece instead
+            Response::Stream(stream) => {
+                let mut items = stream.0.lock().unwrap_or_else(|e| e.into_inner());
+                let mut bytes = STREAM_START.to_vec();
+                for item in items.by_ref() {
+                    bytes.extend_from_slice(&encode_bulk_item(&item));
                 }
+                bytes.extend_from_slice(STREAM_END);
                 bytes
             }
         }
@@ -41,19 +119,67 @@ impl Message for Response {
         let mut lines = input.lines();
 
         match lines.next() {
+            Some(line) if line.as_bytes() == &STREAM_START[..STREAM_START.len() - 1] => {
+                let items = bytes
+                    .get(STREAM_START.len()..)
+                    .ok_or(DecodingError::InvalidResponseFormat)?;
+                decode_stream(items).map(Response::Array)
+            }
+            Some(line) if line.starts_with('*') => {
+                let count = line[1..]
+                    .parse::<usize>()
+                    .map_err(|_| DecodingError::InvalidResponseFormat)?;
+                let mut rest = bytes
+                    .get(line.len() + 1..)
+                    .ok_or(DecodingError::InvalidResponseFormat)?;
+                let mut items = Vec::with_capacity(count.min(rest.len()));
+                for _ in 0..count {
+                    if let Some(after) = rest.strip_prefix(NULL) {
+                        items.push(None);
+                        rest = after;
+                        continue;
+                    }
+                    let (item, after) = decode_bulk_item(rest)?;
+                    items.push(Some(item));
+                    rest = after;
+                }
+                if items.iter().all(Option::is_some) {
+                    Ok(Response::Array(items.into_iter().flatten().collect()))
+                } else {
+                    Ok(Response::NullableArray(items))
+                }
+            }
             Some(line) if line.starts_with("+OK") => Ok(Response::Success),
+            Some(line) if line.starts_with("+NOTMODIFIED") => Ok(Response::NotModified),
+            Some(line) if line.starts_with("+CREATED") => Ok(Response::Created),
+            Some(line) if line.starts_with("+UPDATED") => Ok(Response::Updated),
             Some(line) if line.starts_with("-ERR") => {
                 let error_message = line.trim_start_matches("-ERR ").to_string();
                 Ok(Response::Error(error_message))
             }
+            Some(line) if line.starts_with(':') => line[1..]
+                .parse()
+                .map(Response::Integer)
+                .map_err(|_| DecodingError::InvalidResponseFormat),
             Some(line) if line.starts_with("$") => {
                 if line == "$-1" {
-                    Ok(Response::BulkString(String::new())) // Represent null bulk string as empty string
+                    Ok(Response::Null)
                 } else {
-                    let content = lines.next().unwrap_or("").to_string();
-                    Ok(Response::BulkString(content))
+                    let len = line[1..]
+                        .parse::<usize>()
+                        .map_err(|_| DecodingError::InvalidResponseFormat)?;
+                    // content may contain newlines, so take exactly `len` bytes after the header
+                    let start = line.len() + 1;
+                    let content = start
+                        .checked_add(len)
+                        .and_then(|end| bytes.get(start..end))
+                        .ok_or(DecodingError::InvalidResponseFormat)?;
+                    Ok(Response::BulkString(
+                        String::from_utf8_lossy(content).to_string(),
+                    ))
                 }
             }
+            // arrays were one item per line before items were length-prefixed
             Some(line) => {
                 if let Ok(count) = line.parse::<usize>() {
                     let items: Vec<String> = lines.take(count).map(|s| s.to_string()).collect();
@@ -68,6 +194,20 @@ impl Message for Response {
 }
 
 impl Response {
+    /// Encoded response split into pieces written one after the other.
+    ///
+    /// Only a stream has more than one piece, and its items are produced as the pieces are consumed.
+    pub fn into_chunks(self) -> Box<dyn Iterator<Item = Vec<u8>> + Send> {
+        match self {
+            Response::Stream(stream) => Box::new(
+                std::iter::once(STREAM_START.to_vec())
+                    .chain(stream.into_items().map(|item| encode_bulk_item(&item)))
+                    .chain(std::iter::once(STREAM_END.to_vec())),
+            ),
+            response => Box::new(std::iter::once(response.to_bytes())),
+        }
+    }
+
     pub fn from_decoding_error(error: DecodingError) -> Self {
         Response::Error(error.to_string())
     }
@@ -77,6 +217,45 @@ impl Response {
     }
 }
 
+fn encode_bulk_item(item: &str) -> Vec<u8> {
+    Response::BulkString(item.to_string()).to_bytes()
+}
+
+/// Decodes the `$<length>\n<item>\n` at the start of the bytes, returning it with the bytes after it.
+fn decode_bulk_item(bytes: &[u8]) -> Result<(String, &[u8]), DecodingError> {
+    let header_end = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or(DecodingError::InvalidResponseFormat)?;
+    let len = std::str::from_utf8(&bytes[..header_end])
+        .ok()
+        .and_then(|header| header.strip_prefix('$'))
+        .and_then(|len| len.parse::<usize>().ok())
+        .ok_or(DecodingError::InvalidResponseFormat)?;
+    let start = header_end + 1;
+    let item = bytes
+        .get(start..start.saturating_add(len))
+        .ok_or(DecodingError::InvalidResponseFormat)?;
+    // skip the newline closing the item
+    let rest = bytes
+        .get(start + len + 1..)
+        .ok_or(DecodingError::InvalidResponseFormat)?;
+    Ok((String::from_utf8_lossy(item).to_string(), rest))
+}
+
+/// Decodes the items following the stream start marker, which arrive as a whole [`Response::Array`].
+fn decode_stream(mut bytes: &[u8]) -> Result<Vec<String>, DecodingError> {
+    let mut items = Vec::new();
+    loop {
+        if bytes.starts_with(STREAM_END) {
+            return Ok(items);
+        }
+        let (item, rest) = decode_bulk_item(bytes)?;
+        items.push(item);
+        bytes = rest;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -118,6 +297,39 @@ mod tests {
         assert_eq!(response, Response::Success);
     }
 
+    #[test]
+    fn test_response_not_modified_roundtrip() {
+        let bytes = Response::NotModified.to_bytes();
+        assert_eq!(bytes, b"+NOTMODIFIED\n");
+        assert_eq!(Response::from_bytes(&bytes).unwrap(), Response::NotModified);
+    }
+
+    #[test]
+    fn test_response_set_outcome_roundtrip() {
+        for (response, bytes) in [
+            (Response::Created, &b"+CREATED\n"[..]),
+            (Response::Updated, b"+UPDATED\n"),
+        ] {
+            assert_eq!(Response::from_bytes(bytes).unwrap(), response);
+            assert_eq!(response.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_response_integer_roundtrip() {
+        for (value, bytes) in [(1, &b":1\n"[..]), (0, b":0\n"), (-42, b":-42\n")] {
+            assert_eq!(Response::Integer(value).to_bytes(), bytes);
+            assert_eq!(
+                Response::from_bytes(bytes).unwrap(),
+                Response::Integer(value)
+            );
+        }
+        assert_eq!(
+            Response::from_bytes(b":one\n"),
+            Err(DecodingError::InvalidResponseFormat)
+        );
+    }
+
     #[test]
     fn test_response_error_encode_simple() {
         let response = Response::Error("Invalid command".to_string());
@@ -172,14 +384,79 @@ mod tests {
         assert_eq!(response, Response::BulkString("Hello, world!".to_string()));
     }
 
+    #[test]
+    fn test_response_bulk_string_roundtrip_newlines() {
+        let response = Response::BulkString("a\nb\n\nc".to_string());
+        let bytes = response.to_bytes();
+        assert_eq!(bytes, b"$6\na\nb\n\nc\n");
+        assert_eq!(Response::from_bytes(&bytes).unwrap(), response);
+    }
+
+    #[test]
+    fn test_response_bulk_string_decode_truncated() {
+        let response = Response::from_bytes(b"$13\nHello\n");
+        assert_eq!(response, Err(DecodingError::InvalidResponseFormat));
+
+        // a length no content could have
+        let response = Response::from_bytes(b"$18446744073709551615\nHello\n");
+        assert_eq!(response, Err(DecodingError::InvalidResponseFormat));
+    }
+
+    #[test]
+    fn test_response_stream_encode() {
+        let items = vec!["a\nb".to_string(), "c".to_string()];
+        let expected = b"*STREAM\n$3\na\nb\n$1\nc\n*END\n";
+
+        let response = Response::Stream(ResponseStream::new(items.clone().into_iter()));
+        assert_eq!(response.to_bytes(), expected);
+
+        let response = Response::Stream(ResponseStream::new(items.clone().into_iter()));
+        let chunks: Vec<Vec<u8>> = response.into_chunks().collect();
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.concat(), expected);
+
+        assert_eq!(Response::from_bytes(expected), Ok(Response::Array(items)));
+    }
+
+    #[test]
+    fn test_response_stream_decode_invalid() {
+        let cases: Vec<&[u8]> = vec![
+            b"*STREAM\n$1\na\n",
+            b"*STREAM\n$5\na\n*END\n",
+            b"*STREAM\nitem\n*END\n",
+            // cut short before the marker's newline or the end marker
+            b"*STREAM",
+            b"*STREAM\n",
+        ];
+        for input in cases {
+            assert_eq!(
+                Response::from_bytes(input),
+                Err(DecodingError::InvalidResponseFormat)
+            );
+        }
+        assert_eq!(
+            Response::from_bytes(b"*STREAM\n*END\n"),
+            Ok(Response::Array(vec![]))
+        );
+    }
+
     #[test]
     fn test_response_array_encode() {
         let response = Response::Array(vec!["Hello".to_string(), "world".to_string()]);
-        assert_eq!(response.to_bytes(), b"2\nHello\nworld\n");
+        assert_eq!(response.to_bytes(), b"*2\n$5\nHello\n$5\nworld\n");
     }
 
     #[test]
     fn test_response_array_decode() {
+        let response = Response::from_bytes(b"*2\n$5\nHello\n$5\nworld\n").unwrap();
+        assert_eq!(
+            response,
+            Response::Array(vec!["Hello".to_string(), "world".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_response_array_decode_legacy() {
         let response = Response::from_bytes(b"2\nHello\nworld\n").unwrap();
         assert_eq!(
             response,
@@ -196,7 +473,8 @@ mod tests {
     #[test]
     fn test_response_array_encode_empty() {
         let response = Response::Array(vec![]);
-        assert_eq!(response.to_bytes(), b"0\n");
+        assert_eq!(response.to_bytes(), b"*0\n");
+        assert_eq!(Response::from_bytes(b"*0\n").unwrap(), response);
     }
 
     #[test]
@@ -213,16 +491,70 @@ mod tests {
 
     #[test]
     fn test_response_bulk_string_decode_empty() {
-        let response = Response::from_bytes(b"$-1\n").unwrap();
+        let response = Response::from_bytes(b"$0\n\n").unwrap();
         assert_eq!(response, Response::BulkString(String::new()));
     }
 
-    // TODO: these characters are now implemented incorrectly, and they would break the protocol
-    // The test is now passing as a result of the incorrect implementation, and it should be fixed in protocol design first
     #[test]
-    fn test_response_array_encode_special_characters() {
-        let response = Response::Array(vec!["Hello\nworld".to_string()]);
-        assert_eq!(response.to_bytes(), b"1\nHello\nworld\n");
+    fn test_response_null_roundtrip() {
+        assert_eq!(Response::Null.to_bytes(), b"$-1\n");
+        assert_eq!(Response::from_bytes(b"$-1\n").unwrap(), Response::Null);
+        assert_ne!(
+            Response::from_bytes(&Response::BulkString(String::new()).to_bytes()).unwrap(),
+            Response::Null
+        );
+    }
+
+    #[test]
+    fn test_response_nullable_array_roundtrip() {
+        let response =
+            Response::NullableArray(vec![Some("hello".to_string()), None, Some(String::new())]);
+        let bytes = b"*3\n$5\nhello\n$-1\n$0\n\n";
+        assert_eq!(response.to_bytes(), bytes);
+        assert_eq!(Response::from_bytes(bytes), Ok(response));
+
+        // without missing items, it cannot be told apart from an array
+        let response = Response::NullableArray(vec![Some("hello".to_string())]);
+        assert_eq!(
+            Response::from_bytes(&response.to_bytes()),
+            Ok(Response::Array(vec!["hello".to_string()]))
+        );
+    }
+
+    #[test]
+    fn test_response_array_special_characters_roundtrip() {
+        let items = vec![
+            "Hello\nworld".to_string(),
+            "crlf\r\nid".to_string(),
+            "\n".to_string(),
+            String::new(),
+        ];
+        let bytes = Response::Array(items.clone()).to_bytes();
+        assert_eq!(
+            bytes,
+            b"*4\n$11\nHello\nworld\n$8\ncrlf\r\nid\n$1\n\n\n$0\n\n"
+        );
+        assert_eq!(Response::from_bytes(&bytes), Ok(Response::Array(items)));
+    }
+
+    #[test]
+    fn test_response_array_decode_invalid() {
+        let cases: Vec<&[u8]> = vec![
+            b"*2\n$1\na\n",
+            b"*1\n$5\na\n",
+            b"*1\nitem\n",
+            b"*x\n",
+            // cut short in the header or right after it
+            b"*3",
+            b"*1\n",
+            b"*1\n$1",
+        ];
+        for input in cases {
+            assert_eq!(
+                Response::from_bytes(input),
+                Err(DecodingError::InvalidResponseFormat)
+            );
+        }
     }
 
     #[test]
@@ -0,0 +1,359 @@
+use super::{CollectionIndex, SearchEngine, SearchOptions, DEFAULT_LIMIT};
+use crate::storage::{EntityType, StorageOperations};
+use crate::{lang, storage::StorageError};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+/// Default term frequency saturation, the higher the more repeated terms keep adding to the score.
+pub const DEFAULT_K1: f64 = 1.2;
+/// Default length normalization, from 0 (length ignored) to 1 (score fully scaled by length).
+pub const DEFAULT_B: f64 = 0.75;
+
+/// Corpus statistics of a single collection, kept up to date as documents are indexed.
+#[derive(Default)]
+struct Bm25Collection {
+    /// Token to the ids of the documents containing it, the document frequency is its length
+    postings: HashMap<String, HashSet<String>>,
+    /// Document id to how many times each of its tokens appears in it
+    term_frequencies: HashMap<String, HashMap<String, usize>>,
+    /// Document id to its length, in tokens
+    lengths: HashMap<String, usize>,
+    /// Sum of the lengths of every document, in tokens
+    total_length: usize,
+}
+
+impl Bm25Collection {
+    fn insert(&mut self, id: &str, tokens: Vec<String>) {
+        self.total_length += tokens.len();
+        self.lengths.insert(id.to_string(), tokens.len());
+
+        let mut frequencies: HashMap<String, usize> = HashMap::new();
+        for token in tokens {
+            *frequencies.entry(token).or_insert(0) += 1;
+        }
+        for token in frequencies.keys() {
+            self.postings
+                .entry(token.clone())
+                .or_default()
+                .insert(id.to_string());
+        }
+        self.term_frequencies.insert(id.to_string(), frequencies);
+    }
+
+    fn remove(&mut self, id: &str) {
+        let Some(frequencies) = self.term_frequencies.remove(id) else {
+            return;
+        };
+
+        self.total_length -= self.lengths.remove(id).unwrap_or(0);
+        for token in frequencies.into_keys() {
+            if let Some(ids) = self.postings.get_mut(&token) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+
+    fn average_length(&self) -> f64 {
+        match self.term_frequencies.len() {
+            0 => 0.0,
+            documents => self.total_length as f64 / documents as f64,
+        }
+    }
+
+    /// Inverse document frequency, never negative so a token found in most documents still
+    /// counts a little rather than lowering the score.
+    fn idf(&self, token: &str) -> f64 {
+        let documents = self.term_frequencies.len() as f64;
+        let frequency = self.postings.get(token).map_or(0, HashSet::len) as f64;
+        (1.0 + (documents - frequency + 0.5) / (frequency + 0.5)).ln()
+    }
+}
+
+// Index is a map of buckets, each containing a map of collections and their statistics.
+type IndexStore = RwLock<HashMap<String, HashMap<String, Bm25Collection>>>;
+
+/// Ranks documents with Okapi BM25 rather than by how many query tokens they contain, so a match
+/// on a rare token weighs more than one on a token most documents share.
+///
+/// Documents keep their term frequencies in the index, so removing one does not need its content.
+pub struct Bm25SearchEngine {
+    index: IndexStore,
+    k1: f64,
+    b: f64,
+    max_results: usize,
+}
+
+impl Bm25SearchEngine {
+    pub fn new() -> Self {
+        Self::with_params(DEFAULT_K1, DEFAULT_B, DEFAULT_LIMIT)
+    }
+
+    /// Uses the given term frequency saturation `k1` and length normalization `b`, and returns at
+    /// most `max_results` ids from a search that sets no limit.
+    pub fn with_params(k1: f64, b: f64, max_results: usize) -> Self {
+        Self {
+            index: RwLock::new(HashMap::new()),
+            k1,
+            b,
+            max_results,
+        }
+    }
+}
+
+impl Default for Bm25SearchEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SearchEngine for Bm25SearchEngine {
+    fn max_results(&self) -> usize {
+        self.max_results
+    }
+
+    fn set_max_results(&mut self, max_results: usize) {
+        self.max_results = max_results;
+    }
+
+    fn index(
+        &self,
+        storage: &dyn StorageOperations,
+        bucket_name: &str,
+        collection_name: &str,
+        id: &str,
+        content: &str,
+    ) -> Result<(), StorageError> {
+        let config = storage.collection_config(bucket_name, collection_name);
+        let mut tokens = self.tokenize(content, &config.tokenizer());
+
+        let mut index = self.index.write().map_err(|_| StorageError::PoisonError)?;
+        let collection = index
+            .entry(bucket_name.to_string())
+            .or_default()
+            .entry(collection_name.to_string())
+            .or_default();
+        collection.remove(id);
+
+        if tokens.len() < config.min_tokens {
+            return Ok(());
+        }
+        config.cap_tokens(&mut tokens);
+        collection.insert(id, tokens);
+
+        Ok(())
+    }
+
+    fn search_with_options(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, StorageError> {
+        let options = &options.with_default_limit(self.max_results);
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        let bucket = index
+            .get(bucket_name)
+            .ok_or(StorageError::NotFound(EntityType::Bucket))?;
+        let collection = bucket
+            .get(collection_name)
+            .ok_or(StorageError::NotFound(EntityType::Collection))?;
+
+        let tokens = lang::query::tokenize_query(query, &options.tokenizer);
+        let average_length = collection.average_length();
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+        for token in &tokens {
+            let Some(ids) = collection.postings.get(token) else {
+                continue;
+            };
+            let idf = collection.idf(token);
+
+            for id in ids.iter().filter(|id| options.matches_id(id)) {
+                let frequency = collection.term_frequencies[id][token] as f64;
+                let length = collection.lengths[id] as f64;
+                let normalization = 1.0 - self.b + self.b * length / average_length;
+                *scores.entry(id.as_str()).or_insert(0.0) +=
+                    idf * frequency * (self.k1 + 1.0) / (frequency + self.k1 * normalization);
+            }
+        }
+
+        if options.match_all {
+            scores.retain(|id, _| {
+                let frequencies = &collection.term_frequencies[*id];
+                tokens.iter().all(|token| frequencies.contains_key(token))
+            });
+        }
+
+        let mut ranked: Vec<(&str, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+        Ok(options.page(ranked.into_iter().map(|(id, _)| id.to_string())))
+    }
+
+    fn remove_from_index(
+        &self,
+        _storage: &dyn StorageOperations,
+        bucket_name: &str,
+        collection_name: &str,
+        id: &str,
+    ) -> Result<(), StorageError> {
+        let mut index = self.index.write().map_err(|_| StorageError::PoisonError)?;
+        if let Some(collection) = index
+            .get_mut(bucket_name)
+            .and_then(|bucket| bucket.get_mut(collection_name))
+        {
+            collection.remove(id);
+        }
+
+        Ok(())
+    }
+
+    fn clear_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError> {
+        let mut index = self.index.write().map_err(|_| StorageError::PoisonError)?;
+        if let Some(bucket) = index.get_mut(bucket_name) {
+            bucket.remove(collection_name);
+            if bucket.is_empty() {
+                index.remove(bucket_name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn clear_bucket(&self, bucket_name: &str) -> Result<(), StorageError> {
+        self.index
+            .write()
+            .map_err(|_| StorageError::PoisonError)?
+            .remove(bucket_name);
+
+        Ok(())
+    }
+
+    fn token_count(&self) -> Result<usize, StorageError> {
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        Ok(index
+            .values()
+            .flat_map(HashMap::values)
+            .map(|collection| collection.postings.len())
+            .sum())
+    }
+
+    fn collection_index_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<CollectionIndex, StorageError> {
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        let collection = index
+            .get(bucket_name)
+            .and_then(|bucket| bucket.get(collection_name));
+
+        Ok(collection
+            .into_iter()
+            .flat_map(|collection| &collection.postings)
+            .filter(|(token, _)| matches(token))
+            .map(|(token, ids)| (token.clone(), ids.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mock::MockStorage;
+
+    fn engine_with(documents: &[(&str, &str)]) -> Bm25SearchEngine {
+        let engine = Bm25SearchEngine::new();
+        let storage = MockStorage::new();
+        for (id, content) in documents {
+            engine
+                .index(&storage, "bucket", "collection", id, content)
+                .unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn test_rare_term_outranks_frequent_one() {
+        // both match one query token once, in documents of the same length
+        let engine = engine_with(&[
+            ("frequent", "common filler"),
+            ("rare", "unusual filler"),
+            ("1", "common other"),
+            ("2", "common words"),
+            ("3", "common again"),
+        ]);
+
+        let results = engine
+            .search("bucket", "collection", "common unusual")
+            .unwrap();
+        assert_eq!(results[0], "rare");
+        assert_eq!(results.len(), 5);
+    }
+
+    #[test]
+    fn test_shorter_document_ranks_first() {
+        let engine = engine_with(&[
+            ("long", "rust and many more words around it"),
+            ("short", "rust book"),
+            ("other", "python"),
+        ]);
+
+        assert_eq!(
+            engine.search("bucket", "collection", "rust").unwrap(),
+            ["short", "long"]
+        );
+    }
+
+    #[test]
+    fn test_statistics_follow_removals() {
+        let storage = MockStorage::new();
+        let engine = engine_with(&[("1", "hello world"), ("2", "hello there")]);
+
+        engine
+            .index(&storage, "bucket", "collection", "1", "goodbye")
+            .unwrap();
+        engine
+            .remove_from_index(&storage, "bucket", "collection", "2")
+            .unwrap();
+
+        let index = engine.index.read().unwrap();
+        let collection = &index["bucket"]["collection"];
+        assert_eq!(collection.total_length, 1);
+        assert_eq!(collection.term_frequencies.len(), 1);
+        assert_eq!(collection.postings.keys().collect::<Vec<_>>(), ["goodbye"]);
+        drop(index);
+
+        assert!(engine
+            .search("bucket", "collection", "hello")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            engine.search("bucket", "collection", "goodbye").unwrap(),
+            ["1"]
+        );
+    }
+
+    #[test]
+    fn test_search_missing_collection() {
+        let engine = engine_with(&[("1", "hello")]);
+        assert_eq!(
+            engine.search("bucket", "missing", "hello"),
+            Err(StorageError::NotFound(EntityType::Collection))
+        );
+        assert_eq!(
+            engine.search("missing", "collection", "hello"),
+            Err(StorageError::NotFound(EntityType::Bucket))
+        );
+    }
+}
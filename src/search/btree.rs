@@ -1,4 +1,4 @@
-use super::SearchEngine;
+use super::{CollectionIndex, SearchEngine, SearchOptions, DEFAULT_LIMIT};
 use crate::{
     lang,
     storage::{StorageError, StorageOperations},
@@ -13,17 +13,36 @@ use std::{
 
 pub struct BTreeSearchEngine {
     index: RwLock<BTreeMap<String, HashSet<String>>>,
+    /// bucket+collection+document id -> the tokens it is indexed under, so it is removed from
+    /// those even once the config of the collection changed
+    documents: RwLock<BTreeMap<String, HashSet<String>>>,
+    max_results: usize,
 }
 
 impl BTreeSearchEngine {
     pub fn new() -> Self {
+        Self::with_max_results(DEFAULT_LIMIT)
+    }
+
+    /// Returns at most `max_results` ids from a search that sets no limit.
+    pub fn with_max_results(max_results: usize) -> Self {
         Self {
             index: RwLock::new(BTreeMap::new()),
+            documents: RwLock::new(BTreeMap::new()),
+            max_results,
         }
     }
 }
 
 impl SearchEngine for BTreeSearchEngine {
+    fn max_results(&self) -> usize {
+        self.max_results
+    }
+
+    fn set_max_results(&mut self, max_results: usize) {
+        self.max_results = max_results;
+    }
+
     fn index(
         &self,
         storage: &dyn StorageOperations,
@@ -41,13 +60,18 @@ impl SearchEngine for BTreeSearchEngine {
             return Err(e);
         }
 
-        let mut content = content.to_string();
-        let tokens = lang::tokenize_iter(&mut content);
+        let config = storage.collection_config(bucket_name, collection_name);
+        let mut tokens = self.tokenize(content, &config.tokenizer());
+
+        if tokens.len() < config.min_tokens {
+            return Ok(());
+        }
+        config.cap_tokens(&mut tokens);
 
         let mut unlocked_index = self.index.write().unwrap();
 
-        for token in tokens {
-            let key = generate_key(bucket_name, collection_name, &token);
+        for token in &tokens {
+            let key = generate_key(bucket_name, collection_name, token);
             let mut entry = unlocked_index.get_mut(&key);
             if entry.is_none() {
                 unlocked_index.insert(key.clone(), HashSet::new());
@@ -55,19 +79,30 @@ impl SearchEngine for BTreeSearchEngine {
             }
             entry.unwrap().insert(id.to_string());
         }
+        self.documents.write().unwrap().insert(
+            generate_key(bucket_name, collection_name, id),
+            tokens.into_iter().collect(),
+        );
 
         Ok(())
     }
 
     fn remove_from_index(
         &self,
-        storage: &dyn StorageOperations,
+        _storage: &dyn StorageOperations,
         bucket_name: &str,
         collection_name: &str,
         id: &str,
     ) -> Result<(), crate::storage::StorageError> {
-        let content = storage.get_document(bucket_name, collection_name, id)?;
-        let tokens = lang::tokenize(&content.content);
+        let Some(tokens) =
+            self.documents
+                .write()
+                .unwrap()
+                .remove(&generate_key(bucket_name, collection_name, id))
+        else {
+            // the document was not indexed, so there is nothing to remove
+            return Ok(());
+        };
 
         let mut unlocked_index = self.index.write().unwrap();
 
@@ -88,30 +123,164 @@ impl SearchEngine for BTreeSearchEngine {
         Ok(())
     }
 
-    fn search(
+    fn search_with_options(
         &self,
         bucket_name: &str,
         collection_name: &str,
         query: &str,
+        options: &SearchOptions,
     ) -> Result<Vec<String>, StorageError> {
-        let tokens = lang::tokenize(query);
-
-        let mut results: HashSet<String> = HashSet::new();
+        let options = &options.with_default_limit(self.max_results);
+        let tokens = lang::query::tokenize_query(query, &options.tokenizer);
 
         let reader = self.index.read().unwrap();
 
-        for token in tokens {
-            let key = generate_key(bucket_name, collection_name, &token);
-            if let Some(ids) = reader.get(&key) {
-                results.extend(ids.iter().map(|id| id.clone()));
-            }
-        }
+        // ids matching more tokens of the query rank higher, like in the std engine
+        Ok(options.rank_matches(tokens.iter().map(|token| {
+            let key = generate_key(bucket_name, collection_name, token);
+            reader
+                .get(&key)
+                .map(|ids| {
+                    ids.iter()
+                        .filter(|id| options.matches_id(id))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default()
+        })))
+    }
+
+    fn search_prefix(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, StorageError> {
+        let options = &options.with_default_limit(self.max_results);
+        let tokens = lang::query::tokenize_query(query, &options.tokenizer);
+
+        let reader = self.index.read().map_err(|_| StorageError::PoisonError)?;
+
+        let results = options.combine_matches(tokens.iter().map(|token| {
+            // keys sharing the prefix are contiguous, and the bucket and collection leading the
+            // key keep the scan from running into the next collection
+            let prefix = generate_key(bucket_name, collection_name, token);
+            reader
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .flat_map(|(_, ids)| ids.iter().filter(|id| options.matches_id(id)).cloned())
+                .collect()
+        }));
+
+        let mut results: Vec<String> = results.into_iter().collect();
+        results.sort();
+        Ok(options.page(results))
+    }
+
+    /// Candidates are the keys sharing the first character of a query token, which are
+    /// contiguous in the tree.
+    fn search_fuzzy(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        max_distance: usize,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, StorageError> {
+        let options = &options.with_default_limit(self.max_results);
+        let reader = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        let collection_prefix = generate_key(bucket_name, collection_name, "");
+
+        Ok(super::fuzzy_matches(
+            query,
+            max_distance,
+            options,
+            |first| {
+                let prefix = generate_key(bucket_name, collection_name, &first.to_string());
+                let collection_prefix = collection_prefix.as_str();
+                reader
+                    .range(prefix.clone()..)
+                    .take_while(move |(key, _)| key.starts_with(&prefix))
+                    .filter_map(move |(key, ids)| Some((key.strip_prefix(collection_prefix)?, ids)))
+            },
+        ))
+    }
 
-        Ok(results
-            .into_iter()
-            .map(|id| id.as_str().to_string())
+    fn token_count(&self) -> Result<usize, StorageError> {
+        Ok(self
+            .index
+            .read()
+            .map_err(|_| StorageError::PoisonError)?
+            .len())
+    }
+
+    fn ids_for_token(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        token: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        let key = generate_key(bucket_name, collection_name, token);
+        let unlocked_index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        let mut ids: Vec<String> = unlocked_index
+            .get(&key)
+            .map(|ids| ids.iter().cloned().collect())
+            .unwrap_or_default();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn collection_index_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<CollectionIndex, StorageError> {
+        let prefix = generate_key(bucket_name, collection_name, "");
+        let unlocked_index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+
+        Ok(unlocked_index
+            .range(prefix.clone()..)
+            .map_while(|(key, ids)| Some((key.strip_prefix(&prefix)?, ids)))
+            .filter(|(token, _)| matches(token))
+            .map(|(token, ids)| (token.to_string(), ids.clone()))
             .collect())
     }
+
+    fn clear_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError> {
+        let prefix = generate_key(bucket_name, collection_name, "");
+        remove_prefixed(&mut self.index.write().unwrap(), &prefix);
+        remove_prefixed(&mut self.documents.write().unwrap(), &prefix);
+
+        Ok(())
+    }
+
+    fn clear_bucket(&self, bucket_name: &str) -> Result<(), StorageError> {
+        let prefix = format!("{bucket_name}~ZZAP~");
+        remove_prefixed(&mut self.index.write().unwrap(), &prefix);
+        remove_prefixed(&mut self.documents.write().unwrap(), &prefix);
+
+        Ok(())
+    }
+}
+
+/// Removes the keys starting with the prefix, which are contiguous in the tree: those of one
+/// collection start at its bare prefix, and so do the collections of one bucket.
+fn remove_prefixed(tree: &mut BTreeMap<String, HashSet<String>>, prefix: &str) {
+    let keys: Vec<String> = tree
+        .range(prefix.to_string()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(prefix))
+        .cloned()
+        .collect();
+    for key in keys {
+        tree.remove(&key);
+    }
 }
 
 fn generate_key(bucket_name: &str, collection_name: &str, token: &str) -> String {
@@ -225,6 +394,21 @@ mod tests {
         assert_eq!(results[0], doc_id);
     }
 
+    #[test]
+    fn test_search_is_case_insensitive() {
+        let engine = BTreeSearchEngine::new();
+        let storage = MockStorage::new();
+
+        engine
+            .index(&storage, "bucket", "collection", "doc", "Hello")
+            .unwrap();
+
+        assert_eq!(
+            engine.search("bucket", "collection", "hello").unwrap(),
+            ["doc"]
+        );
+    }
+
     #[test]
     fn test_search_non_existent_items() {
         let engine = BTreeSearchEngine::new();
@@ -278,4 +462,78 @@ mod tests {
         );
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_clear_collection() {
+        let engine = BTreeSearchEngine::new();
+        let storage = MockStorage::new();
+        let bucket_name = "test_bucket";
+
+        for collection_name in ["cleared", "sibling"] {
+            engine
+                .index(
+                    &storage,
+                    bucket_name,
+                    collection_name,
+                    "doc",
+                    "shared content",
+                )
+                .unwrap();
+        }
+
+        engine.clear_collection(bucket_name, "cleared").unwrap();
+
+        let result = engine.search(bucket_name, "cleared", "shared content");
+        assert!(result.is_err() || result.unwrap().is_empty());
+        assert_eq!(
+            engine.search(bucket_name, "sibling", "shared").unwrap(),
+            ["doc"]
+        );
+
+        // clearing a missing collection is a no-op
+        assert!(engine.clear_collection(bucket_name, "missing").is_ok());
+        assert!(engine.clear_collection("missing", "cleared").is_ok());
+    }
+
+    #[test]
+    fn test_search_prefix() {
+        let engine = BTreeSearchEngine::new();
+        let storage = MockStorage::new();
+        let bucket_name = "test_bucket";
+
+        for (collection_name, id, content) in [
+            ("posts", "1", "some content"),
+            ("posts", "2", "a container"),
+            ("posts", "3", "unrelated words"),
+            // sorted right after the searched collection, its tokens must not leak in
+            ("posts2", "4", "contact"),
+            ("other", "5", "content"),
+        ] {
+            engine
+                .index(&storage, bucket_name, collection_name, id, content)
+                .unwrap();
+        }
+
+        let options = SearchOptions::default();
+        assert_eq!(
+            engine
+                .search_prefix(bucket_name, "posts", "cont", &options)
+                .unwrap(),
+            ["1", "2"]
+        );
+        assert_eq!(
+            engine
+                .search_prefix(bucket_name, "posts", "contai unrel", &options)
+                .unwrap(),
+            ["2", "3"]
+        );
+        assert!(engine
+            .search_prefix(bucket_name, "posts", "contact", &options)
+            .unwrap()
+            .is_empty());
+        assert!(engine
+            .search_prefix(bucket_name, "missing", "cont", &options)
+            .unwrap()
+            .is_empty());
+    }
 }
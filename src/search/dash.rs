@@ -1,4 +1,4 @@
-use super::SearchEngine;
+use super::{batch_index_parallel, CollectionIndex, SearchEngine, SearchOptions, DEFAULT_LIMIT};
 use crate::{
     lang,
     storage::{StorageError, StorageOperations},
@@ -11,17 +11,36 @@ use std::collections::HashSet;
 
 pub struct DashSearchEngine {
     index: DashMap<String, DashMap<String, HashSet<String>>>,
+    /// Bucket+collection -> document id -> the tokens it is indexed under, so it is removed from
+    /// those even once the config of the collection changed
+    documents: DashMap<String, DashMap<String, HashSet<String>>>,
+    max_results: usize,
 }
 
 impl DashSearchEngine {
     pub fn new() -> Self {
+        Self::with_max_results(DEFAULT_LIMIT)
+    }
+
+    /// Returns at most `max_results` ids from a search that sets no limit.
+    pub fn with_max_results(max_results: usize) -> Self {
         Self {
             index: DashMap::new(),
+            documents: DashMap::new(),
+            max_results,
         }
     }
 }
 
 impl SearchEngine for DashSearchEngine {
+    fn max_results(&self) -> usize {
+        self.max_results
+    }
+
+    fn set_max_results(&mut self, max_results: usize) {
+        self.max_results = max_results;
+    }
+
     fn index(
         &self,
         storage: &dyn StorageOperations,
@@ -39,77 +58,175 @@ impl SearchEngine for DashSearchEngine {
             return Err(e);
         }
 
-        let tokens = lang::tokenize(content);
+        let config = storage.collection_config(bucket_name, collection_name);
+        let mut tokens = self.tokenize(content, &config.tokenizer());
 
-        let bucket_plus_collection = generate_key(bucket_name, collection_name);
-        let collection = self
-            .index
-            .entry(bucket_plus_collection)
-            .or_insert_with(DashMap::new);
+        if tokens.len() < config.min_tokens {
+            return Ok(());
+        }
+        config.cap_tokens(&mut tokens);
 
-        for token in tokens {
-            let mut entry = collection.entry(token).or_insert_with(HashSet::new);
+        let bucket_plus_collection = generate_key(bucket_name, collection_name);
+        // only locked exclusively to create it, so documents of a collection are indexed in parallel
+        let collection = match self.index.get(&bucket_plus_collection) {
+            Some(collection) => collection,
+            None => self
+                .index
+                .entry(bucket_plus_collection.clone())
+                .or_default()
+                .downgrade(),
+        };
+
+        for token in &tokens {
+            let mut entry = collection.entry(token.clone()).or_insert_with(HashSet::new);
             entry.insert(id.to_string());
         }
 
+        let documents = match self.documents.get(&bucket_plus_collection) {
+            Some(documents) => documents,
+            None => self
+                .documents
+                .entry(bucket_plus_collection)
+                .or_default()
+                .downgrade(),
+        };
+        documents.insert(id.to_string(), tokens.into_iter().collect());
+
         Ok(())
     }
 
-    fn remove_from_index(
+    /// Indexes the documents in parallel, the index takes concurrent inserts.
+    fn batch_index(
         &self,
         storage: &dyn StorageOperations,
         bucket_name: &str,
         collection_name: &str,
+        docs: Vec<(String, String)>,
+    ) -> Result<(), StorageError> {
+        batch_index_parallel(self, storage, bucket_name, collection_name, docs)
+    }
+
+    fn remove_from_index(
+        &self,
+        _storage: &dyn StorageOperations,
+        bucket_name: &str,
+        collection_name: &str,
         id: &str,
     ) -> Result<(), crate::storage::StorageError> {
-        let content = storage.get_document(bucket_name, collection_name, id)?;
-        let tokens = lang::tokenize(&content.content);
-
         let bucket_plus_collection = generate_key(bucket_name, collection_name);
-        let collection = self
-            .index
-            .entry(bucket_plus_collection)
-            .or_insert_with(DashMap::new);
+        let Some((_, tokens)) = self
+            .documents
+            .get(&bucket_plus_collection)
+            .and_then(|documents| documents.remove(id))
+        else {
+            // the document was not indexed, so there is nothing to remove
+            return Ok(());
+        };
+        let Some(collection) = self.index.get(&bucket_plus_collection) else {
+            return Ok(());
+        };
 
         for token in tokens {
-            let mut entry = collection.entry(token.clone()).or_insert_with(HashSet::new);
+            let Some(mut entry) = collection.get_mut(&token) else {
+                continue;
+            };
             entry.remove(id);
 
             if entry.is_empty() {
                 drop(entry);
-                collection.remove(&token);
+                // checked again under the lock, another document may have been added meanwhile
+                collection.remove_if(&token, |_, ids| ids.is_empty());
             }
         }
 
         Ok(())
     }
 
-    fn search(
+    fn search_with_options(
         &self,
         bucket_name: &str,
         collection_name: &str,
         query: &str,
+        options: &SearchOptions,
     ) -> Result<Vec<String>, StorageError> {
-        let tokens = lang::tokenize(query);
+        let options = &options.with_default_limit(self.max_results);
+        let tokens = lang::query::tokenize_query(query, &options.tokenizer);
+
+        // a missing collection has no results, looking it up must not create it
+        let Some(collection) = self.index.get(&generate_key(bucket_name, collection_name)) else {
+            return Ok(Vec::new());
+        };
+
+        // ids matching more tokens of the query rank higher, like in the std engine
+        Ok(options.rank_matches(tokens.iter().map(|token| {
+            collection
+                .get(token)
+                .map(|ids| {
+                    ids.iter()
+                        .filter(|id| options.matches_id(id))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default()
+        })))
+    }
 
-        let bucket_plus_collection = generate_key(bucket_name, collection_name);
-        let collection = self
+    fn token_count(&self) -> Result<usize, StorageError> {
+        Ok(self.index.iter().map(|collection| collection.len()).sum())
+    }
+
+    fn ids_for_token(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        token: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        let mut ids: Vec<String> = self
             .index
-            .entry(bucket_plus_collection)
-            .or_insert_with(DashMap::new);
+            .get(&generate_key(bucket_name, collection_name))
+            .and_then(|collection| collection.get(token).map(|ids| ids.clone()))
+            .into_iter()
+            .flatten()
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
 
-        let mut results: HashSet<String> = HashSet::new();
+    fn collection_index_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<CollectionIndex, StorageError> {
+        let Some(collection) = self.index.get(&generate_key(bucket_name, collection_name)) else {
+            return Ok(CollectionIndex::new());
+        };
+
+        Ok(collection
+            .iter()
+            .filter(|entry| matches(entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect())
+    }
 
-        for token in tokens {
-            if let Some(ids) = collection.get(&token) {
-                results.extend(ids.iter().map(|id| id.clone()));
-            }
-        }
+    fn clear_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError> {
+        let bucket_plus_collection = generate_key(bucket_name, collection_name);
+        self.index.remove(&bucket_plus_collection);
+        self.documents.remove(&bucket_plus_collection);
 
-        Ok(results
-            .into_iter()
-            .map(|id| id.as_str().to_string())
-            .collect())
+        Ok(())
+    }
+
+    fn clear_bucket(&self, bucket_name: &str) -> Result<(), StorageError> {
+        let prefix = generate_key(bucket_name, "");
+        self.index.retain(|key, _| !key.starts_with(&prefix));
+        self.documents.retain(|key, _| !key.starts_with(&prefix));
+
+        Ok(())
     }
 }
 
@@ -267,4 +384,88 @@ mod tests {
         );
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_clear_collection() {
+        let engine = DashSearchEngine::new();
+        let storage = MockStorage::new();
+        let bucket_name = "test_bucket";
+
+        for collection_name in ["cleared", "sibling"] {
+            engine
+                .index(
+                    &storage,
+                    bucket_name,
+                    collection_name,
+                    "doc",
+                    "shared content",
+                )
+                .unwrap();
+        }
+
+        engine.clear_collection(bucket_name, "cleared").unwrap();
+
+        assert_eq!(
+            engine.search(bucket_name, "cleared", "shared content"),
+            Ok(vec![])
+        );
+        assert_eq!(
+            engine.search(bucket_name, "sibling", "shared").unwrap(),
+            ["doc"]
+        );
+
+        // clearing a missing collection is a no-op
+        assert!(engine.clear_collection(bucket_name, "missing").is_ok());
+        assert!(engine.clear_collection("missing", "cleared").is_ok());
+    }
+
+    #[test]
+    fn test_lookups_do_not_create_collections() {
+        let engine = DashSearchEngine::new();
+        let storage = MockStorage::new();
+        storage
+            .add_document("bucket", "collection", Document::new("doc", "content"))
+            .unwrap();
+
+        assert!(engine
+            .search("bucket", "collection", "content")
+            .unwrap()
+            .is_empty());
+        engine
+            .remove_from_index(&storage, "bucket", "collection", "doc")
+            .unwrap();
+
+        assert_eq!(engine.index.len(), 0);
+    }
+
+    #[test]
+    fn test_batch_index_matches_sequential_indexing() {
+        let storage = MockStorage::new();
+        let mut docs: Vec<(String, String)> = (0..200)
+            .map(|i| (i.to_string(), format!("shared word{} group{}", i, i % 7)))
+            .collect();
+        // indexed last, replaces the first document of that id
+        docs.push(("0".to_string(), "replaced".to_string()));
+
+        // the engine keeps the tokens of every document, so indexing an id again replaces them
+        let sequential = DashSearchEngine::new();
+        for (id, content) in &docs {
+            sequential
+                .index(&storage, "bucket", "collection", id, content)
+                .unwrap();
+        }
+        let parallel = DashSearchEngine::new();
+        parallel
+            .batch_index(&storage, "bucket", "collection", docs)
+            .unwrap();
+
+        assert_eq!(
+            parallel.collection_index("bucket", "collection").unwrap(),
+            sequential.collection_index("bucket", "collection").unwrap()
+        );
+        assert_eq!(
+            parallel.search("bucket", "collection", "replaced").unwrap(),
+            ["0"]
+        );
+    }
 }
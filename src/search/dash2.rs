@@ -1,4 +1,4 @@
-use super::SearchEngine;
+use super::{batch_index_parallel, CollectionIndex, SearchEngine, SearchOptions, DEFAULT_LIMIT};
 use crate::{
     lang,
     storage::{StorageError, StorageOperations},
@@ -11,17 +11,36 @@ use std::collections::HashSet;
 
 pub struct Dash2SearchEngine {
     index: DashMap<String, HashSet<String>>,
+    /// bucket+collection+document id -> the tokens it is indexed under, so it is removed from
+    /// those even once the config of the collection changed
+    documents: DashMap<String, HashSet<String>>,
+    max_results: usize,
 }
 
 impl Dash2SearchEngine {
     pub fn new() -> Self {
+        Self::with_max_results(DEFAULT_LIMIT)
+    }
+
+    /// Returns at most `max_results` ids from a search that sets no limit.
+    pub fn with_max_results(max_results: usize) -> Self {
         Self {
             index: DashMap::new(),
+            documents: DashMap::new(),
+            max_results,
         }
     }
 }
 
 impl SearchEngine for Dash2SearchEngine {
+    fn max_results(&self) -> usize {
+        self.max_results
+    }
+
+    fn set_max_results(&mut self, max_results: usize) {
+        self.max_results = max_results;
+    }
+
     fn index(
         &self,
         storage: &dyn StorageOperations,
@@ -39,72 +58,151 @@ impl SearchEngine for Dash2SearchEngine {
             return Err(e);
         }
 
-        let mut content = content.to_string();
-        let tokens = lang::tokenize_iter(&mut content);
+        let config = storage.collection_config(bucket_name, collection_name);
+        let mut tokens = self.tokenize(content, &config.tokenizer());
 
-        for token in tokens {
-            let key = generate_key(bucket_name, collection_name, &token);
-            let mut entry = self
-                .index
-                .try_get_mut(&key)
-                .try_unwrap()
-                .or_else(|| {
-                    self.index.insert(key.clone(), HashSet::new());
-                    self.index.try_get_mut(&key).try_unwrap()
-                })
-                .unwrap();
-            entry.insert(id.to_string());
+        if tokens.len() < config.min_tokens {
+            return Ok(());
         }
+        config.cap_tokens(&mut tokens);
+
+        for token in &tokens {
+            let key = generate_key(bucket_name, collection_name, token);
+            self.index.entry(key).or_default().insert(id.to_string());
+        }
+        self.documents.insert(
+            generate_key(bucket_name, collection_name, id),
+            tokens.into_iter().collect(),
+        );
 
         Ok(())
     }
 
-    fn remove_from_index(
+    /// Indexes the documents in parallel, the index takes concurrent inserts.
+    fn batch_index(
         &self,
         storage: &dyn StorageOperations,
         bucket_name: &str,
         collection_name: &str,
+        docs: Vec<(String, String)>,
+    ) -> Result<(), StorageError> {
+        batch_index_parallel(self, storage, bucket_name, collection_name, docs)
+    }
+
+    fn remove_from_index(
+        &self,
+        _storage: &dyn StorageOperations,
+        bucket_name: &str,
+        collection_name: &str,
         id: &str,
     ) -> Result<(), crate::storage::StorageError> {
-        let content = storage.get_document(bucket_name, collection_name, id)?;
-        let tokens = lang::tokenize(&content.content);
+        let Some((_, tokens)) =
+            self.documents
+                .remove(&generate_key(bucket_name, collection_name, id))
+        else {
+            // the document was not indexed, so there is nothing to remove
+            return Ok(());
+        };
 
         for token in tokens {
             let key = generate_key(bucket_name, collection_name, &token);
-            let mut entry = self.index.entry(key.clone()).or_insert_with(HashSet::new);
+            let Some(mut entry) = self.index.get_mut(&key) else {
+                continue;
+            };
             entry.remove(id);
 
             if entry.is_empty() {
                 drop(entry);
-                self.index.remove(&key);
+                // checked again under the lock, another document may have been added meanwhile
+                self.index.remove_if(&key, |_, ids| ids.is_empty());
             }
         }
 
         Ok(())
     }
 
-    fn search(
+    fn search_with_options(
         &self,
         bucket_name: &str,
         collection_name: &str,
         query: &str,
+        options: &SearchOptions,
     ) -> Result<Vec<String>, StorageError> {
-        let tokens = lang::tokenize(query);
+        let options = &options.with_default_limit(self.max_results);
+        let tokens = lang::query::tokenize_query(query, &options.tokenizer);
+
+        // ids matching more tokens of the query rank higher, like in the std engine
+        Ok(options.rank_matches(tokens.iter().map(|token| {
+            let key = generate_key(bucket_name, collection_name, token);
+            self.index
+                .get(&key)
+                .map(|ids| {
+                    ids.iter()
+                        .filter(|id| options.matches_id(id))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default()
+        })))
+    }
 
-        let mut results: HashSet<String> = HashSet::new();
+    fn token_count(&self) -> Result<usize, StorageError> {
+        Ok(self.index.len())
+    }
 
-        for token in tokens {
-            let key = generate_key(bucket_name, collection_name, &token);
-            if let Some(ids) = self.index.get(&key) {
-                results.extend(ids.iter().map(|id| id.clone()));
-            }
-        }
+    fn ids_for_token(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        token: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        let key = generate_key(bucket_name, collection_name, token);
+        let mut ids: Vec<String> = self
+            .index
+            .get(&key)
+            .map(|ids| ids.iter().cloned().collect())
+            .unwrap_or_default();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn collection_index_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<CollectionIndex, StorageError> {
+        let prefix = generate_key(bucket_name, collection_name, "");
 
-        Ok(results
-            .into_iter()
-            .map(|id| id.as_str().to_string())
+        Ok(self
+            .index
+            .iter()
+            .filter_map(|entry| {
+                let token = entry.key().strip_prefix(&prefix)?;
+                matches(token).then(|| (token.to_string(), entry.value().clone()))
+            })
             .collect())
     }
+
+    fn clear_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError> {
+        let prefix = generate_key(bucket_name, collection_name, "");
+        self.index.retain(|key, _| !key.starts_with(&prefix));
+        self.documents.retain(|key, _| !key.starts_with(&prefix));
+
+        Ok(())
+    }
+
+    fn clear_bucket(&self, bucket_name: &str) -> Result<(), StorageError> {
+        let prefix = format!("{bucket_name}~ZZAP~");
+        self.index.retain(|key, _| !key.starts_with(&prefix));
+        self.documents.retain(|key, _| !key.starts_with(&prefix));
+
+        Ok(())
+    }
 }
 
 fn generate_key(bucket_name: &str, collection_name: &str, token: &str) -> String {
@@ -222,6 +320,21 @@ mod tests {
         assert_eq!(results[0], doc_id);
     }
 
+    #[test]
+    fn test_search_is_case_insensitive() {
+        let engine = Dash2SearchEngine::new();
+        let storage = MockStorage::new();
+
+        engine
+            .index(&storage, "bucket", "collection", "doc", "Hello")
+            .unwrap();
+
+        assert_eq!(
+            engine.search("bucket", "collection", "hello").unwrap(),
+            ["doc"]
+        );
+    }
+
     #[test]
     fn test_search_non_existent_items() {
         let engine = Dash2SearchEngine::new();
@@ -275,4 +388,36 @@ mod tests {
         );
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_clear_collection() {
+        let engine = Dash2SearchEngine::new();
+        let storage = MockStorage::new();
+        let bucket_name = "test_bucket";
+
+        for collection_name in ["cleared", "sibling"] {
+            engine
+                .index(
+                    &storage,
+                    bucket_name,
+                    collection_name,
+                    "doc",
+                    "shared content",
+                )
+                .unwrap();
+        }
+
+        engine.clear_collection(bucket_name, "cleared").unwrap();
+
+        let result = engine.search(bucket_name, "cleared", "shared content");
+        assert!(result.is_err() || result.unwrap().is_empty());
+        assert_eq!(
+            engine.search(bucket_name, "sibling", "shared").unwrap(),
+            ["doc"]
+        );
+
+        // clearing a missing collection is a no-op
+        assert!(engine.clear_collection(bucket_name, "missing").is_ok());
+        assert!(engine.clear_collection("missing", "cleared").is_ok());
+    }
 }
@@ -1,14 +1,186 @@
+mod bm25;
 mod btree;
 mod dash;
 mod dash2;
+mod ngram;
 mod std;
 
 pub use {
-    btree::BTreeSearchEngine, dash::DashSearchEngine, dash2::Dash2SearchEngine,
-    std::StdSearchEngine,
+    bm25::Bm25SearchEngine, btree::BTreeSearchEngine, dash::DashSearchEngine,
+    dash2::Dash2SearchEngine, ngram::NgramSearchEngine, std::StdSearchEngine,
 };
 
-use crate::storage::{StorageError, StorageOperations, StorageOperationsInternal};
+use crate::lang;
+pub use crate::lang::TokenizerOptions;
+use crate::storage::{
+    EntityType, StorageError, StorageOperations, StorageOperationsInternal, StoredValue,
+};
+use ::std::cmp::Reverse;
+use ::std::collections::{BinaryHeap, HashMap, HashSet};
+use ::std::fmt;
+use ::std::ops::Range;
+use dashmap::DashMap;
+use rayon::prelude::*;
+
+/// Index entries of a single collection, as token to the ids of documents containing it.
+pub type CollectionIndex = HashMap<String, HashSet<String>>;
+
+/// Search engine chosen at runtime, see [`engine_by_name`].
+pub type DynSearchEngine = Box<dyn SearchEngine + Send + Sync>;
+
+/// Builds an empty engine from its name: `std`, `dash`, `dash2`, `btree`, `bm25` or `ngram`,
+/// returning at most `max_results` ids from a search that sets no limit.
+pub fn engine_by_name(name: &str, max_results: usize) -> Option<DynSearchEngine> {
+    let mut engine: DynSearchEngine = match name {
+        "std" => Box::new(StdSearchEngine::new()),
+        "dash" => Box::new(DashSearchEngine::new()),
+        "dash2" => Box::new(Dash2SearchEngine::new()),
+        "btree" => Box::new(BTreeSearchEngine::new()),
+        "bm25" => Box::new(Bm25SearchEngine::new()),
+        "ngram" => Box::new(NgramSearchEngine::new()),
+        _ => return None,
+    };
+    engine.set_max_results(max_results);
+    Some(engine)
+}
+
+/// Number of ids a search returns when the query sets no limit, unless the engine is built with
+/// another `max_results`.
+pub const DEFAULT_LIMIT: usize = 10;
+
+/// Largest edit distance a fuzzy search accepts. Past it, most tokens of a collection are as close
+/// to the query as the one meant.
+pub const MAX_FUZZY_DISTANCE: usize = 3;
+
+/// Largest `LIMIT` or `OFFSET` a search accepts, so the ids an engine ranks stay bounded whatever
+/// the client asks for.
+pub const MAX_PAGE_BOUND: usize = 100_000;
+
+/// Most tokens of the index a single query token matches in a fuzzy search, the closest ones.
+pub const FUZZY_CANDIDATES: usize = 64;
+
+/// Optional clauses narrowing down a search query.
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// Only ids starting with this prefix are returned.
+    pub id_prefix: Option<String>,
+    /// Byte offsets of the matching words are returned next to each id.
+    pub highlight: bool,
+    /// Query tokens keep their case, whatever the collection is configured with.
+    pub case_sensitive: bool,
+    /// Most ids returned, the [`max_results`](SearchEngine::max_results) of the engine if `None`.
+    pub limit: Option<usize>,
+    /// Best ranked ids skipped before the returned ones, for paging through results.
+    pub offset: usize,
+    /// Only documents matching every token of the query are returned, rather than any of them.
+    pub match_all: bool,
+    /// Tokenizer the collection is indexed with, set from its configuration rather than the query.
+    pub tokenizer: TokenizerOptions,
+}
+
+/// A search result along with where the query matched its content.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchHit {
+    pub id: String,
+    /// Byte ranges of the matching words within the content.
+    pub offsets: Vec<Range<usize>>,
+}
+
+/// Renders as `<id> <start>-<end> ...`, the format of highlighted `SEARCH` results.
+impl fmt::Display for SearchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        for offset in &self.offsets {
+            write!(f, " {}-{}", offset.start, offset.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl SearchOptions {
+    /// Uses the tokenizer of the searched collection, refined by the clauses of the query.
+    pub fn set_tokenizer(&mut self, tokenizer: TokenizerOptions) {
+        self.tokenizer = TokenizerOptions {
+            case_sensitive: tokenizer.case_sensitive || self.case_sensitive,
+            ..tokenizer
+        };
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+
+    /// The same options, limited to `max_results` ids unless they set a limit of their own.
+    pub fn with_default_limit(&self, max_results: usize) -> SearchOptions {
+        SearchOptions {
+            limit: Some(self.limit.unwrap_or(max_results)),
+            ..self.clone()
+        }
+    }
+
+    /// Number of best ranked ids an engine must find to fill the requested page.
+    pub fn ranked_len(&self) -> usize {
+        self.offset.saturating_add(self.limit())
+    }
+
+    /// Keeps the requested page of ranked ids, best first: `offset` ids are skipped, then at
+    /// most `limit` are kept.
+    pub fn page(&self, ranked: impl IntoIterator<Item = String>) -> Vec<String> {
+        ranked
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit())
+            .collect()
+    }
+
+    /// Ids matching the query, given the ids matching each of its tokens: those matching any
+    /// token, or those matching every one with [`match_all`](Self::match_all).
+    pub fn combine_matches(
+        &self,
+        per_token: impl IntoIterator<Item = HashSet<String>>,
+    ) -> HashSet<String> {
+        let mut per_token = per_token.into_iter();
+        if !self.match_all {
+            return per_token.flatten().collect();
+        }
+
+        let first = per_token.next().unwrap_or_default();
+        per_token.fold(first, |matched, ids| &matched & &ids)
+    }
+
+    /// Ranked page of the ids matching the query, given the ids matching each of its tokens: the
+    /// ids matching the most tokens come first, ties broken by id.
+    pub fn rank_matches(
+        &self,
+        per_token: impl IntoIterator<Item = HashSet<String>>,
+    ) -> Vec<String> {
+        let mut found_ids: HashMap<String, usize> = HashMap::new();
+        let mut tokens = 0;
+        for ids in per_token {
+            tokens += 1;
+            for id in ids {
+                *found_ids.entry(id).or_insert(0) += 1;
+            }
+        }
+        if self.match_all {
+            found_ids.retain(|_, count| *count == tokens);
+        }
+
+        let found_ids = found_ids
+            .iter()
+            .map(|(id, count)| (id.as_str(), *count))
+            .collect();
+        self.page(top_ids(found_ids, self.ranked_len()))
+    }
+
+    pub fn matches_id(&self, id: &str) -> bool {
+        match &self.id_prefix {
+            Some(prefix) => id.starts_with(prefix.as_str()),
+            None => true,
+        }
+    }
+}
 
 pub trait SearchEngine {
     fn initialize(&self, storage: &dyn StorageOperationsInternal) -> Result<(), StorageError> {
@@ -17,24 +189,37 @@ pub trait SearchEngine {
             let bucket_name = bucket_ref.key();
             let bucket = bucket_ref.value();
             for collection_ref in bucket.iter() {
-                let collection_name = collection_ref.key();
-                let collection = collection_ref.value();
-                for document_ref in collection.iter() {
-                    let document_id = document_ref.key();
-                    let document = document_ref.value();
-                    self.index(
-                        storage,
-                        bucket_name,
-                        collection_name,
-                        document_id,
-                        &document,
-                    )?;
-                }
+                index_documents(
+                    self,
+                    storage,
+                    bucket_name,
+                    collection_ref.key(),
+                    collection_ref.value(),
+                )?;
             }
         }
         Ok(())
     }
 
+    /// Drops the index entries of the collection and indexes its stored documents again, for
+    /// when the index no longer matches the storage. Returns how many documents were indexed.
+    fn reindex_collection(
+        &self,
+        storage: &dyn StorageOperationsInternal,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<usize, StorageError> {
+        let store = storage.store()?;
+        let bucket = store
+            .get(bucket_name)
+            .ok_or(StorageError::NotFound(EntityType::Bucket))?;
+        let collection = bucket
+            .get(collection_name)
+            .ok_or(StorageError::NotFound(EntityType::Collection))?;
+        self.clear_collection(bucket_name, collection_name)?;
+        index_documents(self, storage, bucket_name, collection_name, &collection)
+    }
+
     fn index(
         &self,
         storage: &dyn StorageOperations,
@@ -44,13 +229,98 @@ pub trait SearchEngine {
         content: &str,
     ) -> Result<(), StorageError>;
 
+    /// Most ids a search returns when its options set no limit. Every search of the engine
+    /// applies it, so engines return as many results whichever of them runs the query.
+    fn max_results(&self) -> usize {
+        DEFAULT_LIMIT
+    }
+
+    /// Changes [`max_results`](SearchEngine::max_results), for the searches run from now on.
+    /// Ignored by engines always returning the default.
+    fn set_max_results(&mut self, _max_results: usize) {}
+
     fn search(
         &self,
         bucket_name: &str,
         collection_name: &str,
         query: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        self.search_with_options(
+            bucket_name,
+            collection_name,
+            query,
+            &SearchOptions::default(),
+        )
+    }
+
+    fn search_with_options(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        options: &SearchOptions,
     ) -> Result<Vec<String>, StorageError>;
 
+    /// Searches for documents containing a token starting with any token of the query, i.e.
+    /// `cont` finds both `content` and `container`.
+    ///
+    /// Every match counts the same, so ids are ranked by themselves. Goes through the whole
+    /// [`collection_index`](Self::collection_index) unless the engine keeps its tokens sorted.
+    fn search_prefix(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, StorageError> {
+        let options = &options.with_default_limit(self.max_results());
+        let prefixes = lang::query::tokenize_query(query, &options.tokenizer);
+        let mut per_prefix: Vec<HashSet<String>> = vec![HashSet::new(); prefixes.len()];
+        for (token, ids) in self.collection_index(bucket_name, collection_name)? {
+            for (prefix, matched) in prefixes.iter().zip(&mut per_prefix) {
+                if token.starts_with(prefix.as_str()) {
+                    matched.extend(ids.iter().filter(|id| options.matches_id(id)).cloned());
+                }
+            }
+        }
+        let results = options.combine_matches(per_prefix);
+
+        let mut results: Vec<String> = results.into_iter().collect();
+        results.sort();
+        Ok(options.page(results))
+    }
+
+    /// Searches for documents containing a token within `max_distance` edits of a token of the
+    /// query, i.e. `helo` finds `hello` at a distance of 1. The first character of a query token
+    /// is taken as typed, so candidates are the tokens starting with it.
+    ///
+    /// Ids are ranked by how many query tokens they match, then by how close the matches are.
+    /// Goes through every token of the collection unless the engine keeps its tokens sorted, only
+    /// copying out those close enough to a query token.
+    fn search_fuzzy(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        max_distance: usize,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, StorageError> {
+        let options = &options.with_default_limit(self.max_results());
+        let query_tokens = lang::query::tokenize_query(query, &options.tokenizer);
+        let index = self.collection_index_matching(bucket_name, collection_name, &|token| {
+            query_tokens.iter().any(|query_token| {
+                query_token.chars().next() == token.chars().next()
+                    && lang::edit_distance(query_token, token, max_distance).is_some()
+            })
+        })?;
+        Ok(fuzzy_matches(query, max_distance, options, |first| {
+            index
+                .iter()
+                .filter(move |(token, _)| token.starts_with(first))
+                .map(|(token, ids)| (token.as_str(), ids))
+        }))
+    }
+
     fn remove_from_index(
         &self,
         storage: &dyn StorageOperations,
@@ -59,6 +329,61 @@ pub trait SearchEngine {
         id: &str,
     ) -> Result<(), StorageError>;
 
+    /// Removes every index entry of the collection, without looking documents up in storage.
+    fn clear_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError>;
+
+    /// Removes every index entry of every collection of the bucket.
+    fn clear_bucket(&self, bucket_name: &str) -> Result<(), StorageError>;
+
+    /// Ids of the documents indexed under the token, sorted, empty if the token isn't indexed in
+    /// the collection. Goes through the whole [`collection_index`](Self::collection_index) unless
+    /// the engine can look the token up.
+    fn ids_for_token(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        token: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        let mut ids: Vec<String> = self
+            .collection_index(bucket_name, collection_name)?
+            .remove(token)
+            .into_iter()
+            .flatten()
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Returns every index entry of the collection, empty if nothing was indexed in it.
+    fn collection_index(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<CollectionIndex, StorageError> {
+        self.collection_index_matching(bucket_name, collection_name, &|_| true)
+    }
+
+    /// Returns the index entries of the collection whose token `matches`, which is called under
+    /// the lock of the index so that only those entries are copied out of it.
+    fn collection_index_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<CollectionIndex, StorageError>;
+
+    /// Number of distinct tokens indexed, counted once per collection they are indexed in.
+    fn token_count(&self) -> Result<usize, StorageError>;
+
+    /// Splits content into the tokens this engine indexes it under.
+    fn tokenize(&self, content: &str, options: &TokenizerOptions) -> Vec<String> {
+        lang::tokenize_with(content, options)
+    }
+
     fn batch_index(
         &self,
         storage: &dyn StorageOperations,
@@ -73,3 +398,127 @@ pub trait SearchEngine {
         Ok(())
     }
 }
+
+/// Indexes every document of a stored collection, returning how many there were.
+fn index_documents<E: SearchEngine + ?Sized>(
+    engine: &E,
+    storage: &dyn StorageOperations,
+    bucket_name: &str,
+    collection_name: &str,
+    collection: &DashMap<String, StoredValue>,
+) -> Result<usize, StorageError> {
+    for document_ref in collection.iter() {
+        engine.index(
+            storage,
+            bucket_name,
+            collection_name,
+            document_ref.key(),
+            &document_ref.value().content,
+        )?;
+    }
+    Ok(collection.len())
+}
+
+/// The `n` ids found the most times, most found first and ties broken by id.
+///
+/// Keeps a heap of the `n` best ids seen so far rather than sorting every match, so a token
+/// shared by most documents costs no more than `n` entries on top of the counts.
+fn top_ids(found_ids: HashMap<&str, usize>, n: usize) -> Vec<String> {
+    // min-heap of the best ids, its top is the first one to give up its place
+    let capacity = n.min(found_ids.len()).saturating_add(1);
+    let mut top: BinaryHeap<Reverse<(usize, Reverse<&str>)>> = BinaryHeap::with_capacity(capacity);
+    for (id, count) in found_ids {
+        top.push(Reverse((count, Reverse(id))));
+        if top.len() > n {
+            top.pop();
+        }
+    }
+
+    top.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((_, Reverse(id)))| id.to_string())
+        .collect()
+}
+
+/// Ranked page of a fuzzy search, see [`SearchEngine::search_fuzzy`], given the indexed tokens
+/// starting with a character along with the ids of the documents containing them.
+fn fuzzy_matches<'a, I>(
+    query: &str,
+    max_distance: usize,
+    options: &SearchOptions,
+    candidates: impl Fn(char) -> I,
+) -> Vec<String>
+where
+    I: Iterator<Item = (&'a str, &'a HashSet<String>)>,
+{
+    // distance of the closest token each id matches, per query token
+    let mut per_token: Vec<HashMap<&str, usize>> = Vec::new();
+    for query_token in lang::query::tokenize_query(query, &options.tokenizer) {
+        let Some(first) = query_token.chars().next() else {
+            continue;
+        };
+        let mut matched: Vec<(usize, &str, &HashSet<String>)> = candidates(first)
+            .filter_map(|(token, ids)| {
+                let distance = lang::edit_distance(&query_token, token, max_distance)?;
+                Some((distance, token, ids))
+            })
+            .collect();
+        matched.sort_unstable_by_key(|&(distance, token, _)| (distance, token));
+        matched.truncate(FUZZY_CANDIDATES);
+
+        let mut distances = HashMap::new();
+        for (distance, _, ids) in matched {
+            for id in ids.iter().filter(|id| options.matches_id(id)) {
+                let closest = distances.entry(id.as_str()).or_insert(distance);
+                *closest = distance.min(*closest);
+            }
+        }
+        per_token.push(distances);
+    }
+
+    let matched = options.combine_matches(
+        per_token
+            .iter()
+            .map(|distances| distances.keys().map(|id| id.to_string()).collect()),
+    );
+    let mut ranked: Vec<(usize, usize, String)> = matched
+        .into_iter()
+        .map(|id| {
+            let distances = per_token
+                .iter()
+                .filter_map(|distances| distances.get(id.as_str()));
+            let (count, total) = distances.fold((0, 0), |(count, total), distance| {
+                (count + 1, total + distance)
+            });
+            (count, total, id)
+        })
+        .collect();
+    ranked.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+    options.page(ranked.into_iter().map(|(_, _, id)| id))
+}
+
+/// [`SearchEngine::batch_index`] spread over the rayon thread pool, for engines whose index takes
+/// concurrent inserts.
+///
+/// Of several documents under the same id, only the last one is indexed, as when they are indexed
+/// one after the other.
+fn batch_index_parallel(
+    engine: &(impl SearchEngine + Sync),
+    storage: &dyn StorageOperations,
+    bucket_name: &str,
+    collection_name: &str,
+    docs: Vec<(String, String)>,
+) -> Result<(), StorageError> {
+    let last: HashMap<&str, usize> = docs
+        .iter()
+        .enumerate()
+        .map(|(position, (id, _))| (id.as_str(), position))
+        .collect();
+
+    docs.par_iter()
+        .enumerate()
+        .filter(|(position, (id, _))| last[id.as_str()] == *position)
+        .try_for_each(|(_, (id, content))| {
+            engine.index(storage, bucket_name, collection_name, id, content)
+        })
+}
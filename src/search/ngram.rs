@@ -0,0 +1,405 @@
+use super::{CollectionIndex, SearchEngine, SearchOptions, DEFAULT_LIMIT};
+use crate::storage::{EntityType, StorageOperations};
+use crate::{lang, storage::StorageError};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+/// Default length of the n-grams tokens are split into.
+pub const DEFAULT_N: usize = 3;
+
+/// N-grams and tokens of a single collection.
+#[derive(Default)]
+struct NgramCollection {
+    /// N-gram to the ids of the documents with a token containing it
+    grams: HashMap<String, HashSet<String>>,
+    /// Document id to its distinct tokens, to tell actual matches from documents merely holding
+    /// every n-gram of the query token
+    tokens: HashMap<String, HashSet<String>>,
+}
+
+impl NgramCollection {
+    fn insert(&mut self, id: &str, tokens: Vec<String>, n: usize) {
+        let tokens: HashSet<String> = tokens.into_iter().collect();
+        for gram in tokens.iter().flat_map(|token| lang::ngrams(token, n)) {
+            self.grams.entry(gram).or_default().insert(id.to_string());
+        }
+        self.tokens.insert(id.to_string(), tokens);
+    }
+
+    fn remove(&mut self, id: &str, n: usize) {
+        let Some(tokens) = self.tokens.remove(id) else {
+            return;
+        };
+
+        for gram in tokens.iter().flat_map(|token| lang::ngrams(token, n)) {
+            if let Some(ids) = self.grams.get_mut(&gram) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.grams.remove(&gram);
+                }
+            }
+        }
+    }
+
+    /// Ids of the documents with a token for which `matches` holds, given the query token.
+    ///
+    /// Only documents holding every n-gram of the query token are checked, unless it is too short
+    /// to have any.
+    fn matching(
+        &self,
+        query_token: &str,
+        n: usize,
+        options: &SearchOptions,
+        matches: fn(&str, &str) -> bool,
+    ) -> HashSet<String> {
+        let candidates: Vec<&String> = if query_token.chars().count() < n {
+            self.tokens.keys().collect()
+        } else {
+            let postings: Option<Vec<&HashSet<String>>> = lang::ngrams(query_token, n)
+                .iter()
+                .map(|gram| self.grams.get(gram))
+                .collect();
+            let Some(mut postings) = postings else {
+                return HashSet::new();
+            };
+            postings.sort_by_key(|ids| ids.len());
+            let (rarest, others) = postings.split_first().expect("a token has n-grams");
+            rarest
+                .iter()
+                .filter(|id| others.iter().all(|ids| ids.contains(*id)))
+                .collect()
+        };
+
+        candidates
+            .into_iter()
+            .filter(|id| options.matches_id(id))
+            .filter(|id| {
+                self.tokens[*id]
+                    .iter()
+                    .any(|token| matches(token, query_token))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+// Index is a map of buckets, each containing a map of collections and their n-grams.
+type IndexStore = RwLock<HashMap<String, HashMap<String, NgramCollection>>>;
+
+/// Finds documents with a token containing each query token rather than equal to it, i.e. `app`
+/// finds "application", at the cost of indexing every n-gram of every token.
+///
+/// Ids are ranked by how many query tokens their document matches.
+pub struct NgramSearchEngine {
+    index: IndexStore,
+    n: usize,
+    max_results: usize,
+}
+
+impl NgramSearchEngine {
+    pub fn new() -> Self {
+        Self::with_size(DEFAULT_N, DEFAULT_LIMIT)
+    }
+
+    /// Splits tokens into n-grams of `n` characters. Shorter n-grams find more candidates for each
+    /// query token, longer ones make the index larger. Returns at most `max_results` ids from a
+    /// search that sets no limit.
+    pub fn with_size(n: usize, max_results: usize) -> Self {
+        Self {
+            index: RwLock::new(HashMap::new()),
+            n: n.max(1),
+            max_results,
+        }
+    }
+
+    fn search_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        options: &SearchOptions,
+        matches: fn(&str, &str) -> bool,
+    ) -> Result<Vec<String>, StorageError> {
+        let options = &options.with_default_limit(self.max_results);
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        let bucket = index
+            .get(bucket_name)
+            .ok_or(StorageError::NotFound(EntityType::Bucket))?;
+        let collection = bucket
+            .get(collection_name)
+            .ok_or(StorageError::NotFound(EntityType::Collection))?;
+
+        let per_token: Vec<HashSet<String>> =
+            lang::query::tokenize_query(query, &options.tokenizer)
+                .iter()
+                .map(|token| collection.matching(token, self.n, options, matches))
+                .collect();
+        let results = options.combine_matches(per_token.iter().cloned());
+
+        let mut ranked: Vec<(usize, String)> = results
+            .into_iter()
+            .map(|id| (per_token.iter().filter(|ids| ids.contains(&id)).count(), id))
+            .collect();
+        ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        Ok(options.page(ranked.into_iter().map(|(_, id)| id)))
+    }
+}
+
+impl Default for NgramSearchEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SearchEngine for NgramSearchEngine {
+    fn max_results(&self) -> usize {
+        self.max_results
+    }
+
+    fn set_max_results(&mut self, max_results: usize) {
+        self.max_results = max_results;
+    }
+
+    fn index(
+        &self,
+        storage: &dyn StorageOperations,
+        bucket_name: &str,
+        collection_name: &str,
+        id: &str,
+        content: &str,
+    ) -> Result<(), StorageError> {
+        let config = storage.collection_config(bucket_name, collection_name);
+        let mut tokens = self.tokenize(content, &config.tokenizer());
+
+        let mut index = self.index.write().map_err(|_| StorageError::PoisonError)?;
+        let collection = index
+            .entry(bucket_name.to_string())
+            .or_default()
+            .entry(collection_name.to_string())
+            .or_default();
+        collection.remove(id, self.n);
+
+        if tokens.len() < config.min_tokens {
+            return Ok(());
+        }
+        config.cap_tokens(&mut tokens);
+        collection.insert(id, tokens, self.n);
+
+        Ok(())
+    }
+
+    fn search_with_options(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, StorageError> {
+        self.search_matching(
+            bucket_name,
+            collection_name,
+            query,
+            options,
+            |token, query| token.contains(query),
+        )
+    }
+
+    /// Looks prefixes up through their n-grams like any other query token, rather than going
+    /// through the whole collection.
+    fn search_prefix(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, StorageError> {
+        self.search_matching(
+            bucket_name,
+            collection_name,
+            query,
+            options,
+            |token, prefix| token.starts_with(prefix),
+        )
+    }
+
+    fn remove_from_index(
+        &self,
+        _storage: &dyn StorageOperations,
+        bucket_name: &str,
+        collection_name: &str,
+        id: &str,
+    ) -> Result<(), StorageError> {
+        let mut index = self.index.write().map_err(|_| StorageError::PoisonError)?;
+        if let Some(collection) = index
+            .get_mut(bucket_name)
+            .and_then(|bucket| bucket.get_mut(collection_name))
+        {
+            collection.remove(id, self.n);
+        }
+
+        Ok(())
+    }
+
+    fn clear_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError> {
+        let mut index = self.index.write().map_err(|_| StorageError::PoisonError)?;
+        if let Some(bucket) = index.get_mut(bucket_name) {
+            bucket.remove(collection_name);
+            if bucket.is_empty() {
+                index.remove(bucket_name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn clear_bucket(&self, bucket_name: &str) -> Result<(), StorageError> {
+        self.index
+            .write()
+            .map_err(|_| StorageError::PoisonError)?
+            .remove(bucket_name);
+
+        Ok(())
+    }
+
+    fn token_count(&self) -> Result<usize, StorageError> {
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        Ok(index
+            .values()
+            .flat_map(HashMap::values)
+            .map(|collection| {
+                collection
+                    .tokens
+                    .values()
+                    .flatten()
+                    .collect::<HashSet<_>>()
+                    .len()
+            })
+            .sum())
+    }
+
+    /// Whole tokens rather than n-grams, like the index of any other engine.
+    fn collection_index_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<CollectionIndex, StorageError> {
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        let mut tokens = CollectionIndex::new();
+        if let Some(collection) = index
+            .get(bucket_name)
+            .and_then(|bucket| bucket.get(collection_name))
+        {
+            for (id, document_tokens) in &collection.tokens {
+                for token in document_tokens.iter().filter(|token| matches(token)) {
+                    tokens.entry(token.clone()).or_default().insert(id.clone());
+                }
+            }
+        }
+
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mock::MockStorage;
+
+    fn engine_with(documents: &[(&str, &str)]) -> NgramSearchEngine {
+        let engine = NgramSearchEngine::new();
+        let storage = MockStorage::new();
+        for (id, content) in documents {
+            engine
+                .index(&storage, "bucket", "collection", id, content)
+                .unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn test_partial_query_matches_longer_token() {
+        let engine = engine_with(&[
+            ("1", "An application server"),
+            ("2", "Apples and pears"),
+            ("3", "Nothing related"),
+        ]);
+
+        assert_eq!(
+            engine.search("bucket", "collection", "app").unwrap(),
+            ["1", "2"]
+        );
+        assert_eq!(
+            engine.search("bucket", "collection", "plicat").unwrap(),
+            ["1"]
+        );
+        // documents matching more query tokens rank first
+        assert_eq!(
+            engine.search("bucket", "collection", "ears app").unwrap(),
+            ["2", "1"]
+        );
+        // shorter than an n-gram, every token is checked
+        assert_eq!(
+            engine.search("bucket", "collection", "pp").unwrap(),
+            ["1", "2"]
+        );
+    }
+
+    #[test]
+    fn test_every_ngram_is_not_enough() {
+        // holds "app" and "ppl", but no token containing "appl"
+        let engine = engine_with(&[("1", "app ppl"), ("2", "apply")]);
+        assert_eq!(
+            engine.search("bucket", "collection", "appl").unwrap(),
+            ["2"]
+        );
+    }
+
+    #[test]
+    fn test_search_prefix() {
+        let engine = engine_with(&[("1", "application"), ("2", "happy")]);
+        let options = SearchOptions::default();
+        assert_eq!(
+            engine
+                .search_prefix("bucket", "collection", "app", &options)
+                .unwrap(),
+            ["1"]
+        );
+    }
+
+    #[test]
+    fn test_removals() {
+        let storage = MockStorage::new();
+        let engine = engine_with(&[("1", "application"), ("2", "apple")]);
+
+        engine
+            .index(&storage, "bucket", "collection", "1", "other")
+            .unwrap();
+        engine
+            .remove_from_index(&storage, "bucket", "collection", "2")
+            .unwrap();
+
+        assert!(engine
+            .search("bucket", "collection", "app")
+            .unwrap()
+            .is_empty());
+        let index = engine.index.read().unwrap();
+        let collection = &index["bucket"]["collection"];
+        let mut grams: Vec<&String> = collection.grams.keys().collect();
+        grams.sort();
+        assert_eq!(grams, ["her", "oth", "the"]);
+        drop(index);
+
+        assert_eq!(
+            engine.collection_index("bucket", "collection").unwrap(),
+            CollectionIndex::from([("other".to_string(), HashSet::from(["1".to_string()]))])
+        );
+    }
+}
@@ -1,14 +1,14 @@
-use super::SearchEngine;
+use super::{top_ids, CollectionIndex, SearchEngine, SearchOptions, DEFAULT_LIMIT};
 use crate::storage::{EntityType, StorageOperations};
 use crate::{lang, storage::StorageError};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex, RwLock},
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, RwLock},
 };
 
-// IndexStore is a map of buckets, each containing a map of collections, each containing a map of tokens (as keys) and a vector of document ids (as values)
+// IndexStore is a map of buckets, each containing a map of collections, each containing a map of tokens (as keys) and a set of document ids (as values)
 // The token is the single word.
-// The document id is the id of the document it belongs to.
+// The document id is the id of the document it belongs to, listed once however many times the token appears in it.
 //                        Bucket
 //                        |
 //                        Collection
@@ -16,16 +16,45 @@ use std::{
 //                        Token
 //                        |
 //                        Document IDs
-type IndexStore = RwLock<HashMap<String, HashMap<String, HashMap<String, Vec<String>>>>>;
+type IndexStore = RwLock<HashMap<String, HashMap<String, HashMap<String, BTreeSet<String>>>>>;
+
+/// Positions of each token within a document, counted in tokens.
+type TokenPositions = HashMap<String, Vec<usize>>;
+// Bucket, then collection, then document id, to the positions of its tokens
+type PositionStore = RwLock<HashMap<String, HashMap<String, HashMap<String, TokenPositions>>>>;
+
+/// Whether the tokens of the phrase appear next to each other, in order.
+fn contains_phrase(positions: &TokenPositions, phrase: &[String]) -> bool {
+    let Some(starts) = positions.get(&phrase[0]) else {
+        return false;
+    };
+    starts.iter().any(|start| {
+        phrase.iter().enumerate().skip(1).all(|(offset, token)| {
+            positions
+                .get(token)
+                .is_some_and(|positions| positions.contains(&(start + offset)))
+        })
+    })
+}
 
 pub struct StdSearchEngine {
     index: Arc<IndexStore>,
+    /// Kept for phrase queries, which need the order of the tokens and not only their presence
+    positions: PositionStore,
+    max_results: usize,
 }
 
 impl StdSearchEngine {
     pub fn new() -> Self {
+        Self::with_max_results(DEFAULT_LIMIT)
+    }
+
+    /// Returns at most `max_results` ids from a search that sets no limit.
+    pub fn with_max_results(max_results: usize) -> Self {
         Self {
             index: Arc::new(RwLock::new(HashMap::new())),
+            positions: RwLock::new(HashMap::new()),
+            max_results,
         }
     }
 
@@ -35,6 +64,14 @@ impl StdSearchEngine {
 }
 
 impl SearchEngine for StdSearchEngine {
+    fn max_results(&self) -> usize {
+        self.max_results
+    }
+
+    fn set_max_results(&mut self, max_results: usize) {
+        self.max_results = max_results;
+    }
+
     fn index(
         &self,
         storage: &dyn StorageOperations,
@@ -51,7 +88,26 @@ impl SearchEngine for StdSearchEngine {
             }
         }
 
-        let tokens = lang::tokenize(content);
+        let config = storage.collection_config(bucket_name, collection_name);
+        let mut tokens = self.tokenize(content, &config.tokenizer());
+
+        if tokens.len() < config.min_tokens {
+            return Ok(());
+        }
+        config.cap_tokens(&mut tokens);
+
+        let mut positions = TokenPositions::new();
+        for (position, token) in tokens.iter().enumerate() {
+            positions.entry(token.clone()).or_default().push(position);
+        }
+        self.positions
+            .write()
+            .map_err(|_| StorageError::PoisonError)?
+            .entry(bucket_name.to_string())
+            .or_default()
+            .entry(collection_name.to_string())
+            .or_default()
+            .insert(id.to_string(), positions);
 
         let mut bucket = self.index.write().map_err(|_| StorageError::PoisonError)?;
         let bucket = bucket
@@ -66,22 +122,20 @@ impl SearchEngine for StdSearchEngine {
         // and if the token is now empty, remove the token
 
         for token in tokens {
-            let ids = collection.entry(token).or_insert_with(Vec::new);
-            ids.push(id.to_string());
+            collection.entry(token).or_default().insert(id.to_string());
         }
 
         Ok(())
     }
 
-    fn search(
+    fn search_with_options(
         &self,
         bucket_name: &str,
         collection_name: &str,
         query: &str,
+        options: &SearchOptions,
     ) -> Result<Vec<String>, StorageError> {
-        // string, found times
-        let found_ids: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
-
+        let options = &options.with_default_limit(self.max_results);
         let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
         let bucket = index
             .get(bucket_name)
@@ -90,51 +144,68 @@ impl SearchEngine for StdSearchEngine {
             .get(collection_name)
             .ok_or(StorageError::NotFound(EntityType::Collection))?;
 
-        let tokens = lang::tokenize(query);
-
-        for token in tokens {
-            if let Some(ids) = collection.get(&token) {
-                for id in ids {
-                    *found_ids.lock().unwrap().entry(id.to_string()).or_insert(0) += 1;
+        let mut tokens = lang::query::tokenize_query(query, &options.tokenizer);
+        // a token repeated in the query counts once
+        let mut seen = HashSet::new();
+        tokens.retain(|token| seen.insert(token.clone()));
+
+        // id, query tokens it contains; ids are borrowed from the index, so nothing is copied per match
+        let mut found_ids: HashMap<&str, usize> = HashMap::new();
+        for token in &tokens {
+            if let Some(ids) = collection.get(token) {
+                for id in ids.iter().filter(|id| options.matches_id(id)) {
+                    *found_ids.entry(id.as_str()).or_insert(0) += 1;
                 }
             }
         }
 
-        // top 10
-        let found_ids = found_ids.lock().expect("Mutex poisoned");
-        let mut found_ids: Vec<(String, usize)> = found_ids.clone().into_iter().collect();
-        found_ids.sort_by_key(|k| k.1);
-        found_ids.reverse();
-        found_ids.truncate(10);
+        if options.match_all {
+            found_ids.retain(|_, count| *count == tokens.len());
+        }
 
-        let found_ids = found_ids.clone().into_iter().map(|(id, _)| id).collect();
+        let phrases = lang::query::quoted_phrases(query, &options.tokenizer);
+        if !phrases.is_empty() {
+            let positions = self
+                .positions
+                .read()
+                .map_err(|_| StorageError::PoisonError)?;
+            let documents = positions
+                .get(bucket_name)
+                .and_then(|bucket| bucket.get(collection_name));
+            found_ids.retain(|id, _| {
+                documents
+                    .and_then(|documents| documents.get(*id))
+                    .is_some_and(|positions| {
+                        phrases
+                            .iter()
+                            .all(|phrase| contains_phrase(positions, phrase))
+                    })
+            });
+        }
 
-        Ok(found_ids)
+        Ok(options.page(top_ids(found_ids, options.ranked_len())))
     }
 
+    /// Removes the id from the tokens it was indexed under, which its positions list: the config
+    /// of the collection may have changed since, tokenizing the content again could miss some.
     fn remove_from_index(
         &self,
-        storage: &dyn StorageOperations,
+        _storage: &dyn StorageOperations,
         bucket_name: &str,
         collection_name: &str,
         id: &str,
     ) -> Result<(), StorageError> {
-        // search all collection index entries (values) vectors for the id
-        // if found, remove the id. if this was the last id, remove the entry
-        // if not found, do nothing
-
-        let document = storage.get_document(bucket_name, collection_name, id);
-
-        if let Err(e) = document {
-            if e.is_not_found() {
-                return Ok(());
-            }
-            return Err(e);
-        }
-
-        let document = document.unwrap();
-
-        let tokens = lang::tokenize(&document.content);
+        let Some(positions) = self
+            .positions
+            .write()
+            .map_err(|_| StorageError::PoisonError)?
+            .get_mut(bucket_name)
+            .and_then(|bucket| bucket.get_mut(collection_name))
+            .and_then(|documents| documents.remove(id))
+        else {
+            // not indexed, i.e. with fewer tokens than the collection requires
+            return Ok(());
+        };
 
         let mut bucket = self.index.write().map_err(|_| StorageError::PoisonError)?;
         let bucket = bucket
@@ -144,9 +215,9 @@ impl SearchEngine for StdSearchEngine {
             .get_mut(collection_name)
             .ok_or(StorageError::NotFound(EntityType::Collection))?;
 
-        for token in tokens {
+        for token in positions.into_keys() {
             if let Some(ids) = collection.get_mut(&token) {
-                ids.retain(|id| id != &id.to_string());
+                ids.remove(id);
 
                 if ids.is_empty() {
                     collection.remove(&token);
@@ -156,6 +227,90 @@ impl SearchEngine for StdSearchEngine {
 
         Ok(())
     }
+
+    fn token_count(&self) -> Result<usize, StorageError> {
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        Ok(index
+            .values()
+            .flat_map(HashMap::values)
+            .map(HashMap::len)
+            .sum())
+    }
+
+    fn ids_for_token(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        token: &str,
+    ) -> Result<Vec<String>, StorageError> {
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        Ok(index
+            .get(bucket_name)
+            .and_then(|bucket| bucket.get(collection_name))
+            .and_then(|collection| collection.get(token))
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect())
+    }
+
+    fn collection_index_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<CollectionIndex, StorageError> {
+        let index = self.index.read().map_err(|_| StorageError::PoisonError)?;
+        let collection = index
+            .get(bucket_name)
+            .and_then(|bucket| bucket.get(collection_name));
+
+        Ok(collection
+            .into_iter()
+            .flatten()
+            .filter(|(token, _)| matches(token))
+            .map(|(token, ids)| (token.clone(), ids.iter().cloned().collect()))
+            .collect())
+    }
+
+    fn clear_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError> {
+        let mut index = self.index.write().map_err(|_| StorageError::PoisonError)?;
+        if let Some(bucket) = index.get_mut(bucket_name) {
+            bucket.remove(collection_name);
+            if bucket.is_empty() {
+                index.remove(bucket_name);
+            }
+        }
+        let mut positions = self
+            .positions
+            .write()
+            .map_err(|_| StorageError::PoisonError)?;
+        if let Some(bucket) = positions.get_mut(bucket_name) {
+            bucket.remove(collection_name);
+            if bucket.is_empty() {
+                positions.remove(bucket_name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn clear_bucket(&self, bucket_name: &str) -> Result<(), StorageError> {
+        self.index
+            .write()
+            .map_err(|_| StorageError::PoisonError)?
+            .remove(bucket_name);
+        self.positions
+            .write()
+            .map_err(|_| StorageError::PoisonError)?
+            .remove(bucket_name);
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -239,6 +394,39 @@ mod tests {
         assert_eq!(collection.len(), 3);
     }
 
+    #[test]
+    fn test_phrase_search() {
+        let engine = StdSearchEngine::new();
+        let storage = MockStorage::new();
+        for (id, content) in [
+            ("ordered", "they said hello world today"),
+            ("reversed", "the world said hello"),
+            ("apart", "hello there world"),
+        ] {
+            engine
+                .index(&storage, "bucket", "collection", id, content)
+                .unwrap();
+        }
+
+        let search = |query: &str| {
+            let mut results = engine.search("bucket", "collection", query).unwrap();
+            results.sort();
+            results
+        };
+        assert_eq!(search("\"hello world\""), ["ordered"]);
+        assert_eq!(search("\"world hello\""), Vec::<String>::new());
+        assert_eq!(search("\"world said\""), ["reversed"]);
+        // unquoted, word order does not matter
+        assert_eq!(search("world hello"), ["apart", "ordered", "reversed"]);
+        // every phrase must match, words outside them only rank
+        assert_eq!(search("\"said hello\" \"hello world\" there"), ["ordered"]);
+
+        engine
+            .index(&storage, "bucket", "collection", "ordered", "goodbye world")
+            .unwrap();
+        assert_eq!(search("\"hello world\""), Vec::<String>::new());
+    }
+
     #[test]
     fn test_index_single_document() {
         let storage = MockStorage::new();
@@ -315,4 +503,171 @@ mod tests {
         );
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_search_id_prefix() {
+        let engine = StdSearchEngine::new();
+        let storage = MockStorage::new();
+        let bucket_name = "test_bucket";
+        let collection_name = "test_collection";
+
+        // more non-matching documents than fit into the result limit
+        for i in 0..12 {
+            engine
+                .index(
+                    &storage,
+                    bucket_name,
+                    collection_name,
+                    &format!("user:2:post:{i}"),
+                    "shared content",
+                )
+                .unwrap();
+        }
+        for id in ["user:1:post:1", "user:1:post:2"] {
+            engine
+                .index(&storage, bucket_name, collection_name, id, "shared")
+                .unwrap();
+        }
+
+        let options = SearchOptions {
+            id_prefix: Some("user:1:".to_string()),
+            ..Default::default()
+        };
+        let mut results = engine
+            .search_with_options(bucket_name, collection_name, "shared content", &options)
+            .unwrap();
+        results.sort();
+        assert_eq!(results, ["user:1:post:1", "user:1:post:2"]);
+
+        let options = SearchOptions {
+            id_prefix: Some("user:3:".to_string()),
+            ..Default::default()
+        };
+        let results = engine
+            .search_with_options(bucket_name, collection_name, "shared", &options)
+            .unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn test_clear_collection() {
+        let engine = StdSearchEngine::new();
+        let storage = MockStorage::new();
+        let bucket_name = "test_bucket";
+
+        for collection_name in ["cleared", "sibling"] {
+            engine
+                .index(
+                    &storage,
+                    bucket_name,
+                    collection_name,
+                    "doc",
+                    "shared content",
+                )
+                .unwrap();
+        }
+
+        engine.clear_collection(bucket_name, "cleared").unwrap();
+
+        assert_eq!(
+            engine.search(bucket_name, "cleared", "shared content"),
+            Err(StorageError::NotFound(EntityType::Collection))
+        );
+        assert_eq!(
+            engine.search(bucket_name, "sibling", "shared").unwrap(),
+            ["doc"]
+        );
+
+        // clearing a missing collection is a no-op
+        assert!(engine.clear_collection(bucket_name, "missing").is_ok());
+        assert!(engine.clear_collection("missing", "cleared").is_ok());
+    }
+
+    #[test]
+    fn test_search_common_token_returns_top_ids() {
+        let engine = StdSearchEngine::new();
+        let storage = MockStorage::new();
+        for i in 0..1000 {
+            let content = format!("common word{} group{}", i % 7, i % 3);
+            engine
+                .index(&storage, "bucket", "collection", &i.to_string(), &content)
+                .unwrap();
+        }
+
+        // sorting every match, the way results were selected before
+        let reference = |query: &str| {
+            let index = engine.index.read().unwrap();
+            let collection = &index["bucket"]["collection"];
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for token in query.split_whitespace() {
+                for id in collection.get(token).into_iter().flatten() {
+                    *counts.entry(id.clone()).or_insert(0) += 1;
+                }
+            }
+            let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            counts.truncate(DEFAULT_LIMIT);
+            counts.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+        };
+
+        for query in ["common", "common word3", "word3 group1 common", "missing"] {
+            let results = engine.search("bucket", "collection", query).unwrap();
+            assert_eq!(results, reference(query), "{}", query);
+        }
+
+        // documents matching every token come first
+        let results = engine
+            .search("bucket", "collection", "word3 group1 common")
+            .unwrap();
+        assert_eq!(results.len(), DEFAULT_LIMIT);
+        assert!(results
+            .iter()
+            .all(|id| id.parse::<usize>().unwrap() % 21 == 10));
+    }
+
+    #[test]
+    fn test_repeated_token_lists_document_once() {
+        let engine = StdSearchEngine::new();
+        let storage = MockStorage::new();
+        for (id, content) in [
+            ("a", "apple apple apple apple"),
+            ("b", "apple banana"),
+            ("c", "apple"),
+        ] {
+            engine
+                .index(&storage, "bucket", "collection", id, content)
+                .unwrap();
+        }
+
+        assert_eq!(
+            engine
+                .ids_for_token("bucket", "collection", "apple")
+                .unwrap(),
+            ["a", "b", "c"]
+        );
+        // repeating a word does not outrank matching another one, equal matches are ordered by id
+        assert_eq!(
+            engine
+                .search("bucket", "collection", "apple banana")
+                .unwrap(),
+            ["b", "a", "c"]
+        );
+        // nor does repeating it in the query
+        assert_eq!(
+            engine
+                .search("bucket", "collection", "apple apple banana")
+                .unwrap(),
+            ["b", "a", "c"]
+        );
+        let options = SearchOptions {
+            match_all: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            engine
+                .search_with_options("bucket", "collection", "apple apple", &options)
+                .unwrap(),
+            ["a", "b", "c"]
+        );
+    }
 }
@@ -1,179 +1,285 @@
-use std::sync::Arc;
-
+use super::frame::{FrameError, FrameReader};
 use super::handler::handle_request;
-use crate::encryption::MockEncryptor;
+use super::Shared;
 use crate::protocol::{Message, Request, Response};
-use crate::search::StdSearchEngine;
-use crate::storage::Storage;
-use std::sync::RwLock as SyncRwLock;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::RwLock as AsyncRwLock;
-use tokio::task;
-
-pub struct Connection {
-    stream: Arc<AsyncRwLock<TcpStream>>,
-    storage: Arc<SyncRwLock<Storage>>,
-    encryption: Arc<MockEncryptor>,
-    search_engine: Arc<SyncRwLock<StdSearchEngine>>,
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+
+/// Traffic of a single connection
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl ConnectionStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_read + self.bytes_written
+    }
+}
+
+/// Requests and responses of a single client, over a plain TCP stream unless another one is given,
+/// i.e. a TLS stream.
+pub struct Connection<S = TcpStream> {
+    stream: S,
+    shared: Shared,
+    frames: FrameReader,
+    stats: ConnectionStats,
+    /// Whether `AUTH` succeeded, only checked when the server has a password
+    authenticated: bool,
+    /// Selected by `USE`, fills in the bucket of requests leaving it as `_` over the configured one
+    bucket: Option<String>,
+    /// Set once the server shuts down, the connection closes instead of reading another request
+    shutdown: watch::Receiver<bool>,
 }
 
-impl Connection {
-    pub fn new(
-        stream: Arc<AsyncRwLock<TcpStream>>,
-        storage: Arc<SyncRwLock<Storage>>,
-        encryption: Arc<MockEncryptor>,
-        search_engine: Arc<SyncRwLock<StdSearchEngine>>,
-    ) -> Self {
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    pub fn new(stream: S, shared: Shared, shutdown: watch::Receiver<bool>) -> Self {
         Self {
             stream,
-            storage,
-            encryption,
-            search_engine,
+            shared,
+            frames: FrameReader::default(),
+            stats: ConnectionStats::default(),
+            authenticated: false,
+            bucket: None,
+            shutdown,
         }
     }
 
+    /// Reads a request, handles it and writes its response, one at a time until the client
+    /// leaves, the server shuts down or a limit is hit. I/O errors end the connection and are
+    /// returned.
     pub async fn handle(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         loop {
-            let stream_clone = self.stream.clone();
-            let storage_clone = self.storage.clone();
-            let encryption_clone = self.encryption.clone();
-            let search_engine_clone = self.search_engine.clone();
-
-            // TODO: double spawn?
-            let handle = task::spawn(async move {
-                let mut buffer = Vec::new();
-                let mut stream = stream_clone.write().await;
-                let mut reader = tokio::io::BufReader::new(&mut *stream);
-                if let Err(e) = reader.read_until(b'\n', &mut buffer).await {
-                    eprintln!("Error reading from stream: {}", e);
-                    return;
-                }
-                drop(stream);
-
-                let req_str = String::from_utf8_lossy(&buffer);
-                #[cfg(debug_assertions)]
-                println!("Received request: {}", req_str);
-
-                let request = match Request::from_bytes(&buffer) {
-                    Ok(req) => req,
-                    Err(e) => {
-                        eprintln!("Error parsing request: {}", e);
-                        let response = Response::from_decoding_error(e);
-                        let mut stream = stream_clone.write().await;
-                        if let Err(e) = stream.write_all(&response.to_bytes()).await {
-                            eprintln!("Error writing response: {}", e);
-                        }
-                        return;
-                    }
-                };
-
-                let response = match handle_request(
-                    request,
-                    &storage_clone,
-                    &*encryption_clone,
-                    &search_engine_clone,
+            let (read_timeout, max_request_bytes) = {
+                let config = self.shared.config.read().unwrap_or_else(|e| e.into_inner());
+                (
+                    config.read_timeout.unwrap_or(Duration::MAX),
+                    config.max_request_bytes,
                 )
-                .await
-                {
-                    Ok(resp) => resp,
-                    Err(e) => {
-                        eprintln!("Error handling request: {}", e);
-                        Response::from_handle_error(e)
+            };
+            let read = self.frames.read_frame(&mut self.stream, max_request_bytes);
+            // a request being read is dropped on shutdown, one already read is handled
+            let frame = tokio::select! {
+                frame = time::timeout(read_timeout, read) => match frame {
+                    Ok(Ok(frame)) => frame,
+                    Ok(Err(FrameError::TooLarge(max_len))) => {
+                        tracing::warn!("Closing connection sending a request over {} bytes", max_len);
+                        let response = Response::Error("request too large".to_string());
+                        self.stream.write_all(&response.to_bytes()).await?;
+                        linger(&mut self.stream).await;
+                        None
+                    }
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) => {
+                        tracing::info!("Closing connection idle for {:?}", read_timeout);
+                        None
                     }
-                };
+                },
+                _ = shutdown_requested(&mut self.shutdown) => None,
+            };
+            // the client closed the connection between requests
+            let Some(buffer) = frame else {
+                break;
+            };
+
+            // a request taking the connection past its cap is not handled at all
+            self.stats.bytes_read = self.frames.received();
+            let max_connection_bytes = self
+                .shared
+                .config
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .max_connection_bytes;
+            if let Some(cap) = max_connection_bytes
+                && self.stats.total_bytes() > cap
+            {
+                tracing::warn!("Closing connection over its cap of {} bytes", cap);
+                let response = Response::Error("connection bandwidth cap exceeded".to_string());
+                self.stats.bytes_written += write_response(&mut self.stream, response).await?;
+                linger(&mut self.stream).await;
+                break;
+            }
 
-                #[cfg(debug_assertions)]
-                println!(
-                    "Sending response: {}",
-                    String::from_utf8_lossy(&response.to_bytes())
-                );
+            let response = self.respond(&buffer).await;
+            tracing::trace!("Sending response: {:?}", response);
+            self.stats.bytes_written += write_response(&mut self.stream, response).await?;
+        }
 
-                let mut stream = stream_clone.write().await;
-                if let Err(e) = stream.write_all(&response.to_bytes()).await {
-                    eprintln!("Error writing response: {}", e);
-                }
-            });
+        Ok(())
+    }
 
-            // Await the task to ensure any errors are propagated
-            handle.await?;
+    /// Parses and handles a request, turning any error into the response to send.
+    async fn respond(&mut self, buffer: &[u8]) -> Response {
+        // arguments are left out, they may hold a password or an encryption key
+        let command = buffer
+            .split(u8::is_ascii_whitespace)
+            .next()
+            .unwrap_or_default();
+        tracing::trace!(
+            "Received request: {} of {} bytes",
+            String::from_utf8_lossy(command),
+            buffer.len()
+        );
+
+        let (parse_mode, password_required) = {
+            let config = self.shared.config.read().unwrap_or_else(|e| e.into_inner());
+            (config.parse_mode, config.password.is_some())
+        };
+        let mut request = match Request::from_bytes_with_mode(buffer, parse_mode) {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::debug!("Error parsing request: {}", e);
+                return Response::from_decoding_error(e);
+            }
+        };
 
-            // Break the loop if needed (e.g., client disconnects)
-            if self.stream.read().await.peek(&mut [0; 1]).await? == 0 {
-                break;
+        let is_auth = matches!(request, Request::Auth { .. });
+        // probes of liveness and readiness need no password, they neither read nor change data
+        let exempt = is_auth || matches!(request, Request::Ping | Request::Health);
+        if password_required && !self.authenticated && !exempt {
+            return Response::Error("NOAUTH".to_string());
+        }
+        let used = match &request {
+            Request::Use { bucket } => Some(bucket.clone()),
+            _ => None,
+        };
+        if let Some(default) = &self.bucket
+            && let Some(bucket) = request.bucket_mut()
+            && bucket.is_empty()
+        {
+            bucket.clone_from(default);
+        }
+        let shared = &self.shared;
+        match handle_request(
+            request,
+            &shared.storage,
+            &*shared.encryption,
+            &shared.search_engine,
+            &shared.config,
+            shared.indexer.as_deref(),
+            &shared.status,
+        )
+        .await
+        {
+            Ok(response) => {
+                self.authenticated |= is_auth;
+                if let Some(bucket) = used {
+                    self.bucket = Some(bucket).filter(|bucket| !bucket.is_empty());
+                }
+                response
             }
+            Err(e) => Response::from_handle_error(e),
         }
+    }
+}
 
-        Ok(())
+/// Time the rest of a rejected request is discarded for before its connection closes.
+const LINGER: Duration = Duration::from_secs(1);
+
+/// Closes the sending side, then discards what the client still sends for a moment. Closing a
+/// connection with unread data resets it, which may drop the reply before the client reads it.
+async fn linger(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) {
+    let _ = stream.shutdown().await;
+    let mut discarded = [0; 4096];
+    let _ = time::timeout(LINGER, async {
+        while matches!(stream.read(&mut discarded).await, Ok(read) if read > 0) {}
+    })
+    .await;
+}
+
+/// Resolves once the server shuts down, never if it is gone without doing so.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|&stop| stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Writes the response piece by piece, so streamed items are produced only as they are sent.
+/// Returns the number of bytes written.
+async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    response: Response,
+) -> std::io::Result<u64> {
+    let mut written = 0;
+    for chunk in response.into_chunks() {
+        writer.write_all(&chunk).await?;
+        written += chunk.len() as u64;
     }
+    Ok(written)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::{Message, Request, Response};
+    use crate::client::{read_response, DEFAULT_MAX_RESPONSE_BYTES};
+    use crate::config::ZzapConfig;
+    use crate::encryption::MockEncryptor;
+    use crate::protocol::{Message, Request, Response, ResponseStream};
+    use crate::search::{DynSearchEngine, StdSearchEngine};
+    use crate::storage::{Document, Storage, StorageOperations};
     use std::net::SocketAddr;
-    use tokio::io::AsyncReadExt;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::sync::RwLock as SyncRwLock;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
     use tokio::net::TcpListener;
-    use tokio::time::{sleep, Duration};
+    use tokio::task::JoinHandle;
+    use tokio::time::sleep;
 
     const DEFAULT_STORAGE_PATH: &str = "test.db";
 
     async fn setup_server() -> SocketAddr {
+        setup_server_with_config(ZzapConfig::default()).await
+    }
+
+    async fn setup_server_with_config(config: ZzapConfig) -> SocketAddr {
+        spawn_server(config).await.0
+    }
+
+    /// What a connection of a server with the given configuration shares with the others.
+    fn shared(storage: Arc<Storage>, config: ZzapConfig) -> Shared {
+        let search_engine: Arc<SyncRwLock<DynSearchEngine>> =
+            Arc::new(SyncRwLock::new(Box::new(StdSearchEngine::new())));
+        Shared {
+            storage,
+            encryption: Arc::new(MockEncryptor),
+            search_engine,
+            config: Arc::new(SyncRwLock::new(config)),
+            indexer: None,
+            status: Default::default(),
+        }
+    }
+
+    /// Serves a single connection, exposing its storage and the task handling it.
+    async fn spawn_server(config: ZzapConfig) -> (SocketAddr, Arc<Storage>, JoinHandle<()>) {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
 
-        let storage = Arc::new(SyncRwLock::new(Storage::new(DEFAULT_STORAGE_PATH)));
-        let encryption = Arc::new(MockEncryptor);
-        let search_engine = Arc::new(SyncRwLock::new(StdSearchEngine::new()));
-
-        tokio::spawn(async move {
+        let storage = Arc::new(Storage::new(DEFAULT_STORAGE_PATH));
+        let shared = shared(storage.clone(), config);
+        let handle = tokio::spawn(async move {
             let (stream, _) = listener.accept().await.unwrap();
-            let stream = Arc::new(AsyncRwLock::new(stream));
-            let mut connection = Connection::new(stream, storage, encryption, search_engine);
+            let mut connection = Connection::new(stream, shared, watch::channel(false).1);
             connection.handle().await.unwrap();
         });
 
-        addr
+        (addr, storage, handle)
     }
 
-    // TODO: change to common function to read response from stream
     async fn command(stream: &mut TcpStream, command: Request, expected: Response) {
         stream.write_all(&command.to_bytes()).await.unwrap();
-        let mut buffer = Vec::new();
         let mut reader = tokio::io::BufReader::new(stream);
-        reader.read_until(b'\n', &mut buffer).await.unwrap();
-        let response = String::from_utf8(buffer).unwrap();
-
-        // if response is number, parse it as int N and read N lines
-        if let Ok(n) = response.trim().parse::<usize>() {
-            let mut lines: Vec<String> = vec![response.clone()];
-            for _ in 0..n {
-                let mut buffer = Vec::new();
-                reader.read_until(b'\n', &mut buffer).await.unwrap();
-                lines.push(String::from_utf8(buffer).unwrap());
-            }
-            let response = format!("{}{}\n", response, lines.join(""));
-            let response = Response::from_bytes(&response.as_bytes()).unwrap();
-
-            assert_eq!(response, expected);
-
-            return;
-        }
-
-        // if response starts with $, then we need to continue reading until we get the full string
-        if response.starts_with("$") {
-            let start = response.clone();
-            let mut buffer = Vec::new();
-            reader.read_until(b'\n', &mut buffer).await.unwrap();
-            let response = String::from_utf8(buffer).unwrap();
-            let response = format!("{}{}\n", start, response);
-            let response = Response::from_bytes(&response.as_bytes()).unwrap();
-            assert_eq!(response, expected);
-            return;
-        }
-
-        let response = Response::from_bytes(&response.as_bytes()).unwrap();
+        let response = read_response(&mut reader, DEFAULT_MAX_RESPONSE_BYTES)
+            .await
+            .unwrap();
         assert_eq!(response, expected);
     }
 
@@ -198,6 +304,7 @@ mod tests {
             id: "first_record".into(),
             content: "value1".into(),
             key: None,
+            ttl: None,
         };
 
         command(&mut stream, set_request, Response::Success).await;
@@ -248,6 +355,7 @@ mod tests {
             id: "first_record".into(),
             content: large_value.clone(),
             key: None,
+            ttl: None,
         };
 
         command(&mut stream, set_request, Response::Success).await;
@@ -279,6 +387,7 @@ mod tests {
             id: "first_record".into(),
             content: "value1".into(),
             key: None,
+            ttl: None,
         };
         stream.write_all(&set_request.to_bytes()).await.unwrap();
 
@@ -293,12 +402,12 @@ mod tests {
 
     #[tokio::test]
     async fn test_client_disconnect_mid_command() {
-        let addr = setup_server().await;
+        let (addr, storage, handle) = spawn_server(ZzapConfig::default()).await;
 
         // Client
         let mut stream = TcpStream::connect(addr).await.unwrap();
 
-        let set_request = "SET b c fir";
+        let set_request = "SET b c 1 fir";
         stream.write_all(&set_request.as_bytes()).await.unwrap();
 
         // Do not read the response
@@ -306,8 +415,159 @@ mod tests {
         // Simulate unexpected client disconnect
         drop(stream);
 
-        // Give the server some time to process the disconnect
-        sleep(Duration::from_millis(100)).await;
+        // The connection closes cleanly without handling the partial request
+        handle.await.unwrap();
+        assert!(storage.get_document("b", "c", "1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect_between_commands() {
+        let (addr, _, handle) = spawn_server(ZzapConfig::default()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        command(&mut stream, Request::Ping, Response::Success).await;
+        drop(stream);
+
+        // The connection ends without an error
+        handle.await.unwrap();
+    }
+
+    /// Reads the given requests, then fails every write as a client gone mid-response would.
+    struct BrokenPipe(&'static [u8]);
+
+    impl AsyncRead for BrokenPipe {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let len = self.0.len().min(buf.remaining());
+            buf.put_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for BrokenPipe {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_error_ends_connection() {
+        let storage = Arc::new(Storage::new(DEFAULT_STORAGE_PATH));
+        let stream = BrokenPipe(b"SET b c 1 5:first\nSET b c 2 6:second\n");
+        let mut connection = Connection::new(
+            stream,
+            shared(storage.clone(), ZzapConfig::default()),
+            watch::channel(false).1,
+        );
+
+        let error = connection.handle().await.unwrap_err();
+        let error = error.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+        // the request whose response failed is handled, none is read after it
+        assert!(storage.get_document("b", "c", "1").is_ok());
+        assert!(storage.get_document("b", "c", "2").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_split_across_segments() {
+        let addr = setup_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        for part in ["SE", "T b c 1 ", "hello wo", "rld\n"] {
+            stream.write_all(part.as_bytes()).await.unwrap();
+            sleep(Duration::from_millis(20)).await;
+        }
+        let mut reader = tokio::io::BufReader::new(&mut stream);
+        let mut response = Vec::new();
+        reader.read_until(b'\n', &mut response).await.unwrap();
+        assert_eq!(response, b"+OK\n");
+
+        // a write holding a request and the start of the next one
+        stream.write_all(b"PING\nGET b").await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        stream.write_all(b" c 1\n").await.unwrap();
+        let mut reader = tokio::io::BufReader::new(&mut stream);
+        let mut response = Vec::new();
+        reader.read_until(b'\n', &mut response).await.unwrap();
+        assert_eq!(response, b"+OK\n");
+        let mut response = vec![0; "$11\nhello world\n".len()];
+        reader.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, b"$11\nhello world\n");
+    }
+
+    #[tokio::test]
+    async fn test_set_content_with_newlines() {
+        let addr = setup_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream
+            .write_all(b"SET b c i 11:Hello\nWorld\nGET b c i\n")
+            .await
+            .unwrap();
+        let expected = b"+OK\n$11\nHello\nWorld\n";
+        let mut reader = tokio::io::BufReader::new(&mut stream);
+        let mut responses = vec![0; expected.len()];
+        reader.read_exact(&mut responses).await.unwrap();
+        assert_eq!(responses, expected);
+    }
+
+    #[tokio::test]
+    async fn test_import_documents_over_several_lines() {
+        let addr = setup_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let documents = "{\"id\":\"1\",\"content\":\"one\"}\n{\"id\":\"2\",\"content\":\"two\"}";
+        let import = format!(
+            "IMPORT b c REPLACE {}:{}\nGET b c 2\n",
+            documents.len(),
+            documents
+        );
+        stream.write_all(import.as_bytes()).await.unwrap();
+        let expected = b":2\n$3\ntwo\n";
+        let mut reader = tokio::io::BufReader::new(&mut stream);
+        let mut responses = vec![0; expected.len()];
+        reader.read_exact(&mut responses).await.unwrap();
+        assert_eq!(responses, expected);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let addr = setup_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        stream.write_all(b"PING\nPING\n").await.unwrap();
+        let mut reader = tokio::io::BufReader::new(&mut stream);
+        let mut responses = vec![0; "+OK\n+OK\n".len()];
+        reader.read_exact(&mut responses).await.unwrap();
+        assert_eq!(responses, b"+OK\n+OK\n");
+
+        // later requests see the effect of earlier ones, responses come back in order
+        stream
+            .write_all(b"SET b c 1 5:first\nGET b c 1\nSET b c 1 6:second\nGET b c 1\n")
+            .await
+            .unwrap();
+        let expected = b"+OK\n$5\nfirst\n+OK\n$6\nsecond\n";
+        let mut reader = tokio::io::BufReader::new(&mut stream);
+        let mut responses = vec![0; expected.len()];
+        reader.read_exact(&mut responses).await.unwrap();
+        assert_eq!(responses, expected);
     }
 
     // tests passing error from handler
@@ -330,4 +590,270 @@ mod tests {
         )
         .await;
     }
+
+    #[tokio::test]
+    async fn test_bandwidth_cap_closes_connection() {
+        // every PING exchange moves 9 bytes: "PING\n" in and "+OK\n" out, so the fourth PING
+        // takes the connection to 32 bytes
+        let addr = setup_server_with_config(ZzapConfig {
+            max_connection_bytes: Some(30),
+            ..Default::default()
+        })
+        .await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (read_half, mut write_half) = stream.into_split();
+        let mut reader = tokio::io::BufReader::new(read_half);
+
+        let mut lines = Vec::new();
+        for _ in 0..4 {
+            write_half.write_all(b"PING\n").await.unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            lines.push(line);
+        }
+        assert_eq!(
+            lines,
+            [
+                "+OK\n",
+                "+OK\n",
+                "+OK\n",
+                "-ERR connection bandwidth cap exceeded\n"
+            ]
+        );
+
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_cap_refuses_request_before_handling_it() {
+        let (addr, storage, handle) = spawn_server(ZzapConfig {
+            max_connection_bytes: Some(30),
+            ..Default::default()
+        })
+        .await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let content = "a".repeat(40);
+        stream
+            .write_all(format!("SET b c 1 {}:{}\n", content.len(), content).as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"-ERR connection bandwidth cap exceeded\n");
+        handle.await.unwrap();
+        assert!(storage.get_document("b", "c", "1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_too_large_closes_connection() {
+        let config = || ZzapConfig {
+            max_request_bytes: Some(1024),
+            ..Default::default()
+        };
+        let expected = b"-ERR request too large\n";
+
+        let (addr, storage, handle) = spawn_server(config()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let content = "a".repeat(2048);
+        stream
+            .write_all(format!("SET b c 1 {}\n", content).as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, expected);
+        handle.await.unwrap();
+        assert!(storage.get_document("b", "c", "1").is_err());
+
+        // rejected on the declared length, before the content is sent
+        let (addr, _, handle) = spawn_server(config()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"SET b c 1 1000000:").await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, expected);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout_closes_idle_connection() {
+        let (addr, _, handle) = spawn_server(ZzapConfig {
+            read_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        })
+        .await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // a request answered in time does not close the connection
+        stream.write_all(b"PING\n").await.unwrap();
+        let mut response = vec![0; "+OK\n".len()];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, b"+OK\n");
+
+        // nothing more is sent, the server closes the connection on its own
+        let mut buffer = [0; 16];
+        let read = time::timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            .await
+            .expect("the connection should be closed after the read timeout");
+        assert_eq!(read.unwrap(), 0);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_required_before_commands() {
+        let (addr, storage, _) = spawn_server(ZzapConfig {
+            password: Some("secret".to_string()),
+            ..Default::default()
+        })
+        .await;
+        storage
+            .add_document("b", "c", Document::new("1", "hello"))
+            .unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let get = || Request::Get {
+            bucket: "b".into(),
+            collection: "c".into(),
+            id: "1".into(),
+            key: None,
+        };
+
+        command(&mut stream, get(), Response::Error("NOAUTH".into())).await;
+        command(&mut stream, Request::Ping, Response::Success).await;
+        // answered by the handler, the connection alone is not served by a running server
+        command(
+            &mut stream,
+            Request::Health,
+            Response::Error("Not ready: starting or shutting down".into()),
+        )
+        .await;
+        command(
+            &mut stream,
+            Request::Auth {
+                password: "wrong".into(),
+            },
+            Response::Error("Invalid argument: invalid password".into()),
+        )
+        .await;
+        command(&mut stream, get(), Response::Error("NOAUTH".into())).await;
+
+        command(
+            &mut stream,
+            Request::Auth {
+                password: "secret".into(),
+            },
+            Response::Success,
+        )
+        .await;
+        command(&mut stream, get(), Response::BulkString("hello".into())).await;
+    }
+
+    #[tokio::test]
+    async fn test_use_sets_connection_bucket() {
+        let (addr, storage, _) = spawn_server(ZzapConfig {
+            default_bucket: Some("configured".into()),
+            ..ZzapConfig::default()
+        })
+        .await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let set = |id: &str| format!("SET _ c {} hello", id);
+        command_string(&mut stream, set("1"), Response::Success).await;
+        command_string(&mut stream, "USE b".into(), Response::Success).await;
+        command_string(&mut stream, set("2"), Response::Success).await;
+        // a bucket given in full is used as is
+        command_string(&mut stream, "SET other c 3 hello".into(), Response::Success).await;
+        command_string(
+            &mut stream,
+            "GET _ c 2".into(),
+            Response::BulkString("hello".into()),
+        )
+        .await;
+        command_string(&mut stream, "USE _".into(), Response::Success).await;
+        command_string(&mut stream, set("4"), Response::Success).await;
+
+        assert!(storage.get_document("configured", "c", "1").is_ok());
+        assert!(storage.get_document("b", "c", "2").is_ok());
+        assert!(storage.get_document("other", "c", "3").is_ok());
+        assert!(storage.get_document("configured", "c", "4").is_ok());
+        assert!(storage.get_document("b", "c", "4").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_auth_without_password() {
+        let addr = setup_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        command(&mut stream, Request::Ping, Response::Success).await;
+        command(
+            &mut stream,
+            Request::Auth {
+                password: "secret".into(),
+            },
+            Response::Error("Invalid argument: no password is set".into()),
+        )
+        .await;
+    }
+
+    /// Counts writes and checks that no more items were produced than written so far.
+    struct LockstepSink {
+        produced: Arc<AtomicUsize>,
+        writes: usize,
+        bytes: usize,
+    }
+
+    impl AsyncWrite for LockstepSink {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            self.bytes += buf.len();
+            assert!(self.produced.load(Ordering::SeqCst) <= self.writes);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response_is_written_lazily() {
+        const DOCUMENTS: usize = 10_000;
+        let storage = Arc::new(Storage::new(DEFAULT_STORAGE_PATH));
+        for i in 0..DOCUMENTS {
+            let document = Document::new(&i.to_string(), &format!("document number {}", i));
+            storage.add_document("b", "c", document).unwrap();
+        }
+
+        // exports the collection, fetching each document only when it is about to be sent
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = produced.clone();
+        let items = (0..DOCUMENTS).map(move |i| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            storage
+                .get_document("b", "c", &i.to_string())
+                .unwrap()
+                .content
+        });
+        let response = Response::Stream(ResponseStream::new(items));
+
+        let mut sink = LockstepSink {
+            produced: produced.clone(),
+            writes: 0,
+            bytes: 0,
+        };
+        let written = write_response(&mut sink, response).await.unwrap();
+
+        assert_eq!(produced.load(Ordering::SeqCst), DOCUMENTS);
+        // start marker, one write per item, end marker
+        assert_eq!(sink.writes, DOCUMENTS + 2);
+        assert_eq!(written, sink.bytes as u64);
+    }
 }
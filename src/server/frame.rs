@@ -0,0 +1,378 @@
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    /// The request being read is longer than the limit, in bytes
+    TooLarge(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::TooLarge(max_len) => write!(f, "request longer than {} bytes", max_len),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Splits the bytes of a connection into requests.
+///
+/// A request may arrive over several reads, and a read may hold more than one request, so the bytes
+/// past a complete request are kept for the next one.
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+    /// Bytes of the buffer already searched for the newline ending the request, so a request
+    /// received over many reads is not searched from its start after each of them.
+    scanned: usize,
+    /// Bytes read from the connection so far, those of requests not complete yet included
+    received: u64,
+}
+
+impl FrameReader {
+    /// Reads until a whole request is buffered and returns it, newline included.
+    ///
+    /// Returns `None` once the peer closes the connection. A request cut short by the disconnect is
+    /// dropped rather than handled.
+    ///
+    /// A request longer than `max_len` bytes is an error, raised as soon as the bytes received or
+    /// the declared length of its content go past the limit rather than once it is all buffered.
+    pub async fn read_frame(
+        &mut self,
+        reader: &mut (impl AsyncRead + Unpin),
+        max_len: Option<usize>,
+    ) -> Result<Option<Vec<u8>>, FrameError> {
+        let too_large = |len: usize| max_len.filter(|&max_len| len > max_len);
+
+        loop {
+            if let Some(len) = frame_len(&self.buffer, &mut self.scanned) {
+                if let Some(max_len) = too_large(len) {
+                    return Err(FrameError::TooLarge(max_len));
+                }
+                self.scanned = 0;
+                return Ok(Some(self.buffer.drain(..len).collect()));
+            }
+
+            let pending = sized_content_end(&self.buffer).unwrap_or(0);
+            if let Some(max_len) = too_large(pending.max(self.buffer.len())) {
+                return Err(FrameError::TooLarge(max_len));
+            }
+
+            let read = reader.read_buf(&mut self.buffer).await?;
+            if read == 0 {
+                if !self.buffer.is_empty() {
+                    tracing::warn!(
+                        "Connection closed mid-request, dropping {} bytes",
+                        self.buffer.len()
+                    );
+                    self.buffer.clear();
+                }
+                return Ok(None);
+            }
+            self.received += read as u64;
+        }
+    }
+
+    /// Bytes read from the connection so far, including those of a request not complete yet.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
+
+/// Length of the first complete request in the buffer, if there is one.
+///
+/// A request ends with a newline, except that the length-prefixed content of a `SET`, its
+/// conditional forms, an `APPEND`, an `ADD`, an `MSET` or an `IMPORT` may hold newlines of its
+/// own: such a request ends with the first newline after its last content.
+///
+/// The search for that newline starts at `scanned` at the earliest, and `scanned` is moved to the
+/// end of the buffer when there is none.
+fn frame_len(buffer: &[u8], scanned: &mut usize) -> Option<usize> {
+    let mut line_end = |from: usize| {
+        let from = from.max(*scanned);
+        let end = buffer[from..].iter().position(|&b| b == b'\n');
+        if end.is_none() {
+            *scanned = buffer.len();
+        }
+        end.map(|end| from + end + 1)
+    };
+
+    match sized_content_end(buffer) {
+        // the content is still being received
+        Some(content_end) if content_end > buffer.len() => None,
+        Some(content_end) => line_end(content_end),
+        None => line_end(0),
+    }
+}
+
+/// Where the last `<len>:<content>` argument of the request at the start of the buffer ends,
+/// possibly past the bytes received so far. `None` if the request has no such argument.
+fn sized_content_end(buffer: &[u8]) -> Option<usize> {
+    let mut position = 0;
+    let fields_before_content = match next_field(buffer, &mut position)? {
+        b"SET" | b"SETNX" | b"UPDATE" | b"APPEND" => 3,
+        b"ADD" => 2,
+        b"MSET" => return documents_end(buffer, position),
+        b"IMPORT" => {
+            next_field(buffer, &mut position)?;
+            next_field(buffer, &mut position)?;
+            // `REPLACE` is optional, anything else is the start of the content
+            let before_option = position;
+            if next_field(buffer, &mut position) != Some(b"REPLACE") {
+                position = before_option;
+            }
+            return sized_field_end(buffer, position);
+        }
+        _ => return None,
+    };
+    for _ in 0..fields_before_content {
+        next_field(buffer, &mut position)?;
+    }
+    sized_field_end(buffer, position)
+}
+
+/// Where the `<id> <len>:<content>` documents of an `MSET` end, given the position past the
+/// command.
+///
+/// The next document may not be received yet, but the request cannot end before the first newline
+/// past the last content received, so waiting for that newline is enough.
+fn documents_end(buffer: &[u8], mut position: usize) -> Option<usize> {
+    next_field(buffer, &mut position)?;
+    next_field(buffer, &mut position)?;
+    let count: usize = std::str::from_utf8(next_field(buffer, &mut position)?)
+        .ok()?
+        .parse()
+        .ok()?;
+
+    let mut end = None;
+    for _ in 0..count {
+        if next_field(buffer, &mut position).is_none() {
+            break;
+        }
+        match sized_field_end(buffer, position) {
+            Some(content_end) if content_end > buffer.len() => return Some(content_end),
+            Some(content_end) => {
+                position = content_end;
+                end = Some(content_end);
+            }
+            None => break,
+        }
+    }
+    end
+}
+
+/// Where the `<len>:<content>` field at the position ends, possibly past the bytes received so far.
+fn sized_field_end(buffer: &[u8], mut position: usize) -> Option<usize> {
+    skip_blanks(buffer, &mut position);
+
+    let digits = buffer[position..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    if digits == 0 || buffer.get(position + digits) != Some(&b':') {
+        return None;
+    }
+    // only digits, so it is valid UTF-8; a length too large to parse is left to the parser
+    let len: usize = std::str::from_utf8(&buffer[position..position + digits])
+        .ok()?
+        .parse()
+        .ok()?;
+    (position + digits + 1).checked_add(len)
+}
+
+/// Advances past the next whitespace-separated field of the first line and returns it.
+fn next_field<'a>(buffer: &'a [u8], position: &mut usize) -> Option<&'a [u8]> {
+    skip_blanks(buffer, position);
+    let start = *position;
+    while *position < buffer.len() && !buffer[*position].is_ascii_whitespace() {
+        *position += 1;
+    }
+    (*position > start).then(|| &buffer[start..*position])
+}
+
+/// Skips whitespace other than newlines, which end the line.
+fn skip_blanks(buffer: &[u8], position: &mut usize) {
+    while *position < buffer.len()
+        && buffer[*position].is_ascii_whitespace()
+        && buffer[*position] != b'\n'
+    {
+        *position += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_frames() {
+        let mut frames = FrameReader::default();
+        let mut input: &[u8] = b"PING\nGET b c 1\nSET b c";
+
+        let mut read = Vec::new();
+        while let Some(frame) = frames.read_frame(&mut input, None).await.unwrap() {
+            read.push(frame);
+        }
+        // the trailing partial request is dropped
+        assert_eq!(read, [b"PING\n".to_vec(), b"GET b c 1\n".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_across_reads() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut frames = FrameReader::default();
+
+        let read = tokio::spawn(async move { frames.read_frame(&mut server, None).await.unwrap() });
+        for part in [&b"SET b c "[..], b"1 hel", b"lo\n"] {
+            tokio::io::AsyncWriteExt::write_all(&mut client, part)
+                .await
+                .unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(read.await.unwrap(), Some(b"SET b c 1 hello\n".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_over_limit() {
+        let read = |input: &'static [u8]| async move {
+            let mut input = input;
+            FrameReader::default()
+                .read_frame(&mut input, Some(16))
+                .await
+        };
+
+        assert_eq!(
+            read(b"SET b c 1 hello\n").await.unwrap(),
+            Some(b"SET b c 1 hello\n".to_vec())
+        );
+        assert!(matches!(
+            read(b"SET b c 1 hello world\n").await,
+            Err(FrameError::TooLarge(16))
+        ));
+        // without a newline in sight
+        assert!(matches!(
+            read(b"SET b c 1 hello world").await,
+            Err(FrameError::TooLarge(16))
+        ));
+        // the declared length alone is over the limit, the content is not waited for
+        assert!(matches!(
+            read(b"SET b c 1 100:").await,
+            Err(FrameError::TooLarge(16))
+        ));
+    }
+
+    #[test]
+    fn test_frame_len_resumes_search() {
+        let mut scanned = 0;
+        assert_eq!(frame_len(b"GET b c", &mut scanned), None);
+        assert_eq!(scanned, 7);
+        // only the bytes past those already searched are searched
+        assert_eq!(frame_len(b"GET\nb c 1", &mut scanned), None);
+        assert_eq!(frame_len(b"GET b c 1\n", &mut scanned), Some(10));
+        assert_eq!(scanned, 9);
+
+        // the content may hold newlines below the bytes searched before it was complete
+        let mut scanned = 0;
+        assert_eq!(frame_len(b"SET b c 1 11:Hello", &mut scanned), None);
+        assert_eq!(scanned, 0);
+        assert_eq!(frame_len(b"SET b c 1 11:Hello\nWorld", &mut scanned), None);
+        assert_eq!(scanned, 24);
+        assert_eq!(
+            frame_len(b"SET b c 1 11:Hello\nWorld\n", &mut scanned),
+            Some(25)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_resets_search() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut frames = FrameReader::default();
+
+        let read = tokio::spawn(async move {
+            let first = frames.read_frame(&mut server, None).await.unwrap();
+            let second = frames.read_frame(&mut server, None).await.unwrap();
+            (first, second)
+        });
+        // the second request is shorter than the bytes searched for the end of the first
+        for part in [&b"GET b c 11"[..], b"\nGET b", b" c 2\n"] {
+            tokio::io::AsyncWriteExt::write_all(&mut client, part)
+                .await
+                .unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(
+            read.await.unwrap(),
+            (
+                Some(b"GET b c 11\n".to_vec()),
+                Some(b"GET b c 2\n".to_vec())
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_counts_received_bytes() {
+        let mut frames = FrameReader::default();
+        let mut reader = &b"PING\nGET b"[..];
+
+        assert_eq!(
+            frames.read_frame(&mut reader, None).await.unwrap(),
+            Some(b"PING\n".to_vec())
+        );
+        // the start of the next request was read along with the first one
+        assert_eq!(frames.received(), 10);
+    }
+
+    #[test]
+    fn test_frame_len_sized_content() {
+        // the newline of the content does not end the request
+        assert_eq!(
+            frame_len(b"SET b c 1 11:Hello\nWorld\nPING\n", &mut 0),
+            Some(25)
+        );
+        assert_eq!(
+            frame_len(b"SET b c 1 11:Hello\nWorld key\n", &mut 0),
+            Some(29)
+        );
+        assert_eq!(frame_len(b"ADD b c 3:a\nb\n", &mut 0), Some(14));
+        assert_eq!(frame_len(b"APPEND b c 1 3:a\nb\n", &mut 0), Some(19));
+        assert_eq!(frame_len(b"SETNX b c 1 3:a\nb\n", &mut 0), Some(18));
+        assert_eq!(
+            frame_len(b"MSET b c 2 1 3:a\nb 2 3:c\nd\n", &mut 0),
+            Some(27)
+        );
+        assert_eq!(frame_len(b"IMPORT b c 3:a\nb\n", &mut 0), Some(17));
+        assert_eq!(frame_len(b"IMPORT b c REPLACE 3:a\nb\n", &mut 0), Some(25));
+        assert_eq!(frame_len(b"IMPORT b c REPLACE 3:a\n", &mut 0), None);
+        // the next document is not received yet
+        assert_eq!(frame_len(b"MSET b c 2 1 3:a\nb 2 3:c\n", &mut 0), None);
+        assert_eq!(frame_len(b"MSET b c 2 1 3:a\nb 2", &mut 0), None);
+        assert_eq!(frame_len(b"MSET b c 2 1 3:a\nb", &mut 0), None);
+        // the content or the newline following it is not received yet
+        assert_eq!(frame_len(b"SET b c 1 11:Hello\nWor", &mut 0), None);
+        assert_eq!(frame_len(b"SET b c 1 11:Hello\nWorld", &mut 0), None);
+        assert_eq!(frame_len(b"SET b c 1 11", &mut 0), None);
+
+        // content without a length ends with the line
+        assert_eq!(frame_len(b"SET b c 1 Hello\nWorld\n", &mut 0), Some(16));
+        assert_eq!(frame_len(b"SET b c 1 a5:b\n", &mut 0), Some(15));
+        assert_eq!(frame_len(b"SET b c\n1 5:a\nb\n", &mut 0), Some(8));
+        assert_eq!(frame_len(b"GET b c 5:a\nb\n", &mut 0), Some(12));
+        assert_eq!(frame_len(b"MSET b c 2 1 a\n2 3:b\nc\n", &mut 0), Some(15));
+        assert_eq!(
+            frame_len(b"SET b c 1 99999999999999999999999:a\n", &mut 0),
+            Some(36)
+        );
+    }
+}
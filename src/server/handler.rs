@@ -1,15 +1,41 @@
+use super::indexer::IndexQueue;
+use super::ServerStatus;
+use crate::config::{KeyNormalization, ZzapConfig};
 use crate::encryption::{Encryption, EncryptionError};
-use crate::protocol::{Request, Response};
-use crate::search::{SearchEngine, StdSearchEngine};
-use crate::storage::{Document, Storage, StorageError, StorageOperations};
+use crate::lang;
+use crate::logging;
+use crate::protocol::{
+    scan_cursor, BlacklistAction, ConfigAction, Request, Response, ResponseStream, SetCondition,
+};
+use crate::search::{engine_by_name, DynSearchEngine, SearchEngine, SearchHit, SearchOptions};
+use crate::storage::{
+    unix_millis, Document, EntityType, Storage, StorageError, StorageOperations,
+    StorageOperationsInternal,
+};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::ops::Deref;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, RwLock};
+use tokio::task;
+use tracing::Instrument;
+
+/// Maximum number of ids listed in a dry-run report, after the total count
+const DRY_RUN_SAMPLE_SIZE: usize = 10;
+
+/// Number of ids a `SCAN` returns when the client sets no count
+const DEFAULT_SCAN_COUNT: usize = 10;
 
 #[derive(Debug, PartialEq)]
 pub enum HandleError {
     Encryption(EncryptionError),
     Storage(StorageError),
+    Unsupported(String),
+    NoDefault(&'static str),
+    InvalidArgument(String),
+    /// The server cannot serve requests as usual, for the given reason
+    NotReady(&'static str),
+    /// Storage error on the document of a batch with the given id
+    Document(String, StorageError),
 }
 
 impl fmt::Display for HandleError {
@@ -17,16 +43,66 @@ impl fmt::Display for HandleError {
         match self {
             HandleError::Encryption(e) => write!(f, "Encryption error: {}", e),
             HandleError::Storage(e) => write!(f, "Storage error: {}", e),
+            HandleError::Unsupported(e) => write!(f, "Unsupported: {}", e),
+            HandleError::NoDefault(field) => write!(f, "No default {} configured", field),
+            HandleError::InvalidArgument(e) => write!(f, "Invalid argument: {}", e),
+            HandleError::NotReady(reason) => write!(f, "Not ready: {}", reason),
+            HandleError::Document(id, e) => write!(f, "Storage error on document {}: {}", id, e),
         }
     }
 }
 
 pub async fn handle_request(
     request: Request,
-    storage: &Arc<RwLock<Storage>>,
+    storage: &Arc<Storage>,
+    encryption: &dyn Encryption,
+    search_engine: &Arc<RwLock<DynSearchEngine>>,
+    config: &RwLock<ZzapConfig>,
+    indexer: Option<&IndexQueue>,
+    status: &ServerStatus,
+) -> Result<Response, HandleError> {
+    let request = {
+        let config = config
+            .read()
+            .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+        apply_defaults(request, &config)?
+    };
+
+    let span = tracing::debug_span!(
+        "request",
+        command = request.name(),
+        bucket = request.bucket(),
+        collection = request.collection(),
+    );
+    let result = execute(
+        request,
+        storage,
+        encryption,
+        search_engine,
+        config,
+        indexer,
+        status,
+    )
+    .instrument(span.clone())
+    .await;
+    span.in_scope(|| match &result {
+        Ok(_) => tracing::debug!("Request handled"),
+        Err(e) => tracing::debug!("Error handling request: {}", e),
+    });
+    result
+}
+
+/// Carries out a request, once the defaults are filled in.
+async fn execute(
+    request: Request,
+    shared_storage: &Arc<Storage>,
     encryption: &dyn Encryption,
-    search_engine: &Arc<RwLock<StdSearchEngine>>,
+    search_engine: &Arc<RwLock<DynSearchEngine>>,
+    config: &RwLock<ZzapConfig>,
+    indexer: Option<&IndexQueue>,
+    status: &ServerStatus,
 ) -> Result<Response, HandleError> {
+    let storage: &Storage = shared_storage;
     match request {
         Request::Set {
             bucket,
@@ -34,6 +110,7 @@ pub async fn handle_request(
             id,
             content,
             key,
+            ttl,
         } => {
             let content = match key {
                 Some(key) => encryption
@@ -42,53 +119,352 @@ pub async fn handle_request(
                 None => content,
             };
             let document = Document::new(&id, &content);
-            let storage = storage
+            let report_set_outcome = config
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?
+                .report_set_outcome;
+            let bucket_lock = storage.bucket_lock(&bucket);
+            // reporting the outcome takes the bucket exclusively, so no other write to the
+            // document can land between the existence check and the write
+            let (_shared_guard, _exclusive_guard) = if report_set_outcome {
+                let guard = bucket_lock
+                    .write()
+                    .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+                (None, Some(guard))
+            } else {
+                let guard = bucket_lock
+                    .read()
+                    .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+                (Some(guard), None)
+            };
+            let created = if report_set_outcome {
+                match storage.get_version(&bucket, &collection, &id) {
+                    Ok(_) => Some(false),
+                    Err(e) if e.is_not_found() => Some(true),
+                    Err(e) => return Err(HandleError::Storage(e)),
+                }
+            } else {
+                None
+            };
+            let search_engine = search_engine
                 .read()
                 .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            write_document(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &collection,
+                document,
+            )
+            .map_err(HandleError::Storage)?;
+            if let Some(ttl) = ttl {
+                storage
+                    .set_expiry(&bucket, &collection, &id, Some(expiry_time(ttl)))
+                    .map_err(HandleError::Storage)?;
+            }
+            Ok(match created {
+                Some(true) => Response::Created,
+                Some(false) => Response::Updated,
+                None => Response::Success,
+            })
+        }
+
+        Request::SetIf {
+            condition,
+            bucket,
+            collection,
+            id,
+            content,
+            key,
+            ttl,
+        } => {
+            let content = match key {
+                Some(key) => encryption
+                    .encrypt(&content, &key)
+                    .map_err(HandleError::Encryption)?,
+                None => content,
+            };
+            let bucket_lock = storage.bucket_lock(&bucket);
+            // exclusive, so no other write to the document lands between the check and the write
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            // an expired document is as good as absent, writing over it clears its expiry
+            let exists = match storage.get_version(&bucket, &collection, &id) {
+                Ok(_) => !storage.is_expired(&bucket, &collection, &id, unix_millis()),
+                Err(e) if e.is_not_found() => false,
+                Err(e) => return Err(HandleError::Storage(e)),
+            };
+            if exists != (condition == SetCondition::IfPresent) {
+                return Ok(Response::Integer(0));
+            }
+
             let search_engine = search_engine
                 .read()
                 .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
-            search_engine
-                .index(storage.deref(), &bucket, &collection, &id, &content)
-                .map_err(HandleError::Storage)?;
-            storage
-                .add_document(&bucket, &collection, document)
-                .map_err(HandleError::Storage)?;
+            write_document(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &collection,
+                Document::new(&id, &content),
+            )
+            .map_err(HandleError::Storage)?;
+            if let Some(ttl) = ttl {
+                storage
+                    .set_expiry(&bucket, &collection, &id, Some(expiry_time(ttl)))
+                    .map_err(HandleError::Storage)?;
+            }
+            Ok(Response::Integer(1))
+        }
+
+        Request::MSet {
+            bucket,
+            collection,
+            docs,
+        } => {
+            let bucket_lock = storage.bucket_lock(&bucket);
+            // exclusive, so no other write lands between the documents or gets undone by a rollback
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            set_documents(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &collection,
+                docs,
+            )?;
             Ok(Response::Success)
         }
 
+        Request::Add {
+            bucket,
+            collection,
+            content,
+            key,
+        } => {
+            let content = match key {
+                Some(key) => encryption
+                    .encrypt(&content, &key)
+                    .map_err(HandleError::Encryption)?,
+                None => content,
+            };
+            let bucket_lock = storage.bucket_lock(&bucket);
+            // exclusive, so no `SET` takes the generated id before the document is stored
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let id = storage
+                .generate_id(&bucket, &collection)
+                .map_err(HandleError::Storage)?;
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let document = Document::new(&id, &content);
+            write_document(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &collection,
+                document,
+            )
+            .map_err(HandleError::Storage)?;
+            Ok(Response::BulkString(id))
+        }
+
         Request::Search {
             bucket,
             collection,
             query,
+            mut options,
+        } => {
+            options.set_tokenizer(storage.collection_config(&bucket, &collection).tokenizer());
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let mut results = search_engine
+                .search_with_options(&bucket, &collection, &query, &options)
+                .map_err(HandleError::Storage)?;
+            drop(search_engine);
+            without_expired(storage, &bucket, &collection, &mut results);
+            if options.highlight {
+                results = highlight(storage, &bucket, &collection, &query, &options, results)
+                    .map_err(HandleError::Storage)?;
+            }
+            Ok(Response::Array(results))
+        }
+
+        Request::SearchPrefix {
+            bucket,
+            collection,
+            query,
+            mut options,
+        } => {
+            options.set_tokenizer(storage.collection_config(&bucket, &collection).tokenizer());
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let mut results = search_engine
+                .search_prefix(&bucket, &collection, &query, &options)
+                .map_err(HandleError::Storage)?;
+            drop(search_engine);
+            without_expired(storage, &bucket, &collection, &mut results);
+            Ok(Response::Array(results))
+        }
+
+        Request::SearchFuzzy {
+            bucket,
+            collection,
+            max_distance,
+            query,
+            mut options,
+        } => {
+            options.set_tokenizer(storage.collection_config(&bucket, &collection).tokenizer());
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let mut results = search_engine
+                .search_fuzzy(&bucket, &collection, &query, max_distance, &options)
+                .map_err(HandleError::Storage)?;
+            drop(search_engine);
+            without_expired(storage, &bucket, &collection, &mut results);
+            Ok(Response::Array(results))
+        }
+
+        Request::SearchHighlight {
+            bucket,
+            collection,
+            query,
+            mut options,
         } => {
+            options.set_tokenizer(storage.collection_config(&bucket, &collection).tokenizer());
             let search_engine = search_engine
                 .read()
                 .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
-            let results = search_engine
-                .search(&bucket, &collection, &query)
+            let mut results = search_engine
+                .search_with_options(&bucket, &collection, &query, &options)
+                .map_err(HandleError::Storage)?;
+            drop(search_engine);
+            without_expired(storage, &bucket, &collection, &mut results);
+            let results = snippets(storage, &bucket, &collection, &query, &options, results)
                 .map_err(HandleError::Storage)?;
             Ok(Response::Array(results))
         }
 
+        Request::MultiSearch {
+            bucket,
+            collections,
+            query,
+            options,
+        } => {
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let options = options.with_default_limit(search_engine.max_results());
+            let mut per_collection = Vec::with_capacity(collections.len());
+            for collection in &collections {
+                let mut options = options.clone();
+                // every collection could fill the page on its own, so it is cut once merged
+                options.limit = Some(options.ranked_len());
+                options.offset = 0;
+                options.set_tokenizer(storage.collection_config(&bucket, collection).tokenizer());
+                let ids = search_engine
+                    .search_with_options(&bucket, collection, &query, &options)
+                    .and_then(|mut ids| {
+                        without_expired(storage, &bucket, collection, &mut ids);
+                        if !options.highlight {
+                            return Ok(ids);
+                        }
+                        highlight(storage, &bucket, collection, &query, &options, ids)
+                    });
+                match ids {
+                    Ok(ids) => per_collection.push((collection, ids)),
+                    // a missing collection contributes no hits instead of failing the query
+                    Err(e) if e.is_not_found() => continue,
+                    Err(e) => return Err(HandleError::Storage(e)),
+                }
+            }
+            Ok(Response::Array(options.page(merge_ranked(per_collection))))
+        }
         Request::Get {
             bucket,
             collection,
             id,
             key,
         } => {
-            let storage = storage
+            purge_if_expired(storage, search_engine, &bucket, &collection, &id)?;
+            Ok(
+                match read_content(storage, encryption, &bucket, &collection, &id, key)? {
+                    Some(content) => Response::BulkString(content),
+                    None => Response::Null,
+                },
+            )
+        }
+
+        Request::MGet {
+            bucket,
+            collection,
+            ids,
+        } => {
+            for id in &ids {
+                purge_if_expired(storage, search_engine, &bucket, &collection, id)?;
+            }
+            let bucket_lock = storage.bucket_lock(&bucket);
+            // shared, which only holds off the writes locking the bucket exclusively: a plain SET
+            // may still land between two of the documents
+            let _bucket_guard = bucket_lock
                 .read()
                 .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
-            let encrypted_document = storage
+            let contents = ids
+                .iter()
+                .map(|id| read_content(storage, encryption, &bucket, &collection, id, None))
+                .collect::<Result<_, _>>()?;
+            Ok(Response::NullableArray(contents))
+        }
+
+        Request::GetRange {
+            bucket,
+            collection,
+            id,
+            start,
+            end,
+            key,
+        } => {
+            purge_if_expired(storage, search_engine, &bucket, &collection, &id)?;
+            Ok(
+                match read_content(storage, encryption, &bucket, &collection, &id, key)? {
+                    Some(content) => {
+                        Response::BulkString(byte_range(&content, start, end).to_string())
+                    }
+                    None => Response::Null,
+                },
+            )
+        }
+        Request::GetIf {
+            bucket,
+            collection,
+            id,
+            since_version,
+        } => {
+            purge_if_expired(storage, search_engine, &bucket, &collection, &id)?;
+            let version = storage
+                .get_version(&bucket, &collection, &id)
+                .map_err(HandleError::Storage)?;
+            if version <= since_version {
+                return Ok(Response::NotModified);
+            }
+            let document = storage
                 .get_document(&bucket, &collection, &id)
                 .map_err(HandleError::Storage)?;
-            Ok(Response::BulkString(match key {
-                Some(key) => encryption
-                    .decrypt(&encrypted_document.content, &key)
-                    .map_err(HandleError::Encryption)?,
-                None => encrypted_document.content,
-            }))
+            Ok(Response::Array(vec![version.to_string(), document.content]))
         }
 
         Request::Remove {
@@ -96,14 +472,15 @@ pub async fn handle_request(
             collection,
             id,
         } => {
-            let storage = storage
+            let bucket_lock = storage.bucket_lock(&bucket);
+            let _bucket_guard = bucket_lock
                 .read()
                 .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
             let search_engine = search_engine
                 .read()
                 .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
             search_engine
-                .remove_from_index(storage.deref(), &bucket, &collection, &id)
+                .remove_from_index(storage, &bucket, &collection, &id)
                 .map_err(HandleError::Storage)?;
             storage
                 .delete_document(&bucket, &collection, &id)
@@ -111,6 +488,1553 @@ pub async fn handle_request(
             Ok(Response::Success)
         }
 
+        Request::Rename {
+            bucket,
+            collection,
+            old_id,
+            new_id,
+        } => {
+            purge_if_expired(storage, search_engine, &bucket, &collection, &old_id)?;
+            let bucket_lock = storage.bucket_lock(&bucket);
+            // exclusive, so the document is never seen under both ids or under neither
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let document = storage
+                .get_document(&bucket, &collection, &old_id)
+                .map_err(HandleError::Storage)?;
+            if old_id == new_id {
+                return Ok(Response::Success);
+            }
+            let expires_at = storage.expiry(&bucket, &collection, &old_id);
+
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            write_document(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &collection,
+                Document::new(&new_id, &document.content),
+            )
+            .map_err(HandleError::Storage)?;
+            if expires_at.is_some() {
+                storage
+                    .set_expiry(&bucket, &collection, &new_id, expires_at)
+                    .map_err(HandleError::Storage)?;
+            }
+            // the old id may still wait for the indexer, and then has nothing to remove yet
+            ignore_not_found(search_engine.remove_from_index(
+                storage,
+                &bucket,
+                &collection,
+                &old_id,
+            ))
+            .and_then(|_| storage.delete_document(&bucket, &collection, &old_id))
+            .map_err(HandleError::Storage)?;
+            Ok(Response::Success)
+        }
+
+        Request::Copy {
+            bucket,
+            collection,
+            src_id,
+            dst_id,
+            to_collection,
+            replace,
+        } => {
+            let to_collection = to_collection.unwrap_or_else(|| collection.clone());
+            purge_if_expired(storage, search_engine, &bucket, &collection, &src_id)?;
+            purge_if_expired(storage, search_engine, &bucket, &to_collection, &dst_id)?;
+            let bucket_lock = storage.bucket_lock(&bucket);
+            // exclusive, so no other write to the new id lands between the check and the copy
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let document = storage
+                .get_document(&bucket, &collection, &src_id)
+                .map_err(HandleError::Storage)?;
+            if !replace {
+                match storage.get_version(&bucket, &to_collection, &dst_id) {
+                    Ok(_) => {
+                        return Err(HandleError::InvalidArgument(format!(
+                            "{} already exists",
+                            dst_id
+                        )))
+                    }
+                    Err(e) if e.is_not_found() => {}
+                    Err(e) => return Err(HandleError::Storage(e)),
+                }
+            }
+
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            write_document(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &to_collection,
+                Document::new(&dst_id, &document.content),
+            )
+            .map_err(HandleError::Storage)?;
+            Ok(Response::Success)
+        }
+
+        Request::DropCollection { bucket, collection } => {
+            // exclusive, so no document is stored in the collection while it is being dropped
+            let bucket_lock = storage.bucket_lock(&bucket);
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            storage
+                .delete_collection(&bucket, &collection)
+                .map_err(HandleError::Storage)?;
+            search_engine
+                .clear_collection(&bucket, &collection)
+                .map_err(HandleError::Storage)?;
+            Ok(Response::Success)
+        }
+
+        Request::DropBucket { bucket } => {
+            let bucket_lock = storage.bucket_lock(&bucket);
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            storage
+                .delete_bucket(&bucket)
+                .map_err(HandleError::Storage)?;
+            search_engine
+                .clear_bucket(&bucket)
+                .map_err(HandleError::Storage)?;
+            Ok(Response::Success)
+        }
+
+        Request::Exists {
+            bucket,
+            collection,
+            id,
+        } => {
+            purge_if_expired(storage, search_engine, &bucket, &collection, &id)?;
+            let exists = match storage.get_version(&bucket, &collection, &id) {
+                Ok(_) => 1,
+                Err(e) if e.is_not_found() => 0,
+                Err(e) => return Err(HandleError::Storage(e)),
+            };
+            Ok(Response::Integer(exists))
+        }
+
+        Request::Expire {
+            bucket,
+            collection,
+            id,
+            seconds,
+        } => {
+            purge_if_expired(storage, search_engine, &bucket, &collection, &id)?;
+            let bucket_lock = storage.bucket_lock(&bucket);
+            let _bucket_guard = bucket_lock
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let updated =
+                match storage.set_expiry(&bucket, &collection, &id, Some(expiry_time(seconds))) {
+                    Ok(()) => 1,
+                    Err(e) if e.is_not_found() => 0,
+                    Err(e) => return Err(HandleError::Storage(e)),
+                };
+            Ok(Response::Integer(updated))
+        }
+
+        Request::Append {
+            bucket,
+            collection,
+            id,
+            content,
+        } => {
+            purge_if_expired(storage, search_engine, &bucket, &collection, &id)?;
+            // exclusive, so no other write lands between reading the content and writing it back
+            let bucket_lock = storage.bucket_lock(&bucket);
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let mut appended = match storage.get_document(&bucket, &collection, &id) {
+                Ok(document) => document.content,
+                Err(e) if e.is_not_found() => String::new(),
+                Err(e) => return Err(HandleError::Storage(e)),
+            };
+            appended.push_str(&content);
+            let len = appended.len();
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            // indexes the whole content again, words may run on across the old end
+            rewrite_document(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &collection,
+                Document::new(&id, &appended),
+            )
+            .map_err(HandleError::Storage)?;
+            Ok(Response::Integer(len as i64))
+        }
+        Request::Incr {
+            bucket,
+            collection,
+            id,
+            delta,
+        } => {
+            purge_if_expired(storage, search_engine, &bucket, &collection, &id)?;
+            // exclusive, so concurrent increments of the document are applied one after the other
+            let bucket_lock = storage.bucket_lock(&bucket);
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let current = match storage.get_document(&bucket, &collection, &id) {
+                Ok(document) => document.content.parse::<i64>().map_err(|_| {
+                    HandleError::InvalidArgument(format!("content of {} is not an integer", id))
+                })?,
+                Err(e) if e.is_not_found() => 0,
+                Err(e) => return Err(HandleError::Storage(e)),
+            };
+            let value = current.checked_add(delta).ok_or_else(|| {
+                HandleError::InvalidArgument(format!("increment of {} would overflow", id))
+            })?;
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            rewrite_document(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &collection,
+                Document::new(&id, &value.to_string()),
+            )
+            .map_err(HandleError::Storage)?;
+            Ok(Response::Integer(value))
+        }
+
+        Request::ListIds {
+            bucket,
+            collection,
+            limit,
+        } => {
+            let mut ids = collection_ids(storage, &bucket, &collection);
+            // sorted, so the same limit always lists the same ids
+            ids.sort();
+            ids.truncate(limit.unwrap_or(usize::MAX));
+            Ok(Response::Array(ids))
+        }
+
+        Request::Export { bucket, collection } => Ok(Response::Stream(ResponseStream::new(
+            storage.export_collection(&bucket, &collection),
+        ))),
+
+        Request::Import {
+            bucket,
+            collection,
+            content,
+            replace,
+        } => {
+            let normalization = config
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?
+                .key_normalization;
+            // the whole dump is parsed first, so a malformed line doesn't leave half of it stored
+            let mut docs = parse_import(&content, normalization)?;
+            let bucket_lock = storage.bucket_lock(&bucket);
+            // exclusive, so no other write lands between the existence checks and the documents
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            if !replace {
+                let now = unix_millis();
+                let mut kept = Vec::with_capacity(docs.len());
+                for (id, content) in docs {
+                    match storage.get_version(&bucket, &collection, &id) {
+                        Ok(_) if !storage.is_expired(&bucket, &collection, &id, now) => {}
+                        Ok(_) => kept.push((id, content)),
+                        Err(e) if e.is_not_found() => kept.push((id, content)),
+                        Err(e) => return Err(HandleError::Document(id, e)),
+                    }
+                }
+                docs = kept;
+            }
+            let imported = docs.len();
+
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            // all or nothing, a failed write rolls back both the storage and the index
+            set_documents(
+                storage,
+                search_engine.as_ref(),
+                indexer,
+                &bucket,
+                &collection,
+                docs,
+            )?;
+            Ok(Response::Integer(imported as i64))
+        }
+
+        Request::Scan {
+            bucket,
+            collection,
+            cursor,
+            count,
+        } => {
+            let count = count.unwrap_or(DEFAULT_SCAN_COUNT);
+            let (ids, more) = storage.ids_after(&bucket, &collection, cursor.as_deref(), count);
+            let next = ids.last().filter(|_| more).map(String::as_str);
+            let mut items = vec![scan_cursor(next)];
+            items.extend(ids);
+            Ok(Response::Array(items))
+        }
+
         Request::Ping => Ok(Response::Success),
+        // unlike `PING`, fails while the server is up but not fit to take traffic
+        Request::Health => status
+            .health()
+            .map(|()| Response::Success)
+            .map_err(HandleError::NotReady),
+        // only checks the password, connections keep track of whether they authenticated
+        Request::Auth { password } => {
+            let config = config
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            match &config.password {
+                None => Err(HandleError::InvalidArgument(
+                    "no password is set".to_string(),
+                )),
+                Some(expected) if *expected == password => Ok(Response::Success),
+                Some(_) => Err(HandleError::InvalidArgument("invalid password".to_string())),
+            }
+        }
+        // only checks the bucket, connections keep track of the one they use
+        Request::Use { .. } => Ok(Response::Success),
+        Request::Noop => Ok(Response::Success),
+        Request::Sync => {
+            if let Some(indexer) = indexer {
+                indexer.sync().await;
+            }
+            Ok(Response::Success)
+        }
+
+        Request::Save => {
+            super::persist(shared_storage.clone())
+                .await
+                .map_err(HandleError::Storage)?;
+            Ok(Response::Success)
+        }
+
+        Request::Stats => {
+            let stats = storage.stats();
+            let tokens = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?
+                .token_count()
+                .map_err(HandleError::Storage)?;
+            Ok(Response::Array(vec![
+                format!("buckets {}", stats.buckets),
+                format!("collections {}", stats.collections),
+                format!("documents {}", stats.documents),
+                format!("tokens {}", tokens),
+                format!("memory {}", stats.content_bytes),
+                format!("connections {}", status.connections.load(Ordering::Relaxed)),
+            ]))
+        }
+
+        Request::Info => {
+            let config = config
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let info = [
+                format!("version {}", env!("CARGO_PKG_VERSION")),
+                format!("uptime {}", status.started_at.elapsed().as_secs()),
+                format!("addr {}", config.addr),
+                format!("engine {}", config.engine),
+                format!("persistence_path {}", config.persistence_path.display()),
+                format!("documents {}", storage.stats().documents),
+            ];
+            Ok(Response::BulkString(info.join("\n")))
+        }
+
+        Request::DryRun(request) => dry_run(*request, storage),
+        Request::SetEngine { name } => {
+            let max_results = config
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?
+                .max_results;
+            let engine = engine_by_name(&name, max_results)
+                .ok_or_else(|| HandleError::InvalidArgument(format!("unknown engine {}", name)))?;
+            let _rebuilding = status.rebuild();
+            // built without the engine lock, so searches and writes carry on with the old engine
+            let build_storage = shared_storage.clone();
+            let (mut engine, versions) = task::spawn_blocking(move || {
+                build_engine(&build_storage, engine.as_ref()).map(|versions| (engine, versions))
+            })
+            .await
+            .map_err(|e| HandleError::Storage(StorageError::OperationFailed(e.to_string())))?
+            .map_err(HandleError::Storage)?;
+            // writes index under a read lock, so none is half done while the write lock is held,
+            // and only what they changed since the build is left to index before the swap
+            let mut search_engine = search_engine
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            catch_up(storage, engine.as_ref(), versions).map_err(HandleError::Storage)?;
+            std::mem::swap(&mut *search_engine, &mut engine);
+            config
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?
+                .engine = name;
+            Ok(Response::Success)
+        }
+        Request::Blacklist { action } => {
+            match action {
+                BlacklistAction::Show => {}
+                BlacklistAction::Regenerate => {
+                    let search_engine = search_engine
+                        .read()
+                        .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+                    let frequencies = token_frequencies(storage, search_engine.as_ref())
+                        .map_err(HandleError::Storage)?;
+                    storage
+                        .set_blacklist(lang::generate_blacklist(frequencies))
+                        .map_err(HandleError::Storage)?;
+                }
+                BlacklistAction::Clear => storage
+                    .set_blacklist(HashSet::new())
+                    .map_err(HandleError::Storage)?,
+            }
+            let mut blacklist: Vec<String> = storage.blacklist().iter().cloned().collect();
+            blacklist.sort();
+            Ok(Response::Array(blacklist))
+        }
+        Request::Verify { bucket, collection } => {
+            // read-only, so it holds its locks shared and holds no write up. A write done meanwhile
+            // may be reported until it is
+            let bucket_lock = storage.bucket_lock(&bucket);
+            let _bucket_guard = bucket_lock
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let report = verify(storage, search_engine.as_ref(), &bucket, &collection)
+                .map_err(HandleError::Storage)?;
+            Ok(Response::Array(report))
+        }
+        Request::Reindex { bucket, collection } => {
+            let _rebuilding = status.rebuild();
+            // exclusive, so no write is indexed in between and then dropped with the old entries
+            let bucket_lock = storage.bucket_lock(&bucket);
+            let _bucket_guard = bucket_lock
+                .write()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            let reindexed = search_engine
+                .reindex_collection(storage, &bucket, &collection)
+                .map_err(HandleError::Storage)?;
+            Ok(Response::Integer(reindexed as i64))
+        }
+        Request::DumpToken {
+            bucket,
+            collection,
+            token,
+        } => {
+            let tokenizer = storage.collection_config(&bucket, &collection).tokenizer();
+            let search_engine = search_engine
+                .read()
+                .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+            // looked up the way content is indexed, so `Hello` finds what `hello` was indexed under
+            let ids = match search_engine.tokenize(&token, &tokenizer).as_slice() {
+                [] => Vec::new(),
+                [token] => search_engine
+                    .ids_for_token(&bucket, &collection, token)
+                    .map_err(HandleError::Storage)?,
+                _ => {
+                    return Err(HandleError::InvalidArgument(format!(
+                        "{} is more than one token",
+                        token
+                    )))
+                }
+            };
+            Ok(Response::Array(ids))
+        }
+        Request::Config { action } => {
+            let describe = |(setting, value): (&str, String)| format!("{} {}", setting, value);
+            match action {
+                ConfigAction::Get { setting } => {
+                    let config = config
+                        .read()
+                        .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+                    let value = config.get(&setting).map_err(HandleError::InvalidArgument)?;
+                    Ok(Response::Array(vec![describe((
+                        &setting.to_uppercase(),
+                        value,
+                    ))]))
+                }
+                ConfigAction::Set { setting, value } => {
+                    {
+                        let mut config = config
+                            .write()
+                            .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+                        config
+                            .set(&setting, &value)
+                            .map_err(HandleError::InvalidArgument)?;
+                        // the subscriber holds the level it filters with, not the configuration
+                        logging::set_level(config.log_level);
+                    }
+                    if setting.eq_ignore_ascii_case("MAXRESULTS") {
+                        // the engine is locked before the configuration, as `SETENGINE` does
+                        let mut search_engine = search_engine
+                            .write()
+                            .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+                        let max_results = config
+                            .read()
+                            .map_err(|_| HandleError::Storage(StorageError::PoisonError))?
+                            .max_results;
+                        search_engine.set_max_results(max_results);
+                    }
+                    Ok(Response::Success)
+                }
+                ConfigAction::List => {
+                    let config = config
+                        .read()
+                        .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+                    Ok(Response::Array(
+                        config.list().into_iter().map(describe).collect(),
+                    ))
+                }
+            }
+        }
+        Request::Configure {
+            bucket,
+            collection,
+            option,
+            value,
+        } => {
+            let mut config = storage.collection_config(&bucket, &collection);
+            config
+                .set(&option, &value)
+                .map_err(HandleError::InvalidArgument)?;
+            storage
+                .set_collection_config(&bucket, &collection, config)
+                .map_err(HandleError::Storage)?;
+            Ok(Response::Success)
+        }
     }
 }
+
+/// Stores a document and indexes it as a single unit.
+///
+/// If either step fails, the steps already taken are compensated, so the storage and the index
+/// are both left as they were before the call.
+pub(crate) fn set_document(
+    storage: &dyn StorageOperations,
+    search_engine: &dyn SearchEngine,
+    bucket: &str,
+    collection: &str,
+    document: Document,
+) -> Result<(), StorageError> {
+    let previous = previous_document(storage, bucket, collection, &document.id)?;
+    let id = document.id.clone();
+    let content = document.content.clone();
+
+    // the engine looks up the stored content to drop its tokens, so do it before overwriting it
+    if previous.is_some() {
+        ignore_not_found(search_engine.remove_from_index(storage, bucket, collection, &id))?;
+    }
+
+    if let Err(e) = storage.add_document(bucket, collection, document) {
+        restore_index(storage, search_engine, bucket, collection, &previous);
+        return Err(e);
+    }
+
+    if let Err(e) = search_engine.index(storage, bucket, collection, &id, &content) {
+        // drop whatever part of the new document made it into the index, then bring back the old one
+        let _ = search_engine.remove_from_index(storage, bucket, collection, &id);
+        let _ = undo_write(storage, bucket, collection, &id, &previous);
+        restore_index(storage, search_engine, bucket, collection, &previous);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Stores several documents and indexes them in a single batch, as a single unit.
+///
+/// If any step fails, every document is left as it was before the call. Of several documents
+/// under the same id, only the last one is written.
+pub(crate) fn set_documents(
+    storage: &dyn StorageOperations,
+    search_engine: &dyn SearchEngine,
+    indexer: Option<&IndexQueue>,
+    bucket: &str,
+    collection: &str,
+    docs: Vec<(String, String)>,
+) -> Result<(), HandleError> {
+    let mut seen = HashSet::new();
+    let mut docs: Vec<(String, String)> = docs
+        .into_iter()
+        .rev()
+        .filter(|(id, _)| seen.insert(id.clone()))
+        .collect();
+    docs.reverse();
+    let ids: Vec<String> = docs.iter().map(|(id, _)| id.clone()).collect();
+
+    let mut previous = Vec::with_capacity(ids.len());
+    for id in &ids {
+        previous.push(
+            previous_document(storage, bucket, collection, id)
+                .map_err(|e| HandleError::Document(id.clone(), e))?,
+        );
+    }
+
+    // puts back the first `written` documents, then the index entries of every previous one
+    let rollback = |written: usize| {
+        // the engine looks up the stored content to drop its tokens, so do it before restoring it
+        for id in &ids {
+            let _ = search_engine.remove_from_index(storage, bucket, collection, id);
+        }
+        for (id, previous) in ids.iter().zip(&previous).take(written) {
+            let _ = undo_write(storage, bucket, collection, id, previous);
+        }
+        for (id, previous) in ids.iter().zip(&previous) {
+            match indexer {
+                Some(indexer) => indexer.push(bucket, collection, id),
+                None => restore_index(storage, search_engine, bucket, collection, previous),
+            }
+        }
+    };
+
+    for (id, previous) in ids.iter().zip(&previous) {
+        if previous.is_none() {
+            continue;
+        }
+        if let Err(e) =
+            ignore_not_found(search_engine.remove_from_index(storage, bucket, collection, id))
+        {
+            rollback(0);
+            return Err(HandleError::Document(id.clone(), e));
+        }
+    }
+    for (written, (id, content)) in docs.iter().enumerate() {
+        if let Err(e) = storage.add_document(bucket, collection, Document::new(id, content)) {
+            rollback(written);
+            return Err(HandleError::Document(id.clone(), e));
+        }
+    }
+
+    match indexer {
+        Some(indexer) => {
+            for id in &ids {
+                indexer.push(bucket, collection, id);
+            }
+        }
+        None => {
+            if let Err(e) = search_engine.batch_index(storage, bucket, collection, docs) {
+                rollback(ids.len());
+                return Err(HandleError::Storage(e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores the document and indexes it, right away or through the indexer when there is one.
+fn write_document(
+    storage: &dyn StorageOperations,
+    search_engine: &dyn SearchEngine,
+    indexer: Option<&IndexQueue>,
+    bucket: &str,
+    collection: &str,
+    document: Document,
+) -> Result<(), StorageError> {
+    match indexer {
+        Some(indexer) => store_document(
+            storage,
+            search_engine,
+            indexer,
+            bucket,
+            collection,
+            document,
+        ),
+        None => set_document(storage, search_engine, bucket, collection, document),
+    }
+}
+
+/// Replaces the content of a document like [`write_document`], keeping the expiry it had.
+fn rewrite_document(
+    storage: &Storage,
+    search_engine: &dyn SearchEngine,
+    indexer: Option<&IndexQueue>,
+    bucket: &str,
+    collection: &str,
+    document: Document,
+) -> Result<(), StorageError> {
+    let id = document.id.clone();
+    let expires_at = storage.expiry(bucket, collection, &id);
+    write_document(
+        storage,
+        search_engine,
+        indexer,
+        bucket,
+        collection,
+        document,
+    )?;
+    if expires_at.is_some() {
+        storage.set_expiry(bucket, collection, &id, expires_at)?;
+    }
+    Ok(())
+}
+
+fn store_document(
+    storage: &dyn StorageOperations,
+    search_engine: &dyn SearchEngine,
+    indexer: &IndexQueue,
+    bucket: &str,
+    collection: &str,
+    document: Document,
+) -> Result<(), StorageError> {
+    let id = document.id.clone();
+
+    // the engine looks up the stored content to drop its tokens, so do it before overwriting it
+    ignore_not_found(search_engine.remove_from_index(storage, bucket, collection, &id))?;
+    let stored = storage.add_document(bucket, collection, document);
+    // indexes whichever content ended up stored, the previous one if the write failed
+    indexer.push(bucket, collection, &id);
+
+    stored
+}
+
+/// The stored document with its version, kept to undo a write over it. `None` if there is none.
+fn previous_document(
+    storage: &dyn StorageOperations,
+    bucket: &str,
+    collection: &str,
+    id: &str,
+) -> Result<Option<(Document, u64)>, StorageError> {
+    match storage.get_document(bucket, collection, id) {
+        Ok(document) => Ok(Some((
+            document,
+            storage.get_version(bucket, collection, id)?,
+        ))),
+        Err(e) if e.is_not_found() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Puts back the document a write overwrote, at the version it had, or removes the one it created.
+fn undo_write(
+    storage: &dyn StorageOperations,
+    bucket: &str,
+    collection: &str,
+    id: &str,
+    previous: &Option<(Document, u64)>,
+) -> Result<(), StorageError> {
+    match previous {
+        Some((document, version)) => {
+            storage.restore_document(bucket, collection, document.clone(), *version)
+        }
+        None => storage.delete_document(bucket, collection, id),
+    }
+}
+
+fn restore_index(
+    storage: &dyn StorageOperations,
+    search_engine: &dyn SearchEngine,
+    bucket: &str,
+    collection: &str,
+    previous: &Option<(Document, u64)>,
+) {
+    if let Some((previous, _)) = previous {
+        let _ = search_engine.index(storage, bucket, collection, &previous.id, &previous.content);
+    }
+}
+
+fn ignore_not_found(result: Result<(), StorageError>) -> Result<(), StorageError> {
+    match result {
+        Err(e) if e.is_not_found() => Ok(()),
+        result => result,
+    }
+}
+
+/// When a document given `seconds` to live from now expires, in [`unix_millis`].
+fn expiry_time(seconds: u64) -> u64 {
+    unix_millis().saturating_add(seconds.saturating_mul(1000))
+}
+
+/// Content of the document, decrypted with `key` if given. `None` if there is no such document.
+fn read_content(
+    storage: &Storage,
+    encryption: &dyn Encryption,
+    bucket: &str,
+    collection: &str,
+    id: &str,
+    key: Option<String>,
+) -> Result<Option<String>, HandleError> {
+    let document = match storage.get_document(bucket, collection, id) {
+        Ok(document) => document,
+        // a missing bucket or collection is more likely a mistake than a lookup
+        Err(StorageError::NotFound(EntityType::Item)) => return Ok(None),
+        Err(e) => return Err(HandleError::Storage(e)),
+    };
+    Ok(Some(match key {
+        Some(key) => encryption
+            .decrypt(&document.content, &key)
+            .map_err(HandleError::Encryption)?,
+        None => document.content,
+    }))
+}
+
+/// Bytes of the content from `start` up to `end`, clamped to its length. A bound falling inside
+/// a character is moved out of it, so the whole character is included.
+fn byte_range(content: &str, start: usize, end: usize) -> &str {
+    let end = end.min(content.len());
+    if start >= end {
+        return "";
+    }
+    let start = (0..=start)
+        .rev()
+        .find(|&i| content.is_char_boundary(i))
+        .unwrap_or(0);
+    let end = (end..=content.len())
+        .find(|&i| content.is_char_boundary(i))
+        .unwrap_or(content.len());
+    &content[start..end]
+}
+
+/// Removes the document from the storage and the index if it has expired, so it reads as missing.
+fn purge_if_expired(
+    storage: &Storage,
+    search_engine: &RwLock<DynSearchEngine>,
+    bucket: &str,
+    collection: &str,
+    id: &str,
+) -> Result<(), HandleError> {
+    if !storage.is_expired(bucket, collection, id, unix_millis()) {
+        return Ok(());
+    }
+    let bucket_lock = storage.bucket_lock(bucket);
+    let _bucket_guard = bucket_lock
+        .write()
+        .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+    // rewritten or purged by someone else while waiting for the lock
+    if !storage.is_expired(bucket, collection, id, unix_millis()) {
+        return Ok(());
+    }
+    let search_engine = search_engine
+        .read()
+        .map_err(|_| HandleError::Storage(StorageError::PoisonError))?;
+    ignore_not_found(search_engine.remove_from_index(storage, bucket, collection, id))
+        .and_then(|_| ignore_not_found(storage.delete_document(bucket, collection, id)))
+        .map_err(HandleError::Storage)
+}
+
+/// Purges every expired document, returning how many were removed.
+pub(crate) fn purge_expired(
+    storage: &Storage,
+    search_engine: &RwLock<DynSearchEngine>,
+) -> Result<usize, HandleError> {
+    let expired = storage.expired_documents(unix_millis());
+    for (bucket, collection, id) in &expired {
+        purge_if_expired(storage, search_engine, bucket, collection, id)?;
+    }
+    Ok(expired.len())
+}
+
+/// Versions of the documents an engine has indexed, by bucket, collection and id.
+type IndexedVersions = HashMap<(String, String, String), u64>;
+
+/// Indexes every stored document into `engine`, returning the version each was indexed at.
+///
+/// Documents are copied out of a collection before it is indexed, so writes to it are not held
+/// up while it is.
+pub(crate) fn build_engine(
+    storage: &Storage,
+    engine: &dyn SearchEngine,
+) -> Result<IndexedVersions, StorageError> {
+    let mut versions = HashMap::new();
+    let mut collections = Vec::new();
+    for bucket_ref in storage.store.iter() {
+        for collection_ref in bucket_ref.value().iter() {
+            collections.push((bucket_ref.key().clone(), collection_ref.key().clone()));
+        }
+    }
+    for (bucket, collection) in collections {
+        let Some(docs) = storage.store.get(&bucket).and_then(|bucket_ref| {
+            bucket_ref.get(&collection).map(|collection_ref| {
+                collection_ref
+                    .iter()
+                    .map(|document_ref| {
+                        let key = (
+                            bucket.clone(),
+                            collection.clone(),
+                            document_ref.key().clone(),
+                        );
+                        versions.insert(key, document_ref.value().metadata.version);
+                        (
+                            document_ref.key().clone(),
+                            document_ref.value().content.clone(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+        }) else {
+            // dropped since it was listed
+            continue;
+        };
+        engine.batch_index(storage, &bucket, &collection, docs)?;
+    }
+    Ok(versions)
+}
+
+/// Brings an engine built by [`build_engine`] up to date with the storage, indexing the
+/// documents written since and unindexing the ones removed since.
+///
+/// Must run while no write can change the storage or the engines, i.e. under the engine write
+/// lock.
+pub(crate) fn catch_up(
+    storage: &Storage,
+    engine: &dyn SearchEngine,
+    mut versions: IndexedVersions,
+) -> Result<(), StorageError> {
+    for bucket_ref in storage.store.iter() {
+        for collection_ref in bucket_ref.value().iter() {
+            for document_ref in collection_ref.value().iter() {
+                let (bucket, collection, id) =
+                    (bucket_ref.key(), collection_ref.key(), document_ref.key());
+                let indexed = versions.remove(&(bucket.clone(), collection.clone(), id.clone()));
+                if indexed == Some(document_ref.value().metadata.version) {
+                    continue;
+                }
+                if indexed.is_some() {
+                    engine.remove_from_index(storage, bucket, collection, id)?;
+                }
+                engine.index(
+                    storage,
+                    bucket,
+                    collection,
+                    id,
+                    &document_ref.value().content,
+                )?;
+            }
+        }
+    }
+    for (bucket, collection, id) in versions.into_keys() {
+        ignore_not_found(engine.remove_from_index(storage, &bucket, &collection, &id))?;
+    }
+    Ok(())
+}
+
+/// Drops the search results whose document has expired but is not purged yet.
+fn without_expired(storage: &Storage, bucket: &str, collection: &str, ids: &mut Vec<String>) {
+    let now = unix_millis();
+    ids.retain(|id| !storage.is_expired(bucket, collection, id, now));
+}
+
+/// The `(id, content)` documents of an `EXPORT` dump, the last one winning for a repeated id.
+fn parse_import(
+    content: &str,
+    normalization: KeyNormalization,
+) -> Result<Vec<(String, String)>, HandleError> {
+    let mut docs: Vec<(String, String)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid =
+            |e: String| HandleError::InvalidArgument(format!("line {}: {}", number + 1, e));
+        let document: Document = serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+        let id = normalization.normalize(document.id);
+        // such an id couldn't be sent back in a request
+        if id.is_empty() || id.contains(char::is_whitespace) {
+            return Err(invalid(format!("invalid id {:?}", id)));
+        }
+        match positions.get(&id) {
+            Some(&position) => docs[position].1 = document.content,
+            None => {
+                positions.insert(id.clone(), docs.len());
+                docs.push((id, document.content));
+            }
+        }
+    }
+    Ok(docs)
+}
+
+/// Ids of the documents of a collection, in no particular order, none if it doesn't exist.
+fn collection_ids(storage: &Storage, bucket: &str, collection: &str) -> Vec<String> {
+    storage
+        .store
+        .get(bucket)
+        .and_then(|bucket| {
+            bucket
+                .get(collection)
+                .map(|collection| collection.iter().map(|entry| entry.key().clone()).collect())
+        })
+        .unwrap_or_default()
+}
+
+/// Fills in buckets and collections left empty by the client with the configured defaults, and
+/// normalizes buckets, collections and ids with [`ZzapConfig::key_normalization`]
+fn apply_defaults(request: Request, config: &ZzapConfig) -> Result<Request, HandleError> {
+    let normalize = |key: String| config.key_normalization.normalize(key);
+    let bucket = |bucket: String| {
+        default_field(normalize(bucket), &config.default_bucket, "bucket").map(normalize)
+    };
+    let collection = |collection: String| {
+        default_field(
+            normalize(collection),
+            &config.default_collection,
+            "collection",
+        )
+        .map(normalize)
+    };
+
+    Ok(match request {
+        Request::Set {
+            bucket: b,
+            collection: c,
+            id,
+            content,
+            key,
+            ttl,
+        } => Request::Set {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+            content,
+            key,
+            ttl,
+        },
+        Request::SetIf {
+            condition,
+            bucket: b,
+            collection: c,
+            id,
+            content,
+            key,
+            ttl,
+        } => Request::SetIf {
+            condition,
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+            content,
+            key,
+            ttl,
+        },
+        Request::MSet {
+            bucket: b,
+            collection: c,
+            docs,
+        } => Request::MSet {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            docs: docs
+                .into_iter()
+                .map(|(id, content)| (normalize(id), content))
+                .collect(),
+        },
+        Request::Add {
+            bucket: b,
+            collection: c,
+            content,
+            key,
+        } => Request::Add {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            content,
+            key,
+        },
+        Request::MGet {
+            bucket: b,
+            collection: c,
+            ids,
+        } => Request::MGet {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            ids: ids.into_iter().map(normalize).collect(),
+        },
+        Request::Get {
+            bucket: b,
+            collection: c,
+            id,
+            key,
+        } => Request::Get {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+            key,
+        },
+        Request::Exists {
+            bucket: b,
+            collection: c,
+            id,
+        } => Request::Exists {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+        },
+        Request::GetIf {
+            bucket: b,
+            collection: c,
+            id,
+            since_version,
+        } => Request::GetIf {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+            since_version,
+        },
+        Request::GetRange {
+            bucket: b,
+            collection: c,
+            id,
+            start,
+            end,
+            key,
+        } => Request::GetRange {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+            start,
+            end,
+            key,
+        },
+        Request::Search {
+            bucket: b,
+            collection: c,
+            query,
+            options,
+        } => Request::Search {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            query,
+            options,
+        },
+        Request::SearchPrefix {
+            bucket: b,
+            collection: c,
+            query,
+            options,
+        } => Request::SearchPrefix {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            query,
+            options,
+        },
+        Request::SearchFuzzy {
+            bucket: b,
+            collection: c,
+            max_distance,
+            query,
+            options,
+        } => Request::SearchFuzzy {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            max_distance,
+            query,
+            options,
+        },
+        Request::SearchHighlight {
+            bucket: b,
+            collection: c,
+            query,
+            options,
+        } => Request::SearchHighlight {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            query,
+            options,
+        },
+        Request::MultiSearch {
+            bucket: b,
+            collections,
+            query,
+            options,
+        } => Request::MultiSearch {
+            bucket: bucket(b)?,
+            collections: collections
+                .into_iter()
+                .map(collection)
+                .collect::<Result<_, _>>()?,
+            query,
+            options,
+        },
+        Request::Expire {
+            bucket: b,
+            collection: c,
+            id,
+            seconds,
+        } => Request::Expire {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+            seconds,
+        },
+        Request::Append {
+            bucket: b,
+            collection: c,
+            id,
+            content,
+        } => Request::Append {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+            content,
+        },
+        Request::Incr {
+            bucket: b,
+            collection: c,
+            id,
+            delta,
+        } => Request::Incr {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+            delta,
+        },
+        Request::Remove {
+            bucket: b,
+            collection: c,
+            id,
+        } => Request::Remove {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            id: normalize(id),
+        },
+        Request::Rename {
+            bucket: b,
+            collection: c,
+            old_id,
+            new_id,
+        } => Request::Rename {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            old_id: normalize(old_id),
+            new_id: normalize(new_id),
+        },
+        Request::Copy {
+            bucket: b,
+            collection: c,
+            src_id,
+            dst_id,
+            to_collection,
+            replace,
+        } => Request::Copy {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            src_id: normalize(src_id),
+            dst_id: normalize(dst_id),
+            to_collection: to_collection.map(collection).transpose()?,
+            replace,
+        },
+        Request::DropCollection {
+            bucket: b,
+            collection: c,
+        } => Request::DropCollection {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+        },
+        Request::DropBucket { bucket: b } => Request::DropBucket { bucket: bucket(b)? },
+        Request::ListIds {
+            bucket: b,
+            collection: c,
+            limit,
+        } => Request::ListIds {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            limit,
+        },
+        Request::Export {
+            bucket: b,
+            collection: c,
+        } => Request::Export {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+        },
+        // the ids are inside the content, normalized once it is parsed
+        Request::Import {
+            bucket: b,
+            collection: c,
+            content,
+            replace,
+        } => Request::Import {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            content,
+            replace,
+        },
+        Request::Scan {
+            bucket: b,
+            collection: c,
+            cursor,
+            count,
+        } => Request::Scan {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            cursor,
+            count,
+        },
+        Request::Verify {
+            bucket: b,
+            collection: c,
+        } => Request::Verify {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+        },
+        Request::Reindex {
+            bucket: b,
+            collection: c,
+        } => Request::Reindex {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+        },
+        Request::DumpToken {
+            bucket: b,
+            collection: c,
+            token,
+        } => Request::DumpToken {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            token,
+        },
+        Request::Configure {
+            bucket: b,
+            collection: c,
+            option,
+            value,
+        } => Request::Configure {
+            bucket: bucket(b)?,
+            collection: collection(c)?,
+            option,
+            value,
+        },
+        Request::DryRun(request) => Request::DryRun(Box::new(apply_defaults(*request, config)?)),
+        // left empty rather than filled in, it stands for the configured default
+        Request::Use { bucket } => Request::Use {
+            bucket: normalize(bucket),
+        },
+        request @ (Request::Ping
+        | Request::Health
+        | Request::Auth { .. }
+        | Request::Noop
+        | Request::Sync
+        | Request::Blacklist { .. }
+        | Request::Config { .. }
+        | Request::Save
+        | Request::Stats
+        | Request::Info
+        | Request::SetEngine { .. }) => request,
+    })
+}
+
+fn default_field(
+    value: String,
+    default: &Option<String>,
+    name: &'static str,
+) -> Result<String, HandleError> {
+    if !value.is_empty() {
+        return Ok(value);
+    }
+    default.clone().ok_or(HandleError::NoDefault(name))
+}
+
+/// Interleaves per-collection results rank by rank, so that the best hit of every
+/// collection comes before the second best of any. Hits are tagged `collection/id`.
+fn merge_ranked(per_collection: Vec<(&String, Vec<String>)>) -> Vec<String> {
+    let longest = per_collection
+        .iter()
+        .map(|(_, ids)| ids.len())
+        .max()
+        .unwrap_or(0);
+    let mut merged = Vec::new();
+    for rank in 0..longest {
+        for (collection, ids) in &per_collection {
+            if let Some(id) = ids.get(rank) {
+                merged.push(format!("{}/{}", collection, id));
+            }
+        }
+    }
+    merged
+}
+
+/// Appends the byte offsets of the words matching the query to each id, as rendered by
+/// [`SearchHit`]. Documents removed since the search are dropped from the results.
+fn highlight(
+    storage: &dyn StorageOperations,
+    bucket: &str,
+    collection: &str,
+    query: &str,
+    options: &SearchOptions,
+    ids: Vec<String>,
+) -> Result<Vec<String>, StorageError> {
+    let mut hits = Vec::with_capacity(ids.len());
+    for id in ids {
+        let document = match storage.get_document(bucket, collection, &id) {
+            Ok(document) => document,
+            Err(e) if e.is_not_found() => continue,
+            Err(e) => return Err(e),
+        };
+        let offsets = lang::query::match_offsets(&document.content, query, &options.tokenizer);
+        hits.push(SearchHit { id, offsets }.to_string());
+    }
+    Ok(hits)
+}
+
+/// Appends a snippet of the content around its first match to each id, as `<id> <snippet>`.
+/// Documents removed since the search are dropped from the results.
+fn snippets(
+    storage: &dyn StorageOperations,
+    bucket: &str,
+    collection: &str,
+    query: &str,
+    options: &SearchOptions,
+    ids: Vec<String>,
+) -> Result<Vec<String>, StorageError> {
+    let mut hits = Vec::with_capacity(ids.len());
+    for id in ids {
+        let document = match storage.get_document(bucket, collection, &id) {
+            Ok(document) => document,
+            Err(e) if e.is_not_found() => continue,
+            Err(e) => return Err(e),
+        };
+        let snippet = lang::query::snippet(&document.content, query, &options.tokenizer);
+        hits.push(format!("{} {}", id, snippet));
+    }
+    Ok(hits)
+}
+
+/// Counts the documents containing each token, over the index of every collection.
+fn token_frequencies(
+    storage: &dyn StorageOperationsInternal,
+    search_engine: &dyn SearchEngine,
+) -> Result<HashMap<String, usize>, StorageError> {
+    let mut frequencies = HashMap::new();
+    for bucket in storage.store()?.iter() {
+        for collection in bucket.iter() {
+            let index = search_engine.collection_index(bucket.key(), collection.key())?;
+            for (token, ids) in index {
+                *frequencies.entry(token).or_default() += ids.len();
+            }
+        }
+    }
+    Ok(frequencies)
+}
+
+/// Compares the index of a collection with the tokens of its stored documents.
+///
+/// Read-only, it reports one `missing <id> <token>` or `extra <id> <token>` line per discrepancy,
+/// sorted, and nothing when both agree.
+fn verify(
+    storage: &dyn StorageOperationsInternal,
+    search_engine: &dyn SearchEngine,
+    bucket: &str,
+    collection: &str,
+) -> Result<Vec<String>, StorageError> {
+    let config = storage.collection_config(bucket, collection);
+    let tokenizer = config.tokenizer();
+    let store = storage.store()?;
+
+    let mut expected = HashSet::new();
+    if let Some(bucket_ref) = store.get(bucket)
+        && let Some(documents) = bucket_ref.get(collection)
+    {
+        for document in documents.iter() {
+            let mut tokens = search_engine.tokenize(&document.content, &tokenizer);
+            if tokens.len() < config.min_tokens {
+                continue;
+            }
+            config.cap_tokens(&mut tokens);
+            for token in tokens {
+                expected.insert((document.key().clone(), token));
+            }
+        }
+    }
+
+    let mut actual = HashSet::new();
+    for (token, ids) in search_engine.collection_index(bucket, collection)? {
+        for id in ids {
+            actual.insert((id, token.clone()));
+        }
+    }
+
+    let mut report: Vec<String> = expected
+        .difference(&actual)
+        .map(|(id, token)| format!("missing {} {}", id, token))
+        .chain(
+            actual
+                .difference(&expected)
+                .map(|(id, token)| format!("extra {} {}", id, token)),
+        )
+        .collect();
+    report.sort();
+    Ok(report)
+}
+
+/// Computes the set of documents a destructive command would affect, without applying it.
+///
+/// The report is an array where the first item is the number of affected documents,
+/// followed by up to `DRY_RUN_SAMPLE_SIZE` of their ids.
+fn dry_run(request: Request, storage: &Storage) -> Result<Response, HandleError> {
+    let affected: Vec<String> = match request {
+        Request::Remove {
+            bucket,
+            collection,
+            id,
+        } => match storage.get_document(&bucket, &collection, &id) {
+            Ok(document) => vec![document.id],
+            Err(e) if e.is_not_found() => vec![],
+            Err(e) => return Err(HandleError::Storage(e)),
+        },
+        Request::DropBucket { bucket } => storage
+            .store
+            .get(&bucket)
+            .map(|bucket| {
+                bucket
+                    .iter()
+                    .flat_map(|collection| {
+                        collection
+                            .iter()
+                            .map(|entry| entry.key().clone())
+                            .collect::<Vec<_>>()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Request::DropCollection { bucket, collection } => storage
+            .store
+            .get(&bucket)
+            .and_then(|bucket| {
+                bucket
+                    .get(&collection)
+                    .map(|collection| collection.iter().map(|entry| entry.key().clone()).collect())
+            })
+            .unwrap_or_default(),
+        _ => {
+            return Err(HandleError::Unsupported(
+                "dry-run is only available for destructive commands".to_string(),
+            ))
+        }
+    };
+
+    let mut report = vec![affected.len().to_string()];
+    report.extend(affected.into_iter().take(DRY_RUN_SAMPLE_SIZE));
+    Ok(Response::Array(report))
+}
@@ -0,0 +1,94 @@
+use crate::search::DynSearchEngine;
+use crate::storage::{Storage, StorageError, StorageOperations};
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use tokio::sync::watch;
+
+/// Document waiting to be indexed, its content is read from storage when the job runs.
+struct IndexJob {
+    bucket: String,
+    collection: String,
+    id: String,
+}
+
+/// Indexes documents stored by `SET` on a background thread, see [`ZzapConfig::async_indexing`].
+///
+/// [`ZzapConfig::async_indexing`]: crate::config::ZzapConfig::async_indexing
+pub struct IndexQueue {
+    jobs: mpsc::Sender<IndexJob>,
+    /// Number of jobs enqueued but not indexed yet
+    pending: Arc<watch::Sender<usize>>,
+}
+
+impl IndexQueue {
+    /// Starts the worker, which stops once the queue is dropped.
+    pub fn new(storage: Arc<Storage>, search_engine: Arc<RwLock<DynSearchEngine>>) -> Self {
+        let (jobs, receiver) = mpsc::channel::<IndexJob>();
+        let pending = Arc::new(watch::Sender::new(0));
+
+        let worker_pending = pending.clone();
+        thread::spawn(move || {
+            for job in receiver {
+                if let Err(e) = index_stored(&storage, &search_engine, &job) {
+                    tracing::error!(
+                        "Error indexing {}/{}/{}: {}",
+                        job.bucket,
+                        job.collection,
+                        job.id,
+                        e
+                    );
+                }
+                worker_pending.send_modify(|pending| *pending -= 1);
+            }
+        });
+
+        Self { jobs, pending }
+    }
+
+    pub fn push(&self, bucket: &str, collection: &str, id: &str) {
+        self.pending.send_modify(|pending| *pending += 1);
+        let job = IndexJob {
+            bucket: bucket.to_string(),
+            collection: collection.to_string(),
+            id: id.to_string(),
+        };
+        if self.jobs.send(job).is_err() {
+            // the worker is gone, nothing will ever index the job
+            self.pending.send_modify(|pending| *pending -= 1);
+        }
+    }
+
+    /// Resolves once every job enqueued so far has been indexed.
+    pub async fn sync(&self) {
+        let mut pending = self.pending.subscribe();
+        // only fails when the sender is dropped, which `self` prevents
+        let _ = pending.wait_for(|pending| *pending == 0).await;
+    }
+}
+
+fn index_stored(
+    storage: &Storage,
+    search_engine: &RwLock<DynSearchEngine>,
+    job: &IndexJob,
+) -> Result<(), StorageError> {
+    let bucket_lock = storage.bucket_lock(&job.bucket);
+    // exclusive, so no `SET` or `REMOVE` of the document can interleave with its indexing
+    let _bucket_guard = bucket_lock.write().map_err(|_| StorageError::PoisonError)?;
+    let search_engine = search_engine
+        .read()
+        .map_err(|_| StorageError::PoisonError)?;
+
+    match storage.get_document(&job.bucket, &job.collection, &job.id) {
+        Ok(document) => search_engine.index(
+            storage,
+            &job.bucket,
+            &job.collection,
+            &job.id,
+            &document.content,
+        ),
+        // removed before its turn came
+        Err(e) if e.is_not_found() => Ok(()),
+        Err(e) => Err(e),
+    }
+}
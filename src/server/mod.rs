@@ -1,22 +1,110 @@
 mod connection;
+mod frame;
 pub mod handler;
+pub mod indexer;
 #[cfg(test)]
 mod test;
+mod tls;
 
+use crate::config::ZzapConfig;
 use crate::encryption::MockEncryptor;
-use crate::search::StdSearchEngine;
-use crate::storage::Storage;
+use crate::protocol::{Message, Response};
+use crate::search::DynSearchEngine;
+use crate::storage::{Storage, StorageError, StorageOperations};
+use indexer::IndexQueue;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::RwLock as SyncRwLock;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::sync::RwLock as AsyncRwLock;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, MissedTickBehavior};
+use tracing::Instrument;
+
+/// How often expired documents are purged, those read in between are purged on the spot.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest time a change of [`ZzapConfig::persist_interval`] takes to be noticed.
+const AUTOSAVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Time a client has to complete the TLS handshake, so stalled ones do not delay the shutdown
+/// for longer than that.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after failing to accept a connection, so running out of file descriptors does not spin
+/// the accepting loop.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 pub struct ZzapServer {
     addr: SocketAddr,
-    storage: Arc<SyncRwLock<Storage>>,
+    shared: Shared,
+}
+
+/// What every connection shares with the server, cloned for each of them.
+#[derive(Clone)]
+struct Shared {
+    /// Synchronizes itself, connections share it without a lock of their own
+    storage: Arc<Storage>,
     encryption: Arc<MockEncryptor>,
-    search_engine: Arc<SyncRwLock<StdSearchEngine>>,
+    /// Engines synchronize their index themselves, the lock is only taken exclusively by
+    /// `SETENGINE` to swap in another engine
+    search_engine: Arc<SyncRwLock<DynSearchEngine>>,
+    /// Shared by every connection, `CONFIG SET` changes apply to all of them
+    config: Arc<SyncRwLock<ZzapConfig>>,
+    indexer: Option<Arc<IndexQueue>>,
+    status: Arc<ServerStatus>,
+}
+
+/// How the server is doing, as reported by `INFO` and `STATS`.
+pub struct ServerStatus {
+    /// When the server was created, `INFO` reports the uptime from it
+    pub started_at: Instant,
+    /// Connections being served, those turned away over the limit are not counted
+    pub connections: AtomicUsize,
+    /// Set once storage and index are loaded and connections are accepted, cleared on shutdown
+    pub ready: AtomicBool,
+    /// Index rebuilds in progress, `HEALTH` reports not ready while there is any
+    pub rebuilding: AtomicUsize,
+}
+
+impl ServerStatus {
+    /// Whether the server can serve requests as usual, with the reason when it cannot.
+    pub fn health(&self) -> Result<(), &'static str> {
+        if !self.ready.load(Ordering::Acquire) {
+            return Err("starting or shutting down");
+        }
+        if self.rebuilding.load(Ordering::Acquire) > 0 {
+            return Err("rebuilding the index");
+        }
+        Ok(())
+    }
+
+    /// Counts an index rebuild as in progress until the returned guard is dropped.
+    pub fn rebuild(&self) -> RebuildGuard<'_> {
+        self.rebuilding.fetch_add(1, Ordering::AcqRel);
+        RebuildGuard(self)
+    }
+}
+
+impl Default for ServerStatus {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            connections: AtomicUsize::new(0),
+            ready: AtomicBool::new(false),
+            rebuilding: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// An index rebuild in progress, see [`ServerStatus::rebuild`].
+pub struct RebuildGuard<'a>(&'a ServerStatus);
+
+impl Drop for RebuildGuard<'_> {
+    fn drop(&mut self) {
+        self.0.rebuilding.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 impl ZzapServer {
@@ -24,35 +112,247 @@ impl ZzapServer {
         addr: SocketAddr,
         storage: Storage,
         encryption: MockEncryptor,
-        search_engine: StdSearchEngine,
+        search_engine: DynSearchEngine,
+        config: ZzapConfig,
     ) -> Self {
+        let storage = Arc::new(storage);
+        let search_engine = Arc::new(SyncRwLock::new(search_engine));
+        let indexer = config
+            .async_indexing
+            .then(|| Arc::new(IndexQueue::new(storage.clone(), search_engine.clone())));
         Self {
             addr,
-            storage: Arc::new(SyncRwLock::new(storage)),
-            encryption: Arc::new(encryption),
-            search_engine: Arc::new(SyncRwLock::new(search_engine)),
+            shared: Shared {
+                storage,
+                encryption: Arc::new(encryption),
+                search_engine,
+                config: Arc::new(SyncRwLock::new(config)),
+                indexer,
+                status: Arc::new(ServerStatus::default()),
+            },
         }
     }
 
+    /// Serves until the process receives SIGINT or SIGTERM, see [`ZzapServer::serve`].
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(self.addr).await?;
+        self.serve(listener, shutdown_signal()).await
+    }
+
+    /// Accepts connections until `shutdown` resolves, then shuts down gracefully: connections
+    /// finish the request they are handling and are closed, and the storage is persisted once
+    /// they are all gone.
+    ///
+    /// Connections are encrypted with TLS when the configuration names a certificate and key.
+    /// Past the configured maximum of connections, new ones are answered with an error and closed.
+    /// Failing to accept a connection is logged, and accepting resumes after a short pause.
+    pub async fn serve(
+        &self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (stop, stopped) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        let (tls, connection_limit) = {
+            let config = self.shared.config.read().unwrap_or_else(|e| e.into_inner());
+            let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+                (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+                _ => None,
+            };
+            let connection_limit = config
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max)));
+            (tls, connection_limit)
+        };
+        let persisting = tokio::spawn(persist_periodically(
+            self.shared.storage.clone(),
+            self.shared.config.clone(),
+        ));
+        let sweeping = tokio::spawn(sweep_expired(
+            self.shared.storage.clone(),
+            self.shared.search_engine.clone(),
+        ));
+        // storage and index are loaded before the server is created
+        self.shared.status.ready.store(true, Ordering::Release);
 
         loop {
-            let (socket, _) = listener.accept().await?;
+            let (socket, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    // the listener still works, and leaving the loop would skip the final persist
+                    Err(e) => {
+                        tracing::error!("Error accepting connection: {}", e);
+                        tokio::select! {
+                            _ = time::sleep(ACCEPT_RETRY_DELAY) => continue,
+                            _ = &mut shutdown => break,
+                        }
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
 
-            let socket = Arc::new(AsyncRwLock::new(socket));
-            let storage = self.storage.clone();
-            let encryption = self.encryption.clone();
-            let search_engine = self.search_engine.clone();
+            let shared = self.shared.clone();
+            let stopped = stopped.clone();
+            // taken before spawning, so connections past the limit never hold a permit
+            let permit = connection_limit
+                .as_ref()
+                .map(|limit| limit.clone().try_acquire_owned())
+                .transpose();
 
-            let mut conn = connection::Connection::new(socket, storage, encryption, search_engine);
+            match tls.clone() {
+                None => connections.spawn(admit(socket, peer, shared, stopped, permit)),
+                // the handshake takes round trips, keep it off the accepting loop
+                Some(acceptor) => connections.spawn(async move {
+                    match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                        Ok(Ok(stream)) => admit(stream, peer, shared, stopped, permit).await,
+                        Ok(Err(e)) => tracing::warn!(%peer, "Error in TLS handshake: {}", e),
+                        Err(_) => tracing::warn!(%peer, "TLS handshake not completed in time"),
+                    }
+                }),
+            };
+            // forget connections closed since, so the set only holds open ones
+            while connections.try_join_next().is_some() {}
+        }
 
-            // TODO: double spawn?
-            tokio::spawn(async move {
-                if let Err(e) = conn.handle().await {
-                    eprintln!("Error handling connection: {}", e);
+        tracing::info!("zzap server shutting down");
+        self.shared.status.ready.store(false, Ordering::Release);
+        persisting.abort();
+        sweeping.abort();
+        drop(listener);
+        // the receivers are only dropped with the connections, which are awaited below
+        let _ = stop.send(true);
+        while connections.join_next().await.is_some() {}
+
+        persist(self.shared.storage.clone()).await?;
+        Ok(())
+    }
+}
+
+/// Serves a client holding a permit until it leaves, or tells it that the server is at its
+/// maximum of connections and closes the connection.
+async fn admit<S>(
+    mut stream: S,
+    peer: SocketAddr,
+    shared: Shared,
+    shutdown: watch::Receiver<bool>,
+    permit: Result<Option<OwnedSemaphorePermit>, TryAcquireError>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    let Ok(_permit) = permit else {
+        tracing::warn!(%peer, "Rejecting connection, the maximum of connections is reached");
+        let response = Response::Error("too many connections".to_string());
+        let _ = stream.write_all(&response.to_bytes()).await;
+        let _ = stream.shutdown().await;
+        return;
+    };
+    serve_client(stream, peer, shared, shutdown).await;
+}
+
+/// Handles the requests of a client until it leaves or the server shuts down, within a span
+/// identifying the client by its address.
+async fn serve_client<S>(
+    stream: S,
+    peer: SocketAddr,
+    shared: Shared,
+    shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    let span = tracing::info_span!("connection", %peer);
+    async move {
+        tracing::debug!("Connection opened");
+        let status = shared.status.clone();
+        status.connections.fetch_add(1, Ordering::Relaxed);
+        let mut conn = connection::Connection::new(stream, shared, shutdown);
+        if let Err(e) = conn.handle().await {
+            tracing::warn!("Error handling connection: {}", e);
+        }
+        status.connections.fetch_sub(1, Ordering::Relaxed);
+        tracing::debug!("Connection closed");
+    }
+    .instrument(span)
+    .await
+}
+
+/// Persists the storage every [`ZzapConfig::persist_interval`], never while it is `None`, until
+/// the task is aborted.
+///
+/// The interval is read again every [`AUTOSAVE_CHECK_INTERVAL`] at most, so `CONFIG SET
+/// AUTOSAVEINTERVAL` applies without a restart, and counts from the end of the last save. A save
+/// taking longer than the interval delays the next one rather than overlapping it. Errors are
+/// logged and the next save is attempted on schedule.
+async fn persist_periodically(storage: Arc<Storage>, config: Arc<SyncRwLock<ZzapConfig>>) {
+    // there is nothing new to save yet
+    let mut saved = time::Instant::now();
+
+    loop {
+        let interval = config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .persist_interval;
+        let due = interval.map(|interval| saved + interval);
+        let check = time::Instant::now() + AUTOSAVE_CHECK_INTERVAL;
+        time::sleep_until(due.map_or(check, |due| due.min(check))).await;
+
+        if due.is_some_and(|due| due <= time::Instant::now()) {
+            if let Err(e) = persist(storage.clone()).await {
+                tracing::error!("Error persisting storage: {}", e);
+            }
+            saved = time::Instant::now();
+        }
+    }
+}
+
+/// Persists the storage on a blocking thread, writing and syncing a large snapshot would hold up
+/// the other tasks of the worker otherwise.
+pub(crate) async fn persist(storage: Arc<Storage>) -> Result<(), StorageError> {
+    task::spawn_blocking(move || storage.persist())
+        .await
+        .map_err(|e| StorageError::OperationFailed(e.to_string()))?
+}
+
+/// Purges expired documents every [`EXPIRY_SWEEP_INTERVAL`], until the task is aborted.
+async fn sweep_expired(storage: Arc<Storage>, search_engine: Arc<SyncRwLock<DynSearchEngine>>) {
+    let mut ticks = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+        let storage = storage.clone();
+        let search_engine = search_engine.clone();
+        let purged =
+            task::spawn_blocking(move || handler::purge_expired(&storage, &search_engine)).await;
+        match purged {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => tracing::error!("Error purging expired documents: {}", e),
+            Err(e) => tracing::error!("Error purging expired documents: {}", e),
+        }
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
                 }
-            });
+            }
+            Err(e) => {
+                tracing::error!("Error listening for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
         }
     }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
@@ -1,42 +1,79 @@
+use crate::config::{KeyNormalization, ZzapConfig};
 use crate::encryption::{Encryption, MockEncryptor};
 use crate::protocol::{Message, Request, Response};
-use crate::search::StdSearchEngine;
-use crate::server::handler::{handle_request, HandleError};
-use crate::storage::{EntityType, Storage, StorageError};
+use crate::search::{
+    engine_by_name, BTreeSearchEngine, Bm25SearchEngine, CollectionIndex, Dash2SearchEngine,
+    DashSearchEngine, DynSearchEngine, NgramSearchEngine, SearchEngine, SearchOptions,
+    StdSearchEngine, DEFAULT_LIMIT,
+};
+use crate::server::handler::{
+    build_engine, catch_up, handle_request, purge_expired, set_document, set_documents, HandleError,
+};
+use crate::server::indexer::IndexQueue;
+use crate::server::{ServerStatus, ZzapServer};
+use crate::storage::mock::MockStorage;
+use crate::storage::{Document, EntityType, Storage, StorageError, StorageOperations};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+fn std_engine() -> Arc<RwLock<DynSearchEngine>> {
+    Arc::new(RwLock::new(Box::new(StdSearchEngine::new())))
+}
 
 #[track_caller]
 async fn command_predicate(
-    storage: &Arc<RwLock<Storage>>,
+    storage: &Arc<Storage>,
     encryptor: &MockEncryptor,
-    search_engine: &Arc<RwLock<StdSearchEngine>>,
+    search_engine: &Arc<RwLock<DynSearchEngine>>,
     command: &str,
     predicate: impl Fn(Result<Response, HandleError>) -> bool,
 ) {
     let request = Request::from_bytes(command.as_bytes()).unwrap();
-    let result = handle_request(request, storage, encryptor, search_engine).await;
+    let result = handle_request(
+        request,
+        storage,
+        encryptor,
+        search_engine,
+        &RwLock::new(ZzapConfig::default()),
+        None,
+        &ServerStatus::default(),
+    )
+    .await;
 
     assert!(predicate(result));
 }
 
 async fn command(
-    storage: &Arc<RwLock<Storage>>,
+    storage: &Arc<Storage>,
     encryptor: &MockEncryptor,
-    search_engine: &Arc<RwLock<StdSearchEngine>>,
+    search_engine: &Arc<RwLock<DynSearchEngine>>,
     command: &str,
     expected: Result<Response, HandleError>,
 ) {
     let request = Request::from_bytes(command.as_bytes()).unwrap();
-    let result = handle_request(request, storage, encryptor, search_engine).await;
+    let result = handle_request(
+        request,
+        storage,
+        encryptor,
+        search_engine,
+        &RwLock::new(ZzapConfig::default()),
+        None,
+        &ServerStatus::default(),
+    )
+    .await;
 
     assert_eq!(result, expected);
 }
 
 #[tokio::test]
 async fn simple() {
-    let storage = Arc::new(RwLock::new(Storage::new("test.db")));
+    let storage = Arc::new(Storage::new("test.db"));
     let encryptor = MockEncryptor;
-    let search_engine = Arc::new(RwLock::new(StdSearchEngine::new()));
+    let search_engine = std_engine();
 
     command(
         &storage,
@@ -97,9 +134,9 @@ async fn simple() {
 
 #[tokio::test]
 async fn index_cleans_properly() {
-    let storage = Arc::new(RwLock::new(Storage::new("test.db")));
+    let storage = Arc::new(Storage::new("test.db"));
     let encryptor = MockEncryptor;
-    let search_engine = Arc::new(RwLock::new(StdSearchEngine::new()));
+    let search_engine = std_engine();
 
     command(
         &storage,
@@ -197,9 +234,9 @@ async fn index_cleans_properly() {
 
 #[tokio::test]
 async fn with_encryption() {
-    let storage = Arc::new(RwLock::new(Storage::new("test.db")));
+    let storage = Arc::new(Storage::new("test.db"));
     let encryptor = MockEncryptor;
-    let search_engine = Arc::new(RwLock::new(StdSearchEngine::new()));
+    let search_engine = std_engine();
 
     let id = "1".to_string();
     let data = "test_article".to_string();
@@ -233,3 +270,3534 @@ async fn with_encryption() {
     )
     .await;
 }
+
+#[tokio::test]
+async fn dry_run_does_not_mutate() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SET default articles 1 13:first article",
+        Ok(Response::Success),
+    )
+    .await;
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "DRYRUN REMOVE default articles 1",
+        Ok(Response::Array(vec!["1".to_string(), "1".to_string()])),
+    )
+    .await;
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "DRYRUN REMOVE default articles 2",
+        Ok(Response::Array(vec!["0".to_string()])),
+    )
+    .await;
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "GET default articles 1",
+        Ok(Response::BulkString("first article".to_string())),
+    )
+    .await;
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SEARCH default articles first",
+        Ok(Response::Array(vec!["1".to_string()])),
+    )
+    .await;
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "DRYRUN GET default articles 1",
+        Err(HandleError::Unsupported(
+            "dry-run is only available for destructive commands".to_string(),
+        )),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn search_with_id_prefix() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for id in ["user:1:post:1", "user:12:post:1", "user:2:post:1"] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SET default posts {id} 5:hello"),
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SEARCH default posts IDPREFIX user:1: hello",
+        Ok(Response::Array(vec!["user:1:post:1".to_string()])),
+    )
+    .await;
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SEARCH default posts IDPREFIX user:3: hello",
+        Ok(Response::Array(vec![])),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn search_json_array_field() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        let cases = vec![
+            (format!("SETENGINE {}", name), Ok(Response::Success)),
+            (
+                r#"SET default posts 1 31:{"tags":["rust","db","search"]}"#.to_string(),
+                Ok(Response::Success),
+            ),
+            (
+                "SEARCH default posts tags:db".to_string(),
+                Ok(Response::Array(vec!["1".to_string()])),
+            ),
+            (
+                "SEARCH default posts tags:go".to_string(),
+                Ok(Response::Array(vec![])),
+            ),
+            // overwriting the document drops every token of the old array
+            (
+                r#"SET default posts 1 15:{"tags":["go"]}"#.to_string(),
+                Ok(Response::Success),
+            ),
+            (
+                "SEARCH default posts tags:db".to_string(),
+                Ok(Response::Array(vec![])),
+            ),
+            (
+                "SEARCH default posts tags:rust".to_string(),
+                Ok(Response::Array(vec![])),
+            ),
+            (
+                "SEARCH default posts tags:go".to_string(),
+                Ok(Response::Array(vec!["1".to_string()])),
+            ),
+        ];
+
+        for (cmd, expected) in cases {
+            command(&storage, &encryptor, &search_engine, &cmd, expected).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn search_json_nested_field() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let doc1 = r#"{"title":"rust","author":{"name":"ada"}}"#;
+    let doc2 = r#"{"title":"ada","body":"rust"}"#;
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        let cases = vec![
+            (format!("SETENGINE {}", name), Ok(Response::Success)),
+            (
+                format!("SET default posts 1 {}:{}", doc1.len(), doc1),
+                Ok(Response::Success),
+            ),
+            (
+                format!("SET default posts 2 {}:{}", doc2.len(), doc2),
+                Ok(Response::Success),
+            ),
+            (
+                "SET default posts 3 12:rust and ada".to_string(),
+                Ok(Response::Success),
+            ),
+            (
+                "SEARCH default posts title:rust".to_string(),
+                Ok(Response::Array(vec!["1".to_string()])),
+            ),
+            (
+                "SEARCH default posts body:rust".to_string(),
+                Ok(Response::Array(vec!["2".to_string()])),
+            ),
+            (
+                "SEARCH default posts author.name:ada".to_string(),
+                Ok(Response::Array(vec!["1".to_string()])),
+            ),
+            (
+                "SEARCH default posts author:ada".to_string(),
+                Ok(Response::Array(vec![])),
+            ),
+        ];
+
+        for (cmd, expected) in cases {
+            command(&storage, &encryptor, &search_engine, &cmd, expected).await;
+        }
+
+        // unscoped terms match every field and plain documents alike
+        command_predicate(
+            &storage,
+            &encryptor,
+            &search_engine,
+            "SEARCH default posts rust",
+            |result| match result {
+                Ok(Response::Array(mut ids)) => {
+                    ids.sort();
+                    ids == ["1", "2", "3"]
+                }
+                _ => false,
+            },
+        )
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn search_multiple_collections() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for (collection, id) in [("posts", "1"), ("comments", "7")] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SET default {collection} {id} 11:hello world"),
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SEARCH default posts,comments hello",
+        Ok(Response::Array(vec![
+            "posts/1".to_string(),
+            "comments/7".to_string(),
+        ])),
+    )
+    .await;
+
+    // collections that don't exist are skipped
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SEARCH default missing,comments world",
+        Ok(Response::Array(vec!["comments/7".to_string()])),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn save_makes_data_recoverable() {
+    const PERSISTENCE_PATH: &str = "test_save.db";
+
+    let storage = Arc::new(Storage::new(PERSISTENCE_PATH));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SET default articles 1 5:saved",
+        Ok(Response::Success),
+    )
+    .await;
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SAVE",
+        Ok(Response::Success),
+    )
+    .await;
+
+    // no shutdown of the first storage, the file must already be complete
+    let mut recovered = Storage::new(PERSISTENCE_PATH);
+    recovered.initialize().unwrap();
+    std::fs::remove_file(PERSISTENCE_PATH).unwrap();
+    std::fs::remove_file("test_save.zzap_collections").unwrap();
+
+    let document = recovered.get_document("default", "articles", "1").unwrap();
+    assert_eq!(document.content, "saved");
+}
+
+#[tokio::test]
+async fn persist_is_an_alias_of_save() {
+    const PERSISTENCE_PATH: &str = "test_persist.db";
+
+    let storage = Arc::new(Storage::new(PERSISTENCE_PATH));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SET b c 1 9:persisted",
+        Ok(Response::Success),
+    )
+    .await;
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "PERSIST",
+        Ok(Response::Success),
+    )
+    .await;
+
+    let mut recovered = Storage::new(PERSISTENCE_PATH);
+    recovered.initialize().unwrap();
+    std::fs::remove_file(PERSISTENCE_PATH).unwrap();
+    std::fs::remove_file("test_persist.zzap_collections").unwrap();
+
+    let document = recovered.get_document("b", "c", "1").unwrap();
+    assert_eq!(document.content, "persisted");
+}
+
+#[tokio::test]
+async fn save_does_not_hold_up_other_requests() {
+    const PERSISTENCE_PATH: &str = "test_save_concurrent.db";
+    const DOCUMENTS: usize = 20_000;
+
+    let storage = Arc::new(Storage::new(PERSISTENCE_PATH));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    for i in 0..DOCUMENTS {
+        let document = Document::new(&i.to_string(), &format!("document number {}", i).repeat(10));
+        storage.add_document("b", "c", document).unwrap();
+    }
+
+    let save = tokio::spawn({
+        let storage = storage.clone();
+        let search_engine = search_engine.clone();
+        async move {
+            handle_request(
+                Request::Save,
+                &storage,
+                &MockEncryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            )
+            .await
+        }
+    });
+    // lets the save start, the runtime has a single thread so it would run to the end if it blocked
+    tokio::task::yield_now().await;
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SET b c new 5:hello",
+        Ok(Response::Success),
+    )
+    .await;
+    assert!(!save.is_finished());
+    assert_eq!(save.await.unwrap(), Ok(Response::Success));
+
+    let mut recovered = Storage::new(PERSISTENCE_PATH);
+    recovered.initialize().unwrap();
+    std::fs::remove_file(PERSISTENCE_PATH).unwrap();
+    std::fs::remove_file("test_save_concurrent.zzap_collections").unwrap();
+    assert!(recovered.get_document("b", "c", "0").is_ok());
+}
+
+#[tokio::test]
+async fn abbreviated_commands_use_configured_defaults() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let config = RwLock::new(ZzapConfig {
+        default_bucket: Some("default".to_string()),
+        default_collection: Some("posts".to_string()),
+        ..Default::default()
+    });
+
+    let cases = vec![
+        ("SET _ _ 1 5:hello", Ok(Response::Success)),
+        (
+            "GET default posts 1",
+            Ok(Response::BulkString("hello".to_string())),
+        ),
+        (
+            "GET _ posts 1",
+            Ok(Response::BulkString("hello".to_string())),
+        ),
+        (
+            "SEARCH _ _ hello",
+            Ok(Response::Array(vec!["1".to_string()])),
+        ),
+        ("SET _ other 2 5:hello", Ok(Response::Success)),
+        (
+            "SEARCH _ other,_ hello",
+            Ok(Response::Array(vec![
+                "other/2".to_string(),
+                "posts/1".to_string(),
+            ])),
+        ),
+        ("REMOVE _ other 2", Ok(Response::Success)),
+        ("REMOVE _ _ 1", Ok(Response::Success)),
+        (
+            "GET default posts 1",
+            Err(HandleError::Storage(StorageError::NotFound(
+                EntityType::Bucket,
+            ))),
+        ),
+    ];
+
+    for (command, expected) in cases {
+        let request = Request::from_bytes(command.as_bytes()).unwrap();
+        let result = handle_request(
+            request,
+            &storage,
+            &encryptor,
+            &search_engine,
+            &config,
+            None,
+            &ServerStatus::default(),
+        )
+        .await;
+        assert_eq!(result, expected, "{}", command);
+    }
+
+    // without configured defaults abbreviated commands are rejected
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "GET _ posts 1",
+        Err(HandleError::NoDefault("bucket")),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn short_documents_are_stored_but_not_indexed() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let cases = vec![
+        ("CONFIGURE default notes MINTOKENS 3", Ok(Response::Success)),
+        ("SET default notes 1 8:hi there", Ok(Response::Success)),
+        (
+            "SET default notes 2 14:hi there folks",
+            Ok(Response::Success),
+        ),
+        (
+            "GET default notes 1",
+            Ok(Response::BulkString("hi there".to_string())),
+        ),
+        (
+            "SEARCH default notes hi",
+            Ok(Response::Array(vec!["2".to_string()])),
+        ),
+        (
+            "CONFIGURE default notes MINTOKENS few",
+            Err(HandleError::InvalidArgument(
+                "invalid value few for MINTOKENS".to_string(),
+            )),
+        ),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn long_documents_are_indexed_up_to_the_cap() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let found = || Ok(Response::Array(vec!["1".to_string()]));
+
+    let cases = vec![
+        ("CONFIGURE default logs MAXTOKENS 2", Ok(Response::Success)),
+        (
+            "SET default logs 1 22:alpha beta gamma delta",
+            Ok(Response::Success),
+        ),
+        (
+            "GET default logs 1",
+            Ok(Response::BulkString("alpha beta gamma delta".to_string())),
+        ),
+        ("SEARCH default logs alpha", found()),
+        ("SEARCH default logs beta", found()),
+        ("SEARCH default logs gamma", Ok(Response::Array(vec![]))),
+        ("VERIFY default logs", Ok(Response::Array(vec![]))),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn set_engine_reindexes_documents() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let cases = vec![
+        ("SET default posts 1 11:hello world", Ok(Response::Success)),
+        ("SETENGINE btree", Ok(Response::Success)),
+        (
+            "SEARCH default posts world",
+            Ok(Response::Array(vec!["1".to_string()])),
+        ),
+        ("SET default posts 2 11:hello again", Ok(Response::Success)),
+        (
+            "SEARCH default posts again",
+            Ok(Response::Array(vec!["2".to_string()])),
+        ),
+        (
+            "SETENGINE lucene",
+            Err(HandleError::InvalidArgument(
+                "unknown engine lucene".to_string(),
+            )),
+        ),
+        ("SETENGINE dash2", Ok(Response::Success)),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+
+    command_predicate(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SEARCH default posts hello",
+        |result| match result {
+            Ok(Response::Array(mut ids)) => {
+                ids.sort();
+                ids == ["1", "2"]
+            }
+            _ => false,
+        },
+    )
+    .await;
+}
+
+#[test]
+fn catch_up_indexes_writes_since_build() {
+    let storage = Storage::new("test.db");
+    let old_engine = StdSearchEngine::new();
+    for (id, content) in [
+        ("1", "hello world"),
+        ("2", "hello again"),
+        ("3", "hello there"),
+    ] {
+        set_document(&storage, &old_engine, "b", "c", Document::new(id, content)).unwrap();
+    }
+
+    let engine = BTreeSearchEngine::new();
+    let versions = build_engine(&storage, &engine).unwrap();
+    // written while the engine was being built
+    set_document(
+        &storage,
+        &old_engine,
+        "b",
+        "c",
+        Document::new("1", "goodbye world"),
+    )
+    .unwrap();
+    storage.delete_document("b", "c", "2").unwrap();
+    set_document(
+        &storage,
+        &old_engine,
+        "b",
+        "c",
+        Document::new("4", "hello later"),
+    )
+    .unwrap();
+    catch_up(&storage, &engine, versions).unwrap();
+
+    let search = |query: &str| {
+        let mut ids = engine.search("b", "c", query).unwrap();
+        ids.sort();
+        ids
+    };
+    assert_eq!(search("hello"), ["3", "4"]);
+    assert_eq!(search("goodbye"), ["1"]);
+    assert!(search("again").is_empty());
+}
+
+#[tokio::test]
+async fn info_reports_server() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let config = RwLock::new(ZzapConfig::default());
+    let status = ServerStatus {
+        started_at: Instant::now() - std::time::Duration::from_secs(90),
+        ..Default::default()
+    };
+
+    for request in [
+        "SET b c 1 5:hello",
+        "SET b other 1 5:hello",
+        "SETENGINE btree",
+    ] {
+        let request = Request::from_bytes(request.as_bytes()).unwrap();
+        let response = handle_request(
+            request,
+            &storage,
+            &encryptor,
+            &search_engine,
+            &config,
+            None,
+            &status,
+        )
+        .await;
+        assert_eq!(response, Ok(Response::Success));
+    }
+
+    let response = handle_request(
+        Request::Info,
+        &storage,
+        &encryptor,
+        &search_engine,
+        &config,
+        None,
+        &status,
+    )
+    .await;
+    let Ok(Response::BulkString(info)) = response else {
+        panic!("unexpected response {:?}", response);
+    };
+    let info: Vec<(&str, &str)> = info
+        .lines()
+        .map(|line| line.split_once(' ').unwrap())
+        .collect();
+    let keys: Vec<&str> = info.iter().map(|(key, _)| *key).collect();
+    assert_eq!(
+        keys,
+        [
+            "version",
+            "uptime",
+            "addr",
+            "engine",
+            "persistence_path",
+            "documents"
+        ]
+    );
+    let value = |key: &str| info.iter().find(|(k, _)| *k == key).unwrap().1;
+
+    let version: Vec<u64> = value("version")
+        .split('.')
+        .map(|part| part.parse().unwrap())
+        .collect();
+    assert_eq!(version.len(), 3);
+    assert!(value("uptime").parse::<u64>().unwrap() >= 90);
+    assert_eq!(value("addr"), "0.0.0.0:13413");
+    assert_eq!(value("engine"), "btree");
+    assert_eq!(value("persistence_path"), "storage.db");
+    assert_eq!(value("documents"), "2");
+}
+
+#[tokio::test]
+async fn stats_count_stored_and_indexed_data() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let stats = |tokens: usize| {
+        Ok(Response::Array(vec![
+            "buckets 1".to_string(),
+            "collections 2".to_string(),
+            "documents 3".to_string(),
+            format!("tokens {}", tokens),
+            "memory 30".to_string(),
+            "connections 0".to_string(),
+        ]))
+    };
+    for cmd in [
+        "SET b c 1 11:hello world",
+        "SET b c 2 11:hello there",
+        "SET b other 1 5:hello",
+    ] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            cmd,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    // "hello", "world" and "there" in one collection, "hello" again in the other
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SETENGINE {}", name),
+            Ok(Response::Success),
+        )
+        .await;
+        command(&storage, &encryptor, &search_engine, "STATS", stats(4)).await;
+    }
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "REMOVE b c 2",
+        Ok(Response::Success),
+    )
+    .await;
+    command_predicate(&storage, &encryptor, &search_engine, "STATS", |result| {
+        matches!(result, Ok(Response::Array(stats)) if stats[2] == "documents 2" && stats[3] == "tokens 3")
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn search_accent_insensitive_when_configured() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let found = || Ok(Response::Array(vec!["1".to_string()]));
+
+    let cases = vec![
+        (
+            "CONFIGURE default enabled DEACCENT true",
+            Ok(Response::Success),
+        ),
+        ("SET default enabled 1 8:résumé", Ok(Response::Success)),
+        ("SET default disabled 1 8:résumé", Ok(Response::Success)),
+        ("SEARCH default enabled resume", found()),
+        ("SEARCH default enabled résumé", found()),
+        (
+            "SEARCH default disabled resume",
+            Ok(Response::Array(vec![])),
+        ),
+        ("SEARCH default disabled résumé", found()),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn stop_words_are_neither_indexed_nor_searched() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let found = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    let cases = vec![
+        ("CONFIGURE b c STOPWORDS true", Ok(Response::Success)),
+        ("SET b c 1 11:The cat sat", Ok(Response::Success)),
+        ("SET b other 1 11:The cat sat", Ok(Response::Success)),
+        ("SEARCH b c the", found(&[])),
+        ("SEARCH b c the and", found(&[])),
+        ("SEARCH b c the cat", found(&["1"])),
+        ("SEARCH b other the", found(&["1"])),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+
+    let index = search_engine
+        .read()
+        .unwrap()
+        .collection_index("b", "c")
+        .unwrap();
+    let mut tokens: Vec<&String> = index.keys().collect();
+    tokens.sort();
+    assert_eq!(tokens, ["cat", "sat"]);
+}
+
+#[tokio::test]
+async fn stemmed_words_match_their_inflections() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let found = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    let cases = vec![
+        ("CONFIGURE b c STEM true", Ok(Response::Success)),
+        ("SET b c 1 12:Running late", Ok(Response::Success)),
+        ("SET b other 1 12:Running late", Ok(Response::Success)),
+        ("SEARCH b c runs", found(&["1"])),
+        ("SEARCH b c run", found(&["1"])),
+        ("SEARCH b c HIGHLIGHT run", found(&["1 0-7"])),
+        ("SEARCH b other runs", found(&[])),
+        ("SEARCH b other running", found(&["1"])),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+/// Runs the commands with every engine, then checks that no token of collection `b c` lists
+/// document 1 anymore: a document is removed from the tokens it was indexed under, whatever the
+/// collection tokenizes it into by then.
+async fn assert_removed_from_every_engine(commands: &[String]) {
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        let storage = Arc::new(Storage::new("test.db"));
+        let search_engine: Arc<RwLock<DynSearchEngine>> =
+            Arc::new(RwLock::new(engine_by_name(name, DEFAULT_LIMIT).unwrap()));
+        for cmd in commands {
+            let request = Request::from_bytes(cmd.as_bytes()).unwrap();
+            let result = handle_request(
+                request,
+                &storage,
+                &MockEncryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            )
+            .await;
+            assert!(result.is_ok(), "{} with {}: {:?}", cmd, name, result);
+        }
+
+        let index = search_engine
+            .read()
+            .unwrap()
+            .collection_index("b", "c")
+            .unwrap();
+        let stale: Vec<&String> = index
+            .iter()
+            .filter(|(_, ids)| ids.contains("1"))
+            .map(|(token, _)| token)
+            .collect();
+        assert!(stale.is_empty(), "{} keeps {:?}", name, stale);
+    }
+}
+
+#[tokio::test]
+async fn stem_change_leaves_no_stale_tokens() {
+    let commands = [
+        "CONFIGURE b c STEM true",
+        "SET b c 1 12:Running late",
+        "SET b c 2 7:Running",
+        "CONFIGURE b c STEM false",
+        "REMOVE b c 1",
+    ];
+    assert_removed_from_every_engine(&commands.map(String::from)).await;
+}
+
+#[tokio::test]
+async fn stopwords_change_leaves_no_stale_tokens() {
+    let commands = [
+        "SET b c 1 11:The cat sat",
+        "SET b c 2 7:The dog",
+        "CONFIGURE b c STOPWORDS true",
+        "REMOVE b c 1",
+    ];
+    assert_removed_from_every_engine(&commands.map(String::from)).await;
+}
+
+#[tokio::test]
+async fn blacklist_change_leaves_no_stale_tokens() {
+    // "common" is blacklisted once every document is indexed with it, see
+    // `blacklist_regenerate_and_clear`
+    let mut commands: Vec<String> = (0..20)
+        .map(|doc| {
+            let words: Vec<String> = (0..60).map(|word| format!("w{}x{}", doc, word)).collect();
+            let content = format!("common {}", words.join(" "));
+            format!("SET b c {} {}:{}", doc, content.len(), content)
+        })
+        .collect();
+    commands.push("BLACKLIST REGENERATE".to_string());
+    commands.push("REMOVE b c 1".to_string());
+    assert_removed_from_every_engine(&commands).await;
+}
+
+#[tokio::test]
+async fn conditional_sets_check_existence() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let ids = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    let cases = vec![
+        ("UPDATE b c 1 missing", Ok(Response::Integer(0))),
+        ("SETNX b c 1 hello", Ok(Response::Integer(1))),
+        ("SETNX b c 1 other", Ok(Response::Integer(0))),
+        ("GET b c 1", Ok(Response::BulkString("hello".to_string()))),
+        // a refused write leaves the index alone
+        ("SEARCH b c other", ids(&[])),
+        ("SEARCH b c missing", ids(&[])),
+        ("UPDATE b c 1 updated", Ok(Response::Integer(1))),
+        ("GET b c 1", Ok(Response::BulkString("updated".to_string()))),
+        ("SEARCH b c hello", ids(&[])),
+        ("SEARCH b c updated", ids(&["1"])),
+        ("UPDATE b c 2 never", Ok(Response::Integer(0))),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn mset_writes_every_document() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let ids = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+    let content = |content: &str| Ok(Response::BulkString(content.to_string()));
+
+    let cases = vec![
+        ("SET b c 1 old content", Ok(Response::Success)),
+        (
+            "MSET b c 4 1 11:hello world 2 15:hello\nmultiline 3 5:other 3 5:third",
+            Ok(Response::Success),
+        ),
+        ("GET b c 1", content("hello world")),
+        ("GET b c 2", content("hello\nmultiline")),
+        // the last document under an id wins
+        ("GET b c 3", content("third")),
+        ("SEARCH b c hello", ids(&["1", "2"])),
+        ("SEARCH b c old", ids(&[])),
+        ("SEARCH b c other", ids(&[])),
+        ("SEARCH b c third", ids(&["3"])),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn mget_returns_null_for_missing_documents() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let contents = |contents: &[Option<&str>]| {
+        Ok(Response::NullableArray(
+            contents.iter().map(|c| c.map(String::from)).collect(),
+        ))
+    };
+
+    let cases = vec![
+        ("SET b c 1 hello", Ok(Response::Success)),
+        ("SET b c 3 11:hello\nworld", Ok(Response::Success)),
+        (
+            "MGET b c 1 2 3 1",
+            contents(&[Some("hello"), None, Some("hello\nworld"), Some("hello")]),
+        ),
+        ("MGET b c 2", contents(&[None])),
+        (
+            "MGET b missing 1",
+            Err(HandleError::Storage(StorageError::NotFound(
+                EntityType::Collection,
+            ))),
+        ),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn get_range_returns_a_slice_of_the_content() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let slice = |content: &str| Ok(Response::BulkString(content.to_string()));
+
+    let cases = vec![
+        ("SET b c 1 11:hello world", Ok(Response::Success)),
+        ("GETRANGE b c 1 0 5", slice("hello")),
+        ("GETRANGE b c 1 6 11", slice("world")),
+        // clamped to the content
+        ("GETRANGE b c 1 6 100", slice("world")),
+        ("GETRANGE b c 1 0 11", slice("hello world")),
+        ("GETRANGE b c 1 11 20", slice("")),
+        ("GETRANGE b c 1 5 5", slice("")),
+        ("GETRANGE b c 1 8 2", slice("")),
+        ("GETRANGE b c 2 0 5", Ok(Response::Null)),
+        // "é" takes two bytes, a bound inside it includes it whole
+        ("SET b c 3 6:caf\u{e9}!", Ok(Response::Success)),
+        ("GETRANGE b c 3 4 6", slice("\u{e9}!")),
+        ("GETRANGE b c 3 0 4", slice("caf\u{e9}")),
+        ("SET b c 4 11:hello world secret", Ok(Response::Success)),
+        ("GETRANGE b c 4 6 11 secret", slice("world")),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn get_if_returns_content_only_when_newer() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let versioned =
+        |version: &str, content: &str| Ok(Response::Array(vec![version.into(), content.into()]));
+
+    let cases = vec![
+        ("SET b c 1 first", Ok(Response::Success)),
+        ("GETIF b c 1 0", versioned("1", "first")),
+        ("SET b c 1 second", Ok(Response::Success)),
+        // newer than the client's copy
+        ("GETIF b c 1 1", versioned("2", "second")),
+        // same version
+        ("GETIF b c 1 2", Ok(Response::NotModified)),
+        // the client claims a version the server never produced
+        ("GETIF b c 1 5", Ok(Response::NotModified)),
+        (
+            "GETIF b c 2 0",
+            Err(HandleError::Storage(StorageError::NotFound(
+                EntityType::Item,
+            ))),
+        ),
+        // set again after a removal, it carries on rather than going back to a version clients
+        // may have cached
+        ("REMOVE b c 1", Ok(Response::Success)),
+        ("SET b c 1 third", Ok(Response::Success)),
+        ("GETIF b c 1 2", versioned("3", "third")),
+        ("SET b c 3 other", Ok(Response::Success)),
+        ("DROPCOLLECTION b c", Ok(Response::Success)),
+        ("SET b c 3 again", Ok(Response::Success)),
+        ("GETIF b c 3 2", versioned("4", "again")),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn search_escaped_operator_characters() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let found = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    let cases = vec![
+        ("SET b c 1 18:python for scripts", Ok(Response::Success)),
+        ("SET b c 2 14:for the python", Ok(Response::Success)),
+        (r#"SEARCH b c "python for""#, found(&["1"])),
+        // escaped quotes are searched as words, so the phrase no longer has to match
+        (r#"SEARCH b c \"python for\""#, found(&["1", "2"])),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn search_highlight_returns_match_offsets() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let cases = vec![
+        (
+            "SET default posts 1 29:Hello, world! Say hello again",
+            Ok(Response::Success),
+        ),
+        ("SET default notes 1 11:hello there", Ok(Response::Success)),
+        (
+            "SEARCH default posts HIGHLIGHT hello",
+            Ok(Response::Array(vec!["1 0-5 18-23".to_string()])),
+        ),
+        (
+            "SEARCH default posts HIGHLIGHT world",
+            Ok(Response::Array(vec!["1 7-12".to_string()])),
+        ),
+        (
+            "SEARCH default posts,notes HIGHLIGHT hello",
+            Ok(Response::Array(vec![
+                "posts/1 0-5 18-23".to_string(),
+                "notes/1 0-5".to_string(),
+            ])),
+        ),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn search_highlight_returns_snippets() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let long = format!(
+        "{}the needle is here {}",
+        "hay ".repeat(30),
+        "stack ".repeat(30)
+    );
+    let cases = vec![
+        (
+            "SET default posts 1 29:Hello, world! Say hello again".to_string(),
+            Ok(Response::Success),
+        ),
+        (
+            format!("SET default posts 2 {}:{}", long.len(), long),
+            Ok(Response::Success),
+        ),
+        (
+            "SEARCHHL default posts world".to_string(),
+            Ok(Response::Array(vec![
+                "1 Hello, <em>world</em>! Say hello again".to_string(),
+            ])),
+        ),
+        (
+            "SEARCHHL default posts needle".to_string(),
+            Ok(Response::Array(vec![format!(
+                "2 ...{}the <em>needle</em> is here {}...",
+                "hay ".repeat(8),
+                "stack ".repeat(5).trim_end()
+            )])),
+        ),
+        (
+            "SEARCHHL default posts missing".to_string(),
+            Ok(Response::Array(vec![])),
+        ),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, &cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn async_indexing_is_searchable_after_sync() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let indexer = IndexQueue::new(storage.clone(), search_engine.clone());
+    let config = RwLock::new(ZzapConfig {
+        async_indexing: true,
+        ..Default::default()
+    });
+    let found = || Ok(Response::Array(vec!["1".to_string()]));
+
+    let cases = vec![
+        ("SET b c 1 hello", Ok(Response::Success)),
+        // stored right away, even if not searchable yet
+        ("GET b c 1", Ok(Response::BulkString("hello".to_string()))),
+        ("SYNC", Ok(Response::Success)),
+        ("SEARCH b c hello", found()),
+        ("SET b c 1 goodbye", Ok(Response::Success)),
+        ("SYNC", Ok(Response::Success)),
+        ("SEARCH b c hello", Ok(Response::Array(vec![]))),
+        ("SEARCH b c goodbye", found()),
+        ("VERIFY b c", Ok(Response::Array(vec![]))),
+    ];
+
+    for (cmd, expected) in cases {
+        let request = Request::from_bytes(cmd.as_bytes()).unwrap();
+        let result = handle_request(
+            request,
+            &storage,
+            &encryptor,
+            &search_engine,
+            &config,
+            Some(&indexer),
+            &ServerStatus::default(),
+        )
+        .await;
+        assert_eq!(result, expected, "{}", cmd);
+    }
+}
+
+#[tokio::test]
+async fn search_case_sensitive_when_configured() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let ids = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    let cases = vec![
+        (
+            "CONFIGURE b exact CASESENSITIVE true",
+            Ok(Response::Success),
+        ),
+        ("SET b exact 1 9:Apple pie", Ok(Response::Success)),
+        ("SET b exact 2 11:apple juice", Ok(Response::Success)),
+        ("SET b loose 1 9:Apple pie", Ok(Response::Success)),
+        ("SET b loose 2 11:apple juice", Ok(Response::Success)),
+        ("SEARCH b exact Apple", ids(&["1"])),
+        ("SEARCH b exact apple", ids(&["2"])),
+        ("SEARCH b exact APPLE", ids(&[])),
+        ("SEARCH b loose APPLE", ids(&["1", "2"])),
+        // the index of a case-insensitive collection only holds lowercase tokens
+        ("SEARCH b loose CASESENSITIVE Apple", ids(&[])),
+        ("SEARCH b loose CASESENSITIVE apple", ids(&["1", "2"])),
+    ];
+
+    for (cmd, expected) in cases {
+        command_predicate(&storage, &encryptor, &search_engine, cmd, |result| {
+            let sorted = result.map(|response| match response {
+                Response::Array(mut ids) => {
+                    ids.sort();
+                    Response::Array(ids)
+                }
+                response => response,
+            });
+            sorted == expected
+        })
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn blacklist_regenerate_and_clear() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let array = |items: &[&str]| {
+        Ok(Response::Array(
+            items.iter().map(|i| i.to_string()).collect(),
+        ))
+    };
+
+    // 20 documents sharing "common" next to 60 words of their own, 1201 tokens in total
+    for doc in 0..20 {
+        let words: Vec<String> = (0..60).map(|word| format!("w{}x{}", doc, word)).collect();
+        let content = format!("common {}", words.join(" "));
+        let cmd = format!("SET b c {} {}:{}", doc, content.len(), content);
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &cmd,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    let cases = vec![
+        ("BLACKLIST SHOW", array(&[])),
+        ("BLACKLIST REGENERATE", array(&["common"])),
+        ("BLACKLIST SHOW", array(&["common"])),
+        ("SEARCH b c common", array(&[])),
+        ("SEARCH b c common w3x7", array(&["3"])),
+        ("SET b fresh 1 11:common word", Ok(Response::Success)),
+        ("SEARCH b fresh word", array(&["1"])),
+        ("BLACKLIST CLEAR", array(&[])),
+        // indexed while blacklisted
+        ("SEARCH b fresh common", array(&[])),
+        ("SET b fresh 2 11:common word", Ok(Response::Success)),
+        ("SEARCH b fresh common", array(&["2"])),
+    ];
+
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn blacklist_survives_restarts() {
+    const PERSISTENCE_PATH: &str = "test_blacklist.db";
+    let mut storage = Storage::new(PERSISTENCE_PATH);
+    storage.initialize().unwrap();
+    let storage = Arc::new(storage);
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let blacklist = |storage: &Storage| {
+        let mut blacklist: Vec<String> = storage.blacklist().iter().cloned().collect();
+        blacklist.sort();
+        blacklist
+    };
+
+    // 20 documents sharing "common" next to 60 words of their own, 1201 tokens in total
+    for doc in 0..20 {
+        let words: Vec<String> = (0..60).map(|word| format!("w{}x{}", doc, word)).collect();
+        let content = format!("common {}", words.join(" "));
+        let cmd = format!("SET b c {} {}:{}", doc, content.len(), content);
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &cmd,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+    let regenerated = Ok(Response::Array(vec!["common".to_string()]));
+    for (cmd, expected) in [
+        ("BLACKLIST REGENERATE", regenerated),
+        ("SAVE", Ok(Response::Success)),
+    ] {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+    drop(storage);
+
+    let mut recovered = Storage::new(PERSISTENCE_PATH);
+    recovered.initialize().unwrap();
+    assert_eq!(blacklist(&recovered), ["common"]);
+
+    // cleared without a save, so only the write-ahead log knows
+    let recovered = Arc::new(recovered);
+    command(
+        &recovered,
+        &encryptor,
+        &search_engine,
+        "BLACKLIST CLEAR",
+        Ok(Response::Array(vec![])),
+    )
+    .await;
+    drop(recovered);
+
+    let mut recovered = Storage::new(PERSISTENCE_PATH);
+    recovered.initialize().unwrap();
+    assert!(blacklist(&recovered).is_empty());
+
+    for path in [
+        PERSISTENCE_PATH,
+        "test_blacklist.zzap_collections",
+        "test_blacklist.zzap_wal",
+    ] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[tokio::test]
+async fn verify_detects_corrupted_index() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let engine = StdSearchEngine::new();
+    let index = engine.get_index();
+    let search_engine: Arc<RwLock<DynSearchEngine>> = Arc::new(RwLock::new(Box::new(engine)));
+
+    for cmd in [
+        "SET default posts 1 11:hello world",
+        "SET default posts 2 5:hello",
+    ] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            cmd,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "VERIFY default posts",
+        Ok(Response::Array(vec![])),
+    )
+    .await;
+
+    {
+        let mut index = index.write().unwrap();
+        let posts = index.get_mut("default").unwrap().get_mut("posts").unwrap();
+        posts.get_mut("hello").unwrap().retain(|id| id != "1");
+        posts.insert("bogus".to_string(), BTreeSet::from(["2".to_string()]));
+    }
+
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "VERIFY default posts",
+        Ok(Response::Array(vec![
+            "extra 2 bogus".to_string(),
+            "missing 1 hello".to_string(),
+        ])),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn append_extends_document_content() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let found = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    let cases = vec![
+        // a missing document is created
+        ("APPEND b c 1 5:quick", Ok(Response::Integer(5))),
+        ("APPEND b c 1 10: brown fox", Ok(Response::Integer(15))),
+        ("APPEND b c 1 6: jumps", Ok(Response::Integer(21))),
+        (
+            "GET b c 1",
+            Ok(Response::BulkString("quick brown fox jumps".to_string())),
+        ),
+        ("SEARCH b c quick", found(&["1"])),
+        ("SEARCH b c fox", found(&["1"])),
+        ("SEARCH b c jumps", found(&["1"])),
+        // a word running on across the old end is indexed whole
+        ("SET b c 2 3:hel", Ok(Response::Success)),
+        ("APPEND b c 2 8:lo world", Ok(Response::Integer(11))),
+        ("SEARCH b c hello", found(&["2"])),
+        ("SEARCH b c hel", found(&[])),
+        ("VERIFY b c", Ok(Response::Array(vec![]))),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn incr_counts_in_document_content() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let found = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    let cases = vec![
+        // a missing document counts from 0
+        ("INCR b c visits", Ok(Response::Integer(1))),
+        ("INCR b c visits", Ok(Response::Integer(2))),
+        ("INCRBY b c visits 10", Ok(Response::Integer(12))),
+        ("DECR b c visits", Ok(Response::Integer(11))),
+        ("DECRBY b c visits 20", Ok(Response::Integer(-9))),
+        ("DECR b c stock", Ok(Response::Integer(-1))),
+        ("GET b c visits", Ok(Response::BulkString("-9".to_string()))),
+        // the new value is indexed in place of the old one
+        ("SEARCH b c -9", found(&["visits"])),
+        ("SEARCH b c 11", found(&[])),
+        ("SET b c title 5:hello", Ok(Response::Success)),
+        (
+            "INCR b c title",
+            Err(HandleError::InvalidArgument(
+                "content of title is not an integer".to_string(),
+            )),
+        ),
+        (
+            "GET b c title",
+            Ok(Response::BulkString("hello".to_string())),
+        ),
+        ("SET b c max 19:9223372036854775807", Ok(Response::Success)),
+        (
+            "INCR b c max",
+            Err(HandleError::InvalidArgument(
+                "increment of max would overflow".to_string(),
+            )),
+        ),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+
+    // a counter keeps its expiry
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "EXPIRE b c visits 60",
+        Ok(Response::Integer(1)),
+    )
+    .await;
+    let expires_at = storage.expiry("b", "c", "visits");
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "INCR b c visits",
+        Ok(Response::Integer(-8)),
+    )
+    .await;
+    assert!(expires_at.is_some());
+    assert_eq!(storage.expiry("b", "c", "visits"), expires_at);
+}
+
+#[tokio::test]
+async fn reindex_repairs_corrupted_index() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let engine = StdSearchEngine::new();
+    let index = engine.get_index();
+    let search_engine: Arc<RwLock<DynSearchEngine>> = Arc::new(RwLock::new(Box::new(engine)));
+    let found = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    let cases = vec![
+        ("SET default posts 1 11:hello world", Ok(Response::Success)),
+        ("SET default posts 2 5:hello", Ok(Response::Success)),
+        ("SET default other 1 5:hello", Ok(Response::Success)),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+
+    {
+        let mut index = index.write().unwrap();
+        let posts = index.get_mut("default").unwrap().get_mut("posts").unwrap();
+        posts.get_mut("hello").unwrap().retain(|id| id != "1");
+        posts.insert("bogus".to_string(), BTreeSet::from(["2".to_string()]));
+        let other = index.get_mut("default").unwrap().get_mut("other").unwrap();
+        other.insert("bogus".to_string(), BTreeSet::from(["1".to_string()]));
+    }
+
+    let cases = vec![
+        ("SEARCH default posts hello", found(&["2"])),
+        ("SEARCH default posts bogus", found(&["2"])),
+        ("REINDEX default posts", Ok(Response::Integer(2))),
+        ("VERIFY default posts", Ok(Response::Array(vec![]))),
+        ("SEARCH default posts hello", found(&["1", "2"])),
+        ("SEARCH default posts world", found(&["1"])),
+        ("SEARCH default posts bogus", found(&[])),
+        // other collections are left as they are
+        ("SEARCH default other bogus", found(&["1"])),
+        (
+            "REINDEX default missing",
+            Err(HandleError::Storage(StorageError::NotFound(
+                EntityType::Collection,
+            ))),
+        ),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn dump_token_lists_indexed_ids() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let dumped = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        let cases = vec![
+            (format!("SETENGINE {}", name), Ok(Response::Success)),
+            (
+                "SET b c 1 17:hello hello world".to_string(),
+                Ok(Response::Success),
+            ),
+            ("SET b c 2 5:hello".to_string(), Ok(Response::Success)),
+            ("DUMPTOKEN b c hello".to_string(), dumped(&["1", "2"])),
+            // tokenized like the content, so the case doesn't matter
+            ("DUMPTOKEN b c World".to_string(), dumped(&["1"])),
+            ("DUMPTOKEN b c missing".to_string(), dumped(&[])),
+            ("DUMPTOKEN b other hello".to_string(), dumped(&[])),
+            ("REMOVE b c 1".to_string(), Ok(Response::Success)),
+            ("DUMPTOKEN b c hello".to_string(), dumped(&["2"])),
+            ("DUMPTOKEN b c world".to_string(), dumped(&[])),
+            ("REMOVE b c 2".to_string(), Ok(Response::Success)),
+        ];
+        for (cmd, expected) in cases {
+            command(&storage, &encryptor, &search_engine, &cmd, expected).await;
+        }
+    }
+
+    // the std tokenizer splits a JSON word into a token per field
+    let cases = vec![
+        ("SETENGINE std", Ok(Response::Success)),
+        (
+            r#"DUMPTOKEN b c {"title":"hello","body":"world"}"#,
+            Err(HandleError::InvalidArgument(
+                r#"{"title":"hello","body":"world"} is more than one token"#.to_string(),
+            )),
+        ),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+/// Indexes into the wrapped engine, then fails on demand, leaving the index half-updated
+struct FailingIndexEngine {
+    inner: StdSearchEngine,
+    fail: AtomicBool,
+}
+
+impl SearchEngine for FailingIndexEngine {
+    fn index(
+        &self,
+        storage: &dyn StorageOperations,
+        bucket_name: &str,
+        collection_name: &str,
+        id: &str,
+        content: &str,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .index(storage, bucket_name, collection_name, id, content)?;
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(StorageError::OperationFailed("injected".to_string()));
+        }
+        Ok(())
+    }
+
+    fn search_with_options(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        query: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, StorageError> {
+        self.inner
+            .search_with_options(bucket_name, collection_name, query, options)
+    }
+
+    fn remove_from_index(
+        &self,
+        storage: &dyn StorageOperations,
+        bucket_name: &str,
+        collection_name: &str,
+        id: &str,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .remove_from_index(storage, bucket_name, collection_name, id)
+    }
+
+    fn clear_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError> {
+        self.inner.clear_collection(bucket_name, collection_name)
+    }
+
+    fn clear_bucket(&self, bucket_name: &str) -> Result<(), StorageError> {
+        self.inner.clear_bucket(bucket_name)
+    }
+
+    fn collection_index_matching(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<CollectionIndex, StorageError> {
+        self.inner
+            .collection_index_matching(bucket_name, collection_name, matches)
+    }
+
+    fn token_count(&self) -> Result<usize, StorageError> {
+        self.inner.token_count()
+    }
+}
+
+#[test]
+fn bucket_lock_only_blocks_its_bucket() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let search_engine = std_engine();
+    let set = |bucket: &str| {
+        let storage = storage.clone();
+        let search_engine = search_engine.clone();
+        let request = Request::from_bytes(format!("SET {} c 1 hello\n", bucket).as_bytes());
+        thread::spawn(move || {
+            Runtime::new().unwrap().block_on(handle_request(
+                request.unwrap(),
+                &storage,
+                &MockEncryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            ))
+        })
+    };
+
+    // stands in for a long operation over every document of bucket `a`
+    let lock = storage.bucket_lock("a");
+    let guard = lock.write().unwrap();
+
+    let blocked = set("a");
+    let unrelated = set("b");
+    assert_eq!(unrelated.join().unwrap(), Ok(Response::Success));
+    assert!(!blocked.is_finished());
+
+    drop(guard);
+    assert_eq!(blocked.join().unwrap(), Ok(Response::Success));
+}
+
+#[test]
+fn health_reports_readiness() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let search_engine = std_engine();
+    let status = Arc::new(ServerStatus::default());
+    let run = |request: Request| {
+        let storage = storage.clone();
+        let search_engine = search_engine.clone();
+        let status = status.clone();
+        thread::spawn(move || {
+            Runtime::new().unwrap().block_on(handle_request(
+                request,
+                &storage,
+                &MockEncryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &status,
+            ))
+        })
+    };
+    let health = || run(Request::Health).join().unwrap();
+
+    // not serving yet
+    assert_eq!(
+        health(),
+        Err(HandleError::NotReady("starting or shutting down"))
+    );
+
+    storage
+        .add_document("b", "c", Document::new("1", "hello"))
+        .unwrap();
+    search_engine
+        .write()
+        .unwrap()
+        .initialize(storage.as_ref())
+        .unwrap();
+    status.ready.store(true, Ordering::Release);
+    assert_eq!(health(), Ok(Response::Success));
+
+    // holds the reindex up while it is in progress
+    let guard = search_engine.write().unwrap();
+    let reindex = run(Request::Reindex {
+        bucket: "b".to_string(),
+        collection: "c".to_string(),
+    });
+    while status.rebuilding.load(Ordering::Acquire) == 0 {
+        thread::yield_now();
+    }
+    assert_eq!(health(), Err(HandleError::NotReady("rebuilding the index")));
+
+    drop(guard);
+    assert_eq!(reindex.join().unwrap(), Ok(Response::Integer(1)));
+    assert_eq!(health(), Ok(Response::Success));
+}
+
+#[test]
+fn concurrent_writers_all_land() {
+    const WRITERS: usize = 8;
+    const DOCUMENTS: usize = 50;
+    let storage = Arc::new(Storage::new("test.db"));
+    let search_engine = std_engine();
+
+    // writers to the same bucket only share its lock, and only in shared mode
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|writer| {
+            let storage = storage.clone();
+            let search_engine = search_engine.clone();
+            thread::spawn(move || {
+                let runtime = Runtime::new().unwrap();
+                for i in 0..DOCUMENTS {
+                    let request = format!("SET b c {}-{} shared writer{}\n", writer, i, writer);
+                    let response = runtime.block_on(handle_request(
+                        Request::from_bytes(request.as_bytes()).unwrap(),
+                        &storage,
+                        &MockEncryptor,
+                        &search_engine,
+                        &RwLock::new(ZzapConfig::default()),
+                        None,
+                        &ServerStatus::default(),
+                    ));
+                    assert_eq!(response, Ok(Response::Success));
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let search_engine = search_engine.read().unwrap();
+    let search = |query: &str| {
+        let options = SearchOptions {
+            limit: Some(WRITERS * DOCUMENTS),
+            ..Default::default()
+        };
+        search_engine
+            .search_with_options("b", "c", query, &options)
+            .unwrap()
+    };
+    assert_eq!(search("shared").len(), WRITERS * DOCUMENTS);
+    for writer in 0..WRITERS {
+        let ids = search(&format!("writer{}", writer));
+        assert_eq!(ids.len(), DOCUMENTS);
+        for id in ids {
+            assert!(storage.get_document("b", "c", &id).is_ok());
+        }
+    }
+}
+
+#[test]
+fn mset_rolls_back_when_indexing_fails() {
+    let storage = Storage::new("test.db");
+    let engine = FailingIndexEngine {
+        inner: StdSearchEngine::new(),
+        fail: AtomicBool::new(false),
+    };
+    let docs = |docs: &[(&str, &str)]| {
+        docs.iter()
+            .map(|(id, content)| (id.to_string(), content.to_string()))
+            .collect()
+    };
+
+    set_documents(
+        &storage,
+        &engine,
+        None,
+        "default",
+        "posts",
+        docs(&[("1", "hello world")]),
+    )
+    .unwrap();
+
+    engine.fail.store(true, Ordering::SeqCst);
+
+    let result = set_documents(
+        &storage,
+        &engine,
+        None,
+        "default",
+        "posts",
+        docs(&[("1", "goodbye moon"), ("2", "fresh")]),
+    );
+    assert_eq!(
+        result,
+        Err(HandleError::Storage(StorageError::OperationFailed(
+            "injected".to_string()
+        )))
+    );
+
+    // the existing document keeps its old version, the new one is neither stored nor indexed
+    assert_eq!(
+        storage
+            .get_document("default", "posts", "1")
+            .unwrap()
+            .content,
+        "hello world"
+    );
+    assert_eq!(storage.get_version("default", "posts", "1"), Ok(1));
+    assert_eq!(
+        storage
+            .get_document("default", "posts", "2")
+            .map(|d| d.content),
+        Err(StorageError::NotFound(EntityType::Item))
+    );
+    assert_eq!(
+        engine.search("default", "posts", "hello").unwrap(),
+        vec!["1"]
+    );
+    for query in ["goodbye", "fresh"] {
+        assert!(engine.search("default", "posts", query).unwrap().is_empty());
+    }
+}
+
+#[test]
+fn set_rolls_back_when_indexing_fails() {
+    let storage = Storage::new("test.db");
+    let engine = FailingIndexEngine {
+        inner: StdSearchEngine::new(),
+        fail: AtomicBool::new(false),
+    };
+    let injected = Err(StorageError::OperationFailed("injected".to_string()));
+
+    set_document(
+        &storage,
+        &engine,
+        "default",
+        "posts",
+        Document::new("1", "hello world"),
+    )
+    .unwrap();
+
+    engine.fail.store(true, Ordering::SeqCst);
+
+    // overwriting an existing document keeps the old version, both stored and indexed
+    let result = set_document(
+        &storage,
+        &engine,
+        "default",
+        "posts",
+        Document::new("1", "goodbye moon"),
+    );
+    assert_eq!(result, injected);
+    assert_eq!(
+        storage
+            .get_document("default", "posts", "1")
+            .unwrap()
+            .content,
+        "hello world"
+    );
+    assert_eq!(storage.get_version("default", "posts", "1"), Ok(1));
+    assert_eq!(
+        engine.search("default", "posts", "hello").unwrap(),
+        vec!["1"]
+    );
+    assert!(engine
+        .search("default", "posts", "goodbye")
+        .unwrap()
+        .is_empty());
+
+    // a new document is neither stored nor indexed
+    let result = set_document(
+        &storage,
+        &engine,
+        "default",
+        "posts",
+        Document::new("2", "fresh"),
+    );
+    assert_eq!(result, injected);
+    assert_eq!(
+        storage
+            .get_document("default", "posts", "2")
+            .map(|d| d.content),
+        Err(StorageError::NotFound(EntityType::Item))
+    );
+    assert!(engine
+        .search("default", "posts", "fresh")
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn set_reports_created_or_updated_when_configured() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let config = RwLock::new(ZzapConfig {
+        report_set_outcome: true,
+        ..Default::default()
+    });
+
+    let cases = vec![
+        ("SET b c 1 first", Ok(Response::Created)),
+        ("SET b c 1 second", Ok(Response::Updated)),
+        ("SET b c 2 other", Ok(Response::Created)),
+        ("REMOVE b c 1", Ok(Response::Success)),
+        ("SET b c 1 third", Ok(Response::Created)),
+    ];
+
+    for (command, expected) in cases {
+        let request = Request::from_bytes(command.as_bytes()).unwrap();
+        let result = handle_request(
+            request,
+            &storage,
+            &encryptor,
+            &search_engine,
+            &config,
+            None,
+            &ServerStatus::default(),
+        )
+        .await;
+        assert_eq!(result, expected, "{}", command);
+    }
+
+    // plain `+OK` unless asked for
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SET b c 3 plain",
+        Ok(Response::Success),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn add_generates_distinct_ids() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let mut ids = Vec::new();
+    for content in ["first", "second"] {
+        let request = Request::from_bytes(format!("ADD b c {}", content).as_bytes()).unwrap();
+        let response = handle_request(
+            request,
+            &storage,
+            &encryptor,
+            &search_engine,
+            &RwLock::new(ZzapConfig::default()),
+            None,
+            &ServerStatus::default(),
+        )
+        .await;
+        match response {
+            Ok(Response::BulkString(id)) => ids.push(id),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    assert_ne!(ids[0], ids[1]);
+
+    for (id, content) in ids.iter().zip(["first", "second"]) {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("GET b c {}", id),
+            Ok(Response::BulkString(content.to_string())),
+        )
+        .await;
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SEARCH b c {}", content),
+            Ok(Response::Array(vec![id.clone()])),
+        )
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn config_changes_apply_to_later_requests() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let config = RwLock::new(ZzapConfig::default());
+
+    let cases = vec![
+        ("SET b c 1 first", Ok(Response::Success)),
+        (
+            "CONFIG GET reportsetoutcome",
+            Ok(Response::Array(vec!["REPORTSETOUTCOME false".to_string()])),
+        ),
+        ("CONFIG SET REPORTSETOUTCOME true", Ok(Response::Success)),
+        ("SET b c 1 second", Ok(Response::Updated)),
+        ("CONFIG SET DEFAULTBUCKET b", Ok(Response::Success)),
+        ("GET _ c 1", Ok(Response::BulkString("second".to_string()))),
+        (
+            "CONFIG SET REPORTSETOUTCOME maybe",
+            Err(HandleError::InvalidArgument(
+                "invalid value maybe for REPORTSETOUTCOME".to_string(),
+            )),
+        ),
+        (
+            "CONFIG SET ASYNCINDEXING true",
+            Err(HandleError::InvalidArgument(
+                "ASYNCINDEXING can only be changed on startup".to_string(),
+            )),
+        ),
+        (
+            "CONFIG LIST",
+            Ok(Response::Array(vec![
+                "PARSEMODE lenient".to_string(),
+                "DEFAULTBUCKET b".to_string(),
+                "DEFAULTCOLLECTION none".to_string(),
+                "MAXCONNECTIONS none".to_string(),
+                "MAXCONNECTIONBYTES none".to_string(),
+                "MAXREQUESTBYTES 67108864".to_string(),
+                "READTIMEOUT none".to_string(),
+                "MAXRESULTS 10".to_string(),
+                "AUTOSAVEINTERVAL 60".to_string(),
+                "ASYNCINDEXING false".to_string(),
+                "REPORTSETOUTCOME true".to_string(),
+                "KEYNORMALIZATION none".to_string(),
+                "LOGLEVEL info".to_string(),
+            ])),
+        ),
+        ("SET b c 2 second", Ok(Response::Created)),
+        ("CONFIG SET MAXRESULTS 1", Ok(Response::Success)),
+        (
+            "SEARCH b c second",
+            Ok(Response::Array(vec!["1".to_string()])),
+        ),
+        (
+            "SEARCH b c LIMIT 2 second",
+            Ok(Response::Array(vec!["1".to_string(), "2".to_string()])),
+        ),
+        // an engine swapped in later is built with it too
+        ("SETENGINE btree", Ok(Response::Success)),
+        (
+            "SEARCH b c second",
+            Ok(Response::Array(vec!["1".to_string()])),
+        ),
+    ];
+
+    for (command, expected) in cases {
+        let request = Request::from_bytes(command.as_bytes()).unwrap();
+        let result = handle_request(
+            request,
+            &storage,
+            &encryptor,
+            &search_engine,
+            &config,
+            None,
+            &ServerStatus::default(),
+        )
+        .await;
+        assert_eq!(result, expected, "{}", command);
+    }
+}
+
+#[tokio::test]
+async fn scan_visits_every_id_once() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let mut expected = Vec::new();
+    for i in 0..25 {
+        let id = format!("doc{}", i);
+        let cmd = format!("SET b c {} 4:text", id);
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &cmd,
+            Ok(Response::Success),
+        )
+        .await;
+        expected.push(id);
+    }
+    expected.sort();
+
+    let mut visited = Vec::new();
+    let mut cursor = "0".to_string();
+    let mut calls = 0;
+    loop {
+        let cmd = format!("SCAN b c {} 7", cursor);
+        let request = Request::from_bytes(cmd.as_bytes()).unwrap();
+        let Ok(Response::Array(items)) = handle_request(
+            request,
+            &storage,
+            &encryptor,
+            &search_engine,
+            &RwLock::new(ZzapConfig::default()),
+            None,
+            &ServerStatus::default(),
+        )
+        .await
+        else {
+            panic!("SCAN failed");
+        };
+        calls += 1;
+        cursor = items[0].clone();
+        visited.extend_from_slice(&items[1..]);
+        if cursor == "0" {
+            break;
+        }
+        if calls == 1 {
+            // changes before the cursor neither skip nor repeat the ids after it
+            for cmd in ["REMOVE b c doc0", "SET b c doc00 4:text"] {
+                command(
+                    &storage,
+                    &encryptor,
+                    &search_engine,
+                    cmd,
+                    Ok(Response::Success),
+                )
+                .await;
+            }
+        }
+    }
+    assert_eq!(calls, 4);
+    assert_eq!(visited, expected);
+
+    let cases = vec![
+        (
+            "SCAN b c >doc4",
+            Ok(Response::Array(
+                ["0", "doc5", "doc6", "doc7", "doc8", "doc9"]
+                    .map(String::from)
+                    .to_vec(),
+            )),
+        ),
+        // resumes after an id that is gone
+        (
+            "SCAN b c >doc45",
+            Ok(Response::Array(
+                ["0", "doc5", "doc6", "doc7", "doc8", "doc9"]
+                    .map(String::from)
+                    .to_vec(),
+            )),
+        ),
+        ("SCAN b c >doc9", Ok(Response::Array(vec!["0".to_string()]))),
+        (
+            "SCAN b c 0 2",
+            Ok(Response::Array(
+                [">doc1", "doc00", "doc1"].map(String::from).to_vec(),
+            )),
+        ),
+        (
+            "SCAN b missing 0",
+            Ok(Response::Array(vec!["0".to_string()])),
+        ),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn export_streams_collection_as_ndjson() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let cases = vec![
+        ("SET b c 2 11:second\nline", Ok(Response::Success)),
+        ("SET b c 1 5:first", Ok(Response::Success)),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+
+    let exported = |lines: &[&str]| {
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        move |result: Result<Response, HandleError>| match result {
+            // a stream decodes as the array of its items
+            Ok(response @ Response::Stream(_)) => {
+                Response::from_bytes(&response.to_bytes()) == Ok(Response::Array(lines.clone()))
+            }
+            _ => false,
+        }
+    };
+    command_predicate(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "EXPORT b c",
+        exported(&[
+            r#"{"id":"1","content":"first"}"#,
+            r#"{"id":"2","content":"second\nline"}"#,
+        ]),
+    )
+    .await;
+    command_predicate(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "EXPORT b missing",
+        exported(&[]),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn import_restores_an_exported_collection() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let found = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+    let not_found = |entity| Err(HandleError::Storage(StorageError::NotFound(entity)));
+    let import =
+        |options: &str, dump: &str| format!("IMPORT b c {}{}:{}", options, dump.len(), dump);
+
+    let cases = vec![
+        ("SET b c 1 11:hello world", Ok(Response::Success)),
+        ("SET b c 2 17:second\nline world", Ok(Response::Success)),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, cmd, expected).await;
+    }
+
+    let export = handle_request(
+        Request::from_bytes(b"EXPORT b c").unwrap(),
+        &storage,
+        &encryptor,
+        &search_engine,
+        &RwLock::new(ZzapConfig::default()),
+        None,
+        &ServerStatus::default(),
+    )
+    .await
+    .unwrap();
+    let Ok(Response::Array(lines)) = Response::from_bytes(&export.to_bytes()) else {
+        panic!("EXPORT should stream its documents");
+    };
+    let dump = lines.join("\n");
+
+    let cases = vec![
+        ("DROPCOLLECTION b c".to_string(), Ok(Response::Success)),
+        ("GET b c 1".to_string(), not_found(EntityType::Bucket)),
+        (import("", &dump), Ok(Response::Integer(2))),
+        (
+            "GET b c 2".to_string(),
+            Ok(Response::BulkString("second\nline world".to_string())),
+        ),
+        ("SEARCH b c hello".to_string(), found(&["1"])),
+        ("SEARCH b c line".to_string(), found(&["2"])),
+        // existing ids are skipped, unless replaced
+        (
+            import(
+                "",
+                "{\"id\":\"1\",\"content\":\"changed\"}\n{\"id\":\"3\",\"content\":\"third\"}",
+            ),
+            Ok(Response::Integer(1)),
+        ),
+        (
+            "GET b c 1".to_string(),
+            Ok(Response::BulkString("hello world".to_string())),
+        ),
+        ("SEARCH b c third".to_string(), found(&["3"])),
+        (
+            import("REPLACE ", "{\"id\":\"1\",\"content\":\"changed\"}\n"),
+            Ok(Response::Integer(1)),
+        ),
+        ("SEARCH b c hello".to_string(), found(&[])),
+        ("SEARCH b c changed".to_string(), found(&["1"])),
+        // a malformed line imports nothing
+        (
+            import("", "{\"id\":\"4\",\"content\":\"fourth\"}\nnot json"),
+            Err(HandleError::InvalidArgument(
+                "line 2: expected ident at line 1 column 2".to_string(),
+            )),
+        ),
+        (
+            import(
+                "",
+                "{\"id\":\"4\",\"content\":\"fourth\"}\n{\"id\":\"a b\",\"content\":\"\"}",
+            ),
+            Err(HandleError::InvalidArgument(
+                "line 2: invalid id \"a b\"".to_string(),
+            )),
+        ),
+        ("GET b c 4".to_string(), Ok(Response::Null)),
+        ("SEARCH b c fourth".to_string(), found(&[])),
+    ];
+    for (cmd, expected) in cases {
+        command(&storage, &encryptor, &search_engine, &cmd, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn lowercase_key_normalization_collapses_keys() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let config = RwLock::new(ZzapConfig {
+        key_normalization: KeyNormalization::Lowercase,
+        ..Default::default()
+    });
+
+    let cases = vec![
+        ("SET Posts Drafts Doc1 5:first", Ok(Response::Success)),
+        ("SET posts drafts doc2 6:second", Ok(Response::Success)),
+        (
+            "GET POSTS DRAFTS DOC1",
+            Ok(Response::BulkString("first".to_string())),
+        ),
+        (
+            "SEARCH pOsTs Drafts second",
+            Ok(Response::Array(vec!["doc2".to_string()])),
+        ),
+        (
+            "LISTIDS posts drafts",
+            Ok(Response::Array(vec![
+                "doc1".to_string(),
+                "doc2".to_string(),
+            ])),
+        ),
+        ("REMOVE posts drafts DOC2", Ok(Response::Success)),
+        ("SEARCH posts drafts second", Ok(Response::Array(vec![]))),
+        (
+            "CONFIG SET KEYNORMALIZATION none",
+            Err(HandleError::InvalidArgument(
+                "KEYNORMALIZATION can only be changed on startup".to_string(),
+            )),
+        ),
+    ];
+
+    for (command, expected) in cases {
+        let request = Request::from_bytes(command.as_bytes()).unwrap();
+        let result = handle_request(
+            request,
+            &storage,
+            &encryptor,
+            &search_engine,
+            &config,
+            None,
+            &ServerStatus::default(),
+        )
+        .await;
+        assert_eq!(result, expected, "{}", command);
+    }
+}
+
+#[tokio::test]
+async fn drop_collection_removes_documents_and_index() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for command_str in [
+        "SET b dropped 1 11:hello world",
+        "SET b dropped 2 11:hello there",
+        "SET b kept 1 11:hello again",
+    ] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            command_str,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    command_predicate(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "DRYRUN DROPCOLLECTION b dropped",
+        |result| match result {
+            Ok(Response::Array(mut report)) => {
+                report[1..].sort();
+                report == ["2", "1", "2"]
+            }
+            _ => false,
+        },
+    )
+    .await;
+
+    let not_found = |entity| Err(HandleError::Storage(StorageError::NotFound(entity)));
+    let cases = vec![
+        ("DROPCOLLECTION b dropped", Ok(Response::Success)),
+        // gone from both the storage and the index, like a collection never written to
+        ("GET b dropped 1", not_found(EntityType::Collection)),
+        ("SEARCH b dropped hello", not_found(EntityType::Collection)),
+        (
+            "DROPCOLLECTION b dropped",
+            not_found(EntityType::Collection),
+        ),
+        (
+            "DROPCOLLECTION missing dropped",
+            not_found(EntityType::Bucket),
+        ),
+        (
+            "SEARCH b kept hello",
+            Ok(Response::Array(vec!["1".to_string()])),
+        ),
+        // the collection can be written to again from scratch
+        ("SET b dropped 3 hello", Ok(Response::Success)),
+        (
+            "SEARCH b dropped hello",
+            Ok(Response::Array(vec!["3".to_string()])),
+        ),
+    ];
+
+    for (command_str, expected) in cases {
+        command(&storage, &encryptor, &search_engine, command_str, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn drop_bucket_removes_every_collection() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for command_str in [
+        "SET dropped c1 1 5:hello",
+        "SET dropped c2 2 5:hello",
+        "SET kept c1 1 5:hello",
+    ] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            command_str,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    let not_found = |entity| Err(HandleError::Storage(StorageError::NotFound(entity)));
+    let cases = vec![
+        (
+            "DRYRUN DROPBUCKET missing",
+            Ok(Response::Array(vec!["0".to_string()])),
+        ),
+        ("DROPBUCKET dropped", Ok(Response::Success)),
+        ("GET dropped c1 1", not_found(EntityType::Bucket)),
+        ("SEARCH dropped c1 hello", not_found(EntityType::Bucket)),
+        ("SEARCH dropped c2 hello", not_found(EntityType::Bucket)),
+        ("DROPBUCKET dropped", not_found(EntityType::Bucket)),
+        (
+            "SEARCH kept c1 hello",
+            Ok(Response::Array(vec!["1".to_string()])),
+        ),
+    ];
+
+    for (command_str, expected) in cases {
+        command(&storage, &encryptor, &search_engine, command_str, expected).await;
+    }
+
+    // no index entry of the bucket is left behind, in any engine
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SETENGINE {}", name),
+            Ok(Response::Success),
+        )
+        .await;
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            "SET gone c 1 5:hello",
+            Ok(Response::Success),
+        )
+        .await;
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            "DROPBUCKET gone",
+            Ok(Response::Success),
+        )
+        .await;
+        let engine = search_engine.read().unwrap();
+        let index = engine.collection_index("gone", "c").unwrap();
+        assert!(index.is_empty(), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn documents_matching_more_tokens_rank_first() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        let cases = vec![
+            (format!("SETENGINE {}", name), Ok(Response::Success)),
+            // ids sort the other way round, so ranking by id would put the best match last
+            ("SET b c a 9:apple pie".to_string(), Ok(Response::Success)),
+            (
+                "SET b c z 18:apple banana split".to_string(),
+                Ok(Response::Success),
+            ),
+            (
+                "SEARCH b c apple banana".to_string(),
+                Ok(Response::Array(vec!["z".to_string(), "a".to_string()])),
+            ),
+        ];
+        for (cmd, expected) in cases {
+            command(&storage, &encryptor, &search_engine, &cmd, expected).await;
+        }
+    }
+}
+
+#[test]
+fn engines_return_the_same_number_of_results() {
+    let storage = MockStorage::new();
+    let engines: Vec<(&str, DynSearchEngine)> = vec![
+        ("std", Box::new(StdSearchEngine::with_max_results(3))),
+        ("dash", Box::new(DashSearchEngine::with_max_results(3))),
+        ("dash2", Box::new(Dash2SearchEngine::with_max_results(3))),
+        ("btree", Box::new(BTreeSearchEngine::with_max_results(3))),
+        (
+            "bm25",
+            Box::new(Bm25SearchEngine::with_params(1.2, 0.75, 3)),
+        ),
+        ("ngram", Box::new(NgramSearchEngine::with_size(3, 3))),
+    ];
+    let limited = SearchOptions {
+        limit: Some(5),
+        ..Default::default()
+    };
+
+    for (name, engine) in engines {
+        for i in 0..20 {
+            let content = format!("common word{}", i);
+            engine
+                .index(&storage, "b", "c", &i.to_string(), &content)
+                .unwrap();
+        }
+        let options = SearchOptions::default();
+        let found = engine.search("b", "c", "common").unwrap();
+        assert_eq!(found.len(), 3, "{}", name);
+        let found = engine.search_prefix("b", "c", "comm", &options).unwrap();
+        assert_eq!(found.len(), 3, "{}", name);
+        let found = engine.search_fuzzy("b", "c", "comon", 1, &options).unwrap();
+        assert_eq!(found.len(), 3, "{}", name);
+        // a limit set by the query still wins
+        let found = engine
+            .search_with_options("b", "c", "common", &limited)
+            .unwrap();
+        assert_eq!(found.len(), 5, "{}", name);
+    }
+
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        let mut engine = engine_by_name(name, 4).unwrap();
+        for i in 0..20 {
+            engine
+                .index(&storage, "b", "c", &i.to_string(), "common")
+                .unwrap();
+        }
+        let found = engine.search("b", "c", "common").unwrap();
+        assert_eq!(found.len(), 4, "{}", name);
+        engine.set_max_results(DEFAULT_LIMIT);
+        let found = engine.search("b", "c", "common").unwrap();
+        assert_eq!(found.len(), DEFAULT_LIMIT, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn exists_reports_presence_without_content() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    let cases = vec![
+        ("EXISTS b c 1", Ok(Response::Integer(0))),
+        ("SET b c 1 5:hello", Ok(Response::Success)),
+        ("EXISTS b c 1", Ok(Response::Integer(1))),
+        ("EXISTS b c 2", Ok(Response::Integer(0))),
+        ("EXISTS b other 1", Ok(Response::Integer(0))),
+        ("REMOVE b c 1", Ok(Response::Success)),
+        ("EXISTS b c 1", Ok(Response::Integer(0))),
+    ];
+
+    for (command_str, expected) in cases {
+        command(&storage, &encryptor, &search_engine, command_str, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn expired_documents_read_as_missing() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let cases = vec![
+        ("SET b c 1 5:hello EX 1", Ok(Response::Success)),
+        ("SET b c 2 11:hello world", Ok(Response::Success)),
+        ("SET b c 3 11:hello again EX 60", Ok(Response::Success)),
+        ("GET b c 1", Ok(Response::BulkString("hello".to_string()))),
+        ("EXPIRE b c missing 10", Ok(Response::Integer(0))),
+        ("EXPIRE b c 2 0", Ok(Response::Integer(1))),
+        ("EXISTS b c 2", Ok(Response::Integer(0))),
+        ("GET b c 2", Ok(Response::Null)),
+        // a write makes the document permanent again
+        ("SET b c 3 11:hello again", Ok(Response::Success)),
+    ];
+    for (command_str, expected) in cases {
+        command(&storage, &encryptor, &search_engine, command_str, expected).await;
+    }
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    // not purged from the index yet, but left out of the results
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SEARCH b c hello",
+        Ok(Response::Array(vec!["3".to_string()])),
+    )
+    .await;
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "GET b c 1",
+        Ok(Response::Null),
+    )
+    .await;
+
+    // the first one was purged as it was read
+    assert_eq!(purge_expired(&storage, &search_engine), Ok(0));
+    assert_eq!(
+        search_engine.read().unwrap().search("b", "c", "hello"),
+        Ok(vec!["3".to_string()])
+    );
+}
+
+#[tokio::test]
+async fn rename_moves_document_to_new_id() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let cases = vec![
+        ("SET b c old 11:hello world", Ok(Response::Success)),
+        ("SET b c taken 13:goodbye world", Ok(Response::Success)),
+        ("RENAME b c old new", Ok(Response::Success)),
+        (
+            "SEARCH b c hello",
+            Ok(Response::Array(vec!["new".to_string()])),
+        ),
+        ("GET b c old", Ok(Response::Null)),
+        (
+            "GET b c new",
+            Ok(Response::BulkString("hello world".to_string())),
+        ),
+        // a document already under the new id is replaced
+        ("RENAME b c new taken", Ok(Response::Success)),
+        ("SEARCH b c goodbye", Ok(Response::Array(vec![]))),
+        (
+            "SEARCH b c world",
+            Ok(Response::Array(vec!["taken".to_string()])),
+        ),
+        ("RENAME b c taken taken", Ok(Response::Success)),
+        (
+            "GET b c taken",
+            Ok(Response::BulkString("hello world".to_string())),
+        ),
+        (
+            "RENAME b c missing other",
+            Err(HandleError::Storage(StorageError::NotFound(
+                EntityType::Item,
+            ))),
+        ),
+    ];
+    for (command_str, expected) in cases {
+        command(&storage, &encryptor, &search_engine, command_str, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn copy_duplicates_document() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    let cases = vec![
+        ("SET b c template 14:hello template", Ok(Response::Success)),
+        ("SET b c taken 13:goodbye world", Ok(Response::Success)),
+        ("COPY b c template copy", Ok(Response::Success)),
+        (
+            "SEARCH b c template",
+            Ok(Response::Array(vec![
+                "copy".to_string(),
+                "template".to_string(),
+            ])),
+        ),
+        // into another collection
+        ("COPY b c template copy TO other", Ok(Response::Success)),
+        (
+            "SEARCH b other hello",
+            Ok(Response::Array(vec!["copy".to_string()])),
+        ),
+        (
+            "GET b other copy",
+            Ok(Response::BulkString("hello template".to_string())),
+        ),
+        // the new id is taken, unless it is replaced
+        (
+            "COPY b c template taken",
+            Err(HandleError::InvalidArgument(
+                "taken already exists".to_string(),
+            )),
+        ),
+        (
+            "GET b c taken",
+            Ok(Response::BulkString("goodbye world".to_string())),
+        ),
+        ("COPY b c template taken REPLACE", Ok(Response::Success)),
+        ("SEARCH b c goodbye", Ok(Response::Array(vec![]))),
+        (
+            "GET b c taken",
+            Ok(Response::BulkString("hello template".to_string())),
+        ),
+        (
+            "COPY b c missing other",
+            Err(HandleError::Storage(StorageError::NotFound(
+                EntityType::Item,
+            ))),
+        ),
+    ];
+    for (command_str, expected) in cases {
+        command(&storage, &encryptor, &search_engine, command_str, expected).await;
+    }
+}
+
+#[tokio::test]
+async fn search_pages_through_results() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for i in 0..25 {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SET b c {:02} 6:common", i),
+            Ok(Response::Success),
+        )
+        .await;
+    }
+    let ids = |range: std::ops::Range<usize>| {
+        Ok(Response::Array(
+            range.map(|i| format!("{:02}", i)).collect(),
+        ))
+    };
+
+    let cases = vec![
+        // ten results unless told otherwise
+        ("SEARCH b c common", ids(0..10)),
+        ("SEARCH b c OFFSET 10 common", ids(10..20)),
+        ("SEARCH b c LIMIT 3 OFFSET 21 common", ids(21..24)),
+        ("SEARCH b c LIMIT 100 common", ids(0..25)),
+        ("SEARCH b c LIMIT 0 common", ids(0..0)),
+        // past the end
+        ("SEARCH b c OFFSET 25 common", ids(0..0)),
+        ("SEARCH b c OFFSET 1000 LIMIT 5 common", ids(0..0)),
+    ];
+
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SETENGINE {}", name),
+            Ok(Response::Success),
+        )
+        .await;
+        for (command_str, expected) in &cases {
+            let request = Request::from_bytes(command_str.as_bytes()).unwrap();
+            let result = handle_request(
+                request,
+                &storage,
+                &encryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            )
+            .await;
+            assert_eq!(&result, expected, "{} with {}", command_str, name);
+        }
+
+        // bounds past what the parser accepts rank no more than the matching ids
+        for (limit, offset, expected) in [(usize::MAX, 0, ids(0..25)), (1, usize::MAX, ids(0..0))] {
+            let request = Request::Search {
+                bucket: "b".into(),
+                collection: "c".into(),
+                query: "common".into(),
+                options: SearchOptions {
+                    limit: Some(limit),
+                    offset,
+                    ..Default::default()
+                },
+            };
+            let result = handle_request(
+                request,
+                &storage,
+                &encryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            )
+            .await;
+            assert_eq!(
+                result, expected,
+                "LIMIT {} OFFSET {} with {}",
+                limit, offset, name
+            );
+        }
+    }
+    assert!(Request::from_bytes(b"SEARCH b c LIMIT 18446744073709551615 common").is_err());
+}
+
+#[tokio::test]
+async fn search_prefix_matches_token_starts() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for command_str in [
+        "SET b c 1 12:some content",
+        "SET b c 2 11:a Container",
+        "SET b c 3 9:unrelated",
+        "SET b other 4 7:contact",
+    ] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            command_str,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    let ids = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+    let cases = vec![
+        ("SEARCHPREFIX b c cont", ids(&["1", "2"])),
+        ("SEARCHPREFIX b c LIMIT 1 OFFSET 1 cont", ids(&["2"])),
+        ("SEARCHPREFIX b c CONT unrel", ids(&["1", "2", "3"])),
+        ("SEARCHPREFIX b c contact", ids(&[])),
+        // whole tokens are prefixes of themselves
+        ("SEARCHPREFIX b c content", ids(&["1"])),
+    ];
+
+    // the btree engine scans its sorted keys, the others their whole collection index
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SETENGINE {}", name),
+            Ok(Response::Success),
+        )
+        .await;
+        for (command_str, expected) in &cases {
+            let request = Request::from_bytes(command_str.as_bytes()).unwrap();
+            let result = handle_request(
+                request,
+                &storage,
+                &encryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            )
+            .await;
+            assert_eq!(&result, expected, "{} with {}", command_str, name);
+        }
+    }
+}
+
+#[tokio::test]
+async fn search_fuzzy_tolerates_typos() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for command_str in [
+        "SET b c 1 11:hello world",
+        "SET b c 2 4:help",
+        "SET b c 3 12:yellow jello",
+        "SET b other 4 5:hello",
+    ] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            command_str,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    let ids = |ids: &[&str]| {
+        Ok(Response::Array(
+            ids.iter().map(|id| id.to_string()).collect(),
+        ))
+    };
+    let cases = vec![
+        ("SEARCHFUZZY b c 1 helo", ids(&["1", "2"])),
+        ("SEARCHFUZZY b c 0 helo", ids(&[])),
+        ("SEARCHFUZZY b c 0 hello", ids(&["1"])),
+        // more query tokens matched first, then closer matches
+        ("SEARCHFUZZY b c 1 helo wrld", ids(&["1", "2"])),
+        ("SEARCHFUZZY b c 2 helpp", ids(&["2", "1"])),
+        ("SEARCHFUZZY b c 1 MATCHALL helo wrld", ids(&["1"])),
+        ("SEARCHFUZZY b c 1 LIMIT 1 OFFSET 1 helo", ids(&["2"])),
+        // the first character is taken as typed
+        ("SEARCHFUZZY b c 1 jello", ids(&["3"])),
+        ("SEARCHFUZZY b missing 1 helo", ids(&[])),
+    ];
+
+    // the btree engine scans the keys sharing a first character, the others their whole index
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SETENGINE {}", name),
+            Ok(Response::Success),
+        )
+        .await;
+        for (command_str, expected) in &cases {
+            let request = Request::from_bytes(command_str.as_bytes()).unwrap();
+            let result = handle_request(
+                request,
+                &storage,
+                &encryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            )
+            .await;
+            assert_eq!(&result, expected, "{} with {}", command_str, name);
+        }
+
+        // the others copy only the tokens a fuzzy search keeps out of their index
+        let index = search_engine
+            .read()
+            .unwrap()
+            .collection_index_matching("b", "c", &|token| token.starts_with('h'))
+            .unwrap();
+        let mut tokens: Vec<&String> = index.keys().collect();
+        tokens.sort();
+        assert_eq!(tokens, ["hello", "help"], "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn search_match_all_requires_every_token() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for command_str in [
+        "SET b c both 17:hello hello world",
+        "SET b c hello 11:hello there",
+        "SET b c world 11:world peace",
+    ] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            command_str,
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    let cases: Vec<(&str, &[&str])> = vec![
+        ("SEARCH b c hello world", &["both", "hello", "world"]),
+        ("SEARCH b c MATCHALL hello world", &["both"]),
+        ("SEARCH b c MATCHALL hello", &["both", "hello"]),
+        ("SEARCH b c MATCHALL hello missing", &[]),
+        ("SEARCHPREFIX b c hel wor", &["both", "hello", "world"]),
+        ("SEARCHPREFIX b c MATCHALL hel wor", &["both"]),
+    ];
+
+    for name in ["std", "btree", "dash", "dash2", "bm25", "ngram"] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SETENGINE {}", name),
+            Ok(Response::Success),
+        )
+        .await;
+        for (command_str, expected) in &cases {
+            let request = Request::from_bytes(command_str.as_bytes()).unwrap();
+            let result = handle_request(
+                request,
+                &storage,
+                &encryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            )
+            .await;
+            let Ok(Response::Array(mut ids)) = result else {
+                panic!("{} with {}: {:?}", command_str, name, result);
+            };
+            ids.sort();
+            assert_eq!(&ids, expected, "{} with {}", command_str, name);
+        }
+    }
+}
+
+#[tokio::test]
+async fn list_ids_returns_every_id() {
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+
+    for id in ["c", "a", "d", "b"] {
+        command(
+            &storage,
+            &encryptor,
+            &search_engine,
+            &format!("SET b c {} 5:hello", id),
+            Ok(Response::Success),
+        )
+        .await;
+    }
+
+    let list = |command_str: &str| {
+        let request = Request::from_bytes(command_str.as_bytes()).unwrap();
+        let storage = storage.clone();
+        let search_engine = search_engine.clone();
+        async move {
+            match handle_request(
+                request,
+                &storage,
+                &MockEncryptor,
+                &search_engine,
+                &RwLock::new(ZzapConfig::default()),
+                None,
+                &ServerStatus::default(),
+            )
+            .await
+            {
+                Ok(Response::Array(ids)) => ids,
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+    };
+
+    let ids: HashSet<String> = list("LISTIDS b c").await.into_iter().collect();
+    assert_eq!(ids, HashSet::from(["a", "b", "c", "d"].map(String::from)));
+    assert_eq!(list("LISTIDS b c 2").await, ["a", "b"]);
+    assert!(list("LISTIDS b missing").await.is_empty());
+    assert!(list("LISTIDS missing c").await.is_empty());
+}
+
+#[tokio::test]
+async fn shutdown_persists_storage() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const PERSISTENCE_PATH: &str = "test_shutdown.db";
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = ZzapServer::new(
+        addr,
+        Storage::new(PERSISTENCE_PATH),
+        MockEncryptor,
+        Box::new(StdSearchEngine::new()),
+        ZzapConfig::default(),
+    );
+    let (shutdown, shutdown_received) = tokio::sync::oneshot::channel::<()>();
+    let serving = tokio::spawn(async move {
+        server
+            .serve(listener, async {
+                let _ = shutdown_received.await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"SET b c 1 9:persisted\n").await.unwrap();
+    let mut response = [0; 4];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"+OK\n");
+
+    // the client stays connected, shutting down must not wait for it to leave
+    shutdown.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+    assert_eq!(client.read(&mut response).await.unwrap(), 0);
+
+    let mut recovered = Storage::new(PERSISTENCE_PATH);
+    recovered.initialize().unwrap();
+    std::fs::remove_file(PERSISTENCE_PATH).unwrap();
+    std::fs::remove_file("test_shutdown.zzap_collections").unwrap();
+
+    let document = recovered.get_document("b", "c", "1").unwrap();
+    assert_eq!(document.content, "persisted");
+}
+
+#[tokio::test]
+async fn connections_past_the_limit_are_rejected() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = ZzapServer::new(
+        addr,
+        Storage::new("test_connection_limit.db"),
+        MockEncryptor,
+        Box::new(StdSearchEngine::new()),
+        ZzapConfig {
+            max_connections: Some(2),
+            persist_interval: None,
+            ..Default::default()
+        },
+    );
+    let (shutdown, shutdown_received) = tokio::sync::oneshot::channel::<()>();
+    let serving = tokio::spawn(async move {
+        server
+            .serve(listener, async {
+                let _ = shutdown_received.await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    // answered once served, so the connection is known to hold its place
+    let connect = || async {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"PING\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\n") {
+            let mut byte = [0];
+            if client.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            response.push(byte[0]);
+        }
+        (client, response)
+    };
+
+    let (mut first, response) = connect().await;
+    assert_eq!(response, b"+OK\n");
+    let (second, response) = connect().await;
+    assert_eq!(response, b"+OK\n");
+    let (mut rejected, response) = connect().await;
+    assert_eq!(response, b"-ERR too many connections\n");
+    assert_eq!(rejected.read(&mut [0]).await.unwrap(), 0);
+
+    first.write_all(b"STATS\n").await.unwrap();
+    let mut stats = Vec::new();
+    let stats = loop {
+        let mut chunk = [0; 256];
+        let read = first.read(&mut chunk).await.unwrap();
+        stats.extend_from_slice(&chunk[..read]);
+        if let Ok(Response::Array(stats)) = Response::from_bytes(&stats) {
+            break stats;
+        }
+    };
+    assert_eq!(stats.last().map(String::as_str), Some("connections 2"));
+
+    // a connection leaving makes room for another
+    drop(second);
+    let mut admitted = false;
+    for _ in 0..100 {
+        if connect().await.1 == b"+OK\n" {
+            admitted = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(admitted);
+
+    shutdown.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+    let _ = std::fs::remove_file("test_connection_limit.db");
+    let _ = std::fs::remove_file("test_connection_limit.zzap_collections");
+}
+
+#[tokio::test]
+async fn storage_is_persisted_periodically() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const PERSISTENCE_PATH: &str = "test_periodic.db";
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = ZzapServer::new(
+        addr,
+        Storage::new(PERSISTENCE_PATH),
+        MockEncryptor,
+        Box::new(StdSearchEngine::new()),
+        ZzapConfig {
+            persist_interval: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        },
+    );
+    let (shutdown, shutdown_received) = tokio::sync::oneshot::channel::<()>();
+    let serving = tokio::spawn(async move {
+        server
+            .serve(listener, async {
+                let _ = shutdown_received.await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"SET b c 1 8:periodic\n").await.unwrap();
+    let mut response = [0; 4];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"+OK\n");
+
+    // saved without a shutdown or a SAVE, while the server keeps running
+    let mut recovered = None;
+    for _ in 0..100 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        if storage.initialize().is_ok() && storage.get_document("b", "c", "1").is_ok() {
+            recovered = Some(storage);
+            break;
+        }
+    }
+    let recovered = recovered.expect("storage was never persisted");
+    assert_eq!(
+        recovered.get_document("b", "c", "1").unwrap().content,
+        "periodic"
+    );
+
+    shutdown.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+    std::fs::remove_file(PERSISTENCE_PATH).unwrap();
+    std::fs::remove_file("test_periodic.zzap_collections").unwrap();
+}
+
+#[tokio::test]
+async fn autosave_interval_applies_while_running() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const PERSISTENCE_PATH: &str = "test_autosave.db";
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = ZzapServer::new(
+        addr,
+        Storage::new(PERSISTENCE_PATH),
+        MockEncryptor,
+        Box::new(StdSearchEngine::new()),
+        ZzapConfig {
+            persist_interval: None,
+            ..Default::default()
+        },
+    );
+    let (shutdown, shutdown_received) = tokio::sync::oneshot::channel::<()>();
+    let serving = tokio::spawn(async move {
+        server
+            .serve(listener, async {
+                let _ = shutdown_received.await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut response = [0; 4];
+    client.write_all(b"SET b c 1 8:autosave\n").await.unwrap();
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"+OK\n");
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert!(!std::path::Path::new(PERSISTENCE_PATH).exists());
+
+    client
+        .write_all(b"CONFIG SET AUTOSAVEINTERVAL 1\n")
+        .await
+        .unwrap();
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"+OK\n");
+    // the snapshot, not only the write-ahead log, holds the document
+    let mut saved = false;
+    for _ in 0..60 {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        if std::path::Path::new(PERSISTENCE_PATH).exists() {
+            saved = true;
+            break;
+        }
+    }
+    assert!(saved, "storage was never persisted");
+
+    shutdown.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+    std::fs::remove_file(PERSISTENCE_PATH).unwrap();
+    std::fs::remove_file("test_autosave.zzap_collections").unwrap();
+}
+
+#[tokio::test]
+async fn tls_connections_round_trip() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    const TLS_DIR: &str = "assets/tests/tls";
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = ZzapServer::new(
+        addr,
+        Storage::new("test_tls.db"),
+        MockEncryptor,
+        Box::new(StdSearchEngine::new()),
+        ZzapConfig {
+            persist_interval: None,
+            tls_cert_path: Some(format!("{}/cert.pem", TLS_DIR).into()),
+            tls_key_path: Some(format!("{}/key.pem", TLS_DIR).into()),
+            ..Default::default()
+        },
+    );
+    let (shutdown, shutdown_received) = tokio::sync::oneshot::channel::<()>();
+    let serving = tokio::spawn(async move {
+        server
+            .serve(listener, async {
+                let _ = shutdown_received.await;
+            })
+            .await
+            .map_err(|e| e.to_string())
+    });
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(format!("{}/ca.pem", TLS_DIR)).unwrap())
+        .unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut client = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+
+    let mut exchange = async |request: &[u8], expected: &[u8]| {
+        client.write_all(request).await.unwrap();
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, expected);
+    };
+    exchange(b"PING\n", b"+OK\n").await;
+    exchange(b"SET b c 1 9:encrypted\n", b"+OK\n").await;
+    exchange(b"GET b c 1\n", b"$9\nencrypted\n").await;
+
+    // a plaintext client does not get past the handshake
+    let mut plain = tokio::net::TcpStream::connect(addr).await.unwrap();
+    plain.write_all(b"PING\n").await.unwrap();
+    let mut response = Vec::new();
+    let _ = plain.read_to_end(&mut response).await;
+    assert!(!response.starts_with(b"+OK"));
+
+    shutdown.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+    std::fs::remove_file("test_tls.db").unwrap();
+    std::fs::remove_file("test_tls.zzap_collections").unwrap();
+}
+
+/// Name of a span and its fields, as `(name, value)` pairs.
+type RecordedSpan = (String, Vec<(String, String)>);
+
+/// Every span opened while it is the default subscriber.
+#[derive(Clone, Default)]
+struct SpanRecorder(Arc<std::sync::Mutex<Vec<RecordedSpan>>>);
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanRecorder {
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        _: &tracing::span::Id,
+        _: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        struct Fields(Vec<(String, String)>);
+        impl tracing::field::Visit for Fields {
+            fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                self.0.push((field.name().to_string(), value.to_string()));
+            }
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .push((field.name().to_string(), format!("{:?}", value)));
+            }
+        }
+
+        let mut fields = Fields(Vec::new());
+        attrs.record(&mut fields);
+        let name = attrs.metadata().name().to_string();
+        self.0.lock().unwrap().push((name, fields.0));
+    }
+}
+
+#[tokio::test]
+async fn requests_are_handled_within_a_span() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let recorder = SpanRecorder::default();
+    let _default =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+
+    let storage = Arc::new(Storage::new("test.db"));
+    let encryptor = MockEncryptor;
+    let search_engine = std_engine();
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "SET posts drafts 1 5:hello",
+        Ok(Response::Success),
+    )
+    .await;
+    command(
+        &storage,
+        &encryptor,
+        &search_engine,
+        "PING",
+        Ok(Response::Success),
+    )
+    .await;
+
+    let field = |name: &str, value: &str| (name.to_string(), value.to_string());
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        [
+            (
+                "request".to_string(),
+                vec![
+                    field("command", "SET"),
+                    field("bucket", "posts"),
+                    field("collection", "drafts"),
+                ]
+            ),
+            ("request".to_string(), vec![field("command", "PING")]),
+        ]
+    );
+}
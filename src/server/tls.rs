@@ -0,0 +1,25 @@
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Terminates TLS with the certificate chain and private key of the given PEM files, the chain
+/// starting with the server certificate.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("invalid certificate {}: {}", cert_path.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("invalid private key {}: {}", key_path.display(), e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or private key: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
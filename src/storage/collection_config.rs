@@ -0,0 +1,108 @@
+use crate::lang::{self, TokenizerOptions};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Settings of a single collection, changed with `CONFIGURE`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CollectionConfig {
+    /// Documents producing fewer tokens are stored, but not indexed. `0` indexes everything.
+    pub min_tokens: usize,
+    /// Only the first tokens of a document are indexed, the rest of its content is stored but
+    /// not searchable. `0` indexes every token.
+    pub max_tokens_per_document: usize,
+    /// Strip diacritics from documents and queries, see [`TokenizerOptions::deaccent`].
+    pub deaccent: bool,
+    /// Index without lowercasing, see [`TokenizerOptions::case_sensitive`].
+    pub case_sensitive: bool,
+    /// Drop common words such as "the" from documents and queries, see [`lang::stop_words`].
+    pub stop_words: bool,
+    /// Reduce English words to their stem in documents and queries, see [`lang::stem::stem`].
+    pub stem: bool,
+    /// Tokens dropped from every collection, filled in by the storage from its own blacklist
+    /// rather than set with `CONFIGURE`.
+    #[serde(skip)]
+    pub blacklist: Arc<HashSet<String>>,
+}
+
+impl CollectionConfig {
+    /// Updates a single option from its wire representation.
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        match option.to_uppercase().as_str() {
+            "MINTOKENS" => self.min_tokens = parse_value(option, value)?,
+            "MAXTOKENS" => self.max_tokens_per_document = parse_value(option, value)?,
+            "DEACCENT" => self.deaccent = parse_value(option, value)?,
+            "CASESENSITIVE" => self.case_sensitive = parse_value(option, value)?,
+            "STOPWORDS" => self.stop_words = parse_value(option, value)?,
+            "STEM" => self.stem = parse_value(option, value)?,
+            _ => return Err(format!("unknown option {}", option)),
+        }
+        Ok(())
+    }
+
+    /// Drops the tokens of a document past `max_tokens_per_document`.
+    pub fn cap_tokens(&self, tokens: &mut Vec<String>) {
+        if self.max_tokens_per_document > 0 {
+            tokens.truncate(self.max_tokens_per_document);
+        }
+    }
+
+    /// Tokenizer options documents of the collection are indexed and searched with.
+    pub fn tokenizer(&self) -> TokenizerOptions {
+        TokenizerOptions {
+            deaccent: self.deaccent,
+            case_sensitive: self.case_sensitive,
+            blacklist: self.blacklist.clone(),
+            stop_words: if self.stop_words {
+                lang::stop_words()
+            } else {
+                Default::default()
+            },
+            stem: self.stem,
+        }
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {} for {}", value, option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_option() {
+        let mut config = CollectionConfig::default();
+        config.set("mintokens", "3").unwrap();
+        assert_eq!(config.min_tokens, 3);
+
+        assert_eq!(
+            config.set("MINTOKENS", "many"),
+            Err("invalid value many for MINTOKENS".to_string())
+        );
+        assert_eq!(
+            config.set("COLOR", "blue"),
+            Err("unknown option COLOR".to_string())
+        );
+        assert_eq!(config.min_tokens, 3);
+    }
+
+    #[test]
+    fn test_cap_tokens() {
+        let tokens = || vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+        let mut uncapped = tokens();
+        CollectionConfig::default().cap_tokens(&mut uncapped);
+        assert_eq!(uncapped, tokens());
+
+        let mut config = CollectionConfig::default();
+        config.set("MAXTOKENS", "2").unwrap();
+        let mut capped = tokens();
+        config.cap_tokens(&mut capped);
+        assert_eq!(capped, ["a", "b"]);
+    }
+}
@@ -0,0 +1,147 @@
+use super::StorageError;
+use std::fmt;
+use std::str::FromStr;
+
+/// Marks a compressed snapshot, followed by the byte of its codec. Snapshots without it are
+/// plain flexbuffers, as written before snapshots could be compressed.
+const MAGIC: &[u8] = b"ZZAPCMP\0";
+const ZSTD: u8 = 1;
+const DEFLATE: u8 = 2;
+
+/// Default levels, a balance of speed and size.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+pub const DEFAULT_DEFLATE_LEVEL: u8 = 6;
+
+/// How snapshots are compressed when written. Any of them is read back whatever the setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Zstandard, at a level from 1 to 22
+    Zstd(i32),
+    /// Deflate, at a level from 0 to 10
+    Deflate(u8),
+}
+
+impl Compression {
+    pub fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        let (codec, compressed) = match *self {
+            Compression::None => return Ok(data),
+            Compression::Zstd(level) => (
+                ZSTD,
+                zstd::encode_all(&*data, level)
+                    .map_err(|e| StorageError::SerializationError(e.to_string()))?,
+            ),
+            Compression::Deflate(level) => {
+                (DEFLATE, miniz_oxide::deflate::compress_to_vec(&data, level))
+            }
+        };
+
+        let mut framed = Vec::with_capacity(MAGIC.len() + 1 + compressed.len());
+        framed.extend_from_slice(MAGIC);
+        framed.push(codec);
+        framed.extend_from_slice(&compressed);
+        Ok(framed)
+    }
+
+    /// Decompresses what any codec wrote, data without the header is returned as is.
+    pub fn decompress(data: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        let Some(rest) = data.strip_prefix(MAGIC) else {
+            return Ok(data);
+        };
+        let error = |e: String| StorageError::DeserializationError(e);
+        match rest.split_first() {
+            Some((&ZSTD, compressed)) => {
+                zstd::decode_all(compressed).map_err(|e| error(e.to_string()))
+            }
+            Some((&DEFLATE, compressed)) => miniz_oxide::inflate::decompress_to_vec(compressed)
+                .map_err(|e| error(e.to_string())),
+            Some((codec, _)) => Err(error(format!("unknown compression codec {}", codec))),
+            None => Err(error("missing compression codec".to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd(level) => write!(f, "zstd:{}", level),
+            Compression::Deflate(level) => write!(f, "deflate:{}", level),
+        }
+    }
+}
+
+/// Parses `<codec>[:<level>]`, i.e. `zstd`, `zstd:19` or `none`.
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (codec, level) = match s.split_once(':') {
+            Some((codec, level)) => (codec, Some(level)),
+            None => (s, None),
+        };
+        let invalid = || format!("invalid compression {}, expected codec[:level]", s);
+
+        Ok(match (codec.to_lowercase().as_str(), level) {
+            ("none", None) => Compression::None,
+            ("zstd", level) => {
+                let level = level.map_or(Ok(DEFAULT_ZSTD_LEVEL), str::parse);
+                match level {
+                    Ok(level @ 1..=22) => Compression::Zstd(level),
+                    _ => return Err(invalid()),
+                }
+            }
+            ("deflate", level) => {
+                let level = level.map_or(Ok(DEFAULT_DEFLATE_LEVEL), str::parse);
+                match level {
+                    Ok(level @ 0..=10) => Compression::Deflate(level),
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"text heavy ".repeat(1000);
+        for compression in [
+            Compression::None,
+            Compression::Zstd(DEFAULT_ZSTD_LEVEL),
+            Compression::Deflate(DEFAULT_DEFLATE_LEVEL),
+        ] {
+            let compressed = compression.compress(data.clone()).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len() / 10, "{}", compression);
+            }
+            assert_eq!(Compression::decompress(compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("none".parse(), Ok(Compression::None));
+        assert_eq!("zstd".parse(), Ok(Compression::Zstd(DEFAULT_ZSTD_LEVEL)));
+        assert_eq!("ZSTD:19".parse(), Ok(Compression::Zstd(19)));
+        assert_eq!("deflate:1".parse(), Ok(Compression::Deflate(1)));
+        for invalid in ["gzip", "zstd:0", "zstd:fast", "deflate:11", "none:1"] {
+            assert_eq!(
+                invalid.parse::<Compression>(),
+                Err(format!(
+                    "invalid compression {}, expected codec[:level]",
+                    invalid
+                ))
+            );
+        }
+        assert_eq!(
+            Compression::Zstd(19).to_string().parse(),
+            Ok(Compression::Zstd(19))
+        );
+    }
+}
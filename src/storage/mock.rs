@@ -1,12 +1,16 @@
-use super::{Document, StorageError, StorageOperations};
+use super::{CollectionConfig, Document, StorageError, StorageOperations};
 use crate::storage::EntityType;
 use std::collections::HashMap;
 use std::sync::RwLock;
 
-pub struct MockStorage(RwLock<HashMap<String, Document>>);
+/// Keeps documents by id alone, with a single config shared by every collection
+pub struct MockStorage(RwLock<HashMap<String, Document>>, RwLock<CollectionConfig>);
 impl MockStorage {
     pub fn new() -> Self {
-        MockStorage(RwLock::new(HashMap::new()))
+        MockStorage(
+            RwLock::new(HashMap::new()),
+            RwLock::new(CollectionConfig::default()),
+        )
     }
 }
 impl StorageOperations for MockStorage {
@@ -25,6 +29,10 @@ impl StorageOperations for MockStorage {
             .ok_or(StorageError::NotFound(EntityType::Item));
         res
     }
+    fn get_version(&self, bucket: &str, collection: &str, id: &str) -> Result<u64, StorageError> {
+        // versions are not tracked, every stored document reports its first write
+        self.get_document(bucket, collection, id).map(|_| 1)
+    }
     fn add_document(
         &self,
         _bucket: &str,
@@ -37,6 +45,15 @@ impl StorageOperations for MockStorage {
             .insert(document.id.clone(), document);
         Ok(())
     }
+    fn restore_document(
+        &self,
+        bucket: &str,
+        collection: &str,
+        document: Document,
+        _version: u64,
+    ) -> Result<(), StorageError> {
+        self.add_document(bucket, collection, document)
+    }
     fn delete_document(
         &self,
         _bucket: &str,
@@ -49,6 +66,45 @@ impl StorageOperations for MockStorage {
             .remove(id);
         Ok(())
     }
+    fn delete_collection(&self, _bucket: &str, _collection: &str) -> Result<(), StorageError> {
+        // every document belongs to the one collection
+        self.0
+            .write()
+            .map_err(|_| StorageError::PoisonError)?
+            .clear();
+        Ok(())
+    }
+    fn delete_bucket(&self, bucket: &str) -> Result<(), StorageError> {
+        self.delete_collection(bucket, "")
+    }
+    fn collection_config(&self, _bucket: &str, _collection: &str) -> CollectionConfig {
+        self.1
+            .read()
+            .map(|config| config.clone())
+            .unwrap_or_default()
+    }
+    fn set_collection_config(
+        &self,
+        _bucket: &str,
+        _collection: &str,
+        config: CollectionConfig,
+    ) -> Result<(), StorageError> {
+        *self.1.write().map_err(|_| StorageError::PoisonError)? = config;
+        Ok(())
+    }
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, String, Document)> + '_> {
+        // documents are not kept per bucket and collection, so both are reported empty
+        let documents: Vec<Document> = self
+            .0
+            .read()
+            .map(|documents| documents.values().cloned().collect())
+            .unwrap_or_default();
+        Box::new(
+            documents
+                .into_iter()
+                .map(|document| (String::new(), String::new(), document)),
+        )
+    }
     fn persist(&self) -> Result<(), StorageError> {
         Ok(())
     }
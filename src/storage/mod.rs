@@ -1,14 +1,34 @@
+mod collection_config;
+mod compression;
 mod error;
 pub mod mock;
+mod value;
+mod wal;
 
+pub use collection_config::*;
+pub use compression::*;
 pub use error::*;
+pub use value::*;
 
-use dashmap::{try_result::TryResult, DashMap};
+use dashmap::{mapref::one::Ref, DashMap};
 use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashSet},
+    io::Write,
+    ops::Bound,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
 };
+use wal::{Wal, WalGuard, WalRecord};
+
+/// Milliseconds since the Unix epoch, the clock document expiry times are measured with.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Document {
@@ -25,19 +45,50 @@ impl Document {
     }
 }
 
+/// Size of the stored data, see [`Storage::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    pub buckets: usize,
+    pub collections: usize,
+    pub documents: usize,
+    /// Bytes of the ids and contents of every document, a lower bound of the memory they take.
+    pub content_bytes: usize,
+}
+
 // Bucket
 // |
 // Collection
 // |
-// Document, where the value is the content and the key is the id
-type StorageInner = DashMap<String, DashMap<String, DashMap<String, String>>>;
+// Document, where the value is the content (with its metadata) and the key is the id
+type StorageInner = DashMap<String, DashMap<String, DashMap<String, StoredValue>>>;
 
 pub struct Storage {
     pub store: Arc<StorageInner>,
     persistence_path: PathBuf,
+    compression: Compression,
+    // serializes snapshots, so explicit and background persists never write the same file at once
+    persist_lock: Mutex<()>,
+    /// Changes to documents since the last snapshot, logged once the storage is loaded
+    wal: Wal,
+    collection_configs: DashMap<(String, String), CollectionConfig>,
+    /// Last id generated by `ADD` in each collection
+    id_counters: DashMap<(String, String), u64>,
+    /// Highest version of the documents removed from each collection. Documents stored anew
+    /// carry on from it, so no version is handed out twice for an id.
+    removed_versions: DashMap<(String, String), u64>,
+    bucket_locks: DashMap<String, Arc<RwLock<()>>>,
+    blacklist: RwLock<Arc<HashSet<String>>>,
+    /// Expiry times set on documents, as `(expires_at, bucket, collection, id)`, so the due ones
+    /// are found without scanning the store. Entries outlive the expiry they were set for when
+    /// the document is overwritten, removed or given another one, and are dropped once due.
+    expiries: Mutex<BTreeSet<(u64, String, String, String)>>,
+    /// Ids of each collection, by bucket then collection, sorted so they are listed from any of
+    /// them without sorting the whole collection
+    sorted_ids: DashMap<String, DashMap<String, BTreeSet<String>>>,
 }
 
-pub trait StorageOperations {
+/// Shared across threads, i.e. by engines indexing documents in parallel.
+pub trait StorageOperations: Sync {
     fn add_document(
         &self,
         bucket: &str,
@@ -50,8 +101,37 @@ pub trait StorageOperations {
         collection: &str,
         id: &str,
     ) -> Result<Document, StorageError>;
+    /// Returns how many times the document has been written, counting those of a document
+    /// removed before under the same id.
+    fn get_version(&self, bucket: &str, collection: &str, id: &str) -> Result<u64, StorageError>;
+    /// Writes back a document as it was at `version`, undoing a write that could not be
+    /// completed.
+    fn restore_document(
+        &self,
+        bucket: &str,
+        collection: &str,
+        document: Document,
+        version: u64,
+    ) -> Result<(), StorageError>;
     fn delete_document(&self, bucket: &str, collection: &str, id: &str)
         -> Result<(), StorageError>;
+    /// Removes the collection and every document in it, the bucket too if nothing is left in it.
+    fn delete_collection(&self, bucket: &str, collection: &str) -> Result<(), StorageError>;
+    /// Removes the bucket with every collection and document in it.
+    fn delete_bucket(&self, bucket: &str) -> Result<(), StorageError>;
+    /// Returns the settings of the collection, defaults if it was never configured.
+    fn collection_config(&self, bucket: &str, collection: &str) -> CollectionConfig;
+    fn set_collection_config(
+        &self,
+        bucket: &str,
+        collection: &str,
+        config: CollectionConfig,
+    ) -> Result<(), StorageError>;
+    /// Yields every document as `(bucket, collection, document)`.
+    ///
+    /// Documents are copied out a bucket at a time, so no lock is held between two calls to
+    /// `next` and writes made meanwhile may or may not be seen.
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, String, Document)> + '_>;
     fn persist(&self) -> Result<(), StorageError>;
     fn load(&mut self) -> Result<(), StorageError>;
     fn initialize(&mut self) -> Result<(), StorageError>;
@@ -61,17 +141,11 @@ pub trait StorageOperationsInternal: StorageOperations {
     fn store(&self) -> Result<Arc<StorageInner>, StorageError>;
 }
 
-trait TryResultUnwrapStorageError<T> {
-    fn unwrap_storage_error(self, entity_type: EntityType) -> Result<T, StorageError>;
-}
-
-impl<T> TryResultUnwrapStorageError<T> for TryResult<T> {
-    fn unwrap_storage_error(self, entity_type: EntityType) -> Result<T, StorageError> {
-        match self {
-            TryResult::Present(item) => Ok(item),
-            TryResult::Absent => Err(StorageError::NotFound(entity_type)),
-            TryResult::Locked => Err(StorageError::Locked(entity_type)),
-        }
+/// Entry of the map, inserted empty if missing.
+fn get_or_insert<'a, V: Default>(map: &'a DashMap<String, V>, key: &str) -> Ref<'a, String, V> {
+    match map.get(key) {
+        Some(value) => value,
+        None => map.entry(key.to_string()).or_default().downgrade(),
     }
 }
 
@@ -80,8 +154,406 @@ impl Storage {
         Storage {
             store: Arc::new(DashMap::new()),
             persistence_path: persistence_path.as_ref().to_path_buf(),
+            compression: Compression::None,
+            persist_lock: Mutex::new(()),
+            wal: Wal::new(persistence_path.as_ref().with_extension("zzap_wal")),
+            collection_configs: DashMap::new(),
+            id_counters: DashMap::new(),
+            removed_versions: DashMap::new(),
+            bucket_locks: DashMap::new(),
+            blacklist: RwLock::default(),
+            expiries: Mutex::default(),
+            sorted_ids: DashMap::new(),
+        }
+    }
+
+    /// Compresses the snapshots written from now on. Snapshots are loaded whatever they were
+    /// written with.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Collection configs and id counters are kept next to the snapshot rather than in it, so
+    /// snapshots written before collections had them still load.
+    fn collections_path(&self) -> PathBuf {
+        self.persistence_path.with_extension("zzap_collections")
+    }
+
+    /// Returns an id no document of the collection has, for a document stored without one.
+    ///
+    /// Ids count up from 1 and are never handed out twice, even after the document is removed or
+    /// the storage restarted. Ids already taken by documents set with an explicit id are skipped.
+    /// The caller holds the bucket lock exclusively until the document is stored, so no
+    /// concurrent `SET` takes the id meanwhile.
+    ///
+    /// The counter is logged but not flushed: storing the document flushes the log past it, before
+    /// the id is handed back to the client.
+    pub fn generate_id(&self, bucket: &str, collection: &str) -> Result<String, StorageError> {
+        let mut wal = self.wal.lock()?;
+        let mut counter = self
+            .id_counters
+            .entry((bucket.to_string(), collection.to_string()))
+            .or_default();
+        loop {
+            *counter += 1;
+            match self.get_version(bucket, collection, &counter.to_string()) {
+                Err(e) if e.is_not_found() => break,
+                _ => continue,
+            }
+        }
+        wal.append(&WalRecord::IdCounter {
+            bucket: bucket.into(),
+            collection: collection.into(),
+            counter: *counter,
+        })?;
+        Ok(counter.to_string())
+    }
+
+    /// Tokens left out of the index and of queries in every collection.
+    pub fn blacklist(&self) -> Arc<HashSet<String>> {
+        self.blacklist
+            .read()
+            .map(|blacklist| blacklist.clone())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the blacklist. Documents indexed before keep their tokens until they are set again,
+    /// but queries stop looking them up right away.
+    pub fn set_blacklist(&self, blacklist: HashSet<String>) -> Result<(), StorageError> {
+        self.logged(|wal| {
+            wal.append(&WalRecord::Blacklist {
+                tokens: blacklist.iter().map(|token| token.into()).collect(),
+            })?;
+            self.replace_blacklist(blacklist)
+        })
+    }
+
+    fn replace_blacklist(&self, blacklist: HashSet<String>) -> Result<(), StorageError> {
+        *self
+            .blacklist
+            .write()
+            .map_err(|_| StorageError::PoisonError)? = Arc::new(blacklist);
+        Ok(())
+    }
+
+    /// Lock guarding a whole bucket, so operations on other buckets carry on in the meantime.
+    ///
+    /// Writes to a single document hold it shared, operations touching many documents of the
+    /// bucket hold it exclusively. It is always taken before the search engine's lock, to avoid
+    /// deadlocks.
+    pub fn bucket_lock(&self, bucket: &str) -> Arc<RwLock<()>> {
+        self.bucket_locks
+            .entry(bucket.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Makes a change holding the write-ahead log, so changes are applied in the order they are
+    /// logged in, then waits for its records to be on disk. The log is released meanwhile, so the
+    /// records of concurrent changes are flushed together.
+    fn logged<T>(
+        &self,
+        change: impl FnOnce(&mut WalGuard) -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        let mut wal = self.wal.lock()?;
+        let result = change(&mut wal);
+        let written = wal.written();
+        drop(wal);
+
+        if let Some(position) = written {
+            self.wal.sync(position)?;
+        }
+        result
+    }
+
+    /// Sets when the document expires, or makes it permanent again with `None`.
+    pub fn set_expiry(
+        &self,
+        bucket: &str,
+        collection: &str,
+        id: &str,
+        expires_at: Option<u64>,
+    ) -> Result<(), StorageError> {
+        self.logged(|wal| {
+            // released before the index is, `expired_documents` reads the store holding it
+            {
+                let bucket_map = self
+                    .store
+                    .get(bucket)
+                    .ok_or(StorageError::NotFound(EntityType::Bucket))?;
+                let collection_map = bucket_map
+                    .get(collection)
+                    .ok_or(StorageError::NotFound(EntityType::Collection))?;
+                let mut value = collection_map
+                    .get_mut(id)
+                    .ok_or(StorageError::NotFound(EntityType::Item))?;
+                wal.append(&WalRecord::Expire {
+                    bucket: bucket.into(),
+                    collection: collection.into(),
+                    id: id.into(),
+                    expires_at,
+                })?;
+                value.metadata.expires_at = expires_at;
+            }
+
+            if let Some(expires_at) = expires_at {
+                self.expiries
+                    .lock()
+                    .map_err(|_| StorageError::PoisonError)?
+                    .insert((expires_at, bucket.into(), collection.into(), id.into()));
+            }
+            Ok(())
+        })
+    }
+
+    /// When the document expires, in [`unix_millis`]. `None` if it never does or is not stored.
+    pub fn expiry(&self, bucket: &str, collection: &str, id: &str) -> Option<u64> {
+        self.store
+            .get(bucket)?
+            .get(collection)?
+            .get(id)?
+            .metadata
+            .expires_at
+    }
+
+    /// Whether the document is stored but expired at `now`, in [`unix_millis`].
+    pub fn is_expired(&self, bucket: &str, collection: &str, id: &str, now: u64) -> bool {
+        self.store
+            .get(bucket)
+            .and_then(|bucket| {
+                let collection = bucket.get(collection)?;
+                let expired = collection.get(id)?.metadata.is_expired(now);
+                Some(expired)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Counts what is stored, expired documents not purged yet included.
+    pub fn stats(&self) -> StorageStats {
+        let mut stats = StorageStats::default();
+        for bucket in self.store.iter() {
+            stats.buckets += 1;
+            for collection in bucket.iter() {
+                stats.collections += 1;
+                for document in collection.iter() {
+                    stats.documents += 1;
+                    stats.content_bytes += document.key().len() + document.content.len();
+                }
+            }
+        }
+        stats
+    }
+
+    /// Every document expired at `now`, as bucket, collection and id.
+    ///
+    /// Only the expiries due by `now` are looked at, those no document holds anymore are dropped
+    /// on the way. The others are kept until their document is gone, should purging it fail.
+    pub fn expired_documents(&self, now: u64) -> Vec<(String, String, String)> {
+        let Ok(mut expiries) = self.expiries.lock() else {
+            return Vec::new();
+        };
+        let mut expired = Vec::new();
+        let mut outdated = Vec::new();
+        // sorted by expiry time first, so the due ones come first
+        for entry in expiries
+            .iter()
+            .take_while(|(expires_at, ..)| *expires_at <= now)
+        {
+            let (expires_at, bucket, collection, id) = entry;
+            let held = self
+                .store
+                .get(bucket)
+                .and_then(|bucket| Some(bucket.get(collection)?.get(id)?.metadata.expires_at))
+                .flatten();
+            if held == Some(*expires_at) {
+                expired.push((bucket.clone(), collection.clone(), id.clone()));
+            } else {
+                outdated.push(entry.clone());
+            }
+        }
+        for entry in &outdated {
+            expiries.remove(entry);
+        }
+        expired
+    }
+
+    /// Up to `count` ids of a collection, the first ones sorting after `after` or from the first
+    /// if `None`, and whether more follow.
+    pub fn ids_after(
+        &self,
+        bucket: &str,
+        collection: &str,
+        after: Option<&str>,
+        count: usize,
+    ) -> (Vec<String>, bool) {
+        let Some(bucket) = self.sorted_ids.get(bucket) else {
+            return (Vec::new(), false);
+        };
+        let Some(ids) = bucket.get(collection) else {
+            return (Vec::new(), false);
+        };
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut range = ids.range::<str, _>((start, Bound::Unbounded));
+        let page = range.by_ref().take(count).cloned().collect();
+        (page, range.next().is_some())
+    }
+
+    /// Adds a stored document to the sorted ids of its collection.
+    fn index_id(&self, bucket: &str, collection: &str, id: &str) {
+        let bucket_ids = get_or_insert(&self.sorted_ids, bucket);
+        let mut ids = match bucket_ids.get_mut(collection) {
+            Some(ids) => ids,
+            None => bucket_ids.entry(collection.to_string()).or_default(),
+        };
+        if !ids.contains(id) {
+            ids.insert(id.to_string());
+        }
+    }
+
+    /// Removes a document from the sorted ids of its collection, and the collection and bucket
+    /// once they are empty.
+    fn unindex_id(&self, bucket_name: &str, collection_name: &str, id: &str) {
+        let Some(bucket) = self.sorted_ids.get(bucket_name) else {
+            return;
+        };
+        if let Some(mut ids) = bucket.get_mut(collection_name) {
+            ids.remove(id);
+        }
+        bucket.remove_if(collection_name, |_, ids| ids.is_empty());
+        if bucket.is_empty() {
+            drop(bucket);
+            self.sorted_ids
+                .remove_if(bucket_name, |_, bucket| bucket.is_empty());
+        }
+    }
+
+    /// Stores a document at `version`, or at the version following the one stored before.
+    fn write_document(
+        &self,
+        bucket: &str,
+        collection: &str,
+        document: Document,
+        version: Option<u64>,
+    ) -> Result<(), StorageError> {
+        self.logged(|wal| {
+            // only locked exclusively the first time, writes to existing collections share the maps
+            let bucket_map = get_or_insert(&self.store, bucket);
+            let collection_map = get_or_insert(&bucket_map, collection);
+            let mut value = collection_map.entry(document.id).or_default();
+            let version = version.unwrap_or_else(|| match value.metadata.version {
+                0 => self.removed_version(bucket, collection) + 1,
+                previous => previous + 1,
+            });
+            wal.append(&WalRecord::Set {
+                bucket: bucket.into(),
+                collection: collection.into(),
+                id: value.key().into(),
+                content: Cow::Borrowed(&document.content),
+                version,
+            })?;
+            value.content = document.content;
+            value.metadata.version = version;
+            value.metadata.expires_at = None;
+            self.index_id(bucket, collection, value.key());
+
+            Ok(())
+        })
+    }
+
+    /// Highest version of the documents removed from the collection, `0` if none was.
+    fn removed_version(&self, bucket: &str, collection: &str) -> u64 {
+        self.removed_versions
+            .get(&(bucket.to_string(), collection.to_string()))
+            .map_or(0, |version| *version)
+    }
+
+    /// Records the version of a document being removed, see [`Storage::removed_version`].
+    fn note_removed(&self, bucket: &str, collection: &str, version: u64) {
+        let mut removed = self
+            .removed_versions
+            .entry((bucket.to_string(), collection.to_string()))
+            .or_default();
+        *removed = (*removed).max(version);
+    }
+
+    /// Records the versions of the documents of a collection being removed.
+    fn note_removed_collection(
+        &self,
+        bucket: &str,
+        collection: &str,
+        documents: &DashMap<String, StoredValue>,
+    ) {
+        if let Some(version) = documents.iter().map(|value| value.metadata.version).max() {
+            self.note_removed(bucket, collection, version);
         }
     }
+
+    /// Rebuilds the expiry and id indexes from the documents, once they are loaded from a
+    /// snapshot.
+    fn index_documents(&mut self) {
+        let mut expiries = BTreeSet::new();
+        let sorted_ids = DashMap::new();
+        for bucket in self.store.iter() {
+            let bucket_ids = DashMap::new();
+            for collection in bucket.iter() {
+                let ids = collection.iter().map(|entry| entry.key().clone()).collect();
+                bucket_ids.insert(collection.key().clone(), ids);
+                for document in collection.iter() {
+                    if let Some(expires_at) = document.metadata.expires_at {
+                        expiries.insert((
+                            expires_at,
+                            bucket.key().clone(),
+                            collection.key().clone(),
+                            document.key().clone(),
+                        ));
+                    }
+                }
+            }
+            sorted_ids.insert(bucket.key().clone(), bucket_ids);
+        }
+        self.expiries = Mutex::new(expiries);
+        self.sorted_ids = sorted_ids;
+    }
+
+    /// Every document of a collection as a line of newline-delimited JSON, sorted by id, i.e.
+    /// `{"id":"1","content":"hello"}`. Empty if the collection doesn't exist.
+    ///
+    /// Lines are produced lazily: ids are listed upfront, but each document is only read when its
+    /// line is, so a large collection is never held in memory twice and writes are not blocked
+    /// in between. Documents removed or expired by then are skipped.
+    pub fn export_collection(
+        &self,
+        bucket: &str,
+        collection: &str,
+    ) -> impl Iterator<Item = String> + Send + 'static {
+        let mut ids: Vec<String> = self
+            .store
+            .get(bucket)
+            .and_then(|bucket| {
+                let collection = bucket.get(collection)?;
+                let ids = collection.iter().map(|entry| entry.key().clone()).collect();
+                Some(ids)
+            })
+            .unwrap_or_default();
+        ids.sort();
+
+        let store = self.store.clone();
+        let (bucket, collection) = (bucket.to_string(), collection.to_string());
+        ids.into_iter().filter_map(move |id| {
+            let content = {
+                let bucket = store.get(&bucket)?;
+                let collection = bucket.get(&collection)?;
+                let value = collection.get(&id)?;
+                if value.metadata.is_expired(unix_millis()) {
+                    return None;
+                }
+                value.content.clone()
+            };
+            // only strings, serializing them can't fail
+            serde_json::to_string(&Document { id, content }).ok()
+        })
+    }
 }
 
 impl StorageOperations for Storage {
@@ -91,17 +563,17 @@ impl StorageOperations for Storage {
         collection: &str,
         document: Document,
     ) -> Result<(), StorageError> {
-        let _res = self
-            .store
-            .try_entry(bucket.to_string())
-            .ok_or(StorageError::Locked(EntityType::Bucket))?
-            .or_insert_with(|| DashMap::new())
-            .try_entry(collection.to_string())
-            .ok_or(StorageError::Locked(EntityType::Collection))?
-            .or_insert_with(|| DashMap::new())
-            .insert(document.id, document.content);
+        self.write_document(bucket, collection, document, None)
+    }
 
-        Ok(())
+    fn restore_document(
+        &self,
+        bucket: &str,
+        collection: &str,
+        document: Document,
+        version: u64,
+    ) -> Result<(), StorageError> {
+        self.write_document(bucket, collection, document, Some(version))
     }
 
     fn get_document(
@@ -112,16 +584,31 @@ impl StorageOperations for Storage {
     ) -> Result<Document, StorageError> {
         let bucket = self
             .store
-            .try_get(bucket)
-            .unwrap_storage_error(EntityType::Bucket)?;
+            .get(bucket)
+            .ok_or(StorageError::NotFound(EntityType::Bucket))?;
         let collection = bucket
-            .try_get(collection)
-            .unwrap_storage_error(EntityType::Collection)?;
+            .get(collection)
+            .ok_or(StorageError::NotFound(EntityType::Collection))?;
         let res = collection
-            .try_get(id)
-            .unwrap_storage_error(EntityType::Item)?;
+            .get(id)
+            .ok_or(StorageError::NotFound(EntityType::Item))?;
 
-        Ok(Document::new(id, &res))
+        Ok(Document::new(id, &res.content))
+    }
+
+    fn get_version(&self, bucket: &str, collection: &str, id: &str) -> Result<u64, StorageError> {
+        let bucket = self
+            .store
+            .get(bucket)
+            .ok_or(StorageError::NotFound(EntityType::Bucket))?;
+        let collection = bucket
+            .get(collection)
+            .ok_or(StorageError::NotFound(EntityType::Collection))?;
+        let res = collection
+            .get(id)
+            .ok_or(StorageError::NotFound(EntityType::Item))?;
+
+        Ok(res.metadata.version)
     }
 
     fn delete_document(
@@ -130,55 +617,200 @@ impl StorageOperations for Storage {
         collection_name: &str,
         id: &str,
     ) -> Result<(), StorageError> {
-        let bucket = self
-            .store
-            .try_get(bucket_name)
-            .unwrap_storage_error(EntityType::Bucket)?;
-        let collection = bucket
-            .try_get(collection_name)
-            .unwrap_storage_error(EntityType::Collection)?;
-        collection.remove(id);
+        self.logged(|wal| {
+            let bucket = self
+                .store
+                .get(bucket_name)
+                .ok_or(StorageError::NotFound(EntityType::Bucket))?;
+            let collection = bucket
+                .get(collection_name)
+                .ok_or(StorageError::NotFound(EntityType::Collection))?;
+            // writes hold the log, so the document cannot come back until it is removed
+            if collection.contains_key(id) {
+                wal.append(&WalRecord::Remove {
+                    bucket: bucket_name.into(),
+                    collection: collection_name.into(),
+                    id: id.into(),
+                })?;
+                if let Some((_, value)) = collection.remove(id) {
+                    self.note_removed(bucket_name, collection_name, value.metadata.version);
+                }
+                self.unindex_id(bucket_name, collection_name, id);
+            }
 
-        if collection.is_empty() {
-            drop(collection);
-            bucket.remove(collection_name);
+            if collection.is_empty() {
+                drop(collection);
+                // checked again under the lock, a concurrent write may have filled it up meanwhile
+                bucket.remove_if(collection_name, |_, collection| collection.is_empty());
 
-            if bucket.is_empty() {
-                drop(bucket);
-                self.store.remove(bucket_name);
+                if bucket.is_empty() {
+                    drop(bucket);
+                    self.store
+                        .remove_if(bucket_name, |_, bucket| bucket.is_empty());
+                }
             }
+
+            Ok(())
+        })
+    }
+
+    fn delete_collection(
+        &self,
+        bucket_name: &str,
+        collection_name: &str,
+    ) -> Result<(), StorageError> {
+        self.logged(|wal| {
+            let bucket = self
+                .store
+                .get(bucket_name)
+                .ok_or(StorageError::NotFound(EntityType::Bucket))?;
+            if !bucket.contains_key(collection_name) {
+                return Err(StorageError::NotFound(EntityType::Collection));
+            }
+            wal.append(&WalRecord::DropCollection {
+                bucket: bucket_name.into(),
+                collection: collection_name.into(),
+            })?;
+            if let Some((_, documents)) = bucket.remove(collection_name) {
+                self.note_removed_collection(bucket_name, collection_name, &documents);
+            }
+            drop(bucket);
+            if let Some(bucket_ids) = self.sorted_ids.get(bucket_name) {
+                bucket_ids.remove(collection_name);
+            }
+            self.sorted_ids
+                .remove_if(bucket_name, |_, bucket| bucket.is_empty());
+
+            self.store
+                .remove_if(bucket_name, |_, bucket| bucket.is_empty());
+
+            Ok(())
+        })
+    }
+
+    fn delete_bucket(&self, bucket_name: &str) -> Result<(), StorageError> {
+        self.logged(|wal| {
+            if !self.store.contains_key(bucket_name) {
+                return Err(StorageError::NotFound(EntityType::Bucket));
+            }
+            wal.append(&WalRecord::DropBucket {
+                bucket: bucket_name.into(),
+            })?;
+            if let Some((_, collections)) = self.store.remove(bucket_name) {
+                for collection in collections.iter() {
+                    self.note_removed_collection(bucket_name, collection.key(), &collection);
+                }
+            }
+            self.sorted_ids.remove(bucket_name);
+            Ok(())
+        })
+    }
+
+    fn iter_all(&self) -> Box<dyn Iterator<Item = (String, String, Document)> + '_> {
+        let buckets: Vec<String> = self
+            .store
+            .iter()
+            .map(|bucket| bucket.key().clone())
+            .collect();
+
+        Box::new(buckets.into_iter().flat_map(move |bucket_name| {
+            let mut documents = Vec::new();
+            // the bucket may have been deleted since the names were collected
+            if let Some(bucket) = self.store.get(&bucket_name) {
+                for collection in bucket.iter() {
+                    for value in collection.iter() {
+                        documents.push((
+                            bucket_name.clone(),
+                            collection.key().clone(),
+                            Document::new(value.key(), &value.content),
+                        ));
+                    }
+                }
+            }
+            documents
+        }))
+    }
+
+    fn collection_config(&self, bucket: &str, collection: &str) -> CollectionConfig {
+        let config = self
+            .collection_configs
+            .get(&(bucket.to_string(), collection.to_string()))
+            .map(|config| config.clone())
+            .unwrap_or_default();
+        CollectionConfig {
+            blacklist: self.blacklist(),
+            ..config
         }
+    }
 
-        Ok(())
+    fn set_collection_config(
+        &self,
+        bucket: &str,
+        collection: &str,
+        config: CollectionConfig,
+    ) -> Result<(), StorageError> {
+        self.logged(|wal| {
+            wal.append(&WalRecord::Configure {
+                bucket: bucket.into(),
+                collection: collection.into(),
+                config: Cow::Borrowed(&config),
+            })?;
+            self.collection_configs
+                .insert((bucket.to_string(), collection.to_string()), config);
+            Ok(())
+        })
     }
 
+    /// Writes a snapshot of the storage to disk, compacting the write-ahead log into it.
+    ///
+    /// Returns only after the snapshot is flushed with fsync, so the data survives a crash.
+    /// Collection configs, id counters and the blacklist are logged as they change and saved with
+    /// the snapshot.
     fn persist(&self) -> Result<(), StorageError> {
-        let tmp_path = self.persistence_path.with_extension("zzap_tmp"); // `zzap_tmp` is used to avoid situation where user would name database file with `tmp` extension
+        let _guard = self
+            .persist_lock
+            .lock()
+            .map_err(|_| StorageError::PoisonError)?;
 
-        let mut s = flexbuffers::FlexbufferSerializer::new();
-        self.store
-            .serialize(&mut s)
-            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
-        let serialized = s.take_buffer();
-        std::fs::write(&tmp_path, serialized)
-            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
-        std::fs::rename(&tmp_path, &self.persistence_path)
-            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
-        Ok(())
+        // the log is only dropped once the snapshot is written, changes made while it is being
+        // written go to a new log whether the snapshot holds them or not
+        self.wal.rotate()?;
+        write_snapshot(&self.persistence_path, &*self.store, self.compression)?;
+
+        let collections = CollectionsSnapshot {
+            configs: snapshot_map(&self.collection_configs),
+            id_counters: snapshot_map(&self.id_counters),
+            removed_versions: snapshot_map(&self.removed_versions),
+            blacklist: self.blacklist().iter().cloned().collect(),
+        };
+        write_snapshot(&self.collections_path(), &collections, self.compression)?;
+        self.wal.compact()
     }
 
+    /// Loads the snapshot and the collection configs, so documents are reindexed with the
+    /// settings they were indexed with. Collections missing from the configs use defaults.
+    ///
+    /// The write-ahead log is then replayed, and changes are logged from then on.
     fn load(&mut self) -> Result<(), StorageError> {
-        if !self.persistence_path.exists() {
-            return Ok(());
+        if let Some(store) = read_snapshot::<StorageInner>(&self.persistence_path)? {
+            self.store = Arc::new(store);
+            self.index_documents();
         }
 
-        let serialized = std::fs::read(&self.persistence_path)
-            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
-        let s = flexbuffers::Reader::get_root(&*serialized)
-            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
-        let store: StorageInner = Deserialize::deserialize(s)
-            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
-        self.store = Arc::new(store);
+        let collections: CollectionsSnapshot =
+            read_snapshot(&self.collections_path())?.unwrap_or_default();
+        self.collection_configs = restore_map(collections.configs);
+        self.id_counters = restore_map(collections.id_counters);
+        self.removed_versions = restore_map(collections.removed_versions);
+        self.replace_blacklist(collections.blacklist.into_iter().collect())?;
+
+        if self.persistence_path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        for record in self.wal.replay()? {
+            self.apply(record);
+        }
+        self.wal.enabled = true;
         Ok(())
     }
 
@@ -188,6 +820,142 @@ impl StorageOperations for Storage {
     }
 }
 
+impl Storage {
+    /// Replays a change of the write-ahead log, which may already be in the snapshot.
+    fn apply(&self, record: WalRecord) {
+        // changes to what is gone already are no-ops, the log may hold changes made before
+        // the snapshot
+        let _ = match record {
+            WalRecord::Set {
+                bucket,
+                collection,
+                id,
+                content,
+                version,
+            } => {
+                let bucket_map = get_or_insert(&self.store, &bucket);
+                let collection_map = get_or_insert(&bucket_map, &collection);
+                let mut value = collection_map.entry(id.into_owned()).or_default();
+                value.content = content.into_owned();
+                value.metadata.version = version;
+                value.metadata.expires_at = None;
+                self.index_id(&bucket, &collection, value.key());
+                Ok(())
+            }
+            WalRecord::Expire {
+                bucket,
+                collection,
+                id,
+                expires_at,
+            } => self.set_expiry(&bucket, &collection, &id, expires_at),
+            WalRecord::Remove {
+                bucket,
+                collection,
+                id,
+            } => self.delete_document(&bucket, &collection, &id),
+            WalRecord::DropCollection { bucket, collection } => {
+                self.delete_collection(&bucket, &collection)
+            }
+            WalRecord::DropBucket { bucket } => self.delete_bucket(&bucket),
+            WalRecord::Configure {
+                bucket,
+                collection,
+                config,
+            } => {
+                self.collection_configs.insert(
+                    (bucket.into_owned(), collection.into_owned()),
+                    config.into_owned(),
+                );
+                Ok(())
+            }
+            WalRecord::IdCounter {
+                bucket,
+                collection,
+                counter,
+            } => {
+                let mut current = self
+                    .id_counters
+                    .entry((bucket.into_owned(), collection.into_owned()))
+                    .or_default();
+                *current = counter.max(*current);
+                Ok(())
+            }
+            WalRecord::Blacklist { tokens } => {
+                self.replace_blacklist(tokens.into_iter().map(Cow::into_owned).collect())
+            }
+        };
+    }
+}
+
+/// Per-collection state persisted next to the snapshot of the documents.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CollectionsSnapshot {
+    configs: Vec<(String, String, CollectionConfig)>,
+    id_counters: Vec<(String, String, u64)>,
+    removed_versions: Vec<(String, String, u64)>,
+    blacklist: Vec<String>,
+}
+
+fn snapshot_map<T: Clone>(map: &DashMap<(String, String), T>) -> Vec<(String, String, T)> {
+    map.iter()
+        .map(|entry| {
+            let (bucket, collection) = entry.key();
+            (bucket.clone(), collection.clone(), entry.value().clone())
+        })
+        .collect()
+}
+
+fn restore_map<T>(entries: Vec<(String, String, T)>) -> DashMap<(String, String), T> {
+    entries
+        .into_iter()
+        .map(|(bucket, collection, value)| ((bucket, collection), value))
+        .collect()
+}
+
+/// Serializes `value` to `path` through a temporary file, so a crash never leaves a partial
+/// snapshot behind. Returns only after the data is flushed with fsync.
+fn write_snapshot<T: Serialize + ?Sized>(
+    path: &Path,
+    value: &T,
+    compression: Compression,
+) -> Result<(), StorageError> {
+    let tmp_path = path.with_extension("zzap_tmp"); // `zzap_tmp` is used to avoid situation where user would name database file with `tmp` extension
+
+    let mut s = flexbuffers::FlexbufferSerializer::new();
+    value
+        .serialize(&mut s)
+        .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+    let serialized = compression.compress(s.take_buffer())?;
+    let mut file = std::fs::File::create(&tmp_path)
+        .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+    file.write_all(&serialized)
+        .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+    file.sync_all()
+        .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+    std::fs::rename(&tmp_path, path)
+        .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+
+    // the rename itself is only durable once the parent directory is synced
+    wal::sync_parent_dir(path)
+}
+
+/// Deserializes a snapshot written by [`write_snapshot`], `None` if there is none yet.
+fn read_snapshot<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>, StorageError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let serialized =
+        std::fs::read(path).map_err(|e| StorageError::SerializationError(e.to_string()))?;
+    let serialized = Compression::decompress(serialized)?;
+    let s = flexbuffers::Reader::get_root(&*serialized)
+        .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+    let value =
+        Deserialize::deserialize(s).map_err(|e| StorageError::SerializationError(e.to_string()))?;
+    Ok(Some(value))
+}
+
 impl StorageOperationsInternal for Storage {
     fn store(&self) -> Result<Arc<StorageInner>, StorageError> {
         Ok(self.store.clone())
@@ -232,6 +1000,25 @@ mod tests {
         for (bucket, collection, document) in documents.clone() {
             let doc_from_storage = storage.get_document(bucket, collection, &document.id)?;
             assert_eq!(doc_from_storage.content, document.content);
+
+            let value = storage
+                .store
+                .get(bucket)
+                .and_then(|b| {
+                    b.get(collection)
+                        .map(|c| c.get(&document.id).unwrap().clone())
+                })
+                .unwrap();
+            assert_eq!(
+                value,
+                StoredValue {
+                    content: document.content,
+                    metadata: Metadata {
+                        version: 1,
+                        ..Default::default()
+                    },
+                }
+            );
         }
 
         for (bucket, collection, document) in documents.clone() {
@@ -253,6 +1040,384 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_iter_all() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = Storage::new("");
+        let paths = [
+            ("b1", "c1", "1"),
+            ("b1", "c1", "2"),
+            ("b1", "c2", "1"),
+            ("b2", "c1", "1"),
+        ];
+        let content =
+            |bucket: &str, collection: &str, id: &str| format!("{} {} {}", bucket, collection, id);
+        for (bucket, collection, id) in paths {
+            let document = Document::new(id, &content(bucket, collection, id));
+            storage.add_document(bucket, collection, document)?;
+        }
+
+        let mut all: Vec<(String, String, String, String)> = storage
+            .iter_all()
+            .map(|(bucket, collection, document)| {
+                (bucket, collection, document.id, document.content)
+            })
+            .collect();
+        all.sort();
+
+        let expected: Vec<(String, String, String, String)> = paths
+            .iter()
+            .map(|&(bucket, collection, id)| {
+                (
+                    bucket.to_string(),
+                    collection.to_string(),
+                    id.to_string(),
+                    content(bucket, collection, id),
+                )
+            })
+            .collect();
+        assert_eq!(all, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_documents() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = Storage::new("");
+        for id in ["1", "2", "3", "4", "5"] {
+            storage.add_document("b", "c", Document::new(id, "content"))?;
+        }
+        storage.set_expiry("b", "c", "1", Some(10))?;
+        storage.set_expiry("b", "c", "2", Some(20))?;
+        storage.set_expiry("b", "c", "3", Some(10))?;
+        storage.set_expiry("b", "c", "4", Some(10))?;
+        storage.set_expiry("b", "c", "5", Some(10))?;
+        // the expiry is pushed back, cleared by an overwrite, and gone with the document
+        storage.set_expiry("b", "c", "3", Some(30))?;
+        storage.add_document("b", "c", Document::new("4", "overwritten"))?;
+        storage.delete_document("b", "c", "5")?;
+
+        let expired = |now| {
+            let mut expired = storage.expired_documents(now);
+            expired.sort();
+            expired
+        };
+        let document = |id: &str| ("b".to_string(), "c".to_string(), id.to_string());
+        assert_eq!(expired(9), vec![]);
+        assert_eq!(expired(10), vec![document("1")]);
+        // kept until the document is purged
+        assert_eq!(expired(15), vec![document("1")]);
+        assert_eq!(storage.expiries.lock().unwrap().len(), 3);
+        storage.delete_document("b", "c", "1")?;
+        assert_eq!(expired(u64::MAX), vec![document("2"), document("3")]);
+        assert_eq!(storage.expiries.lock().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ids_after() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = Storage::new("");
+        for id in ["3", "1", "2", "4"] {
+            storage.add_document("b", "c", Document::new(id, "content"))?;
+        }
+        storage.add_document("b", "other", Document::new("1", "content"))?;
+        storage.add_document("b", "c", Document::new("2", "overwritten"))?;
+        storage.delete_document("b", "c", "4")?;
+
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            storage.ids_after("b", "c", None, 2),
+            (ids(&["1", "2"]), true)
+        );
+        assert_eq!(
+            storage.ids_after("b", "c", Some("2"), 2),
+            (ids(&["3"]), false)
+        );
+        assert_eq!(
+            storage.ids_after("b", "c", Some("0"), 3),
+            (ids(&["1", "2", "3"]), false)
+        );
+
+        storage.delete_collection("b", "c")?;
+        assert_eq!(storage.ids_after("b", "c", None, 2), (vec![], false));
+        assert_eq!(
+            storage.ids_after("b", "other", None, 2),
+            (ids(&["1"]), false)
+        );
+        storage.delete_bucket("b")?;
+        assert!(storage.sorted_ids.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions_carry_on_after_removal() -> Result<(), Box<dyn std::error::Error>> {
+        const PERSISTENCE_PATH: &str = "test_versions.db";
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+        storage.add_document("b", "c", Document::new("1", "first"))?;
+        storage.add_document("b", "c", Document::new("1", "second"))?;
+        storage.delete_document("b", "c", "1")?;
+        storage.add_document("b", "c", Document::new("1", "third"))?;
+        assert_eq!(storage.get_version("b", "c", "1")?, 3);
+
+        storage.restore_document("b", "c", Document::new("1", "second"), 2)?;
+        assert_eq!(storage.get_version("b", "c", "1")?, 2);
+        // removed after the snapshot, then through the log
+        storage.persist()?;
+        storage.delete_bucket("b")?;
+        drop(storage);
+
+        for version in [3, 4] {
+            let mut storage = Storage::new(PERSISTENCE_PATH);
+            storage.initialize()?;
+            storage.add_document("b", "c", Document::new("1", "again"))?;
+            assert_eq!(storage.get_version("b", "c", "1")?, version);
+            storage.delete_document("b", "c", "1")?;
+            storage.persist()?;
+        }
+
+        let storage = Storage::new(PERSISTENCE_PATH);
+        for path in [
+            PathBuf::from(PERSISTENCE_PATH),
+            storage.collections_path(),
+            storage.wal.path.clone(),
+        ] {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_collection() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = Storage::new("");
+        let contents = [
+            ("1", "plain"),
+            ("2", "line\nbreak and \"quotes\""),
+            ("3", r#"{"title":"json"}"#),
+            ("4", "expired"),
+            ("5", "removed"),
+        ];
+        for (id, content) in contents {
+            storage.add_document("b", "c", Document::new(id, content))?;
+        }
+        storage.add_document("b", "other", Document::new("6", "elsewhere"))?;
+        storage.set_expiry("b", "c", "4", Some(0))?;
+
+        let mut lines = storage.export_collection("b", "c");
+        // read when its line is produced, not when the export starts
+        storage.delete_document("b", "c", "5")?;
+        let first = lines.next().unwrap();
+        assert_eq!(first, r#"{"id":"1","content":"plain"}"#);
+
+        let exported: Vec<(String, String)> = std::iter::once(first)
+            .chain(lines)
+            .map(|line| {
+                assert!(!line.contains('\n'));
+                let document: Document = serde_json::from_str(&line).unwrap();
+                (document.id, document.content)
+            })
+            .collect();
+        let expected: Vec<(String, String)> = contents[..3]
+            .iter()
+            .map(|&(id, content)| (id.to_string(), content.to_string()))
+            .collect();
+        assert_eq!(exported, expected);
+
+        assert_eq!(storage.export_collection("b", "missing").count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_locks_are_independent() {
+        let storage = Storage::new("");
+        let lock = storage.bucket_lock("a");
+        let guard = lock.write().unwrap();
+
+        assert!(storage.bucket_lock("a").try_read().is_err());
+        assert!(storage.bucket_lock("b").try_write().is_ok());
+
+        drop(guard);
+        assert!(storage.bucket_lock("a").try_read().is_ok());
+    }
+
+    #[test]
+    fn test_storage_load_legacy_format() -> Result<(), Box<dyn std::error::Error>> {
+        const PERSISTENCE_PATH: &str = "test_legacy.db";
+
+        // before `StoredValue`, documents were persisted as bare strings
+        let legacy: DashMap<String, DashMap<String, DashMap<String, String>>> = DashMap::new();
+        legacy
+            .entry("bucket".to_string())
+            .or_default()
+            .entry("collection".to_string())
+            .or_default()
+            .insert("id".to_string(), "content".to_string());
+
+        let mut s = flexbuffers::FlexbufferSerializer::new();
+        legacy.serialize(&mut s)?;
+        std::fs::write(PERSISTENCE_PATH, s.take_buffer())?;
+
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+        std::fs::remove_file(PERSISTENCE_PATH)?;
+
+        let document = storage.get_document("bucket", "collection", "id")?;
+        assert_eq!(document.content, "content");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_compressed_persistence() -> Result<(), Box<dyn std::error::Error>> {
+        const PERSISTENCE_PATH: &str = "test_compressed.db";
+        let content = "a document compressing well ".repeat(100);
+
+        let mut plain_len = None;
+        for compression in [
+            Compression::None,
+            Compression::Zstd(DEFAULT_ZSTD_LEVEL),
+            Compression::Deflate(DEFAULT_DEFLATE_LEVEL),
+        ] {
+            let storage = Storage::new(PERSISTENCE_PATH).with_compression(compression);
+            storage.add_document("bucket", "collection", Document::new("1", &content))?;
+            storage.persist()?;
+            let len = std::fs::metadata(PERSISTENCE_PATH)?.len();
+            match plain_len {
+                None => plain_len = Some(len),
+                Some(plain_len) => assert!(len < plain_len / 4, "{}", compression),
+            }
+
+            // loaded whatever the storage loading it compresses with
+            let mut storage = Storage::new(PERSISTENCE_PATH);
+            storage.initialize()?;
+            let document = storage.get_document("bucket", "collection", "1")?;
+            assert_eq!(document.content, content);
+        }
+        std::fs::remove_file(PERSISTENCE_PATH)?;
+        std::fs::remove_file(Storage::new(PERSISTENCE_PATH).collections_path())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collection_config_persistence() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::search::{SearchEngine, StdSearchEngine};
+
+        const PERSISTENCE_PATH: &str = "test_config.db";
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+
+        let mut config = CollectionConfig::default();
+        config.set("DEACCENT", "true")?;
+        config.set("MINTOKENS", "2")?;
+        storage.set_collection_config("bucket", "accented", config.clone())?;
+        storage.add_document("bucket", "accented", Document::new("1", "un Résumé"))?;
+        storage.add_document("bucket", "plain", Document::new("1", "un Résumé"))?;
+        storage.persist()?;
+
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+        std::fs::remove_file(PERSISTENCE_PATH)?;
+        std::fs::remove_file(storage.collections_path())?;
+
+        assert_eq!(storage.collection_config("bucket", "accented"), config);
+        // never configured, so defaults
+        assert_eq!(
+            storage.collection_config("bucket", "plain"),
+            CollectionConfig::default()
+        );
+
+        let engine = StdSearchEngine::new();
+        engine.initialize(&storage)?;
+        assert_eq!(engine.search("bucket", "accented", "resume")?, ["1"]);
+        assert!(engine.search("bucket", "plain", "resume")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_id() -> Result<(), Box<dyn std::error::Error>> {
+        const PERSISTENCE_PATH: &str = "test_ids.db";
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+
+        for expected in ["1", "2"] {
+            let id = storage.generate_id("bucket", "collection")?;
+            assert_eq!(id, expected);
+            storage.add_document("bucket", "collection", Document::new(&id, "content"))?;
+        }
+        // counters are per collection
+        assert_eq!(storage.generate_id("bucket", "other")?, "1");
+        // taken by a document with an explicit id
+        storage.add_document("bucket", "collection", Document::new("3", "content"))?;
+        assert_eq!(storage.generate_id("bucket", "collection")?, "4");
+        storage.persist()?;
+
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+        std::fs::remove_file(PERSISTENCE_PATH)?;
+        std::fs::remove_file(storage.collections_path())?;
+
+        // "4" was never stored, but it was handed out already
+        assert_eq!(storage.generate_id("bucket", "collection")?, "5");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_id_after_crash() -> Result<(), Box<dyn std::error::Error>> {
+        const PERSISTENCE_PATH: &str = "test_ids_wal.db";
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+
+        let id = storage.generate_id("bucket", "collection")?;
+        assert_eq!(id, "1");
+        storage.add_document("bucket", "collection", Document::new(&id, "content"))?;
+        storage.delete_document("bucket", "collection", &id)?;
+        let wal_path = storage.wal.path.clone();
+        // the process dies before any snapshot is written
+        drop(storage);
+
+        let mut recovered = Storage::new(PERSISTENCE_PATH);
+        recovered.initialize()?;
+        assert_eq!(recovered.generate_id("bucket", "collection")?, "2");
+
+        for path in [
+            PathBuf::from(PERSISTENCE_PATH),
+            recovered.collections_path(),
+            wal_path,
+        ] {
+            let _ = std::fs::remove_file(path);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_collection() -> Result<(), Box<dyn std::error::Error>> {
+        let storage = Storage::new("test.db");
+        storage.add_document("bucket", "dropped", Document::new("1", "content"))?;
+        storage.add_document("bucket", "dropped", Document::new("2", "content"))?;
+        storage.add_document("bucket", "kept", Document::new("1", "content"))?;
+
+        storage.delete_collection("bucket", "dropped")?;
+        assert!(storage.get_document("bucket", "dropped", "1").is_err());
+        assert!(storage.get_document("bucket", "kept", "1").is_ok());
+        assert_eq!(
+            storage.delete_collection("bucket", "dropped"),
+            Err(StorageError::NotFound(EntityType::Collection))
+        );
+
+        // the last collection takes the bucket with it
+        storage.delete_collection("bucket", "kept")?;
+        assert!(storage.store.is_empty());
+        assert_eq!(
+            storage.delete_collection("bucket", "kept"),
+            Err(StorageError::NotFound(EntityType::Bucket))
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_storage_load_without_persistence_path() -> Result<(), Box<dyn std::error::Error>> {
         let mut storage = Storage::new("");
@@ -260,4 +1425,135 @@ mod tests {
         assert!(res.is_ok());
         Ok(())
     }
+
+    #[test]
+    fn test_wal_recovers_acknowledged_writes() -> Result<(), Box<dyn std::error::Error>> {
+        use std::fs::OpenOptions;
+
+        const PERSISTENCE_PATH: &str = "test_wal.db";
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+        storage.add_document("bucket", "kept", Document::new("1", "snapshotted"))?;
+        storage.persist()?;
+
+        storage.add_document("bucket", "kept", Document::new("1", "overwritten"))?;
+        storage.add_document("bucket", "kept", Document::new("2", "logged"))?;
+        // a persist stopping once the log is moved aside, before the snapshot is written
+        storage.wal.rotate()?;
+        storage.add_document("bucket", "dropped", Document::new("1", "content"))?;
+        storage.delete_collection("bucket", "dropped")?;
+        storage.add_document("other", "collection", Document::new("1", "content"))?;
+        storage.delete_document("other", "collection", "1")?;
+        storage.set_expiry("bucket", "kept", "2", Some(u64::MAX))?;
+        let mut config = CollectionConfig::default();
+        config.set("STEM", "true")?;
+        storage.set_collection_config("bucket", "kept", config.clone())?;
+        let wal_path = storage.wal.path.clone();
+        drop(storage);
+
+        // the process dies in the middle of appending a record
+        OpenOptions::new()
+            .append(true)
+            .open(&wal_path)?
+            .write_all(&[42, 0, 0, 0, 1, 2, 3])?;
+
+        let mut recovered = Storage::new(PERSISTENCE_PATH);
+        recovered.initialize()?;
+        let document = recovered.get_document("bucket", "kept", "1")?;
+        assert_eq!(document.content, "overwritten");
+        assert_eq!(recovered.get_version("bucket", "kept", "1")?, 2);
+        assert_eq!(
+            recovered.get_document("bucket", "kept", "2")?.content,
+            "logged"
+        );
+        assert!(!recovered.is_expired("bucket", "kept", "2", unix_millis()));
+        assert!(recovered.is_expired("bucket", "kept", "2", u64::MAX));
+        assert_eq!(
+            recovered.expired_documents(u64::MAX),
+            vec![("bucket".to_string(), "kept".to_string(), "2".to_string())]
+        );
+        assert!(matches!(
+            recovered.get_document("bucket", "dropped", "1"),
+            Err(StorageError::NotFound(EntityType::Collection))
+        ));
+        assert!(recovered.store.get("other").is_none());
+        // settings are back before the documents are reindexed
+        assert_eq!(recovered.collection_config("bucket", "kept"), config);
+
+        // the torn record is dropped, so writes made after recovery are read back too
+        recovered.add_document("bucket", "kept", Document::new("3", "recovered"))?;
+        drop(recovered);
+        let mut recovered = Storage::new(PERSISTENCE_PATH);
+        recovered.initialize()?;
+        assert_eq!(
+            recovered.get_document("bucket", "kept", "3")?.content,
+            "recovered"
+        );
+
+        // the rotated log is compacted into the snapshot
+        recovered.persist()?;
+        assert!(!recovered.wal.rotated_path().exists());
+        let mut reloaded = Storage::new(PERSISTENCE_PATH);
+        reloaded.initialize()?;
+        assert_eq!(reloaded.stats().documents, 3);
+        // expiries loaded from the snapshot are indexed again
+        assert_eq!(reloaded.expired_documents(u64::MAX).len(), 1);
+        assert_eq!(reloaded.ids_after("bucket", "kept", None, 10).0.len(), 3);
+
+        for path in [
+            PathBuf::from(PERSISTENCE_PATH),
+            reloaded.collections_path(),
+            wal_path,
+        ] {
+            let _ = std::fs::remove_file(path);
+        }
+
+        Ok(())
+    }
+    #[test]
+    fn test_wal_flushes_concurrent_writes() -> Result<(), Box<dyn std::error::Error>> {
+        const PERSISTENCE_PATH: &str = "test_wal_concurrent.db";
+        let mut storage = Storage::new(PERSISTENCE_PATH);
+        storage.initialize()?;
+
+        // writers share flushes, each returns once its own record is on disk
+        std::thread::scope(|scope| {
+            for writer in 0..8 {
+                let storage = &storage;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let id = format!("{}-{}", writer, i);
+                        storage
+                            .add_document("bucket", "collection", Document::new(&id, "content"))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        let wal_path = storage.wal.path.clone();
+        drop(storage);
+
+        let mut recovered = Storage::new(PERSISTENCE_PATH);
+        recovered.initialize()?;
+        assert_eq!(recovered.stats().documents, 400);
+
+        let _ = std::fs::remove_file(wal_path);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_wal_refuses_records_after_a_failed_write() {
+        // every write to it fails, and it can't be truncated back
+        let mut wal = Wal::new(PathBuf::from("/dev/full"));
+        wal.enabled = true;
+        let record = WalRecord::DropBucket {
+            bucket: "bucket".into(),
+        };
+
+        let result = wal.lock().unwrap().append(&record);
+        assert!(matches!(result, Err(StorageError::SerializationError(_))));
+        let result = wal.lock().unwrap().append(&record);
+        assert!(matches!(result, Err(StorageError::OperationFailed(_))));
+    }
 }
@@ -0,0 +1,57 @@
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Metadata stored next to the document content.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    /// Incremented every time the document is written, starting at 1 or past the versions of the
+    /// documents removed from the collection.
+    pub version: u64,
+    /// Milliseconds since the Unix epoch from which the document is treated as absent, see
+    /// [`unix_millis`](super::unix_millis). Cleared by every write.
+    pub expires_at: Option<u64>,
+}
+
+impl Metadata {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Value stored in the storage for each document id.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StoredValue {
+    pub content: String,
+    pub metadata: Metadata,
+}
+
+impl StoredValue {
+    pub fn new(content: String) -> Self {
+        StoredValue {
+            content,
+            metadata: Metadata::default(),
+        }
+    }
+}
+
+// Persistence files written before `StoredValue` existed contain bare `String` values,
+// so both representations are accepted when loading.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredValueRepr {
+    Legacy(String),
+    Current {
+        content: String,
+        #[serde(default)]
+        metadata: Metadata,
+    },
+}
+
+impl<'de> Deserialize<'de> for StoredValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match StoredValueRepr::deserialize(deserializer)? {
+            StoredValueRepr::Legacy(content) => StoredValue::new(content),
+            StoredValueRepr::Current { content, metadata } => StoredValue { content, metadata },
+        })
+    }
+}
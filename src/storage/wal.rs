@@ -0,0 +1,379 @@
+//! Write-ahead log of the changes made to the storage since its last snapshot.
+//!
+//! Every record is written before the change it describes is applied, and flushed to disk before
+//! the change is acknowledged, so a change is never acknowledged before it is durable. Changes
+//! made meanwhile are flushed together, the log is not held while it is being flushed. Loading
+//! replays the log on top of the snapshot.
+//!
+//! Records are framed as their length and the first bytes of their SHA-256, both little-endian,
+//! then the flexbuffers record itself. A crash in the middle of a write leaves a torn record at
+//! the end of the log, which is dropped on the next load. A write that fails is cut from the log
+//! right away, and once a write can't be cut or a flush fails, what the log holds is unknown: it
+//! refuses further records until the storage is loaded again.
+
+use super::{CollectionConfig, StorageError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+const CHECKSUM_LEN: usize = 4;
+const HEADER_LEN: usize = 4 + CHECKSUM_LEN;
+
+/// A change to the storage. Replaying a record twice leaves the storage as replaying it once, so
+/// a log may overlap the snapshot it is replayed on.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(super) enum WalRecord<'a> {
+    /// The document is stored with the content, at the version
+    Set {
+        bucket: Cow<'a, str>,
+        collection: Cow<'a, str>,
+        id: Cow<'a, str>,
+        content: Cow<'a, str>,
+        version: u64,
+    },
+    Expire {
+        bucket: Cow<'a, str>,
+        collection: Cow<'a, str>,
+        id: Cow<'a, str>,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        bucket: Cow<'a, str>,
+        collection: Cow<'a, str>,
+        id: Cow<'a, str>,
+    },
+    DropCollection {
+        bucket: Cow<'a, str>,
+        collection: Cow<'a, str>,
+    },
+    DropBucket {
+        bucket: Cow<'a, str>,
+    },
+    /// The collection is configured with `CONFIGURE`
+    Configure {
+        bucket: Cow<'a, str>,
+        collection: Cow<'a, str>,
+        config: Cow<'a, CollectionConfig>,
+    },
+    /// `ADD` generated the id `counter` in the collection
+    IdCounter {
+        bucket: Cow<'a, str>,
+        collection: Cow<'a, str>,
+        counter: u64,
+    },
+    /// The blacklist is replaced, by `BLACKLIST REGENERATE` or `BLACKLIST CLEAR`
+    Blacklist {
+        tokens: Vec<Cow<'a, str>>,
+    },
+}
+
+pub(super) struct Wal {
+    pub(super) path: PathBuf,
+    /// Set once the storage is loaded, changes made before are not logged
+    pub(super) enabled: bool,
+    file: Mutex<LogFile>,
+    /// How much of the log is on disk, appends wait for it to be past their records
+    synced: Mutex<Synced>,
+    synced_changed: Condvar,
+    /// Set once a record could not be written or flushed, records are refused from then on
+    failed: AtomicBool,
+}
+
+#[derive(Default)]
+struct LogFile {
+    /// Opened on the first record, so a storage that is only read never creates the log
+    file: Option<File>,
+    /// Length of the records the file holds in full
+    len: u64,
+    /// Bytes appended since the log was created, across rotations
+    appended: u64,
+}
+
+#[derive(Default)]
+struct Synced {
+    /// Position in the appended bytes up to which records are on disk
+    up_to: u64,
+    /// Whether an append is flushing the log, for the others as well
+    flushing: bool,
+}
+
+/// Holds the log, so changes are applied in the order they are logged in.
+pub(super) struct WalGuard<'a> {
+    wal: &'a Wal,
+    file: MutexGuard<'a, LogFile>,
+    /// Position of the end of the records appended through the guard
+    written: Option<u64>,
+}
+
+impl WalGuard<'_> {
+    /// Appends the record, to be flushed with [`Wal::sync`] once the log is released. Does nothing
+    /// unless the log is enabled.
+    pub fn append(&mut self, record: &WalRecord) -> Result<(), StorageError> {
+        if !self.wal.enabled {
+            return Ok(());
+        }
+        if self.wal.failed.load(Ordering::SeqCst) {
+            return Err(failed_error());
+        }
+
+        let mut s = flexbuffers::FlexbufferSerializer::new();
+        record
+            .serialize(&mut s)
+            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+        let payload = s.take_buffer();
+        let mut framed = Vec::with_capacity(HEADER_LEN + payload.len());
+        framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        framed.extend_from_slice(&checksum(&payload));
+        framed.extend_from_slice(&payload);
+
+        let LogFile {
+            file,
+            len,
+            appended,
+        } = &mut *self.file;
+        let file = match file {
+            Some(file) => file,
+            file => {
+                let opened = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.wal.path)
+                    .map_err(io_error)?;
+                *len = opened.metadata().map_err(io_error)?.len();
+                file.insert(opened)
+            }
+        };
+        if let Err(e) = file.write_all(&framed) {
+            // the next record must not follow a torn one, replaying would stop short of it
+            if file.set_len(*len).is_err() {
+                self.wal.failed.store(true, Ordering::SeqCst);
+            }
+            return Err(io_error(e));
+        }
+        *len += framed.len() as u64;
+        *appended += framed.len() as u64;
+        self.written = Some(*appended);
+        Ok(())
+    }
+
+    /// Position [`Wal::sync`] must reach for the records appended through the guard to be on
+    /// disk, `None` if there are none.
+    pub fn written(&self) -> Option<u64> {
+        self.written
+    }
+}
+
+impl Wal {
+    pub fn new(path: PathBuf) -> Self {
+        Wal {
+            path,
+            enabled: false,
+            file: Mutex::new(LogFile::default()),
+            synced: Mutex::new(Synced::default()),
+            synced_changed: Condvar::new(),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Log of the changes made before the snapshot being written, kept until it is complete.
+    pub(super) fn rotated_path(&self) -> PathBuf {
+        self.path.with_extension("zzap_wal_old")
+    }
+
+    pub fn lock(&self) -> Result<WalGuard<'_>, StorageError> {
+        Ok(WalGuard {
+            wal: self,
+            file: self.file.lock().map_err(|_| StorageError::PoisonError)?,
+            written: None,
+        })
+    }
+
+    /// Waits for the log to be on disk up to the position, see [`WalGuard::written`]. Flushes it
+    /// unless another append already is, in which case it waits for that flush and flushes what
+    /// was appended meanwhile only if it is still needed.
+    pub fn sync(&self, position: u64) -> Result<(), StorageError> {
+        let mut synced = self.synced.lock().map_err(|_| StorageError::PoisonError)?;
+        loop {
+            if self.failed.load(Ordering::SeqCst) {
+                return Err(failed_error());
+            }
+            if synced.up_to >= position {
+                return Ok(());
+            }
+            if !synced.flushing {
+                break;
+            }
+            synced = self
+                .synced_changed
+                .wait(synced)
+                .map_err(|_| StorageError::PoisonError)?;
+        }
+        synced.flushing = true;
+        drop(synced);
+
+        let flushed = self.flush();
+
+        let mut synced = self.synced.lock().map_err(|_| StorageError::PoisonError)?;
+        synced.flushing = false;
+        match flushed {
+            Ok(up_to) => synced.up_to = synced.up_to.max(up_to),
+            Err(_) => self.failed.store(true, Ordering::SeqCst),
+        }
+        self.synced_changed.notify_all();
+        flushed.map(|_| ())
+    }
+
+    /// Flushes the current log without holding it, returning the position it is on disk up to.
+    fn flush(&self) -> Result<u64, StorageError> {
+        let (file, appended) = {
+            let log = self.file.lock().map_err(|_| StorageError::PoisonError)?;
+            let file = log.file.as_ref().map(File::try_clone).transpose();
+            (file.map_err(io_error)?, log.appended)
+        };
+        // a log rotated meanwhile was flushed before it was moved aside
+        if let Some(file) = file {
+            file.sync_data().map_err(io_error)?;
+        }
+        Ok(appended)
+    }
+
+    /// Records of the rotated log, then of the current one.
+    ///
+    /// Reading stops at the first torn or corrupted record, which the current log is truncated
+    /// to, so later records are not appended after it.
+    pub fn replay(&self) -> Result<Vec<WalRecord<'static>>, StorageError> {
+        let mut records = Vec::new();
+        read_records(&self.rotated_path(), &mut records)?;
+        let valid_len = read_records(&self.path, &mut records)?;
+        if let Some(valid_len) = valid_len {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .and_then(|file| file.set_len(valid_len))
+                .map_err(io_error)?;
+        }
+        Ok(records)
+    }
+
+    /// Moves the current log aside before a snapshot is written, changes made meanwhile start a
+    /// new log.
+    ///
+    /// A log left aside by a snapshot that failed is kept rather than overwritten, the current
+    /// one then goes on until the next snapshot.
+    pub fn rotate(&self) -> Result<(), StorageError> {
+        let mut log = self.file.lock().map_err(|_| StorageError::PoisonError)?;
+        if !self.enabled || !self.path.exists() || self.rotated_path().exists() {
+            return Ok(());
+        }
+
+        // appends waiting for their records to be on disk no longer see this file
+        if let Some(file) = log.file.take()
+            && let Err(e) = file.sync_data()
+        {
+            self.failed.store(true, Ordering::SeqCst);
+            return Err(io_error(e));
+        }
+        log.len = 0;
+        std::fs::rename(&self.path, self.rotated_path()).map_err(io_error)?;
+        sync_parent_dir(&self.path)
+    }
+
+    /// Drops the logs whose changes the snapshot just written holds: the rotated one, and the
+    /// current one too if the log is not enabled, as a storage that was never loaded wrote
+    /// everything it knows.
+    pub fn compact(&self) -> Result<(), StorageError> {
+        let _file = self.file.lock().map_err(|_| StorageError::PoisonError)?;
+        remove_if_exists(&self.rotated_path())?;
+        if !self.enabled {
+            remove_if_exists(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends the valid records of the log to `records`, returning the length they take if the
+/// log goes on with an invalid one. Nothing is read if there is no log.
+fn read_records(
+    path: &Path,
+    records: &mut Vec<WalRecord<'static>>,
+) -> Result<Option<u64>, StorageError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(io_error(e)),
+    };
+
+    let mut offset = 0;
+    while offset < bytes.len() {
+        match decode_record(&bytes[offset..]) {
+            Some((record, len)) => {
+                records.push(record);
+                offset += len;
+            }
+            None => return Ok(Some(offset as u64)),
+        }
+    }
+    Ok(None)
+}
+
+/// The record at the start of the bytes and the length it takes, `None` if it is incomplete or
+/// does not match its checksum.
+fn decode_record(bytes: &[u8]) -> Option<(WalRecord<'static>, usize)> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().ok()?) as usize;
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN.checked_add(len)?)?;
+    if header[4..] != checksum(payload) {
+        return None;
+    }
+
+    let reader = flexbuffers::Reader::get_root(payload).ok()?;
+    let record = WalRecord::deserialize(reader).ok()?;
+    Some((record, HEADER_LEN + len))
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(payload);
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&digest[..CHECKSUM_LEN]);
+    checksum
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), StorageError> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(io_error(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Makes the creation, rename or removal of a file in the directory durable.
+pub(super) fn sync_parent_dir(path: &Path) -> Result<(), StorageError> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .map_err(io_error)?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+fn failed_error() -> StorageError {
+    StorageError::OperationFailed(
+        "the write-ahead log failed, changes are refused until the storage is reloaded".to_string(),
+    )
+}
+
+fn io_error(e: std::io::Error) -> StorageError {
+    StorageError::SerializationError(e.to_string())
+}
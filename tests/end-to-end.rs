@@ -1,7 +1,7 @@
 #![allow(unexpected_cfgs)] // to avoid warnings about missing tarpaulin cfg attributes
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::net::TcpStream;
 use std::process::{Child, Command};
 use std::time::Duration;
@@ -63,15 +63,20 @@ fn read_response(stream: &mut TcpStream) -> Result<String, Box<dyn Error>> {
     reader.read_until(b'\n', &mut buffer)?;
     let response = String::from_utf8(buffer)?;
 
-    // if response is number, parse it as int N and read N lines
-    if let Ok(n) = response.trim().parse::<usize>() {
-        let mut lines: Vec<String> = vec![response.clone()];
+    // if response is an array `*N`, read its N length-prefixed items
+    if let Some(Ok(n)) = response.trim().strip_prefix('*').map(str::parse::<usize>) {
+        let mut buffer = response.into_bytes();
         for _ in 0..n {
-            let mut buffer = Vec::new();
+            let header_start = buffer.len();
             reader.read_until(b'\n', &mut buffer)?;
-            lines.push(String::from_utf8(buffer)?);
+            let len: usize =
+                std::str::from_utf8(&buffer[header_start + 1..buffer.len() - 1])?.parse()?;
+            // the item and its closing newline
+            let mut item = vec![0; len + 1];
+            reader.read_exact(&mut item)?;
+            buffer.extend_from_slice(&item);
         }
-        return Ok(lines.join(""));
+        return Ok(String::from_utf8(buffer)?);
     }
 
     Ok(response)
@@ -124,7 +129,7 @@ async fn e2e_simple() -> Result<(), Box<dyn Error>> {
     command!(
         &mut stream,
         "SEARCH default test_collection test123",
-        "1\ntest_id\n"
+        "*1\n$7\ntest_id\n"
     );
 
     Ok(())
@@ -144,19 +149,23 @@ async fn e2e_index_cleans_properly() -> Result<(), Box<dyn Error>> {
     command!(&mut stream, "SET default articles 42 test_article", "+OK\n");
     command!(&mut stream, "SET default articles 42 other_word", "+OK\n");
 
-    command!(&mut stream, "SEARCH default articles test_article", "0\n");
-    command!(&mut stream, "SEARCH default articles other_word", "1\n42\n");
+    command!(&mut stream, "SEARCH default articles test_article", "*0\n");
+    command!(
+        &mut stream,
+        "SEARCH default articles other_word",
+        "*1\n$2\n42\n"
+    );
 
     command!(&mut stream, "REMOVE default articles 42", "+OK\n");
 
-    command!(&mut stream, "SEARCH default articles test_article", "0\n");
-    command!(&mut stream, "SEARCH default articles other_word", "0\n");
+    command!(&mut stream, "SEARCH default articles test_article", "*0\n");
+    command!(&mut stream, "SEARCH default articles other_word", "*0\n");
 
     command!(&mut stream, "SET default articles 5 first second", "+OK\n");
     command!(&mut stream, "SET default articles 6 first", "+OK\n");
 
     command_predicate!(&mut stream, "SEARCH default articles first", |resp| {
-        resp == "2\n5\n6\n" || resp == "2\n6\n5\n"
+        resp == "*2\n$1\n5\n$1\n6\n" || resp == "*2\n$1\n6\n$1\n5\n"
     });
 
     Ok(())
@@ -277,7 +286,7 @@ async fn e2e_lot_of_clients() -> Result<(), Box<dyn Error>> {
             command!(
                 stream,
                 format!("SEARCH default articles {}", article_name).as_str(),
-                format!("1\n{}\n", article_id)
+                format!("*1\n${}\n{}\n", article_id.to_string().len(), article_id)
             );
         }
 